@@ -0,0 +1,386 @@
+use ffmpeg::{
+    codec, filter, format::context::Input, frame, media::Type, util::frame::video::Video, Packet,
+    Rational,
+};
+
+use ffmpeg::Error as FFmpegError;
+
+use log::debug;
+
+use crate::decode_video::{open_source, VideoDecoderError};
+
+#[derive(Debug)]
+pub enum AudioError {
+    FFMpegError(FFmpegError),
+    NoAudioStream,
+    // The filter graph finished without producing the requested measurement
+    NoMeasurement,
+    // The linked FFMPEG cannot encode the audio stream's codec
+    NoEncoder,
+    // The source could not be opened, e.g. a URL of a protocol the host does not allow
+    OpenError(VideoDecoderError),
+}
+
+impl From<FFmpegError> for AudioError {
+    fn from(value: FFmpegError) -> Self {
+        AudioError::FFMpegError(value)
+    }
+}
+
+impl From<VideoDecoderError> for AudioError {
+    fn from(value: VideoDecoderError) -> Self {
+        AudioError::OpenError(value)
+    }
+}
+
+/// Images FFMPEG can render from a whole audio track
+#[derive(Debug, Copy, Clone)]
+pub enum AudioVisualisation {
+    Waveform,
+    Spectrogram,
+}
+
+impl AudioVisualisation {
+    fn filter_spec(&self, width: u32, height: u32) -> String {
+        match self {
+            AudioVisualisation::Waveform => {
+                format!("showwavespic=s={width}x{height},format=rgb24")
+            }
+            AudioVisualisation::Spectrogram => {
+                format!("showspectrumpic=s={width}x{height}:legend=0,format=rgb24")
+            }
+        }
+    }
+}
+
+/// EBU R128 loudness of a whole audio track
+#[derive(Debug, Copy, Clone)]
+pub struct Loudness {
+    // Integrated loudness in LUFS
+    pub integrated: f32,
+    // Highest true peak across channels in dBFS
+    pub true_peak: f32,
+    // Loudness range in LU
+    pub range: f32,
+}
+
+/// Best audio stream of a source kept as its encoded packets, so the encoder can mux it back
+/// unchanged (stream copy) next to the re-encoded video
+#[derive(Clone)]
+pub struct AudioTrack {
+    pub parameters: codec::Parameters,
+    // Time base the packets' timestamps are in
+    pub time_base: Rational,
+    pub packets: Vec<Packet>,
+}
+
+impl AudioTrack {
+    /// The best audio stream of `ictx` without any packets yet, `None` if it has no audio
+    pub fn of_input(ictx: &Input) -> Option<(usize, Self)> {
+        let stream = ictx.streams().best(Type::Audio)?;
+        let time_base = stream.time_base()?;
+        Some((
+            stream.index(),
+            AudioTrack {
+                parameters: stream.parameters(),
+                time_base,
+                packets: Vec::new(),
+            },
+        ))
+    }
+}
+
+// Best audio stream of a source, decoded into the "in" buffer of a filter graph
+struct AudioGraph {
+    ictx: Input,
+    decoder: ffmpeg::decoder::Audio,
+    audio_stream_index: usize,
+    // Time base of the audio stream, which the frames going through the graph are stamped in
+    time_base: Rational,
+    graph: filter::Graph,
+}
+
+impl AudioGraph {
+    /// Opens `filename` and connects its audio to `sink_filter` through the filters of `spec`
+    fn open(filename: &str, spec: &str, sink_filter: &str) -> Result<Self, AudioError> {
+        let mut audio_graph = Self::open_input(filename)?;
+        audio_graph.connect(spec, sink_filter)?;
+        Ok(audio_graph)
+    }
+
+    /// Opens `filename` with its audio decoder, the graph being connected by `connect`
+    fn open_input(filename: &str) -> Result<Self, AudioError> {
+        ffmpeg::init()?;
+
+        let ictx = open_source(filename, None)?;
+        let input = ictx
+            .streams()
+            .best(Type::Audio)
+            .ok_or(AudioError::NoAudioStream)?;
+        let audio_stream_index = input.index();
+        let decoder = input.decoder()?.audio()?;
+
+        let time_base = input
+            .time_base()
+            .unwrap_or(Rational::new(1, decoder.rate() as i32));
+
+        Ok(AudioGraph {
+            ictx,
+            decoder,
+            audio_stream_index,
+            time_base,
+            graph: filter::Graph::new(),
+        })
+    }
+
+    /// Connects the decoded audio to `sink_filter` through the filters of `spec`
+    fn connect(&mut self, spec: &str, sink_filter: &str) -> Result<(), AudioError> {
+        let buffer_args = format!(
+            "time_base={}:sample_rate={}:sample_fmt={}:channel_layout=0x{:x}",
+            self.time_base,
+            self.decoder.rate(),
+            self.decoder.format().name(),
+            self.decoder.channel_layout().bits()
+        );
+        debug!("Audio Buffer Args {buffer_args}");
+
+        let graph = &mut self.graph;
+        graph.add(
+            &filter::find("abuffer").ok_or(FFmpegError::FilterNotFound)?,
+            "in",
+            &buffer_args,
+        )?;
+        graph.add(
+            &filter::find(sink_filter).ok_or(FFmpegError::FilterNotFound)?,
+            "out",
+            "",
+        )?;
+        graph.output("in", 0)?.input("out", 0)?.parse(spec)?;
+        graph.validate()?;
+
+        Ok(())
+    }
+
+    /// Decodes the whole audio stream into the graph, calling `drain` after every push
+    /// (and once after the final flush) so it can pull whatever the graph produced
+    fn run(
+        &mut self,
+        mut drain: impl FnMut(&mut filter::Graph) -> Result<(), FFmpegError>,
+    ) -> Result<(), AudioError> {
+        let AudioGraph {
+            ictx,
+            decoder,
+            audio_stream_index,
+            graph,
+            ..
+        } = self;
+
+        let mut send_decoded_samples =
+            |decoder: &mut ffmpeg::decoder::Audio| -> Result<(), FFmpegError> {
+                let mut decoded = frame::Audio::empty();
+                while decoder.receive_frame(&mut decoded).is_ok() {
+                    graph
+                        .get("in")
+                        .ok_or(FFmpegError::FilterNotFound)?
+                        .source()
+                        .add(&decoded)?;
+                    drain(graph)?;
+                }
+                Ok(())
+            };
+
+        for res in ictx.packets() {
+            let (stream, packet) = res?;
+            if stream.index() == *audio_stream_index {
+                decoder.send_packet(&packet)?;
+                send_decoded_samples(decoder)?;
+            }
+        }
+        decoder.send_eof()?;
+        send_decoded_samples(decoder)?;
+
+        graph
+            .get("in")
+            .ok_or(FFmpegError::FilterNotFound)?
+            .source()
+            .flush()?;
+        drain(graph)?;
+
+        Ok(())
+    }
+}
+
+/// Decodes the best audio stream of `filename` and renders it into a single RGB24 image
+pub fn render_visualisation(
+    filename: &str,
+    visualisation: AudioVisualisation,
+    width: u32,
+    height: u32,
+) -> Result<Video, AudioError> {
+    let mut audio_graph = AudioGraph::open(
+        filename,
+        &visualisation.filter_spec(width, height),
+        "buffersink",
+    )?;
+
+    // The picture is only rendered once the whole track went through the graph
+    audio_graph.run(|_| Ok(()))?;
+
+    let mut picture = Video::empty();
+    audio_graph
+        .graph
+        .get("out")
+        .ok_or(FFmpegError::FilterNotFound)?
+        .sink()
+        .frame(&mut picture)?;
+
+    Ok(picture)
+}
+
+/// Measures the EBU R128 loudness of the best audio stream of `filename` with FFMPEG's `ebur128`
+pub fn measure_loudness(filename: &str) -> Result<Loudness, AudioError> {
+    let mut audio_graph =
+        AudioGraph::open(filename, "ebur128=peak=true:metadata=1", "abuffersink")?;
+
+    // ebur128 attaches its running measurements to every frame, the last frame holds the totals
+    let mut loudness = None;
+    audio_graph.run(|graph| {
+        let mut measured = frame::Audio::empty();
+        while graph
+            .get("out")
+            .ok_or(FFmpegError::FilterNotFound)?
+            .sink()
+            .frame(&mut measured)
+            .is_ok()
+        {
+            let metadata = measured.metadata();
+            let value = |key: &str| metadata.get(key).and_then(|v| v.parse::<f32>().ok());
+
+            let true_peak = metadata
+                .iter()
+                .filter(|(key, _)| key.starts_with("lavfi.r128.true_peaks_ch"))
+                .filter_map(|(_, v)| v.parse::<f32>().ok())
+                .fold(f32::NEG_INFINITY, f32::max);
+
+            if let (Some(integrated), Some(range)) =
+                (value("lavfi.r128.I"), value("lavfi.r128.LRA"))
+            {
+                loudness = Some(Loudness {
+                    integrated,
+                    true_peak,
+                    range,
+                });
+            }
+        }
+        Ok(())
+    })?;
+
+    loudness.ok_or(AudioError::NoMeasurement)
+}
+
+/// Highest true peak in dBTP normalized audio may reach, leaving headroom for lossy re-encoding
+pub const TRUE_PEAK_LIMIT: f32 = -1.0;
+
+/// Normalizes the best audio stream of `filename` to an integrated loudness of `target_lufs` with
+/// FFMPEG's two pass `loudnorm`: the track is measured with `measure_loudness` first, and the
+/// measurements are handed to `loudnorm` in linear mode so it applies one constant gain. Where
+/// that gain would push the true peak above `TRUE_PEAK_LIMIT`, `loudnorm` falls back to its
+/// dynamic mode, limiting the peaks instead of clipping them. The result is re-encoded with the
+/// source's codec, sample rate and channel layout, as a track to mux in place of the source audio.
+pub fn normalize_loudness(filename: &str, target_lufs: f32) -> Result<AudioTrack, AudioError> {
+    let loudness = measure_loudness(filename)?;
+    debug!(
+        "Normalizing {} LUFS to {target_lufs} LUFS",
+        loudness.integrated
+    );
+
+    // Measurements brought into the ranges loudnorm takes, silence measuring as -inf
+    let measured_i = loudness.integrated.clamp(-99.0, 0.0);
+    let measured_tp = loudness.true_peak.clamp(-99.0, 99.0);
+    let measured_lra = loudness.range.clamp(0.0, 99.0);
+    // ebur128 does not report its gating threshold, which R128 puts 10 LU below the loudness
+    let measured_thresh = (measured_i - 10.0).clamp(-99.0, 0.0);
+    // Linear mode needs a target range covering the measured one, 20 LU being the largest range
+    // every FFMPEG version takes
+    let target_lra = measured_lra.clamp(1.0, 20.0);
+
+    let mut audio_graph = AudioGraph::open_input(filename)?;
+    let mut encoder = audio_encoder(&audio_graph.decoder, audio_graph.time_base)?;
+    // loudnorm outputs 192kHz, aformat has it resampled back to the encoder's rate
+    audio_graph.connect(
+        &format!(
+            "loudnorm=I={target_lufs}:TP={TRUE_PEAK_LIMIT}:LRA={target_lra}:\
+             measured_I={measured_i}:measured_TP={measured_tp}:measured_LRA={measured_lra}:\
+             measured_thresh={measured_thresh}:linear=true,\
+             aformat=sample_fmts={}:sample_rates={}:channel_layouts=0x{:x}",
+            encoder.format().name(),
+            encoder.rate(),
+            encoder.channel_layout().bits()
+        ),
+        "abuffersink",
+    )?;
+    // Encoders taking a fixed number of samples per frame (e.g. AAC) get frames of that size
+    if encoder.frame_size() > 0 {
+        audio_graph
+            .graph
+            .get("out")
+            .ok_or(FFmpegError::FilterNotFound)?
+            .sink()
+            .set_frame_size(encoder.frame_size());
+    }
+
+    let mut packets = Vec::new();
+    audio_graph.run(|graph| {
+        let mut normalized = frame::Audio::empty();
+        while graph
+            .get("out")
+            .ok_or(FFmpegError::FilterNotFound)?
+            .sink()
+            .frame(&mut normalized)
+            .is_ok()
+        {
+            encoder.send_frame(&normalized)?;
+            receive_packets(&mut encoder, &mut packets);
+        }
+        Ok(())
+    })?;
+    encoder.send_eof()?;
+    receive_packets(&mut encoder, &mut packets);
+
+    Ok(AudioTrack {
+        parameters: encoder.parameters(),
+        time_base: audio_graph.time_base,
+        packets,
+    })
+}
+
+// Encoder for the audio `decoder` decodes, with its codec, sample rate and channel layout in the
+// first sample format the encoder takes, stamping packets in `time_base`
+fn audio_encoder(
+    decoder: &ffmpeg::decoder::Audio,
+    time_base: Rational,
+) -> Result<ffmpeg::encoder::Audio, AudioError> {
+    let codec = ffmpeg::encoder::find(decoder.id()).ok_or(AudioError::NoEncoder)?;
+    let format = codec
+        .audio()?
+        .formats()
+        .and_then(|mut formats| formats.next())
+        .unwrap_or(decoder.format());
+
+    let mut encoder = ffmpeg::codec::Encoder::new(codec)?.audio()?;
+    encoder.set_rate(decoder.rate() as i32);
+    encoder.set_channel_layout(decoder.channel_layout());
+    encoder.set_channels(decoder.channel_layout().channels());
+    encoder.set_format(format);
+    encoder.set_bit_rate(decoder.bit_rate());
+    encoder.set_time_base(time_base);
+
+    Ok(encoder.open_as(codec)?)
+}
+
+fn receive_packets(encoder: &mut ffmpeg::encoder::Audio, packets: &mut Vec<Packet>) {
+    let mut packet = Packet::empty();
+    while encoder.receive_packet(&mut packet).is_ok() {
+        packets.push(std::mem::replace(&mut packet, Packet::empty()));
+    }
+}
@@ -1,23 +1,329 @@
+use std::ffi::{c_int, c_void, CString};
+use std::ptr;
+use std::sync::{Arc, Mutex, PoisonError};
+
 use ffmpeg::{
-    codec, dictionary, encoder,
-    format::{input, Pixel},
+    codec, color, encoder,
+    format::{context::Input, stream::Stream, Pixel},
     frame,
     media::Type,
+    packet,
     software::scaling::{context::Context, flag::Flags},
     util::frame::video::Video,
+    util::mathematics::rescale::{Rescale, TIME_BASE},
+    Dictionary, Rational,
 };
 
+use ffmpeg::ffi::{
+    av_frame_copy_props, av_hwdevice_ctx_create, av_hwframe_transfer_data, avformat_alloc_context,
+    avformat_close_input, avformat_find_stream_info, avformat_open_input, AVColorRange,
+    AVColorSpace, AVHWDeviceType, AVIOInterruptCB,
+};
 use ffmpeg::Error as FFmpegError;
 
-use log::debug;
+use log::{debug, error, warn};
+use rayon::prelude::*;
 
+use crate::audio::AudioTrack;
+use crate::memory_io::MemoryInput;
+use crate::network;
 use crate::{
-    AspectRatio, BitRate, FrameMap, FrameRate, Frames, Height, MaxBitRate, VideoInfo, Width,
+    diagnostics, frame_ops, AspectRatio, BitRate, ColorProperties, FrameMap, FrameRate, Frames,
+    Height, MaxBitRate, OperationContext, StreamDescription, VideoInfo, Width,
 };
+
+/// Options controlling how frames are decoded and stored by `dump_frames`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DecodeOptions {
+    // Sources larger than this are downscaled (preserving aspect ratio) before being stored
+    pub max_resolution: Option<(Width, Height)>,
+    // Only the frames with these (inclusive) indices in the stream are stored, from index 0 on
+    pub frame_range: Option<(usize, usize)>,
+    // Only every Nth frame (from the start of the range) is stored, 0 and 1 storing every frame
+    pub frame_stride: usize,
+    // Frames are turned upright according to the stream's display rotation
+    pub auto_rotate: bool,
+    // Sources of more than 8 bits per component are converted to the 8-bit frames instead of
+    // failing with `UnsupportedBitDepth`
+    pub reduce_bit_depth: bool,
+    // Device frames are decoded on, falling back to software decoding when it is unavailable
+    pub hw_device: Option<HwDevice>,
+    // Index in the container of the stream to decode, the best video stream when unset
+    pub stream_index: Option<usize>,
+}
+
+// The stream `options` selects, failing with `StreamNotFound` when it is not a video stream
+fn video_stream<'a>(
+    ictx: &'a Input,
+    options: &DecodeOptions,
+) -> Result<Stream<'a>, VideoDecoderError> {
+    let stream = match options.stream_index {
+        Some(stream_index) => ictx
+            .stream(stream_index)
+            .filter(|stream| stream.parameters().medium() == Type::Video),
+        None => ictx.streams().best(Type::Video),
+    };
+    Ok(stream.ok_or(ffmpeg::Error::StreamNotFound)?)
+}
+
+/// Hardware decoders frames can be decoded with before being downloaded to system memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HwDevice {
+    Vaapi,
+    Cuda,
+}
+
+impl HwDevice {
+    /// The device of FFMPEG's device type name, e.g. "vaapi" or "cuda"
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "vaapi" => Some(HwDevice::Vaapi),
+            "cuda" => Some(HwDevice::Cuda),
+            _ => None,
+        }
+    }
+
+    fn device_type(self) -> AVHWDeviceType {
+        match self {
+            HwDevice::Vaapi => AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI,
+            HwDevice::Cuda => AVHWDeviceType::AV_HWDEVICE_TYPE_CUDA,
+        }
+    }
+}
+
+// Attaches a context of `device` to the decoder being set up, which then decodes on it for codecs
+// the device supports. Returns false if the device could not be opened.
+fn attach_hw_device(context: &mut codec::context::Context, device: HwDevice) -> bool {
+    let mut device_ref = ptr::null_mut();
+    unsafe {
+        let res = av_hwdevice_ctx_create(
+            &mut device_ref,
+            device.device_type(),
+            ptr::null(),
+            ptr::null_mut(),
+            0,
+        );
+        if res < 0 {
+            return false;
+        }
+        // The decoder takes over the reference and releases it when freed
+        (*context.as_mut_ptr()).hw_device_ctx = device_ref;
+    }
+    true
+}
+
+// Copies a frame decoded into device memory to system memory in `format`, which the RGB scaler
+// was set up for, converting it when the device downloads another format (e.g. NV12)
+fn download_frame(
+    frame: frame::Video,
+    format: Pixel,
+    converter: &mut Option<Context>,
+) -> Result<frame::Video, FFmpegError> {
+    let mut downloaded = frame::Video::empty();
+    unsafe {
+        if (*frame.as_ptr()).hw_frames_ctx.is_null() {
+            return Ok(frame);
+        }
+        let res = av_hwframe_transfer_data(downloaded.as_mut_ptr(), frame.as_ptr(), 0);
+        if res < 0 {
+            return Err(FFmpegError::from(res));
+        }
+    }
+    if downloaded.format() != format {
+        // Recreated when the stream changes resolution mid-stream
+        let stale = converter.as_ref().map_or(true, |converter| {
+            let input = converter.input();
+            (input.width, input.height) != (downloaded.width(), downloaded.height())
+        });
+        if stale {
+            *converter = Some(Context::get(
+                downloaded.format(),
+                downloaded.width(),
+                downloaded.height(),
+                format,
+                downloaded.width(),
+                downloaded.height(),
+                Flags::POINT,
+            )?);
+        }
+        let converter = converter.as_mut().expect("converter was created above");
+        let mut converted = frame::Video::empty();
+        converter.run(&downloaded, &mut converted)?;
+        downloaded = converted;
+    }
+    // Timestamps and frame types are not part of the transferred data
+    unsafe {
+        let res = av_frame_copy_props(downloaded.as_mut_ptr(), frame.as_ptr());
+        if res < 0 {
+            return Err(FFmpegError::from(res));
+        }
+    }
+    Ok(downloaded)
+}
+
+// Fails for sources deeper than the 8 bits per component frames are stored with, unless the
+// guest accepted the precision loss
+fn check_bit_depth(format: Pixel, options: &DecodeOptions) -> Result<(), VideoDecoderError> {
+    let depth = frame_ops::component_depth(format);
+    if depth <= 8 {
+        return Ok(());
+    }
+    if options.reduce_bit_depth {
+        debug!("Reducing {depth}-bit {:?} to 8 bits per component", format);
+        return Ok(());
+    }
+    Err(VideoDecoderError::UnsupportedBitDepth(depth))
+}
+
+// Frame rate assumed for streams that do not report one, as the encoder does
+const DEFAULT_FRAME_RATE: Rational = Rational(30, 1);
+
+const MILLISECOND: Rational = Rational(1, 1000);
+
+// Decoded frames converted to RGB at once per pool thread, bounding the decoded frames held
+const SCALE_BATCH_PER_THREAD: usize = 8;
+
+// Converts the timestamps of a video stream to frame indices and times from the stream's start,
+// and back to seek positions
+#[derive(Debug, Clone, Copy)]
+struct StreamClock {
+    // Timestamp of the stream's first frame
+    start_time: i64,
+    time_base: Rational,
+    frame_rate: Rational,
+}
+
+impl StreamClock {
+    fn new(stream: &ffmpeg::Stream, video_info: &VideoInfo) -> Self {
+        StreamClock {
+            start_time: match stream.start_time() {
+                ffmpeg::ffi::AV_NOPTS_VALUE => 0,
+                start_time => start_time,
+            },
+            time_base: video_info.time_base.unwrap_or(TIME_BASE),
+            frame_rate: video_info.frame_rate.0.unwrap_or(DEFAULT_FRAME_RATE),
+        }
+    }
+
+    fn frame_index(&self, timestamp: i64) -> i64 {
+        (timestamp - self.start_time).rescale(self.time_base, self.frame_rate.invert())
+    }
+
+    fn millis(&self, timestamp: i64) -> i64 {
+        (timestamp - self.start_time).rescale(self.time_base, MILLISECOND)
+    }
+
+    // Seeking without a stream index takes positions in AV_TIME_BASE units
+    fn seek_position(&self, millis: i64) -> i64 {
+        self.start_time.rescale(self.time_base, TIME_BASE) + millis.rescale(MILLISECOND, TIME_BASE)
+    }
+
+    fn seek_position_of_frame(&self, index: i64) -> i64 {
+        self.start_time.rescale(self.time_base, TIME_BASE)
+            + index.rescale(self.frame_rate.invert(), TIME_BASE)
+    }
+}
+
+// Maximum number of decoder/scaler pairs kept alive between loads
+const MAX_CACHED_CONTEXTS: usize = 4;
+
+// Stream parameters two sources must share for their decoder and scaler to be interchangeable
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ContextKey {
+    codec_id: codec::Id,
+    width: u32,
+    height: u32,
+    format: i32,
+    color_space: AVColorSpace,
+    color_range: AVColorRange,
+    // Codec specific setup (e.g. H.264 SPS/PPS), decoders configured from different setup differ
+    extradata: Vec<u8>,
+    output_width: u32,
+    output_height: u32,
+    hw_device: Option<HwDevice>,
+}
+
+impl ContextKey {
+    fn new(
+        parameters: &codec::Parameters,
+        max_resolution: Option<(Width, Height)>,
+        hw_device: Option<HwDevice>,
+    ) -> Self {
+        unsafe {
+            let raw = &*parameters.as_ptr();
+            let extradata = if raw.extradata.is_null() {
+                Vec::new()
+            } else {
+                std::slice::from_raw_parts(raw.extradata, raw.extradata_size as usize).to_vec()
+            };
+            let (output_width, output_height) =
+                fit_within(raw.width as u32, raw.height as u32, max_resolution);
+
+            ContextKey {
+                codec_id: parameters.id(),
+                width: raw.width as u32,
+                height: raw.height as u32,
+                format: raw.format,
+                color_space: raw.color_space,
+                color_range: raw.color_range,
+                extradata,
+                output_width,
+                output_height,
+                hw_device,
+            }
+        }
+    }
+}
+
+struct CachedContexts {
+    key: ContextKey,
+    decoder: ffmpeg::decoder::Video,
+    scaler: Context,
+}
+
+// The contexts are only ever used by one decode at a time behind the plugin's write lock
+unsafe impl Send for CachedContexts {}
+
+/// Decoder and scaler contexts kept from previous loads, so consecutive sources with the same
+/// codec and dimensions skip setting them up again
+#[derive(Default)]
+pub struct ContextCache {
+    entries: Vec<CachedContexts>,
+}
+
+impl ContextCache {
+    fn take(&mut self, key: &ContextKey) -> Option<CachedContexts> {
+        let position = self.entries.iter().position(|entry| entry.key == *key)?;
+        Some(self.entries.remove(position))
+    }
+
+    fn insert(&mut self, contexts: CachedContexts) {
+        if self.entries.len() == MAX_CACHED_CONTEXTS {
+            self.entries.remove(0);
+        }
+        self.entries.push(contexts);
+    }
+
+    /// Releases every cached context, returning how many decoder/scaler pairs were dropped
+    pub fn clear(&mut self) -> usize {
+        let released = self.entries.len();
+        self.entries.clear();
+        released
+    }
+}
+
 #[derive(Debug)]
 pub enum VideoDecoderError {
     FFMpegError(FFmpegError),
     CodecError(String),
+    // The guest cancelled the decode through the `OperationContext`
+    Cancelled,
+    // The source has more bits per component than the 8-bit frames store, with the depth
+    UnsupportedBitDepth(u32),
+    // The host does not allow guests to open URLs of this protocol
+    ProtocolNotAllowed(String),
+    // A network input could not be connected to or read from
+    NetworkError(FFmpegError),
 }
 
 impl From<FFmpegError> for VideoDecoderError {
@@ -26,121 +332,1123 @@ impl From<FFmpegError> for VideoDecoderError {
     }
 }
 
-pub fn dump_frames(filename: &String) -> Result<(Frames, VideoInfo), VideoDecoderError> {
+/// Stream indices belonging to each program of the container. Only containers carrying more than
+/// one program (e.g. broadcast MPEG-TS captures) report any, single program sources return none.
+fn program_streams(ictx: &Input) -> Vec<Vec<u32>> {
+    unsafe {
+        let raw = &*ictx.as_ptr();
+        if raw.nb_programs <= 1 {
+            return Vec::new();
+        }
+
+        std::slice::from_raw_parts(raw.programs, raw.nb_programs as usize)
+            .iter()
+            .map(|&program| {
+                let program = &*program;
+                if program.stream_index.is_null() {
+                    return Vec::new();
+                }
+                std::slice::from_raw_parts(program.stream_index, program.nb_stream_indexes as usize)
+                    .to_vec()
+            })
+            .collect()
+    }
+}
+
+/// Kind, codec and (for video) dimensions of every stream of the container, by index
+fn stream_descriptions(ictx: &Input) -> Vec<StreamDescription> {
+    ictx.streams()
+        .map(|stream| {
+            let parameters = stream.parameters();
+            let medium = parameters.medium();
+            let (width, height) = match medium {
+                Type::Video => unsafe {
+                    let raw = &*parameters.as_ptr();
+                    (raw.width.max(0) as u32, raw.height.max(0) as u32)
+                },
+                _ => (0, 0),
+            };
+            StreamDescription {
+                medium,
+                codec: parameters.id(),
+                width,
+                height,
+            }
+        })
+        .collect()
+}
+
+/// Fits `width` x `height` within `max_resolution`, preserving the aspect ratio.
+/// Dimensions already inside the bounds are returned unchanged.
+fn fit_within(width: u32, height: u32, max_resolution: Option<(Width, Height)>) -> (u32, u32) {
+    let Some((max_width, max_height)) = max_resolution else {
+        return (width, height);
+    };
+
+    if width <= max_width.0 && height <= max_height.0 {
+        return (width, height);
+    }
+
+    let scale = f64::min(
+        max_width.0 as f64 / width as f64,
+        max_height.0 as f64 / height as f64,
+    );
+
+    let fitted_width = ((width as f64 * scale).round() as u32).clamp(1, max_width.0);
+    let fitted_height = ((height as f64 * scale).round() as u32).clamp(1, max_height.0);
+    (fitted_width, fitted_height)
+}
+
+pub fn dump_frames(
+    filename: &String,
+    options: &DecodeOptions,
+    cache: &mut ContextCache,
+    operation: &OperationContext,
+) -> Result<(Frames, VideoInfo), VideoDecoderError> {
     ffmpeg::init()?;
 
-    let mut frame_index = 0;
+    // Keep FFMPEG's warnings/errors for this decode so the guest can retrieve them
+    let _diagnostics = diagnostics::capture();
+
+    // Begun before opening so the timeout also covers probing the input
+    operation.begin(0);
+    let mut ictx = open_source(filename, Some(operation))?;
+    decode_all_frames(&mut ictx, options, cache, operation)
+}
+
+// Opens a local file, or a network URL (e.g. `rtsp://`) when the host allows its protocol. FFMPEG
+// is handed the same allowlist, so nested URLs the source references are checked too.
+// Connection failures of URLs are reported as `NetworkError`. With an `operation` the input must
+// not outlive it, see `open_interruptible`.
+pub(crate) fn open_source(
+    filename: &str,
+    operation: Option<&OperationContext>,
+) -> Result<Input, VideoDecoderError> {
+    let protocol = match network::protocol(filename) {
+        Some(protocol) => protocol,
+        None => {
+            return Ok(open_interruptible(
+                filename,
+                network::local_options(),
+                operation,
+            )?)
+        }
+    };
+
+    if !network::is_allowed(protocol) {
+        error!("The host does not allow opening {protocol} URLs");
+        return Err(VideoDecoderError::ProtocolNotAllowed(protocol.to_string()));
+    }
+
+    ffmpeg::format::network::init();
+    open_interruptible(filename, network::input_options(protocol), operation).map_err(|err| {
+        error!("Could not open {filename} {:?}", err);
+        VideoDecoderError::NetworkError(err)
+    })
+}
+
+unsafe extern "C" fn interrupt_operation(opaque: *mut c_void) -> c_int {
+    let operation = &*(opaque as *const OperationContext);
+    operation.is_cancelled() as c_int
+}
+
+// Opens `filename` with `options` as `format::input_with_dictionary` does, with FFMPEG's
+// blocking reads (e.g. of a stalled network share) interrupted once `operation` is cancelled or
+// times out. The input must not outlive `operation`.
+fn open_interruptible(
+    filename: &str,
+    options: Dictionary,
+    operation: Option<&OperationContext>,
+) -> Result<Input, FFmpegError> {
+    let path = CString::new(filename).map_err(|_| FFmpegError::InvalidData)?;
+
+    unsafe {
+        let mut format_context = avformat_alloc_context();
+        if format_context.is_null() {
+            return Err(FFmpegError::Unknown);
+        }
+        if let Some(operation) = operation {
+            (*format_context).interrupt_callback = AVIOInterruptCB {
+                callback: Some(interrupt_operation),
+                opaque: operation as *const OperationContext as *mut c_void,
+            };
+        }
+
+        // Frees the format context itself on failure
+        let mut options = options.disown();
+        let res = avformat_open_input(
+            &mut format_context,
+            path.as_ptr(),
+            ptr::null(),
+            &mut options,
+        );
+        // Options the demuxer or protocol did not consume are handed back
+        Dictionary::own(options);
+        if res < 0 {
+            return Err(FFmpegError::from(res));
+        }
+
+        let res = avformat_find_stream_info(format_context, ptr::null_mut());
+        if res < 0 {
+            avformat_close_input(&mut format_context);
+            return Err(FFmpegError::from(res));
+        }
+
+        Ok(Input::wrap(format_context))
+    }
+}
+
+/// Decodes every frame of an encoded video held in memory, as `dump_frames` does for a file.
+/// The bytes are owned by the decode so they stay alive until it completes.
+pub fn dump_frames_from_bytes(
+    bytes: Vec<u8>,
+    options: &DecodeOptions,
+    cache: &mut ContextCache,
+    operation: &OperationContext,
+) -> Result<(Frames, VideoInfo), VideoDecoderError> {
+    ffmpeg::init()?;
+
+    // Keep FFMPEG's warnings/errors for this decode so the guest can retrieve them
+    let _diagnostics = diagnostics::capture();
+
+    operation.begin(0);
+    let mut memory_input = MemoryInput::open(bytes)?;
+    decode_all_frames(memory_input.input(), options, cache, operation)
+}
+
+fn decode_all_frames(
+    ictx: &mut Input,
+    options: &DecodeOptions,
+    cache: &mut ContextCache,
+    operation: &OperationContext,
+) -> Result<(Frames, VideoInfo), VideoDecoderError> {
     let mut frames = Vec::new();
-    let codec;
-    let input = input(filename);
-    let (width, height, aspect_ratio, frame_rate, format);
-    let input_stream_meta_data: dictionary::Owned;
 
-    let itcx_number_streams;
+    let input = video_stream(ictx, options)?;
 
-    let (bitrate, max_bitrate);
+    let video_stream_index: usize = input.index();
+    let frame_stride = options.frame_stride.max(1) as i64;
+    let (first_frame, last_frame) = match options.frame_range {
+        Some((first_frame, last_frame)) => {
+            operation.set_total(last_frame - first_frame + 1);
+            (first_frame as i64, last_frame as i64)
+        }
+        None => {
+            // Containers without a frame count report 0, leaving the total unknown
+            operation.set_total(input.frames().max(0) as usize);
+            (0, i64::MAX)
+        }
+    };
 
-    match input {
-        Ok(mut ictx) => {
-            let input = ictx
-                .streams()
-                .best(Type::Video)
-                .ok_or(ffmpeg::Error::StreamNotFound)?;
-            itcx_number_streams = ictx.nb_streams();
+    let parameters = input.parameters();
+    let key = ContextKey::new(&parameters, options.max_resolution, options.hw_device);
 
-            let video_stream_index: usize = input.index();
+    let cached = cache.take(&key);
+    let (mut decoder, cached_scaler) = match cached {
+        Some(CachedContexts {
+            mut decoder,
+            scaler,
+            ..
+        }) => {
+            debug!("Reusing cached decoder and scaler contexts");
+            // The key matched every parameter the open decoder was set up from, so it is only
+            // reset after the previous EOF
+            decoder.flush();
+            (decoder, Some(scaler))
+        }
+        None => {
+            let mut context = codec::context::Context::from_parameters(input.parameters())?;
+            // Frame threading decodes several frames at once, one per core
+            context.set_threading(codec::threading::Config {
+                kind: codec::threading::Type::Frame,
+                count: 0,
+            });
+            if let Some(hw_device) = options.hw_device {
+                if !attach_hw_device(&mut context, hw_device) {
+                    warn!("{:?} decoding unavailable, decoding in software", hw_device);
+                }
+            }
+            (context.decoder().video()?, None)
+        }
+    };
 
-            input_stream_meta_data = ictx.metadata().to_owned();
+    check_bit_depth(decoder.format(), options)?;
 
-            let mut decoder = input.decoder()?.video()?;
+    let (output_width, output_height) =
+        fit_within(decoder.width(), decoder.height(), options.max_resolution);
+    if (output_width, output_height) != (decoder.width(), decoder.height()) {
+        debug!(
+            "Downscaling {}x{} -> {}x{} to fit max decode resolution",
+            decoder.width(),
+            decoder.height(),
+            output_width,
+            output_height
+        );
+    }
 
-            codec = encoder::find(codec::Id::H264).ok_or(VideoDecoderError::CodecError(
+    let mut video_info = describe_stream(ictx, &input, &decoder, output_width, output_height)?;
+    let clock = StreamClock::new(&input, &video_info);
+
+    // Audio packets are kept to be muxed back when encoding, except for frame ranges which the
+    // audio of the whole stream would not line up with
+    let mut audio_track = match options.frame_range {
+        Some(_) => None,
+        None => AudioTrack::of_input(ictx),
+    };
+
+    // Scaler to convert YUV420 encoded frame -> RGB Raw frame
+    // Also downscales when the source exceeds the max decode resolution
+    let scaler_params = RgbScalerParams::new(&decoder, &video_info);
+    let converter = RgbConverter::new(scaler_params, cached_scaler);
+
+    // Frames are rotated after scaling, so only the reported dimensions are swapped
+    let quarter_turns = if options.auto_rotate {
+        stream_rotation(&input)
+    } else {
+        0
+    };
+    if quarter_turns % 2 == 1 {
+        std::mem::swap(&mut video_info.width.0, &mut video_info.height.0);
+    }
+
+    // Demuxing stays serial and packets reach the decoder in order, the decoder's frame threads
+    // and the RGB conversion pool below are what spread the work over every core
+    let batch_len = rayon::current_num_threads() * SCALE_BATCH_PER_THREAD;
+    let mut batch = Vec::with_capacity(batch_len);
+    let convert_batch =
+        |batch: &mut Vec<frame::Video>, frames: &mut Frames| -> Result<(), FFmpegError> {
+            for mut frame_map in converter.convert(batch)? {
+                if quarter_turns != 0 {
+                    frame_map.input_frame =
+                        frame_ops::rotate_rgb24(&frame_map.input_frame, quarter_turns);
+                }
+                // A retained frame stands in for the frames skipped after it when reassembled
+                frame_map.duration = frame_stride as u32;
+                frames.push(frame_map);
+            }
+            batch.clear();
+            Ok(())
+        };
+
+    // Start decoding at the keyframe before the range rather than at the first frame.
+    // Frames are then indexed by timestamp, as the frames skipped over cannot be counted.
+    let seeked = first_frame > 0;
+    if seeked {
+        let position = clock.seek_position_of_frame(first_frame);
+        ictx.seek(position, ..position)?;
+        decoder.flush();
+    }
+    let mut frame_index: i64 = 0;
+
+    // Converts frames downloaded from a hardware decoder when it downloads another format
+    let mut download_converter = None;
+
+    // Closure to process out frames, returns true once the frames after the range are reached
+    let mut receive_and_process_decoded_frames =
+        |decoder: &mut ffmpeg::decoder::Video| -> Result<bool, ffmpeg::Error> {
+            let mut decoded_frame = frame::Video::empty();
+            while decoder.receive_frame(&mut decoded_frame).is_ok() {
+                let decoded_frame = std::mem::replace(&mut decoded_frame, frame::Video::empty());
+                if let (true, Some(timestamp)) = (seeked, decoded_frame.timestamp()) {
+                    frame_index = clock.frame_index(timestamp);
+                }
+                let index = frame_index;
+                frame_index += 1;
+
+                if index < first_frame {
+                    continue;
+                }
+                if index > last_frame {
+                    return Ok(true);
+                }
+                if (index - first_frame) % frame_stride != 0 {
+                    continue;
+                }
+
+                debug!(
+                    "R_Frame {index} : {:?} {:?} {:?} {:?} ",
+                    decoded_frame.kind(),
+                    decoded_frame.timestamp(),
+                    decoded_frame.duration(),
+                    decoded_frame.display_number()
+                );
+
+                let decoded_frame =
+                    download_frame(decoded_frame, scaler_params.format, &mut download_converter)?;
+                batch.push(decoded_frame);
+                if batch.len() == batch_len {
+                    convert_batch(&mut batch, &mut frames)?;
+                }
+                operation.advance();
+            }
+            Ok(false)
+        };
+
+    // Iterator over Input Context Packets
+    let mut past_range = false;
+    for (idx, res) in ictx.packets().enumerate() {
+        let (stream, packet) = res?;
+        if operation.is_cancelled() {
+            return Err(VideoDecoderError::Cancelled);
+        }
+        if stream.index() == video_stream_index {
+            debug!("PKT {idx} PTS{:?}   DTS:{:?}", packet.pts(), packet.dts());
+            decoder.send_packet(&packet)?;
+            if receive_and_process_decoded_frames(&mut decoder)? {
+                past_range = true;
+                break;
+            }
+        } else if let Some((audio_stream_index, audio_track)) = audio_track.as_mut() {
+            if stream.index() == *audio_stream_index {
+                audio_track.packets.push(packet);
+            }
+        }
+    }
+    if !past_range {
+        decoder.send_eof()?;
+        receive_and_process_decoded_frames(&mut decoder)?;
+    }
+    convert_batch(&mut batch, &mut frames)?;
+    sort_by_timestamp(&mut frames);
+    video_info.audio = audio_track.map(|(_, audio_track)| audio_track);
+
+    cache.insert(CachedContexts {
+        key,
+        decoder,
+        scaler: converter.into_scaler()?,
+    });
+
+    Ok((frames, video_info))
+}
+
+/// Describes the video `stream` of `ictx` as decoded by `decoder` and stored at
+/// `width` x `height`
+fn describe_stream(
+    ictx: &Input,
+    stream: &ffmpeg::Stream,
+    decoder: &ffmpeg::decoder::Video,
+    width: u32,
+    height: u32,
+) -> Result<VideoInfo, VideoDecoderError> {
+    // Output is encoded with the source's codec, or H.264 when the linked FFMPEG can only decode it
+    let source_codec = stream.parameters().id();
+    let codec = match encoder::find(source_codec).filter(|codec| codec.is_video()) {
+        Some(codec) => codec,
+        None => {
+            debug!("No encoder for {:?}, encoding as h264", source_codec);
+            encoder::find(codec::Id::H264).ok_or(VideoDecoderError::CodecError(
                 "Could not Find Codec h264".into(),
-            ))?;
-
-            debug!("Decoder Codec");
-            debug!("  BitRate {:?}", decoder.bit_rate());
-            debug!("  MaxBitRate {:?}", decoder.max_bit_rate());
-            debug!("  TimeBase {:?}", decoder.time_base());
-            debug!("  Codec");
-            debug!("      Name  {:?}", codec.name());
-            debug!("      Descr {:?}", codec.description());
-
-            // I am wrapping these in Structs so its less likely that I make Type Errors
-            bitrate = BitRate(decoder.bit_rate());
-            max_bitrate = MaxBitRate(decoder.bit_rate());
-            width = Width(decoder.width());
-            height = Height(decoder.height());
-            aspect_ratio = AspectRatio(decoder.aspect_ratio());
-            frame_rate = FrameRate(decoder.frame_rate());
-            format = decoder.format();
-
-            // Scaler to convert YUV420 encoded frame -> RGB Raw frame
-            let mut scaler = Context::get(
-                decoder.format(),
-                decoder.width(),
-                decoder.height(),
-                Pixel::RGB24,
-                decoder.width(),
-                decoder.height(),
-                Flags::BILINEAR,
-            )?;
-
-            // Closure to process out frames
-            let mut receive_and_process_decoded_frames =
-                |decoder: &mut ffmpeg::decoder::Video| -> Result<(), ffmpeg::Error> {
-                    let mut decoded_frame = frame::Video::empty();
-                    while decoder.receive_frame(&mut decoded_frame).is_ok() {
-                        let mut rgb_frame = Video::empty();
-                        scaler.run(&decoded_frame, &mut rgb_frame)?;
-                        debug!(
-                            "R_Frame {frame_index} : {:?} {:?} {:?} {:?} ",
-                            decoded_frame.kind(),
-                            decoded_frame.timestamp(),
-                            decoded_frame.duration(),
-                            decoded_frame.display_number()
-                        );
-
-                        let frame_map = FrameMap {
-                            input_frame: rgb_frame,
-                            frame_type: decoded_frame.kind(),
-                            timestamp: decoded_frame.timestamp(),
-                            output_frame: None,
-                        };
-
-                        frames.push(frame_map);
-                        frame_index += 1;
+            ))?
+        }
+    };
+
+    debug!("Decoder Codec");
+    debug!("  BitRate {:?}", decoder.bit_rate());
+    debug!("  MaxBitRate {:?}", decoder.max_bit_rate());
+    debug!("  TimeBase {:?}", decoder.time_base());
+    debug!("  Codec");
+    debug!("      Name  {:?}", codec.name());
+    debug!("      Descr {:?}", codec.description());
+
+    // I am wrapping these in Structs so its less likely that I make Type Errors
+    VideoInfo::builder()
+        .codec(codec)
+        .source_codec(source_codec)
+        .format(decoder.format())
+        .width(Width(width))
+        .height(Height(height))
+        .aspect_ratio(AspectRatio(decoder.aspect_ratio()))
+        .frame_rate(FrameRate(decoder.frame_rate()))
+        .metadata(ictx.metadata().to_owned())
+        .number_streams(ictx.nb_streams())
+        .bitrate(BitRate(decoder.bit_rate()))
+        .max_bitrate(MaxBitRate(decoder.bit_rate()))
+        .color(ColorProperties {
+            primaries: decoder.color_primaries(),
+            transfer: decoder.color_transfer_characteristic(),
+            matrix: decoder.color_space(),
+            range: decoder.color_range(),
+        })
+        .container_format(ictx.format().name())
+        .time_base(stream.time_base())
+        .programs(program_streams(ictx))
+        .streams(stream_descriptions(ictx))
+        .duration_millis(duration_millis(ictx, stream))
+        .build()
+        .map_err(|field| VideoDecoderError::CodecError(format!("Video info lacks its {field}")))
+}
+
+// Duration of `stream` in milliseconds, from its own duration or else the container's
+fn duration_millis(ictx: &Input, stream: &ffmpeg::Stream) -> Option<i64> {
+    if let (duration, Some(time_base)) = (stream.duration(), stream.time_base()) {
+        // Unknown durations are AV_NOPTS_VALUE, i.e. negative
+        if duration > 0 {
+            return Some(duration.rescale(time_base, MILLISECOND));
+        }
+    }
+    match ictx.duration() {
+        duration if duration > 0 => Some(duration.rescale(TIME_BASE, MILLISECOND)),
+        _ => None,
+    }
+}
+
+/// Describes the best video stream of `filename` from its container and stream parameters only,
+/// without decoding any frames
+pub fn probe(filename: &String) -> Result<VideoInfo, VideoDecoderError> {
+    ffmpeg::init()?;
+
+    let ictx = open_source(filename, None)?;
+    let input = ictx
+        .streams()
+        .best(Type::Video)
+        .ok_or(ffmpeg::Error::StreamNotFound)?;
+    // Opening the decoder only reads the parameters, no packet is sent to it
+    let decoder = input.decoder()?.video()?;
+
+    describe_stream(&ictx, &input, &decoder, decoder.width(), decoder.height())
+}
+
+// What the RGB scaler of a stream is created from, so pool threads can create their own
+#[derive(Debug, Clone, Copy)]
+struct RgbScalerParams {
+    format: Pixel,
+    width: u32,
+    height: u32,
+    output_width: u32,
+    output_height: u32,
+    matrix: color::Space,
+    range: color::Range,
+}
+
+impl RgbScalerParams {
+    fn new(decoder: &ffmpeg::decoder::Video, video_info: &VideoInfo) -> Self {
+        RgbScalerParams {
+            format: decoder.format(),
+            width: decoder.width(),
+            height: decoder.height(),
+            output_width: video_info.width(),
+            output_height: video_info.height(),
+            matrix: video_info.color.matrix,
+            range: video_info.color.range,
+        }
+    }
+
+    fn scaler(&self) -> Result<Context, FFmpegError> {
+        let mut scaler = Context::get(
+            self.format,
+            self.width,
+            self.height,
+            Pixel::RGB24,
+            self.output_width,
+            self.output_height,
+            Flags::BILINEAR,
+        )?;
+        frame_ops::set_yuv_colorspace(&mut scaler, self.matrix, self.range, true);
+        Ok(scaler)
+    }
+
+    // Recreates `scaler` when `decoded_frame` no longer matches its input, as when a stream
+    // changes resolution mid-stream. The frame is still scaled to the stored dimensions, so every
+    // stored frame has the size of the first one.
+    fn fit_to_frame(
+        &self,
+        scaler: &mut Context,
+        decoded_frame: &frame::Video,
+    ) -> Result<(), FFmpegError> {
+        let input = scaler.input();
+        let (format, width, height) = (
+            decoded_frame.format(),
+            decoded_frame.width(),
+            decoded_frame.height(),
+        );
+        if (input.format, input.width, input.height) == (format, width, height) {
+            return Ok(());
+        }
+
+        warn!(
+            "Stream changed from {}x{} {:?} to {width}x{height} {format:?}, scaling to {}x{}",
+            input.width, input.height, input.format, self.output_width, self.output_height
+        );
+        *scaler = RgbScalerParams {
+            format,
+            width,
+            height,
+            ..*self
+        }
+        .scaler()?;
+        Ok(())
+    }
+}
+
+// Converts decoded frames into stored frames on rayon's pool, each pool thread with a scaler of
+// its own that it keeps from one batch to the next
+struct RgbConverter {
+    params: RgbScalerParams,
+    // Scaler of each pool thread by `rayon::current_thread_index`, created with its first frame
+    scalers: Vec<Mutex<Option<PoolScaler>>>,
+}
+
+// Scaler of one pool thread
+struct PoolScaler(Context);
+
+// A scaler is only ever used by the pool thread it belongs to, then by the decode once the pool
+// is done with it
+unsafe impl Send for PoolScaler {}
+
+impl RgbConverter {
+    // `scaler` (e.g. the one kept by the context cache) goes to the first pool thread
+    fn new(params: RgbScalerParams, scaler: Option<Context>) -> Self {
+        let mut scalers: Vec<_> = (0..rayon::current_num_threads())
+            .map(|_| Mutex::new(None))
+            .collect();
+        scalers[0] = Mutex::new(scaler.map(PoolScaler));
+        RgbConverter { params, scalers }
+    }
+
+    // Converts the frames of `batch`, returned in the order of `batch` whichever thread finishes
+    // first
+    fn convert(&self, batch: &[frame::Video]) -> Result<Vec<FrameMap>, FFmpegError> {
+        batch
+            .par_iter()
+            .map(|decoded_frame| {
+                let thread = rayon::current_thread_index().unwrap_or(0) % self.scalers.len();
+                let mut slot = self.scalers[thread]
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                if slot.is_none() {
+                    *slot = Some(PoolScaler(self.params.scaler()?));
+                }
+                let PoolScaler(scaler) = slot.as_mut().expect("scaler created above");
+                rgb_frame_map(scaler, self.params, decoded_frame)
+            })
+            .collect()
+    }
+
+    // A scaler to keep in the context cache, one the pool threads created if they converted any
+    fn into_scaler(self) -> Result<Context, FFmpegError> {
+        let scaler = self
+            .scalers
+            .into_iter()
+            .find_map(|slot| slot.into_inner().unwrap_or_else(PoisonError::into_inner));
+        match scaler {
+            Some(PoolScaler(scaler)) => Ok(scaler),
+            None => self.params.scaler(),
+        }
+    }
+}
+
+// Sorts the frames by timestamp, leaving the frames the decoder gave no timestamp in their index
+// position as `set_frame_ordering` and the encode do
+fn sort_by_timestamp(frames: &mut Frames) {
+    crate::sort_timestamped(frames, |frame_map| frame_map.timestamp);
+}
+
+// Converts a decoded frame into the RGB24 frame stored for it
+fn rgb_frame_map(
+    scaler: &mut Context,
+    scaler_params: RgbScalerParams,
+    decoded_frame: &frame::Video,
+) -> Result<FrameMap, FFmpegError> {
+    scaler_params.fit_to_frame(scaler, decoded_frame)?;
+    let mut rgb_frame = Video::empty();
+    scaler.run(decoded_frame, &mut rgb_frame)?;
+
+    let decode_timestamp = match decoded_frame.packet().dts {
+        ffmpeg::ffi::AV_NOPTS_VALUE => None,
+        dts => Some(dts),
+    };
+
+    Ok(FrameMap {
+        input_frame: rgb_frame,
+        frame_type: decoded_frame.kind().into(),
+        timestamp: decoded_frame.timestamp(),
+        decode_timestamp,
+        duration: 1,
+        force_keyframe: false,
+        output_frame: None,
+        derived_frame: None,
+    })
+}
+
+/// An open video decoded one frame at a time, so only the frames of the packet being decoded
+/// are held in memory rather than the whole clip as with `dump_frames`
+pub struct FrameStream {
+    ictx: Input,
+    video_stream_index: usize,
+    decoder: ffmpeg::decoder::Video,
+    scaler: Context,
+    scaler_params: RgbScalerParams,
+    video_info: VideoInfo,
+    // Set once every packet was sent, the decoder then only drains its remaining frames
+    eof_sent: bool,
+    // Seek positions are relative to the stream's first frame
+    clock: StreamClock,
+    // Frame decoded by `seek` to find where it landed, returned by the next `next_frame`
+    pending: Option<FrameMap>,
+    // Interrupts the blocking reads of `ictx`, which is dropped first as declared before it
+    operation: Arc<OperationContext>,
+}
+
+// The stream is only ever used by one host function at a time behind the plugin's write lock
+unsafe impl Send for FrameStream {}
+
+impl FrameStream {
+    /// Opens `filename` and sets up its decoder without decoding any frame yet. Live network
+    /// streams (e.g. RTSP) have no end, so they can only be read frame by frame this way. Opening
+    /// and every later `seek` or `next_frame` each begin an operation of `operation`, so a
+    /// stalled input is interrupted once that is cancelled or times out, failing with `Cancelled`.
+    pub fn open(
+        filename: &String,
+        options: &DecodeOptions,
+        operation: Arc<OperationContext>,
+    ) -> Result<Self, VideoDecoderError> {
+        ffmpeg::init()?;
+
+        // Begun before opening so the timeout also covers probing the input
+        operation.begin(0);
+        let ictx = open_source(filename, Some(&*operation))
+            .map_err(|err| interrupted_or(&operation, err))?;
+        let input = video_stream(&ictx, options)?;
+        let video_stream_index = input.index();
+        let decoder = input.decoder()?.video()?;
+        check_bit_depth(decoder.format(), options)?;
+
+        let (output_width, output_height) =
+            fit_within(decoder.width(), decoder.height(), options.max_resolution);
+        let video_info = describe_stream(&ictx, &input, &decoder, output_width, output_height)?;
+        let clock = StreamClock::new(&input, &video_info);
+        let scaler_params = RgbScalerParams::new(&decoder, &video_info);
+        let scaler = scaler_params.scaler()?;
+
+        Ok(FrameStream {
+            ictx,
+            video_stream_index,
+            decoder,
+            scaler,
+            scaler_params,
+            video_info,
+            eof_sent: false,
+            clock,
+            pending: None,
+            operation,
+        })
+    }
+
+    /// Seeks to the keyframe at or before `millis` from the start of the stream, so decoding
+    /// resumes there without decoding the frames preceding it. Returns the index the first frame
+    /// after the seek has in the stream along with its actual time in milliseconds, as seeks snap
+    /// to keyframes, or `None` when there is no frame at or after the keyframe.
+    pub fn seek(&mut self, millis: i64) -> Result<Option<(i64, i64)>, VideoDecoderError> {
+        self.operation.begin(0);
+        // Bounding the position from above keeps FFMPEG from landing on the keyframe after it
+        let position = self.clock.seek_position(millis);
+        self.ictx
+            .seek(position, ..position)
+            .map_err(|err| interrupted_or(&self.operation, err.into()))?;
+        self.decoder.flush();
+        self.eof_sent = false;
+        self.pending = None;
+
+        let Some(frame_map) = self.next_frame()? else {
+            return Ok(None);
+        };
+
+        let (index, landed_millis) = match frame_map.timestamp {
+            Some(timestamp) => (
+                self.clock.frame_index(timestamp),
+                self.clock.millis(timestamp),
+            ),
+            // Without a timestamp only the requested position is known
+            None => (0, millis),
+        };
+
+        self.pending = Some(frame_map);
+        Ok(Some((index, landed_millis)))
+    }
+
+    pub fn video_info(&self) -> &VideoInfo {
+        &self.video_info
+    }
+
+    /// Decodes the next frame in presentation order, `None` once the stream is exhausted
+    pub fn next_frame(&mut self) -> Result<Option<FrameMap>, VideoDecoderError> {
+        if let Some(frame_map) = self.pending.take() {
+            return Ok(Some(frame_map));
+        }
+        self.operation.begin(0);
+
+        let mut decoded_frame = frame::Video::empty();
+        loop {
+            if self.decoder.receive_frame(&mut decoded_frame).is_ok() {
+                return Ok(Some(rgb_frame_map(
+                    &mut self.scaler,
+                    self.scaler_params,
+                    &decoded_frame,
+                )?));
+            }
+            if self.eof_sent {
+                return Ok(None);
+            }
+
+            match self.ictx.packets().next() {
+                Some(res) => {
+                    let (stream, packet) =
+                        res.map_err(|err| interrupted_or(&self.operation, err.into()))?;
+                    if stream.index() == self.video_stream_index {
+                        self.decoder.send_packet(&packet)?;
                     }
-                    Ok(())
-                };
-
-            // Iterator over Input Context Packets
-            for (idx, res) in ictx.packets().enumerate() {
-                let (stream, packet) = res?;
-                if stream.index() == video_stream_index {
-                    debug!("PKT {idx} PTS{:?}   DTS:{:?}", packet.pts(), packet.dts());
-                    decoder.send_packet(&packet)?;
-                    receive_and_process_decoded_frames(&mut decoder)?;
+                }
+                None => {
+                    self.decoder.send_eof()?;
+                    self.eof_sent = true;
                 }
             }
-            decoder.send_eof()?;
-            receive_and_process_decoded_frames(&mut decoder)?;
         }
-        Err(err) => return Err(VideoDecoderError::from(err)),
+    }
+}
+
+// `Cancelled` when `err` comes from `operation` interrupting a blocking read, `err` otherwise
+fn interrupted_or(operation: &OperationContext, err: VideoDecoderError) -> VideoDecoderError {
+    if operation.is_cancelled() {
+        VideoDecoderError::Cancelled
+    } else {
+        err
+    }
+}
+
+/// Clockwise quarter turns that display `stream` upright, from its display matrix side data
+fn stream_rotation(stream: &ffmpeg::Stream) -> u32 {
+    let Some(display_matrix) = stream
+        .side_data()
+        .find(|side_data| side_data.kind() == packet::side_data::Type::DisplayMatrix)
+    else {
+        return 0;
     };
 
-    let video_info = VideoInfo {
-        codec,
-        format,
-        width,
-        height,
-        aspect_ratio,
-        frame_rate,
-        input_stream_meta_data,
-        itcx_number_streams,
-        bitrate,
-        max_bitrate,
+    // The matrix is 9 native endian i32s, FFMPEG reports its counter clockwise rotation in degrees
+    let data = display_matrix.data();
+    if data.len() < 9 * std::mem::size_of::<i32>() {
+        return 0;
+    }
+    let degrees = unsafe { ffmpeg::ffi::av_display_rotation_get(data.as_ptr() as *const i32) };
+    if degrees.is_nan() {
+        return 0;
+    }
+
+    ((-degrees / 90.0).round() as i64).rem_euclid(4) as u32
+}
+
+/// Decodes only the first keyframe of `filename`, turns it upright according to the stream's
+/// rotation and scales it so its largest side is `max_dimension`, preserving the aspect ratio
+pub fn decode_preview_frame(
+    filename: &String,
+    max_dimension: u32,
+) -> Result<Video, VideoDecoderError> {
+    ffmpeg::init()?;
+
+    let mut ictx = open_source(filename, None)?;
+    let input = ictx
+        .streams()
+        .best(Type::Video)
+        .ok_or(ffmpeg::Error::StreamNotFound)?;
+    let video_stream_index = input.index();
+    let quarter_turns = stream_rotation(&input);
+    let mut decoder = input.decoder()?.video()?;
+
+    let decoded_frame = decode_first_keyframe(&mut ictx, video_stream_index, &mut decoder)?;
+
+    // Rotating only swaps the sides, so the largest side can be fitted before rotating
+    let scale = max_dimension as f64 / decoder.width().max(decoder.height()) as f64;
+    let scaled_width = ((decoder.width() as f64 * scale).round() as u32).max(1);
+    let scaled_height = ((decoder.height() as f64 * scale).round() as u32).max(1);
+
+    let mut scaler = Context::get(
+        decoder.format(),
+        decoder.width(),
+        decoder.height(),
+        Pixel::RGB24,
+        scaled_width,
+        scaled_height,
+        Flags::BILINEAR,
+    )?;
+    frame_ops::set_yuv_colorspace(
+        &mut scaler,
+        decoder.color_space(),
+        decoder.color_range(),
+        true,
+    );
+
+    let mut rgb_frame = Video::empty();
+    scaler.run(&decoded_frame, &mut rgb_frame)?;
+
+    Ok(frame_ops::rotate_rgb24(&rgb_frame, quarter_turns))
+}
+
+/// Decodes the first keyframe at or after `fraction` of the duration of `filename` (from its
+/// start when the duration is unknown or the input cannot seek), turned upright according to the
+/// stream's rotation. Returns the RGB24 frame with its time from the stream's start in
+/// milliseconds, `None` when the decoder did not timestamp it.
+pub fn decode_thumbnail_frame(
+    filename: &String,
+    fraction: f64,
+) -> Result<(Video, Option<i64>), VideoDecoderError> {
+    ffmpeg::init()?;
+
+    let mut ictx = open_source(filename, None)?;
+    let input = ictx
+        .streams()
+        .best(Type::Video)
+        .ok_or(ffmpeg::Error::StreamNotFound)?;
+    let video_stream_index = input.index();
+    let quarter_turns = stream_rotation(&input);
+    let time_base = input.time_base().unwrap_or(TIME_BASE);
+    let start_time = match input.start_time() {
+        ffmpeg::ffi::AV_NOPTS_VALUE => 0,
+        start_time => start_time,
     };
+    let duration = duration_millis(&ictx, &input);
+    let mut decoder = input.decoder()?.video()?;
 
-    Ok((frames, video_info))
+    if let Some(duration) = duration {
+        let millis = (duration as f64 * fraction) as i64;
+        let position =
+            start_time.rescale(time_base, TIME_BASE) + millis.rescale(MILLISECOND, TIME_BASE);
+        // Bounding the position from below lands on the keyframe after it
+        if let Err(err) = ictx.seek(position, position..) {
+            warn!(
+                "Could not seek to {millis}ms, using the first keyframe {:?}",
+                err
+            );
+        }
+    }
+
+    let decoded_frame = decode_first_keyframe(&mut ictx, video_stream_index, &mut decoder)?;
+    let millis = decoded_frame
+        .timestamp()
+        .map(|timestamp| (timestamp - start_time).rescale(time_base, MILLISECOND));
+
+    let mut scaler = Context::get(
+        decoder.format(),
+        decoder.width(),
+        decoder.height(),
+        Pixel::RGB24,
+        decoder.width(),
+        decoder.height(),
+        Flags::BILINEAR,
+    )?;
+    frame_ops::set_yuv_colorspace(
+        &mut scaler,
+        decoder.color_space(),
+        decoder.color_range(),
+        true,
+    );
+
+    let mut rgb_frame = Video::empty();
+    scaler.run(&decoded_frame, &mut rgb_frame)?;
+
+    Ok((frame_ops::rotate_rgb24(&rgb_frame, quarter_turns), millis))
+}
+
+// Decodes the first frame of the keyframe the demuxer reaches next in the stream at
+// `video_stream_index`, skipping the packets before it which cannot be decoded on their own
+fn decode_first_keyframe(
+    ictx: &mut Input,
+    video_stream_index: usize,
+    decoder: &mut ffmpeg::decoder::Video,
+) -> Result<Video, VideoDecoderError> {
+    let mut decoded_frame = frame::Video::empty();
+    let mut seen_keyframe = false;
+    for res in ictx.packets() {
+        let (stream, packet) = res?;
+        if stream.index() != video_stream_index {
+            continue;
+        }
+        seen_keyframe |= packet.is_key();
+        if !seen_keyframe {
+            continue;
+        }
+        decoder.send_packet(&packet)?;
+        if decoder.receive_frame(&mut decoded_frame).is_ok() {
+            return Ok(decoded_frame);
+        }
+    }
+
+    // Decoders with a frame delay only output once drained
+    decoder.send_eof()?;
+    decoder.receive_frame(&mut decoded_frame)?;
+    Ok(decoded_frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    use crate::encode_video::EncoderSettings;
+    use crate::frame_type::FrameType;
+    use crate::test_clips;
+
+    const BENCH_FRAMES: usize = 240;
+
+    fn decoded_frame(timestamp: Option<i64>) -> FrameMap {
+        FrameMap {
+            input_frame: frame::Video::empty(),
+            frame_type: FrameType::Unknown,
+            timestamp,
+            decode_timestamp: None,
+            duration: 1,
+            force_keyframe: false,
+            output_frame: None,
+            derived_frame: None,
+        }
+    }
+
+    #[test]
+    fn sorts_by_timestamp_whichever_order_frames_arrive_in() {
+        let mut frames: Frames = [Some(2), Some(0), None, Some(3), Some(1)]
+            .into_iter()
+            .map(decoded_frame)
+            .collect();
+
+        sort_by_timestamp(&mut frames);
+        let timestamps: Vec<_> = frames.iter().map(|frame_map| frame_map.timestamp).collect();
+        // The frame without a timestamp keeps its position
+        assert_eq!(timestamps, [Some(0), Some(1), None, Some(2), Some(3)]);
+    }
+
+    #[test]
+    fn pool_conversion_keeps_the_batch_order() {
+        let params = RgbScalerParams {
+            format: Pixel::YUV420P,
+            width: 64,
+            height: 48,
+            output_width: 64,
+            output_height: 48,
+            matrix: color::Space::BT709,
+            range: color::Range::MPEG,
+        };
+        let batch: Vec<_> = (0..64)
+            .map(|pts| {
+                let mut frame = frame::Video::new(Pixel::YUV420P, 64, 48);
+                frame.set_pts(Some(pts));
+                frame
+            })
+            .collect();
+
+        let converter = RgbConverter::new(params, None);
+        let frame_maps = converter.convert(&batch).expect("batch converted");
+        let timestamps: Vec<_> = frame_maps
+            .iter()
+            .map(|frame_map| frame_map.timestamp)
+            .collect();
+        assert_eq!(timestamps, (0..64).map(Some).collect::<Vec<_>>());
+        assert!(frame_maps
+            .iter()
+            .all(|frame_map| frame_map.input_frame.format() == Pixel::RGB24));
+    }
+
+    // Times converting 240 decoded 1080p frames to RGB with a single scaler against the pool.
+    // Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_pool_rgb_conversion() {
+        let params = RgbScalerParams {
+            format: Pixel::YUV420P,
+            width: 1920,
+            height: 1080,
+            output_width: 1920,
+            output_height: 1080,
+            matrix: color::Space::BT709,
+            range: color::Range::MPEG,
+        };
+        let batch_len = rayon::current_num_threads() * SCALE_BATCH_PER_THREAD;
+        let decoded: Vec<_> = (0..BENCH_FRAMES)
+            .map(|pts| {
+                let mut frame = frame::Video::new(Pixel::YUV420P, 1920, 1080);
+                frame.set_pts(Some(pts as i64));
+                // Decoders set the timestamp frames are stored with alongside the pts
+                unsafe { (*frame.as_mut_ptr()).best_effort_timestamp = pts as i64 };
+                frame
+            })
+            .collect();
+
+        let mut scaler = params.scaler().expect("scaler created");
+        let start = Instant::now();
+        for decoded_frame in &decoded {
+            rgb_frame_map(&mut scaler, params, decoded_frame).expect("frame converted");
+        }
+        let serial = start.elapsed();
+
+        let converter = RgbConverter::new(params, Some(scaler));
+        let start = Instant::now();
+        let mut converted = Vec::with_capacity(decoded.len());
+        for batch in decoded.chunks(batch_len) {
+            converted.extend(converter.convert(batch).expect("batch converted"));
+        }
+        let pooled = start.elapsed();
+
+        println!(
+            "{BENCH_FRAMES} frames: one scaler in {serial:?}, {} pool threads in {pooled:?}",
+            rayon::current_num_threads()
+        );
+        // Timings depend on the machine's load, so only the converted frames are checked
+        let timestamps: Vec<_> = converted
+            .iter()
+            .map(|frame_map| frame_map.timestamp)
+            .collect();
+        let expected: Vec<_> = (0..BENCH_FRAMES).map(|pts| Some(pts as i64)).collect();
+        assert_eq!(timestamps, expected);
+    }
+
+    #[test]
+    fn packs_padded_rows_of_widths_not_a_multiple_of_16() {
+        let frames = test_clips::gradient_frames(2, 50, 36);
+        let video_info = test_clips::clip_info(codec::Id::MPEG4, 50, 36);
+        let clip = test_clips::encode_clip(&frames, &video_info, &EncoderSettings::default());
+
+        let (decoded, _) = test_clips::decode_clip(clip, &DecodeOptions::default());
+        assert_eq!(decoded.len(), 2);
+        for frame_map in &decoded {
+            let frame = &frame_map.input_frame;
+            let row_len = 50 * 3;
+            // Rows of 150 bytes are padded to FFMPEG's line alignment
+            assert!(frame.stride(0) > row_len);
+
+            let packed = frame_ops::packed_rgb24_bytes(frame);
+            assert_eq!(packed.len(), row_len * 36);
+            for (row, packed_row) in packed.chunks_exact(row_len).enumerate() {
+                let start = row * frame.stride(0);
+                assert_eq!(packed_row, &frame.data(0)[start..start + row_len]);
+            }
+        }
+    }
+
+    #[test]
+    fn auto_rotate_applies_the_display_matrix() {
+        let frames = test_clips::gradient_frames(2, 64, 48);
+        let video_info = test_clips::clip_info(codec::Id::MPEG4, 64, 48);
+        let clip = test_clips::encode_clip(&frames, &video_info, &EncoderSettings::default());
+        // Recorded in portrait, a quarter turn from the landscape frames
+        let rotated = test_clips::with_display_rotation(clip, 90.0);
+
+        let options = DecodeOptions {
+            auto_rotate: true,
+            ..DecodeOptions::default()
+        };
+        let (decoded, decoded_info) = test_clips::decode_clip(rotated.clone(), &options);
+        assert_eq!((decoded_info.width.0, decoded_info.height.0), (48, 64));
+        for frame_map in &decoded {
+            let frame = &frame_map.input_frame;
+            assert_eq!((frame.width(), frame.height()), (48, 64));
+        }
+
+        // Without auto_rotate the frames are left as stored
+        let (decoded, decoded_info) = test_clips::decode_clip(rotated, &DecodeOptions::default());
+        assert_eq!((decoded_info.width.0, decoded_info.height.0), (64, 48));
+        for frame_map in &decoded {
+            let frame = &frame_map.input_frame;
+            assert_eq!((frame.width(), frame.height()), (64, 48));
+        }
+    }
 }
@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use ffmpeg::ffi::{av_log_format_line2, av_log_get_level, av_log_set_callback, va_list};
+use ffmpeg::util::log::{self as ffmpeg_log, Level};
+
+use log::LevelFilter;
+
+// Maximum number of lines retained, the oldest lines are dropped first
+const MAX_DIAGNOSTIC_LINES: usize = 256;
+
+// Longest single log line formatted by FFMPEG
+const MAX_LINE_LENGTH: usize = 1024;
+
+static DIAGNOSTICS: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+
+/// Routes FFMPEG's log output through `ffmpeg_log_callback`
+pub fn install_log_callback() {
+    unsafe { av_log_set_callback(Some(ffmpeg_log_callback)) };
+}
+
+/// Makes FFMPEG log the lines `filter` lets through, other lines are only kept as diagnostics
+pub fn set_ffmpeg_log_level(filter: LevelFilter) {
+    ffmpeg_log::set_level(match filter {
+        LevelFilter::Off => Level::Quiet,
+        LevelFilter::Error => Level::Error,
+        LevelFilter::Warn => Level::Warning,
+        LevelFilter::Info => Level::Info,
+        LevelFilter::Debug => Level::Debug,
+        LevelFilter::Trace => Level::Trace,
+    });
+}
+
+// `log` level of a line FFMPEG logged at `level`
+fn log_level(level: c_int) -> log::Level {
+    if level <= c_int::from(Level::Error) {
+        log::Level::Error
+    } else if level <= c_int::from(Level::Warning) {
+        log::Level::Warn
+    } else if level <= c_int::from(Level::Info) {
+        log::Level::Info
+    } else if level <= c_int::from(Level::Debug) {
+        log::Level::Debug
+    } else {
+        log::Level::Trace
+    }
+}
+
+/// Clears the previous diagnostics and captures warnings/errors until the returned guard is dropped
+pub fn capture() -> CaptureGuard {
+    if let Ok(mut diagnostics) = DIAGNOSTICS.lock() {
+        diagnostics.clear();
+    }
+    CAPTURING.store(true, Ordering::SeqCst);
+    CaptureGuard
+}
+
+/// Returns the captured lines joined by newlines
+pub fn report() -> String {
+    match DIAGNOSTICS.lock() {
+        Ok(diagnostics) => diagnostics
+            .iter()
+            .cloned()
+            .collect::<Vec<String>>()
+            .join("\n"),
+        Err(_) => String::new(),
+    }
+}
+
+pub struct CaptureGuard;
+
+impl Drop for CaptureGuard {
+    fn drop(&mut self) {
+        CAPTURING.store(false, Ordering::SeqCst);
+    }
+}
+
+unsafe extern "C" fn ffmpeg_log_callback(
+    avcl: *mut c_void,
+    level: c_int,
+    fmt: *const c_char,
+    vl: va_list,
+) {
+    // The va_list can only be consumed once, so format the line here
+    // and log it ourselves instead of handing it to the default callback
+    let mut line = [0 as c_char; MAX_LINE_LENGTH];
+    let mut print_prefix: c_int = 1;
+    let written = av_log_format_line2(
+        avcl,
+        level,
+        fmt,
+        vl,
+        line.as_mut_ptr(),
+        MAX_LINE_LENGTH as c_int,
+        &mut print_prefix,
+    );
+    if written < 0 {
+        return;
+    }
+
+    let message = CStr::from_ptr(line.as_ptr()).to_string_lossy();
+
+    if level <= av_log_get_level() {
+        log::log!(target: "ffmpeg", log_level(level), "{}", message.trim_end());
+    }
+
+    if level <= c_int::from(Level::Warning) && CAPTURING.load(Ordering::SeqCst) {
+        if let Ok(mut diagnostics) = DIAGNOSTICS.lock() {
+            if diagnostics.len() == MAX_DIAGNOSTIC_LINES {
+                diagnostics.pop_front();
+            }
+            diagnostics.push_back(message.trim_end().to_string());
+        }
+    }
+}
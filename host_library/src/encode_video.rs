@@ -1,26 +1,39 @@
+use std::ffi::{c_int, c_void, CString};
+use std::ptr;
 use std::time::Duration;
 
+use ffmpeg::ffi::{av_guess_format, av_opt_find, avformat_query_codec, AV_OPT_SEARCH_FAKE_OBJ};
 use ffmpeg::{
-    codec,
+    codec, color,
     format::{self, Pixel},
     frame, picture, Dictionary, Packet, Rational,
 };
 
 use ffmpeg::software::scaling::{Context as Scaler, Flags};
 use ffmpeg::util::frame::video::Video as AVFrame;
+use ffmpeg::util::mathematics::rescale::Rescale;
 use log::{debug, error, warn};
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 
 use ffmpeg::encoder::Video as AVEncoder;
 use ffmpeg::Error as FFmpegError;
 
-use crate::{time::Time, VideoInfo};
+use crate::audio::AudioTrack;
+use crate::frame_type::FrameType;
+use crate::memory_io::MemoryOutput;
+use crate::{frame_ops, time::Time, ColorProperties, OperationContext, VideoInfo};
 
 #[derive(Debug)]
 pub enum VideoEncoderError {
     FFMpegError(FFmpegError),
     CodecError(String),
+    // No encoder of the requested name is available in the linked FFMPEG
+    UnsupportedCodec(String),
+    // No muxer of the requested name is available in the linked FFMPEG
+    UnsupportedFormat(String),
+    // The guest cancelled the encode through the `OperationContext`
+    Cancelled,
 }
 
 impl From<FFmpegError> for VideoEncoderError {
@@ -29,45 +42,307 @@ impl From<FFmpegError> for VideoEncoderError {
     }
 }
 
+// Pixel format frames are encoded in
+const ENCODER_FORMAT: Pixel = Pixel::YUV420P;
+
+/// Output settings chosen by the guest, applied on top of the source's `VideoInfo`
+#[derive(Debug, Clone, Default)]
+pub struct EncoderSettings {
+    // Overrides the color signaling carried through from the source
+    pub color: Option<ColorProperties>,
+    // Rate control (VBV) buffer size in bits, enforced against the source's max bitrate
+    pub rc_buffer_size: Option<u32>,
+    // Name of the encoder to transcode with (e.g. "libvpx-vp9"), the source's codec when unset
+    pub codec_name: Option<String>,
+    // Target and maximum bitrate in bits per second, replacing the near lossless default
+    pub bit_rate: Option<usize>,
+    pub max_bit_rate: Option<usize>,
+    // Constant rate factor, used instead of the bitrate by encoders supporting it
+    pub crf: Option<f32>,
+    // Dimensions frames are scaled to before encoding, the source's when unset
+    pub resolution: Option<(u32, u32)>,
+    // Keyframe interval and maximum consecutive B-frames, FFMPEG's defaults when unset
+    pub gop_size: Option<u32>,
+    pub max_b_frames: Option<usize>,
+    // Whether the source's frame types are passed to the encoder as hints
+    pub frame_type_hints: bool,
+    // Name of the muxer output files are written with (e.g. "matroska"), inferred from the
+    // file extension when unset
+    pub format_name: Option<String>,
+    // Constant frame rate frames are restamped to, the source's timestamps are kept when unset
+    pub frame_rate: Option<Rational>,
+    // Whether the YUV planes converted from the frames are clamped into the limited range, guarding
+    // the encode against stray values. Full range output is never clamped.
+    pub clamp_limited_range: bool,
+}
+
+// Where the encoded stream is muxed to
+enum EncoderOutput {
+    File(ffmpeg::format::context::output::Output),
+    Memory(MemoryOutput),
+}
+
+impl EncoderOutput {
+    fn context(&self) -> &ffmpeg::format::context::output::Output {
+        match self {
+            EncoderOutput::File(octx) => octx,
+            EncoderOutput::Memory(output) => output.output(),
+        }
+    }
+
+    fn context_mut(&mut self) -> &mut ffmpeg::format::context::output::Output {
+        match self {
+            EncoderOutput::File(octx) => octx,
+            EncoderOutput::Memory(output) => output.output_mut(),
+        }
+    }
+}
+
 pub(crate) struct VideoEncoder {
     // Encoder
     encoder: ffmpeg::encoder::Video,
     // Output Context
-    octx: ffmpeg::format::context::output::Output,
+    octx: EncoderOutput,
     // Output Time Base
     _packet_order_map: BTreeMap<i64, Packet>, // ost_time_bases: Vec<Rational>,
     // Frame scaler / Converter between formats
     scaler: Scaler,
-    // FrameRate
-    frame_rate: i32,
+    // Dimensions the scaler takes frames at, the source's rounded down to even. The scaler
+    // outputs them at the requested resolution if one was set.
+    input_width: u32,
+    input_height: u32,
+    // Time base of the source stream the frames' timestamps are in
+    input_time_base: Option<Rational>,
+    // Constant frame rate position of the next frame, used for frames without a timestamp
+    position: Time,
+    // Interval between frames at the output frame rate
+    frame_interval: Time,
+    // PTS of the last frame sent to the encoder
+    last_pts: Option<i64>,
+    // Whether frames are stamped at the constant frame rate position instead of their timestamp
+    restamp: bool,
+    // Whether a GOP size or B-frames were configured, leaving frame types to the encoder
+    encoder_places_keyframes: bool,
+    frame_type_hints: bool,
+    // Whether converted frames are clamped into the limited range before being sent
+    clamp_limited_range: bool,
+    // Source audio copied into the output as the video catches up with it
+    audio: Option<AudioPassthrough>,
+    // Set once `finish` was attempted, the trailer is then not written again when dropped
+    finished: bool,
+}
+
+// Audio packets not muxed yet, in the source's time base, and the output stream they go to
+struct AudioPassthrough {
+    packets: VecDeque<Packet>,
+    time_base: Rational,
+    stream_index: usize,
+}
+
+// The encoder is only ever used by one host function at a time behind the plugin's write lock
+unsafe impl Send for VideoEncoder {}
+
+// Encodes abandoned midway, by an error or a guest that never finishes them, still get their
+// trailer written so the output is a playable video truncated at the last encoded frame
+impl Drop for VideoEncoder {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        warn!("Encoder dropped before it was finished, finalizing its output");
+        if let Err(err) = self.finish() {
+            error!("Error Finalizing Abandoned Encode {:?}", err);
+        }
+    }
+}
+
+/// An RGB24 frame to encode with what the encoder takes from the source frame it stands for.
+/// The frame is borrowed, so it is still stored should the encode fail.
+#[derive(Clone, Copy)]
+pub struct OutputFrame<'a> {
+    pub frame: &'a frame::Video,
+    // Type of the source frame, passed on as a hint if enabled in the settings
+    pub frame_type: FrameType,
+    // Timestamp of the source frame in the input stream's time base
+    pub timestamp: Option<i64>,
+    // Number of frame intervals the frame is shown for, more than 1 for deduplicated frames
+    pub duration: u32,
+    // Encoded as a keyframe whatever the settings, e.g. at a scene cut
+    pub force_keyframe: bool,
+}
+
+/// Which source dimensions `VideoEncoder` had to crop by one pixel to make them even
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct EvenDimensionAdjustment {
+    pub width_cropped: bool,
+    pub height_cropped: bool,
+}
+
+impl EvenDimensionAdjustment {
+    /// Bit 0 set when the width was cropped, bit 1 when the height was
+    pub fn code(&self) -> i32 {
+        self.width_cropped as i32 | (self.height_cropped as i32) << 1
+    }
 }
 
 impl VideoEncoder {
-    pub fn new(v_info: &VideoInfo, output_file: &String) -> Result<Self, VideoEncoderError> {
-        let mut octx = format::output(&output_file)?;
+    pub fn new(
+        v_info: &VideoInfo,
+        settings: &EncoderSettings,
+        output_file: &String,
+    ) -> Result<Self, VideoEncoderError> {
+        let octx = match &settings.format_name {
+            Some(format_name) => {
+                check_muxer(format_name)?;
+                format::output_as(&output_file, format_name)?
+            }
+            None => format::output(&output_file)?,
+        };
+        Self::with_output(
+            v_info,
+            settings,
+            EncoderOutput::File(octx),
+            Some(output_file.as_str()),
+        )
+    }
+
+    /// Encodes into memory muxed as the container `format_name` (e.g. "mp4"),
+    /// the encoded bytes being returned by `into_bytes` once the frames were processed
+    pub fn new_in_memory(
+        v_info: &VideoInfo,
+        settings: &EncoderSettings,
+        format_name: &str,
+    ) -> Result<Self, VideoEncoderError> {
+        check_muxer(format_name)?;
+        let output = MemoryOutput::open(format_name)?;
+        Self::with_output(v_info, settings, EncoderOutput::Memory(output), None)
+    }
+
+    fn with_output(
+        v_info: &VideoInfo,
+        settings: &EncoderSettings,
+        mut output: EncoderOutput,
+        url: Option<&str>,
+    ) -> Result<Self, VideoEncoderError> {
+        let octx = output.context_mut();
 
         let global_header = octx.format().flags().contains(format::Flags::GLOBAL_HEADER);
         let mut ost: ffmpeg::StreamMut<'_> = octx.add_stream()?;
 
-        // let codec = ffmpeg::encoder::find_by_name("libx264").unwrap();
-        let codec = ffmpeg::encoder::find(codec::Id::H264).ok_or(VideoEncoderError::CodecError(
-            "Could not Find Codec h264".into(),
-        ))?;
+        let codec = match &settings.codec_name {
+            Some(codec_name) => ffmpeg::encoder::find_by_name(codec_name)
+                .ok_or_else(|| VideoEncoderError::UnsupportedCodec(codec_name.clone()))?,
+            None => v_info.codec,
+        };
 
         let mut encoder = ffmpeg::codec::Encoder::new(codec)?.video()?;
 
-        encoder.set_height(v_info.height.0);
-        encoder.set_width(v_info.width.0);
-        encoder.set_format(v_info.format);
+        // YUV420P subsamples chroma by 2 so H.264 requires even dimensions,
+        // odd sources lose their last column and/or row instead of failing to open the encoder
+        let (width, height) = (v_info.width.0 & !1, v_info.height.0 & !1);
+        if width == 0 || height == 0 {
+            return Err(VideoEncoderError::CodecError(format!(
+                "Cannot encode {}x{} video",
+                v_info.width.0, v_info.height.0
+            )));
+        }
+        if (width, height) != (v_info.width.0, v_info.height.0) {
+            warn!(
+                "Cropping {}x{} to even {}x{} for encoding",
+                v_info.width.0, v_info.height.0, width, height
+            );
+        }
+        let (input_width, input_height) = (width, height);
+
+        let (width, height) = match settings.resolution {
+            Some((target_width, target_height)) => {
+                let target = (target_width & !1, target_height & !1);
+                if target.0 == 0 || target.1 == 0 {
+                    return Err(VideoEncoderError::CodecError(format!(
+                        "Cannot encode {target_width}x{target_height} video"
+                    )));
+                }
+                // Allow for the rounding to even dimensions before calling it a change
+                let source_aspect = input_width as f64 / input_height as f64;
+                let target_aspect = target.0 as f64 / target.1 as f64;
+                if (source_aspect / target_aspect - 1.0).abs() > 0.01 {
+                    warn!(
+                        "Scaling {input_width}x{input_height} to {}x{} changes the aspect ratio",
+                        target.0, target.1
+                    );
+                }
+                target
+            }
+            None => (width, height),
+        };
+
+        encoder.set_height(height);
+        encoder.set_width(width);
+        // The format the scaler converts every frame to, whatever the source was decoded from
+        // (e.g. 10-bit sources stored at 8 bits per component)
+        encoder.set_format(ENCODER_FORMAT);
         encoder.set_time_base(Some(ffmpeg::rescale::TIME_BASE));
-        encoder.set_frame_rate(v_info.frame_rate.0);
+        encoder.set_frame_rate(settings.frame_rate.or(v_info.frame_rate.0));
+
+        let color = settings.color.unwrap_or(v_info.color);
+        unsafe {
+            let encoder_ptr = encoder.as_mut_ptr();
+            (*encoder_ptr).color_primaries = color.primaries.into();
+            (*encoder_ptr).color_trc = color.transfer.into();
+            (*encoder_ptr).colorspace = color.matrix.into();
+            (*encoder_ptr).color_range = color.range.into();
+        }
 
         // Keeping the Bit Rate VERY high to not loose information
-        let bitrate_uncompressed = (3 * 8 * v_info.height.0 * v_info.width.0) as usize;
-        encoder.set_bit_rate(bitrate_uncompressed / 2);
+        let bitrate_uncompressed = (3 * 8 * height * width) as usize;
+        let bit_rate = settings.bit_rate.unwrap_or(bitrate_uncompressed / 2);
+
+        // A quality target makes the encoder ignore the bitrate, left at 0 so it does not
+        // switch to bitrate based rate control
+        let crf = match settings.crf {
+            Some(crf) if supports_option(&codec, "crf") => Some(crf),
+            Some(crf) => {
+                warn!(
+                    "Encoder {} has no CRF, ignoring CRF {crf} for bitrate {bit_rate}",
+                    codec.name()
+                );
+                None
+            }
+            None => None,
+        };
+        if crf.is_none() {
+            encoder.set_bit_rate(bit_rate);
+        }
+
+        if let Some(max_bit_rate) = settings.max_bit_rate {
+            encoder.set_max_bit_rate(max_bit_rate);
+        }
+
+        if let Some(rc_buffer_size) = settings.rc_buffer_size {
+            // The buffer only constrains the rate with a maximum to enforce
+            let max_rate = match (settings.max_bit_rate, v_info.max_bitrate.0) {
+                (Some(max_bit_rate), _) => max_bit_rate,
+                (None, 0) => bit_rate,
+                (None, max_bitrate) => max_bitrate,
+            };
+            encoder.set_max_bit_rate(max_rate);
+            unsafe {
+                (*encoder.as_mut_ptr()).rc_buffer_size = rc_buffer_size as i32;
+            }
+        }
+
+        if let Some(gop_size) = settings.gop_size {
+            encoder.set_gop(gop_size);
+        }
+        if let Some(max_b_frames) = settings.max_b_frames {
+            encoder.set_max_b_frames(max_b_frames);
+        }
 
         let mut dict = Dictionary::new();
         dict.set("preset", "slow");
+        if let Some(crf) = crf {
+            dict.set("crf", &crf.to_string());
+        }
         // dict.set("preset", "medium");
 
         let mut encoder: AVEncoder = encoder.open_with(dict)?;
@@ -78,20 +353,32 @@ impl VideoEncoder {
             encoder.set_flags(codec::Flags::GLOBAL_HEADER);
         }
 
+        let audio = match &v_info.audio {
+            Some(audio_track) => add_audio_stream(octx, audio_track)?,
+            None => None,
+        };
+
         octx.set_metadata(v_info.input_stream_meta_data.clone());
-        format::context::output::dump(&octx, 0, Some(&output_file));
+        format::context::output::dump(octx, 0, url);
         octx.write_header()?;
 
         // Write Every Frame out to encoder packet
-        let scaler = Scaler::get(
+        let scaling_flags = if (width, height) == (input_width, input_height) {
+            Flags::empty()
+        } else {
+            Flags::BICUBIC
+        };
+        let mut scaler = Scaler::get(
             Pixel::RGB24,
-            v_info.width.0,
-            v_info.height.0,
-            Pixel::YUV420P,
-            v_info.width.0,
-            v_info.height.0,
-            Flags::empty(),
+            input_width,
+            input_height,
+            ENCODER_FORMAT,
+            width,
+            height,
+            scaling_flags,
         )?;
+        // Convert with the same matrix the stream is signalled with
+        frame_ops::set_yuv_colorspace(&mut scaler, color.matrix, color.range, false);
 
         encoder.set_threading(codec::threading::Config {
             kind: codec::threading::Type::None,
@@ -114,7 +401,7 @@ impl VideoEncoder {
         debug!("==================================");
 
         // TODO: Should i rather fail here ?
-        let frame_rate = match v_info.frame_rate.0 {
+        let frame_rate = match settings.frame_rate.or(v_info.frame_rate.0) {
             Some(fr) => fr,
             None => {
                 warn!("No Frame rate from Decoder Found, Defaulting to 30FPS for encoder");
@@ -124,52 +411,55 @@ impl VideoEncoder {
 
         Ok(VideoEncoder {
             encoder,
-            octx,
+            octx: output,
             _packet_order_map: BTreeMap::new(),
             scaler,
-            frame_rate: frame_rate.0,
+            input_width,
+            input_height,
+            input_time_base: v_info.time_base,
+            position: Time::zero(),
+            frame_interval: Duration::from_secs_f64(
+                frame_rate.denominator() as f64 / frame_rate.numerator() as f64,
+            )
+            .into(),
+            last_pts: None,
+            restamp: settings.frame_rate.is_some(),
+            encoder_places_keyframes: settings.gop_size.is_some()
+                || settings.max_b_frames.is_some(),
+            frame_type_hints: settings.frame_type_hints,
+            clamp_limited_range: settings.clamp_limited_range && color.range != color::Range::JPEG,
+            audio,
+            finished: false,
         })
     }
 
+    pub fn dimension_adjustment(&self, v_info: &VideoInfo) -> EvenDimensionAdjustment {
+        EvenDimensionAdjustment {
+            width_cropped: self.input_width != v_info.width.0,
+            height_cropped: self.input_height != v_info.height.0,
+        }
+    }
+
+    /// Encodes every frame and finishes the output, returning how many frames were encoded.
+    /// Stops at the first frame failing to encode, leaving the output as far as it got to be
+    /// finalized when the encoder is dropped.
     pub fn receive_and_process_decoded_frames(
         &mut self,
-        frames: &mut Vec<(frame::Video, picture::Type, Option<i64>)>,
-    ) -> Result<(), VideoEncoderError> {
-        let duration: Time = Duration::from_nanos(1_000_000_000 / self.frame_rate as u64).into();
-
-        let mut position = Time::zero();
-
-        for (_idx, (out_frame_rgb, _frame_type, _)) in frames.iter_mut().enumerate() {
-            let frame_timestamp_rescale = position
-                .aligned_with_rational(
-                    self.encoder
-                        .time_base()
-                        .unwrap_or(ffmpeg::rescale::TIME_BASE),
-                )
-                .into_value();
-
-            out_frame_rgb.set_pts(frame_timestamp_rescale);
-
-            let mut frame_yuv420 = self.scale(out_frame_rgb)?;
-
-            // TODO Fix Encoding here
-            frame_yuv420.set_kind(picture::Type::I);
-
-            debug!(
-                "F Send {:?} {}",
-                frame_yuv420.pts(),
-                frame_yuv420.display_number()
-            );
-            self.encoder.send_frame(&frame_yuv420)?;
-
-            if let Some(mut packet) = self.encoder_receive_packet()? {
-                // Leaving this here should i want to try reorder the packets again in the futue
-                // self.packet_order_map.insert(packet.pts().unwrap(), packet);
-                self.write_encoded_packets(&mut packet, 0);
+        frames: &[OutputFrame],
+        operation: &OperationContext,
+    ) -> Result<usize, VideoEncoderError> {
+        operation.begin(frames.len());
+
+        for output_frame in frames {
+            if operation.is_cancelled() {
+                // Close the file so what was encoded so far stays playable
+                self.finish()?;
+                return Err(VideoEncoderError::Cancelled);
             }
 
-            let aligned_position = position.aligned_with(&duration);
-            position = aligned_position.add();
+            self.encode_frame(output_frame)?;
+
+            operation.advance();
         }
 
         // Leaving this here should i want to try reorder the packets again in the futue
@@ -180,15 +470,82 @@ impl VideoEncoder {
 
         self.finish()?;
 
-        return Ok(());
+        return Ok(frames.len());
     }
 
-    fn scale(&mut self, frame: &mut AVFrame) -> Result<AVFrame, FFmpegError> {
-        let mut frame_scaled = AVFrame::empty();
-        self.scaler.run(&frame, &mut frame_scaled)?;
+    /// Encodes one frame shown at its source timestamp for its duration, writing any packet the
+    /// encoder has ready. Frames must arrive in presentation order, `finish` writes out what the
+    /// encoder still holds once the last one was sent.
+    pub fn encode_frame(&mut self, output_frame: &OutputFrame) -> Result<(), VideoEncoderError> {
+        let encoder_time_base = self
+            .encoder
+            .time_base()
+            .unwrap_or(ffmpeg::rescale::TIME_BASE);
+
+        // The source timestamps keep variable frame rate video in sync with its audio,
+        // the constant frame rate position only stands in for frames without one, or for every
+        // frame when restamping to a requested output frame rate
+        let frame_timestamp_rescale = match (output_frame.timestamp, self.input_time_base) {
+            (Some(timestamp), Some(input_time_base)) if !self.restamp => {
+                Some(timestamp.rescale(input_time_base, encoder_time_base))
+            }
+            _ => self
+                .position
+                .aligned_with_rational(encoder_time_base)
+                .into_value(),
+        };
+
+        self.last_pts = frame_timestamp_rescale.or(self.last_pts);
+
+        let mut frame_yuv420 = self.scale(output_frame.frame)?;
+        if self.clamp_limited_range {
+            frame_ops::clamp_to_limited_range(&mut frame_yuv420);
+        }
+        frame_yuv420.set_pts(frame_timestamp_rescale);
+
+        // TODO Fix Encoding here
+        // Every frame is forced to be a keyframe unless the guest chose a GOP structure or
+        // asked for the source's frame types, frames of no known type being left to the encoder
+        let kind = match output_frame.frame_type {
+            _ if output_frame.force_keyframe => picture::Type::I,
+            frame_type if self.frame_type_hints => frame_type.into(),
+            _ if self.encoder_places_keyframes => picture::Type::None,
+            _ => picture::Type::I,
+        };
+        frame_yuv420.set_kind(kind);
+
+        debug!(
+            "F Send {:?} {}",
+            frame_yuv420.pts(),
+            frame_yuv420.display_number()
+        );
+        self.encoder.send_frame(&frame_yuv420)?;
+
+        if let Some(mut packet) = self.encoder_receive_packet()? {
+            // Leaving this here should i want to try reorder the packets again in the futue
+            // self.packet_order_map.insert(packet.pts().unwrap(), packet);
+            self.write_encoded_packets(&mut packet, 0);
+        }
+        self.write_audio_until(self.last_pts);
+
+        // Deduplicated frames span several frame intervals
+        for _ in 0..output_frame.duration {
+            let aligned_position = self.position.aligned_with(&self.frame_interval);
+            self.position = aligned_position.add();
+        }
+
+        Ok(())
+    }
 
-        // Copy over PTS from old frame.
-        frame_scaled.set_pts(frame.pts());
+    fn scale(&mut self, frame: &AVFrame) -> Result<AVFrame, FFmpegError> {
+        let mut frame_scaled = AVFrame::empty();
+        if frame.width() != self.input_width || frame.height() != self.input_height {
+            // Drop the odd last column/row to match the even scaler dimensions
+            let cropped = frame_ops::crop_rgb24(frame, 0, 0, self.input_width, self.input_height);
+            self.scaler.run(&cropped, &mut frame_scaled)?;
+        } else {
+            self.scaler.run(frame, &mut frame_scaled)?;
+        }
 
         Ok(frame_scaled)
     }
@@ -213,10 +570,33 @@ impl VideoEncoder {
         Ok(())
     }
 
+    /// The encoded bytes of encoders created with `new_in_memory`, `None` when encoding to a file
+    pub fn into_bytes(mut self) -> Option<Vec<u8>> {
+        match &mut self.octx {
+            EncoderOutput::Memory(output) => Some(output.take_bytes()),
+            EncoderOutput::File(_) => None,
+        }
+    }
+
+    /// Flushes the frames the encoder still holds, the remaining audio and the trailer. The
+    /// trailer is written even when the flush fails, so the output stays playable up to the last
+    /// packet written. Only the first call does anything, later ones (and dropping the encoder)
+    /// return right away.
     pub fn finish(&mut self) -> Result<(), FFmpegError> {
-        self.flush()?;
-        self.octx.write_trailer()?;
-        Ok(())
+        if self.finished {
+            return Ok(());
+        }
+        let flush_result = self.flush();
+        if let Err(err) = &flush_result {
+            error!(
+                "Error Flushing Encoder {:?}, writing the trailer regardless",
+                err
+            );
+        }
+        self.write_audio_until(None);
+        let trailer_result = self.octx.context_mut().write_trailer();
+        self.finished = true;
+        flush_result.and(trailer_result)
     }
 
     fn encoder_receive_packet(&mut self) -> Result<Option<Packet>, FFmpegError> {
@@ -229,6 +609,45 @@ impl VideoEncoder {
         }
     }
 
+    // Muxes the audio packets starting up to `video_pts` (in the encoder's time base) so audio and
+    // video stay interleaved, or all that are left when `None`
+    fn write_audio_until(&mut self, video_pts: Option<i64>) {
+        let encoder_time_base = self
+            .encoder
+            .time_base()
+            .unwrap_or(ffmpeg::rescale::TIME_BASE);
+        let audio = match self.audio.as_mut() {
+            Some(audio) => audio,
+            None => return,
+        };
+        let output_time_base = self
+            .octx
+            .context()
+            .stream(audio.stream_index)
+            .and_then(|stream| stream.time_base())
+            .unwrap_or(audio.time_base);
+
+        while let Some(packet) = audio.packets.front() {
+            let packet_pts = packet
+                .pts()
+                .or(packet.dts())
+                .map(|pts| pts.rescale(audio.time_base, encoder_time_base));
+            if let (Some(video_pts), Some(packet_pts)) = (video_pts, packet_pts) {
+                if packet_pts > video_pts {
+                    break;
+                }
+            }
+
+            let mut packet = audio.packets.pop_front().expect("front packet exists");
+            packet.set_stream(audio.stream_index);
+            packet.set_position(-1);
+            packet.rescale_ts(audio.time_base, output_time_base);
+            if let Err(err) = packet.write_interleaved(self.octx.context_mut()) {
+                error!("write_interleaved audio {:?}", err);
+            }
+        }
+    }
+
     fn write_encoded_packets(&mut self, packet: &mut Packet, ost_index: usize) {
         packet.set_stream(ost_index);
         packet.set_position(-1);
@@ -245,6 +664,7 @@ impl VideoEncoder {
                 .unwrap_or(ffmpeg::rescale::TIME_BASE),
             // TODO: Will Defaulting to TIME_BASE cause a potential source of errors here ?
             self.octx
+                .context()
                 .stream(0)
                 .expect("Could not Find Stream at index")
                 .time_base()
@@ -253,9 +673,156 @@ impl VideoEncoder {
 
         debug!("P Write F {:?} {:?}", packet.pts(), packet.dts());
 
-        let write_interleaved = packet.write_interleaved(&mut self.octx);
+        let write_interleaved = packet.write_interleaved(self.octx.context_mut());
         if let Err(err) = write_interleaved {
             error!("write_interleaved {:?}", err);
         };
     }
 }
+
+/// Whether the linked FFMPEG has a muxer named `format_name`, e.g. "mp4", "matroska" or "webm"
+pub fn muxer_exists(format_name: &str) -> bool {
+    let format_name = match CString::new(format_name) {
+        Ok(format_name) => format_name,
+        Err(_) => return false,
+    };
+    unsafe { !av_guess_format(format_name.as_ptr(), ptr::null(), ptr::null()).is_null() }
+}
+
+fn check_muxer(format_name: &str) -> Result<(), VideoEncoderError> {
+    if muxer_exists(format_name) {
+        Ok(())
+    } else {
+        Err(VideoEncoderError::UnsupportedFormat(
+            format_name.to_string(),
+        ))
+    }
+}
+
+// Whether `codec` has the private option `name`, e.g. "crf" which only some encoders implement
+fn supports_option(codec: &ffmpeg::Codec, name: &str) -> bool {
+    let name = match CString::new(name) {
+        Ok(name) => name,
+        Err(_) => return false,
+    };
+    unsafe {
+        let priv_class = (*codec.as_ptr()).priv_class;
+        if priv_class.is_null() {
+            return false;
+        }
+        // Searching the class through a pointer to it avoids allocating the encoder's options
+        let option = av_opt_find(
+            &priv_class as *const _ as *mut c_void,
+            name.as_ptr(),
+            ptr::null(),
+            0,
+            AV_OPT_SEARCH_FAKE_OBJ as c_int,
+        );
+        !option.is_null()
+    }
+}
+
+// `std_compliance` of `avformat_query_codec` accepting what FFMPEG muxes by default
+const FF_COMPLIANCE_NORMAL: c_int = 0;
+
+// Adds a stream copying `audio_track` to `octx`, `None` if the container cannot hold its codec
+fn add_audio_stream(
+    octx: &mut format::context::Output,
+    audio_track: &AudioTrack,
+) -> Result<Option<AudioPassthrough>, FFmpegError> {
+    let codec_id = audio_track.parameters.id();
+    let supported = unsafe {
+        avformat_query_codec(
+            octx.format().as_ptr(),
+            codec_id.into(),
+            FF_COMPLIANCE_NORMAL,
+        )
+    };
+    if supported != 1 {
+        warn!(
+            "Dropping the {:?} audio, which {} cannot hold",
+            codec_id,
+            octx.format().name()
+        );
+        return Ok(None);
+    }
+
+    let mut ast = octx.add_stream()?;
+    ast.set_parameters(audio_track.parameters.clone());
+    unsafe {
+        // The source container's tag may mean something else in the output container
+        (*(*ast.as_mut_ptr()).codecpar).codec_tag = 0;
+    }
+
+    Ok(Some(AudioPassthrough {
+        packets: audio_track.packets.iter().cloned().collect(),
+        time_base: audio_track.time_base,
+        stream_index: ast.index(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::decode_video::DecodeOptions;
+    use crate::test_clips::{self, CLIP_FPS};
+
+    const CLIP_FRAMES: usize = 10;
+
+    #[test]
+    fn keeps_the_input_timestamps() {
+        let frames = test_clips::gradient_frames(CLIP_FRAMES, 64, 48);
+        let video_info = test_clips::clip_info(codec::Id::MPEG4, 64, 48);
+        let clip = test_clips::encode_clip(&frames, &video_info, &EncoderSettings::default());
+
+        let (decoded, decoded_info) = test_clips::decode_clip(clip, &DecodeOptions::default());
+        let time_base = decoded_info.time_base.expect("stream time base");
+        // The frames were stamped 0..CLIP_FRAMES in 1/CLIP_FPS
+        let timestamps: Vec<_> = decoded
+            .iter()
+            .map(|frame_map| {
+                frame_map
+                    .timestamp
+                    .expect("decoded frame timestamp")
+                    .rescale(time_base, Rational::new(1, CLIP_FPS))
+            })
+            .collect();
+        assert_eq!(timestamps.len(), CLIP_FRAMES);
+        assert_eq!(timestamps.first(), Some(&0));
+        assert_eq!(timestamps.last(), Some(&(CLIP_FRAMES as i64 - 1)));
+    }
+
+    #[test]
+    fn encodes_8_bit_frames_of_deeper_sources() {
+        let frames = test_clips::gradient_frames(CLIP_FRAMES, 64, 48);
+        // As described for a 10-bit source loaded with bit depth reduction
+        let mut video_info = test_clips::clip_info(codec::Id::MPEG4, 64, 48);
+        video_info.format = Pixel::YUV420P10LE;
+        let clip = test_clips::encode_clip(&frames, &video_info, &EncoderSettings::default());
+
+        let (decoded, decoded_info) = test_clips::decode_clip(clip, &DecodeOptions::default());
+        assert_eq!(decoded.len(), CLIP_FRAMES);
+        assert_eq!(decoded_info.format, ENCODER_FORMAT);
+    }
+
+    #[test]
+    fn places_keyframes_every_gop_size_frames() {
+        let frames = test_clips::gradient_frames(60, 64, 48);
+        let video_info = test_clips::clip_info(codec::Id::MPEG4, 64, 48);
+        let settings = EncoderSettings {
+            gop_size: Some(30),
+            ..EncoderSettings::default()
+        };
+        let clip = test_clips::encode_clip(&frames, &video_info, &settings);
+
+        let (decoded, _) = test_clips::decode_clip(clip, &DecodeOptions::default());
+        let keyframes: Vec<_> = decoded
+            .iter()
+            .enumerate()
+            .filter(|(_, frame_map)| frame_map.frame_type == FrameType::I)
+            .map(|(idx, _)| idx)
+            .collect();
+        assert_eq!(keyframes, [0, 30]);
+    }
+}
@@ -0,0 +1,1002 @@
+use std::ffi::c_int;
+
+use ffmpeg::color;
+use ffmpeg::ffi::{
+    av_adler32_update, av_frame_is_writable, av_image_fill_linesizes, av_image_get_buffer_size,
+    av_pix_fmt_desc_get, sws_getCoefficients, sws_setColorspaceDetails, AVColorSpace,
+    SWS_CS_DEFAULT,
+};
+use ffmpeg::format::Pixel;
+use ffmpeg::software::scaling::{Context as Scaler, Flags};
+use ffmpeg::util::frame::video::Video;
+
+use crate::Frames;
+
+const RGB24_BYTES_PER_PIXEL: usize = 3;
+const RGBA_BYTES_PER_PIXEL: usize = 4;
+
+// Lowest and highest values of the limited (studio swing) range, chroma reaching higher than luma
+const LIMITED_RANGE_MIN: u8 = 16;
+const LIMITED_LUMA_MAX: u8 = 235;
+const LIMITED_CHROMA_MAX: u8 = 240;
+
+const F32_BYTES: usize = 4;
+
+/// Clamps the luma plane of a YUV frame into the limited 16-235 range and its chroma planes into
+/// 16-240
+pub fn clamp_to_limited_range(frame: &mut Video) {
+    for plane in 0..frame.planes() {
+        let max = if plane == 0 {
+            LIMITED_LUMA_MAX
+        } else {
+            LIMITED_CHROMA_MAX
+        };
+        for value in frame.data_mut(plane).iter_mut() {
+            *value = (*value).clamp(LIMITED_RANGE_MIN, max);
+        }
+    }
+}
+
+/// Overwrites the pixels of an RGB24 frame with interleaved little endian `f32` samples in
+/// 0.0-1.0, three per pixel in row order. With `clamp` out of range samples (NaN as 0.0) are
+/// clamped into the range, otherwise the index of the first one is returned and the frame is left
+/// partly written.
+pub fn fill_rgb24_from_f32(frame: &mut Video, bytes: &[u8], clamp: bool) -> Result<(), usize> {
+    let stride = frame.stride(0);
+    let row_len = frame.width() as usize * RGB24_BYTES_PER_PIXEL;
+    let height = frame.height() as usize;
+    let data = frame.data_mut(0);
+
+    let mut samples = bytes
+        .chunks_exact(F32_BYTES)
+        .map(|sample| f32::from_le_bytes(sample.try_into().expect("chunks of 4 bytes")))
+        .enumerate();
+    for y in 0..height {
+        for value in &mut data[y * stride..y * stride + row_len] {
+            let (idx, sample) = samples.next().expect("buffer holds every sample");
+            let sample = match sample {
+                sample if (0.0..=1.0).contains(&sample) => sample,
+                _ if !clamp => return Err(idx),
+                sample if sample.is_nan() => 0.0,
+                sample => sample.clamp(0.0, 1.0),
+            };
+            *value = (sample * 255.0).round() as u8;
+        }
+    }
+    Ok(())
+}
+
+/// Size in bytes of an RGB24 frame as interleaved `f32` samples, as `fill_rgb24_from_f32` reads it
+pub fn f32_frame_byte_size(width: u32, height: u32) -> usize {
+    width as usize * height as usize * RGB24_BYTES_PER_PIXEL * F32_BYTES
+}
+
+/// Lookup table mapping each byte value `v` to `255 * (v / 255) ^ (1 / gamma)`
+pub fn gamma_lut(gamma: f32) -> [u8; 256] {
+    let mut lut = [0u8; 256];
+    for (value, mapped) in lut.iter_mut().enumerate() {
+        *mapped = (255.0 * (value as f32 / 255.0).powf(1.0 / gamma)).round() as u8;
+    }
+    lut
+}
+
+/// Maps every channel of every pixel of an RGB24 frame through `lut`
+pub fn apply_lut_rgb24(frame: &mut Video, lut: &[u8; 256]) {
+    let stride = frame.stride(0);
+    let row_len = frame.width() as usize * RGB24_BYTES_PER_PIXEL;
+    let height = frame.height() as usize;
+    let data = frame.data_mut(0);
+
+    for row in 0..height {
+        for value in data[row * stride..row * stride + row_len].iter_mut() {
+            *value = lut[*value as usize];
+        }
+    }
+}
+
+/// Composites an RGBA frame over black into `composited`, an RGB24 frame of the same size,
+/// scaling each channel by the pixel's alpha so transparent areas come out black rather than in
+/// the color they hide
+pub fn composite_over_black(frame: &Video, composited: &mut Video) {
+    let (width, height) = (frame.width() as usize, frame.height() as usize);
+
+    let src_stride = frame.stride(0);
+    let dst_stride = composited.stride(0);
+    let src_data = frame.data(0);
+    let dst_data = composited.data_mut(0);
+
+    for row in 0..height {
+        let src_row = &src_data[row * src_stride..][..width * RGBA_BYTES_PER_PIXEL];
+        let dst_row = &mut dst_data[row * dst_stride..][..width * RGB24_BYTES_PER_PIXEL];
+        for (src, dst) in src_row
+            .chunks_exact(RGBA_BYTES_PER_PIXEL)
+            .zip(dst_row.chunks_exact_mut(RGB24_BYTES_PER_PIXEL))
+        {
+            let alpha = src[3] as u16;
+            for channel in 0..RGB24_BYTES_PER_PIXEL {
+                dst[channel] = ((src[channel] as u16 * alpha + 127) / 255) as u8;
+            }
+        }
+    }
+
+    composited.set_pts(frame.pts());
+}
+
+/// Copies `overlay` onto `base` with its top left corner at `x`, `y`.
+/// Both frames must be RGB24 and the overlay must fit inside `base`.
+pub fn overlay_rgb24(base: &mut Video, overlay: &Video, x: u32, y: u32) {
+    let base_stride = base.stride(0);
+    let overlay_stride = overlay.stride(0);
+    let row_len = overlay.width() as usize * RGB24_BYTES_PER_PIXEL;
+
+    let overlay_data = overlay.data(0);
+    let base_data = base.data_mut(0);
+
+    for row in 0..overlay.height() as usize {
+        let src = row * overlay_stride;
+        let dst = (y as usize + row) * base_stride + x as usize * RGB24_BYTES_PER_PIXEL;
+        base_data[dst..dst + row_len].copy_from_slice(&overlay_data[src..src + row_len]);
+    }
+}
+
+/// Scales every frame of `pip_frames` to `pip_width` x `pip_height` and overlays it at `x`, `y`
+/// onto the output frames, starting from the input frame where no output was written yet.
+/// When the PiP video is shorter than the main video its last frame is held.
+pub fn compose_pip(
+    frames: &mut Frames,
+    pip_frames: &Frames,
+    x: u32,
+    y: u32,
+    pip_width: u32,
+    pip_height: u32,
+) -> Result<(), ffmpeg::Error> {
+    let Some(first_pip) = pip_frames.first() else {
+        return Ok(());
+    };
+
+    let mut scaler = Scaler::get(
+        Pixel::RGB24,
+        first_pip.input_frame.width(),
+        first_pip.input_frame.height(),
+        Pixel::RGB24,
+        pip_width,
+        pip_height,
+        Flags::BILINEAR,
+    )?;
+
+    let mut scaled_pip = Video::empty();
+    for (idx, frame_map) in frames.iter_mut().enumerate() {
+        if let Some(pip) = pip_frames.get(idx) {
+            scaler.run(&pip.input_frame, &mut scaled_pip)?;
+        }
+
+        let output = frame_map
+            .output_frame
+            .get_or_insert_with(|| frame_map.input_frame.clone());
+        overlay_rgb24(output, &scaled_pip, x, y);
+    }
+
+    Ok(())
+}
+
+/// Resizes every frame to `width` x `height`, the result becoming the frame's input with any
+/// output or derived frame dropped. Frames without an output yet are scaled from their input frame.
+pub fn scale_frames(frames: &mut Frames, width: u32, height: u32) -> Result<(), ffmpeg::Error> {
+    let Some(first) = frames.first() else {
+        return Ok(());
+    };
+
+    let mut scaler = Scaler::get(
+        Pixel::RGB24,
+        first.current_frame().width(),
+        first.current_frame().height(),
+        Pixel::RGB24,
+        width,
+        height,
+        Flags::BILINEAR,
+    )?;
+
+    for frame_map in frames.iter_mut() {
+        let mut scaled = Video::empty();
+        scaler.run(frame_map.current_frame(), &mut scaled)?;
+        scaled.set_pts(frame_map.input_frame.pts());
+        frame_map.input_frame = scaled;
+        frame_map.output_frame = None;
+        frame_map.derived_frame = None;
+    }
+
+    Ok(())
+}
+
+/// Copies the `width` x `height` region at `x`, `y` of an RGB24 frame into a new frame.
+/// The region must lie inside the frame.
+pub fn crop_rgb24(frame: &Video, x: u32, y: u32, width: u32, height: u32) -> Video {
+    let mut cropped = Video::new(Pixel::RGB24, width, height);
+
+    let src_stride = frame.stride(0);
+    let dst_stride = cropped.stride(0);
+    let row_len = width as usize * RGB24_BYTES_PER_PIXEL;
+
+    let src_data = frame.data(0);
+    let dst_data = cropped.data_mut(0);
+
+    for row in 0..height as usize {
+        let src = (y as usize + row) * src_stride + x as usize * RGB24_BYTES_PER_PIXEL;
+        let dst = row * dst_stride;
+        dst_data[dst..dst + row_len].copy_from_slice(&src_data[src..src + row_len]);
+    }
+
+    cropped.set_pts(frame.pts());
+    cropped
+}
+
+/// Copies the `width` x `height` region at `x`, `y` of a frame of any format into a new frame.
+/// The region must lie inside the frame, with `x` and `y` aligned as by `align_to_chroma`.
+pub fn crop_frame(frame: &Video, x: u32, y: u32, width: u32, height: u32) -> Video {
+    let format = frame.format();
+    let mut cropped = Video::new(format, width, height);
+
+    // The packed row length up to `x` is the byte offset of column `x` in each plane
+    let column_offsets = packed_linesizes(format, x);
+    let row_lens = packed_linesizes(format, width);
+    let log2_chroma_h = format.descriptor().map_or(0, |desc| desc.log2_chroma_h());
+
+    for plane in 0..cropped.planes() {
+        // Planes 1 and 2 hold chroma, any plane 3 holds full resolution alpha
+        let first_row = match plane {
+            1 | 2 => (y >> log2_chroma_h) as usize,
+            _ => y as usize,
+        };
+        let row_len = row_lens[plane];
+        let src_stride = frame.stride(plane);
+        let dst_stride = cropped.stride(plane);
+        let plane_height = cropped.plane_height(plane) as usize;
+
+        let src_data = frame.data(plane);
+        let dst_data = cropped.data_mut(plane);
+
+        for row in 0..plane_height {
+            let src = (first_row + row) * src_stride + column_offsets[plane];
+            let dst = row * dst_stride;
+            dst_data[dst..dst + row_len].copy_from_slice(&src_data[src..src + row_len]);
+        }
+    }
+
+    cropped.set_pts(frame.pts());
+    cropped
+}
+
+/// Rounds `x`, `y` down to a multiple of the chroma subsampling of `format` (even coordinates for
+/// 4:2:0), leaving them unchanged for formats without subsampling
+pub fn align_to_chroma(format: Pixel, x: u32, y: u32) -> (u32, u32) {
+    let (log2_w, log2_h) = format
+        .descriptor()
+        .map_or((0, 0), |desc| (desc.log2_chroma_w(), desc.log2_chroma_h()));
+    ((x >> log2_w) << log2_w, (y >> log2_h) << log2_h)
+}
+
+/// Rotates an RGB24 frame clockwise by `quarter_turns` multiples of 90 degrees
+pub fn rotate_rgb24(frame: &Video, quarter_turns: u32) -> Video {
+    let quarter_turns = quarter_turns % 4;
+    let (width, height) = (frame.width() as usize, frame.height() as usize);
+    let (rotated_width, rotated_height) = match quarter_turns {
+        1 | 3 => (height, width),
+        _ => (width, height),
+    };
+
+    let mut rotated = Video::new(Pixel::RGB24, rotated_width as u32, rotated_height as u32);
+
+    let src_stride = frame.stride(0);
+    let dst_stride = rotated.stride(0);
+    let src_data = frame.data(0);
+    let dst_data = rotated.data_mut(0);
+
+    for y in 0..height {
+        for x in 0..width {
+            // Where the source pixel lands in the rotated frame
+            let (dst_x, dst_y) = match quarter_turns {
+                1 => (height - 1 - y, x),
+                2 => (width - 1 - x, height - 1 - y),
+                3 => (y, width - 1 - x),
+                _ => (x, y),
+            };
+            let src = y * src_stride + x * RGB24_BYTES_PER_PIXEL;
+            let dst = dst_y * dst_stride + dst_x * RGB24_BYTES_PER_PIXEL;
+            dst_data[dst..dst + RGB24_BYTES_PER_PIXEL]
+                .copy_from_slice(&src_data[src..src + RGB24_BYTES_PER_PIXEL]);
+        }
+    }
+
+    rotated.set_pts(frame.pts());
+    rotated
+}
+
+/// Rotates a frame of any format clockwise by `quarter_turns` multiples of 90 degrees, keeping
+/// its format. Planar formats are rotated plane by plane, which keeps the chroma planes aligned
+/// with the luma plane as long as the subsampling is the same both ways (e.g. 4:2:0). Formats
+/// subsampled one way only (e.g. 4:2:2) or packing chroma with luma swap their subsampling
+/// direction when turned by 90 degrees, so they are rotated as RGB24 and converted back.
+pub fn rotate_frame(frame: &Video, quarter_turns: u32) -> Result<Video, ffmpeg::Error> {
+    let quarter_turns = quarter_turns % 4;
+    let format = frame.format();
+    if format == Pixel::RGB24 {
+        return Ok(rotate_rgb24(frame, quarter_turns));
+    }
+
+    let (log2_w, log2_h) = format
+        .descriptor()
+        .map_or((0, 0), |desc| (desc.log2_chroma_w(), desc.log2_chroma_h()));
+    let subsampled = log2_w != 0 || log2_h != 0;
+    let planewise =
+        !subsampled || (frame.planes() > 1 && (quarter_turns % 2 == 0 || log2_w == log2_h));
+    if !planewise {
+        let rotated = rotate_rgb24(&convert_pixel_format(frame, Pixel::RGB24)?, quarter_turns);
+        return convert_pixel_format(&rotated, format);
+    }
+
+    let (rotated_width, rotated_height) = match quarter_turns {
+        1 | 3 => (frame.height(), frame.width()),
+        _ => (frame.width(), frame.height()),
+    };
+    let mut rotated = Video::new(format, rotated_width, rotated_height);
+    let row_lens = packed_linesizes(format, frame.width());
+
+    for plane in 0..frame.planes() {
+        let (width, height) = (
+            frame.plane_width(plane) as usize,
+            frame.plane_height(plane) as usize,
+        );
+        // Bytes of one sample of the plane, e.g. 2 for the interleaved chroma of NV12
+        let sample_len = row_lens[plane] / width.max(1);
+        let src_stride = frame.stride(plane);
+        let dst_stride = rotated.stride(plane);
+        let src_data = frame.data(plane);
+        let dst_data = rotated.data_mut(plane);
+
+        for y in 0..height {
+            for x in 0..width {
+                let (dst_x, dst_y) = match quarter_turns {
+                    1 => (height - 1 - y, x),
+                    2 => (width - 1 - x, height - 1 - y),
+                    3 => (y, width - 1 - x),
+                    _ => (x, y),
+                };
+                let src = y * src_stride + x * sample_len;
+                let dst = dst_y * dst_stride + dst_x * sample_len;
+                dst_data[dst..dst + sample_len].copy_from_slice(&src_data[src..src + sample_len]);
+            }
+        }
+    }
+
+    rotated.set_pts(frame.pts());
+    Ok(rotated)
+}
+
+/// Adler-32 checksum of `bytes`, as computed by zlib and FFMPEG
+pub fn adler32(bytes: &[u8]) -> u32 {
+    unsafe { av_adler32_update(1, bytes.as_ptr(), bytes.len() as _) as u32 }
+}
+
+/// Builds an RGB24 frame from tightly packed `width` x `height` pixels
+pub fn rgb24_from_packed(bytes: &[u8], width: u32, height: u32) -> Video {
+    let mut frame = Video::new(Pixel::RGB24, width, height);
+
+    let stride = frame.stride(0);
+    let row_len = width as usize * RGB24_BYTES_PER_PIXEL;
+    let data = frame.data_mut(0);
+
+    for row in 0..height as usize {
+        data[row * stride..row * stride + row_len]
+            .copy_from_slice(&bytes[row * row_len..(row + 1) * row_len]);
+    }
+
+    frame
+}
+
+/// Returns the pixels of an RGB24 frame with any row padding removed
+pub fn packed_rgb24_bytes(frame: &Video) -> Vec<u8> {
+    let stride = frame.stride(0);
+    let row_len = frame.width() as usize * RGB24_BYTES_PER_PIXEL;
+    let data = frame.data(0);
+
+    let mut packed = Vec::with_capacity(row_len * frame.height() as usize);
+    for row in 0..frame.height() as usize {
+        packed.extend_from_slice(&data[row * stride..row * stride + row_len]);
+    }
+    packed
+}
+
+/// Bytes a tightly packed `w` x `h` frame of `format` occupies across all of its planes,
+/// with chroma planes subsampled as the format's descriptor specifies
+pub fn frame_byte_size(format: Pixel, w: u32, h: u32) -> usize {
+    let size = unsafe { av_image_get_buffer_size(format.into(), w as c_int, h as c_int, 1) };
+    size.max(0) as usize
+}
+
+/// Bits per component of `format` (e.g. 10 for YUV420P10LE), per FFMPEG's pixel descriptor
+pub fn component_depth(format: Pixel) -> u32 {
+    let descriptor = unsafe { av_pix_fmt_desc_get(format.into()) };
+    if descriptor.is_null() {
+        return 0;
+    }
+    unsafe { (*descriptor).comp[0].depth.max(0) as u32 }
+}
+
+// Bytes per row of each plane of a tightly packed `width` wide frame of `format`
+fn packed_linesizes(format: Pixel, width: u32) -> [usize; 4] {
+    let mut linesizes: [c_int; 4] = [0; 4];
+    unsafe { av_image_fill_linesizes(linesizes.as_mut_ptr(), format.into(), width as c_int) };
+    linesizes.map(|linesize| linesize.max(0) as usize)
+}
+
+/// Returns the planes of a frame of any format one after the other with row padding removed,
+/// `frame_byte_size` bytes in total
+pub fn packed_frame_bytes(frame: &Video) -> Vec<u8> {
+    let mut packed = Vec::with_capacity(frame_byte_size(
+        frame.format(),
+        frame.width(),
+        frame.height(),
+    ));
+    for plane in 0..frame.planes() {
+        append_packed_plane(frame, plane, &mut packed);
+    }
+    packed
+}
+
+/// Returns plane `plane` of a frame of any format with row padding removed, copying each row
+/// from the frame's linesize rather than assuming it equals the row length
+pub fn packed_plane_bytes(frame: &Video, plane: usize) -> Vec<u8> {
+    let mut packed = Vec::new();
+    append_packed_plane(frame, plane, &mut packed);
+    packed
+}
+
+/// Writes the planes of `frame` packed as by `packed_frame_bytes` straight into `out`, which
+/// must hold `frame_byte_size` bytes, sparing the intermediate buffer
+pub fn write_packed_frame(frame: &Video, out: &mut [u8]) {
+    let linesizes = packed_linesizes(frame.format(), frame.width());
+    let mut offset = 0;
+    for (plane, &row_len) in linesizes.iter().enumerate().take(frame.planes()) {
+        let stride = frame.stride(plane);
+        let data = frame.data(plane);
+        for row in 0..frame.plane_height(plane) as usize {
+            out[offset..offset + row_len]
+                .copy_from_slice(&data[row * stride..row * stride + row_len]);
+            offset += row_len;
+        }
+    }
+}
+
+fn append_packed_plane(frame: &Video, plane: usize, packed: &mut Vec<u8>) {
+    let row_len = packed_linesizes(frame.format(), frame.width())[plane];
+    let stride = frame.stride(plane);
+    let data = frame.data(plane);
+    for row in 0..frame.plane_height(plane) as usize {
+        packed.extend_from_slice(&data[row * stride..row * stride + row_len]);
+    }
+}
+
+/// Builds a `width` x `height` frame of `format` from planes packed as by `packed_frame_bytes`
+pub fn frame_from_packed(bytes: &[u8], format: Pixel, width: u32, height: u32) -> Video {
+    let mut frame = Video::new(format, width, height);
+    fill_from_packed(&mut frame, bytes);
+    frame
+}
+
+/// Overwrites the pixels of `frame` with planes packed as by `packed_frame_bytes` for its format
+/// and dimensions
+pub fn fill_from_packed(frame: &mut Video, bytes: &[u8]) {
+    let linesizes = packed_linesizes(frame.format(), frame.width());
+
+    let mut offset = 0;
+    for (plane, &row_len) in linesizes.iter().enumerate().take(frame.planes()) {
+        let stride = frame.stride(plane);
+        let plane_height = frame.plane_height(plane) as usize;
+        let data = frame.data_mut(plane);
+        for row in 0..plane_height {
+            data[row * stride..row * stride + row_len]
+                .copy_from_slice(&bytes[offset..offset + row_len]);
+            offset += row_len;
+        }
+    }
+}
+
+// Most frames `FramePool` keeps, bounding the memory held by frames not in use
+const MAX_POOLED_FRAMES: usize = 4;
+
+/// Frames no longer in use kept for reuse, sparing an allocation per frame in long write loops
+#[derive(Default)]
+pub struct FramePool {
+    frames: Vec<Video>,
+}
+
+impl FramePool {
+    /// A `width` x `height` frame of `format` with unspecified pixels, reusing a pooled frame
+    /// of that size and format when there is one
+    pub fn take(&mut self, format: Pixel, width: u32, height: u32) -> Video {
+        let pooled = self.frames.iter().position(|frame| {
+            frame.format() == format && frame.width() == width && frame.height() == height
+        });
+        match pooled {
+            Some(position) => {
+                let mut frame = self.frames.swap_remove(position);
+                frame.set_pts(None);
+                frame
+            }
+            None => Video::new(format, width, height),
+        }
+    }
+
+    /// Keeps `frame` for a later `take`, unless the pool is full or its buffers are still
+    /// referenced elsewhere (e.g. by an encoder holding the frame)
+    pub fn recycle(&mut self, mut frame: Video) {
+        let writable = unsafe { av_frame_is_writable(frame.as_mut_ptr()) } > 0;
+        if writable && self.frames.len() < MAX_POOLED_FRAMES {
+            self.frames.push(frame);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+}
+
+/// Converts `frame` to `format` keeping its dimensions and timestamp
+pub fn convert_pixel_format(frame: &Video, format: Pixel) -> Result<Video, ffmpeg::Error> {
+    let mut scaler = Scaler::get(
+        frame.format(),
+        frame.width(),
+        frame.height(),
+        format,
+        frame.width(),
+        frame.height(),
+        Flags::BILINEAR,
+    )?;
+
+    let mut converted = Video::empty();
+    scaler.run(frame, &mut converted)?;
+    converted.set_pts(frame.pts());
+    Ok(converted)
+}
+
+/// Converts `frame` into `converted`, a frame of the same size in the target format, keeping the
+/// timestamp. Unlike `convert_pixel_format` this reuses the buffers of `converted`.
+pub fn convert_pixel_format_into(
+    frame: &Video,
+    converted: &mut Video,
+) -> Result<(), ffmpeg::Error> {
+    let mut scaler = Scaler::get(
+        frame.format(),
+        frame.width(),
+        frame.height(),
+        converted.format(),
+        converted.width(),
+        converted.height(),
+        Flags::BILINEAR,
+    )?;
+
+    scaler.run(frame, converted)?;
+    converted.set_pts(frame.pts());
+    Ok(())
+}
+
+/// Scales `frame` to `width` x `height` with the given swscale algorithm, keeping its pixel format
+/// and timestamp
+pub fn resize_frame(
+    frame: &Video,
+    width: u32,
+    height: u32,
+    flags: Flags,
+) -> Result<Video, ffmpeg::Error> {
+    let mut scaler = Scaler::get(
+        frame.format(),
+        frame.width(),
+        frame.height(),
+        frame.format(),
+        width,
+        height,
+        flags,
+    )?;
+
+    let mut resized = Video::empty();
+    scaler.run(frame, &mut resized)?;
+    resized.set_pts(frame.pts());
+    Ok(resized)
+}
+
+/// Tightly packed pixel bytes of an RGB24 frame with red and blue swapped, as OpenCV expects
+pub fn packed_bgr24_bytes(frame: &Video) -> Vec<u8> {
+    let mut packed = packed_rgb24_bytes(frame);
+    for pixel in packed.chunks_exact_mut(RGB24_BYTES_PER_PIXEL) {
+        pixel.swap(0, 2);
+    }
+    packed
+}
+
+/// Makes `scaler` convert between RGB and YUV using the coefficients of `matrix` and the
+/// given YUV `range`, rather than swscale's BT.601 limited range default.
+/// `yuv_is_source` selects whether the scaler converts YUV -> RGB or RGB -> YUV.
+pub fn set_yuv_colorspace(
+    scaler: &mut Scaler,
+    matrix: color::Space,
+    range: color::Range,
+    yuv_is_source: bool,
+) {
+    unsafe {
+        let yuv_coefficients = sws_getCoefficients(AVColorSpace::from(matrix) as c_int);
+        let rgb_coefficients = sws_getCoefficients(SWS_CS_DEFAULT as c_int);
+        let yuv_full_range = (range == color::Range::JPEG) as c_int;
+
+        let (src_table, src_range, dst_table, dst_range) = if yuv_is_source {
+            (yuv_coefficients, yuv_full_range, rgb_coefficients, 1)
+        } else {
+            (rgb_coefficients, 1, yuv_coefficients, yuv_full_range)
+        };
+
+        sws_setColorspaceDetails(
+            scaler.as_mut_ptr(),
+            src_table,
+            src_range,
+            dst_table,
+            dst_range,
+            0,
+            1 << 16,
+            1 << 16,
+        );
+    }
+}
+
+/// Mean absolute difference per byte between two RGB24 frames, `0.0` for identical frames.
+/// Frames of different dimensions are maximally different.
+pub fn mean_abs_diff(a: &Video, b: &Video) -> f32 {
+    if a.width() != b.width() || a.height() != b.height() {
+        return f32::MAX;
+    }
+
+    let row_len = a.width() as usize * RGB24_BYTES_PER_PIXEL;
+    let (a_stride, b_stride) = (a.stride(0), b.stride(0));
+    let (a_data, b_data) = (a.data(0), b.data(0));
+
+    let mut total: u64 = 0;
+    for row in 0..a.height() as usize {
+        let a_row = &a_data[row * a_stride..row * a_stride + row_len];
+        let b_row = &b_data[row * b_stride..row * b_stride + row_len];
+        total += a_row
+            .iter()
+            .zip(b_row)
+            .map(|(a, b)| a.abs_diff(*b) as u64)
+            .sum::<u64>();
+    }
+
+    total as f32 / (row_len * a.height() as usize) as f32
+}
+
+/// Collapses runs of consecutive frames whose difference to the first frame of the run is at most
+/// `threshold` into that first frame, extending its duration by the frames it replaces
+pub fn dedupe_frames(frames: Frames, threshold: f32) -> Frames {
+    let mut deduped: Frames = Vec::with_capacity(frames.len());
+
+    for frame_map in frames {
+        if let Some(kept) = deduped.last_mut() {
+            if mean_abs_diff(kept.current_frame(), frame_map.current_frame()) <= threshold {
+                kept.duration += frame_map.duration;
+                continue;
+            }
+        }
+        deduped.push(frame_map);
+    }
+
+    deduped
+}
+
+/// Downscales an RGB24 frame to a `width` x `height` grayscale image, each output pixel being
+/// the average BT.601 luma of the source pixels it covers
+pub fn grayscale_thumbnail(frame: &Video, width: usize, height: usize) -> Vec<f32> {
+    let (src_width, src_height) = (frame.width() as usize, frame.height() as usize);
+    let stride = frame.stride(0);
+    let data = frame.data(0);
+
+    let mut thumbnail = vec![0.0; width * height];
+    for out_y in 0..height {
+        let y0 = out_y * src_height / height;
+        let y1 = ((out_y + 1) * src_height / height).max(y0 + 1);
+        for out_x in 0..width {
+            let x0 = out_x * src_width / width;
+            let x1 = ((out_x + 1) * src_width / width).max(x0 + 1);
+
+            let mut luma_sum = 0.0;
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    let px = y * stride + x * RGB24_BYTES_PER_PIXEL;
+                    luma_sum += 0.299 * data[px] as f32
+                        + 0.587 * data[px + 1] as f32
+                        + 0.114 * data[px + 2] as f32;
+                }
+            }
+            thumbnail[out_y * width + out_x] = luma_sum / ((y1 - y0) * (x1 - x0)) as f32;
+        }
+    }
+    thumbnail
+}
+
+// Side of the grayscale image the DCT is taken over
+const PHASH_SIZE: usize = 32;
+// Side of the block of lowest frequency DCT coefficients forming the hash
+const PHASH_LOW_FREQUENCIES: usize = 8;
+
+/// DCT based perceptual hash of an RGB24 frame:
+/// 1. Downscale to a 32x32 grayscale image, discarding detail and color
+/// 2. Take its orthonormal 2D DCT-II, keeping the top left 8x8 (lowest frequency) coefficients
+/// 3. Set bit `v * 8 + u` when coefficient `(u, v)` is greater than the median of the 63 AC
+///    coefficients
+///
+/// Visually similar frames produce hashes with a small Hamming distance, even after re-encoding.
+pub fn perceptual_hash(frame: &Video) -> u64 {
+    const N: usize = PHASH_SIZE;
+    const LOW: usize = PHASH_LOW_FREQUENCIES;
+
+    let pixels = grayscale_thumbnail(frame, N, N);
+
+    // cos_table[k * N + n] = alpha(k) * cos((2n + 1) * k * PI / 2N)
+    let cos_table: Vec<f32> = (0..N * N)
+        .map(|i| {
+            let (k, n) = (i / N, i % N);
+            let alpha = if k == 0 {
+                (1.0 / N as f32).sqrt()
+            } else {
+                (2.0 / N as f32).sqrt()
+            };
+            alpha * (((2 * n + 1) * k) as f32 * std::f32::consts::PI / (2 * N) as f32).cos()
+        })
+        .collect();
+
+    // Transform the rows, then the columns of the low frequencies only
+    let mut rows = vec![0.0f32; N * LOW];
+    for y in 0..N {
+        for u in 0..LOW {
+            rows[y * LOW + u] = (0..N)
+                .map(|x| pixels[y * N + x] * cos_table[u * N + x])
+                .sum();
+        }
+    }
+
+    let mut coefficients = [0.0f32; LOW * LOW];
+    for v in 0..LOW {
+        for u in 0..LOW {
+            coefficients[v * LOW + u] = (0..N)
+                .map(|y| rows[y * LOW + u] * cos_table[v * N + y])
+                .sum();
+        }
+    }
+
+    // The DC coefficient is the average brightness and would skew the median
+    let mut ac_coefficients = coefficients[1..].to_vec();
+    ac_coefficients.sort_by(|a, b| a.total_cmp(b));
+    let median = ac_coefficients[ac_coefficients.len() / 2];
+
+    coefficients
+        .iter()
+        .enumerate()
+        .filter(|(_, coefficient)| **coefficient > median)
+        .fold(0u64, |hash, (bit, _)| hash | (1 << bit))
+}
+
+/// Difference hash of an RGB24 frame:
+/// 1. Downscale to a 9x8 grayscale image by averaging the pixels each output pixel covers
+/// 2. Set bit `y * 8 + x` when pixel `(x, y)` is brighter than its right neighbour `(x + 1, y)`
+///
+/// Cheaper than `perceptual_hash` and as robust to re-encoding, but more sensitive to crops.
+pub fn difference_hash(frame: &Video) -> u64 {
+    const WIDTH: usize = 9;
+    const HEIGHT: usize = 8;
+
+    let pixels = grayscale_thumbnail(frame, WIDTH, HEIGHT);
+
+    (0..HEIGHT)
+        .flat_map(|y| (0..WIDTH - 1).map(move |x| (y, x)))
+        .filter(|&(y, x)| pixels[y * WIDTH + x] > pixels[y * WIDTH + x + 1])
+        .fold(0u64, |hash, (y, x)| hash | (1 << (y * (WIDTH - 1) + x)))
+}
+
+// Side of the square blocks motion is estimated for
+const FLOW_BLOCK_SIZE: usize = 8;
+// Furthest a block is searched for in each direction, in pixels
+const FLOW_SEARCH_RADIUS: isize = 8;
+
+/// BT.601 luma of every pixel of an RGB24 frame, tightly packed
+fn luma_plane(frame: &Video) -> Vec<u8> {
+    let (width, height) = (frame.width() as usize, frame.height() as usize);
+    let stride = frame.stride(0);
+    let data = frame.data(0);
+
+    let mut luma = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let px = y * stride + x * RGB24_BYTES_PER_PIXEL;
+            let value =
+                0.299 * data[px] as f32 + 0.587 * data[px + 1] as f32 + 0.114 * data[px + 2] as f32;
+            luma.push(value.round() as u8);
+        }
+    }
+    luma
+}
+
+/// Dense optical flow from `prev` to `next` (same size RGB24 frames) by block matching:
+/// for every 8x8 block of `prev` the displacement within +-8 pixels minimising the sum of absolute
+/// luma differences in `next` is found and assigned to each of the block's pixels.
+/// Returns `width * height` interleaved `(dx, dy)` pairs in row major order.
+pub fn block_matching_flow(prev: &Video, next: &Video) -> Vec<f32> {
+    let (width, height) = (prev.width() as usize, prev.height() as usize);
+    let prev_luma = luma_plane(prev);
+    let next_luma = luma_plane(next);
+
+    let mut flow = vec![0.0f32; width * height * 2];
+
+    for block_y in (0..height).step_by(FLOW_BLOCK_SIZE) {
+        for block_x in (0..width).step_by(FLOW_BLOCK_SIZE) {
+            let block_w = FLOW_BLOCK_SIZE.min(width - block_x);
+            let block_h = FLOW_BLOCK_SIZE.min(height - block_y);
+
+            let mut best = (0isize, 0isize);
+            let mut best_sad = u32::MAX;
+
+            for dy in -FLOW_SEARCH_RADIUS..=FLOW_SEARCH_RADIUS {
+                for dx in -FLOW_SEARCH_RADIUS..=FLOW_SEARCH_RADIUS {
+                    let (target_x, target_y) = (block_x as isize + dx, block_y as isize + dy);
+                    if target_x < 0
+                        || target_y < 0
+                        || target_x as usize + block_w > width
+                        || target_y as usize + block_h > height
+                    {
+                        continue;
+                    }
+
+                    let mut sad = 0u32;
+                    for row in 0..block_h {
+                        let prev_row = (block_y + row) * width + block_x;
+                        let next_row = (target_y as usize + row) * width + target_x as usize;
+                        sad += prev_luma[prev_row..prev_row + block_w]
+                            .iter()
+                            .zip(&next_luma[next_row..next_row + block_w])
+                            .map(|(a, b)| a.abs_diff(*b) as u32)
+                            .sum::<u32>();
+                    }
+
+                    // Prefer the smallest displacement among equally good matches
+                    let closer = dx.abs() + dy.abs() < best.0.abs() + best.1.abs();
+                    if sad < best_sad || (sad == best_sad && closer) {
+                        best_sad = sad;
+                        best = (dx, dy);
+                    }
+                }
+            }
+
+            for y in block_y..block_y + block_h {
+                for x in block_x..block_x + block_w {
+                    flow[(y * width + x) * 2] = best.0 as f32;
+                    flow[(y * width + x) * 2 + 1] = best.1 as f32;
+                }
+            }
+        }
+    }
+
+    flow
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    const WRITE_LOOP_FRAMES: usize = 1000;
+    const STAGED_FRAMES: usize = 100;
+
+    #[test]
+    fn recycled_frames_are_handed_out_again() {
+        let mut pool = FramePool::default();
+        let frame = pool.take(Pixel::RGB24, 64, 48);
+        let buffer = frame.data(0).as_ptr();
+        pool.recycle(frame);
+
+        let reused = pool.take(Pixel::RGB24, 64, 48);
+        assert_eq!(reused.data(0).as_ptr(), buffer);
+        pool.recycle(reused);
+        // Frames of another size are allocated rather than handed out
+        assert_ne!(pool.take(Pixel::RGB24, 32, 24).data(0).as_ptr(), buffer);
+    }
+
+    #[test]
+    fn float_samples_are_clamped_or_rejected() {
+        let samples = [0.0f32, 0.5, 1.0, -0.25, 1.5, f32::NAN];
+        let bytes: Vec<u8> = samples
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        let mut frame = Video::new(Pixel::RGB24, 2, 1);
+
+        assert_eq!(fill_rgb24_from_f32(&mut frame, &bytes, false), Err(3));
+        assert_eq!(fill_rgb24_from_f32(&mut frame, &bytes, true), Ok(()));
+        assert_eq!(&frame.data(0)[..6], &[0, 128, 255, 0, 255, 0]);
+    }
+
+    #[test]
+    fn resizes_to_the_target_dimensions() {
+        let mut frame = Video::new(Pixel::YUV420P, 1920, 1080);
+        frame.set_pts(Some(42));
+
+        let resized = resize_frame(&frame, 640, 640, Flags::BILINEAR).expect("frame resized");
+        assert_eq!((resized.width(), resized.height()), (640, 640));
+        assert_eq!(resized.format(), Pixel::YUV420P);
+        assert_eq!(resized.pts(), Some(42));
+    }
+
+    // Times writing 1000 1080p frames as `write_frame` does followed by their release once
+    // encoded, allocating each frame against taking them from the pool.
+    // Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_pooled_write_loop() {
+        let (width, height) = (1920, 1080);
+        let bytes = vec![0x80; frame_byte_size(Pixel::RGB24, width, height)];
+
+        let start = Instant::now();
+        for _ in 0..WRITE_LOOP_FRAMES {
+            let mut frame = Video::new(Pixel::RGB24, width, height);
+            fill_from_packed(&mut frame, &bytes);
+        }
+        let allocated = start.elapsed();
+
+        let mut pool = FramePool::default();
+        let mut allocations = 0;
+        let start = Instant::now();
+        for _ in 0..WRITE_LOOP_FRAMES {
+            if pool.frames.is_empty() {
+                allocations += 1;
+            }
+            let mut frame = pool.take(Pixel::RGB24, width, height);
+            fill_from_packed(&mut frame, &bytes);
+            pool.recycle(frame);
+        }
+        let pooled = start.elapsed();
+
+        println!(
+            "{WRITE_LOOP_FRAMES} frames: {WRITE_LOOP_FRAMES} allocations in {allocated:?}, \
+             pooled {allocations} allocations in {pooled:?}"
+        );
+        assert_eq!(allocations, 1);
+    }
+
+    // Times handing 100 4K frames to the guest as `get_frame` does, packing each into a buffer
+    // then copied into guest memory, against `map_frame` packing them straight into a staging slot.
+    // Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_staged_4k_frames() {
+        let (width, height) = (3840, 2160);
+        let frame = Video::new(Pixel::RGB24, width, height);
+        let frame_len = frame_byte_size(Pixel::RGB24, width, height);
+        // Stands in for the guest's linear memory
+        let mut guest_memory = vec![0; frame_len];
+
+        let start = Instant::now();
+        for _ in 0..STAGED_FRAMES {
+            guest_memory.copy_from_slice(&packed_rgb24_bytes(&frame));
+        }
+        let copied = start.elapsed();
+        let copied_bytes = guest_memory.clone();
+
+        let start = Instant::now();
+        for _ in 0..STAGED_FRAMES {
+            write_packed_frame(&frame, &mut guest_memory);
+        }
+        let staged = start.elapsed();
+
+        println!(
+            "{STAGED_FRAMES} {width}x{height} frames: copied in {copied:?}, staged in {staged:?}, \
+             saving {:?}",
+            copied.saturating_sub(staged)
+        );
+        assert_eq!(guest_memory, copied_bytes);
+    }
+}
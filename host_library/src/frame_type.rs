@@ -0,0 +1,55 @@
+//! Codes of the frame (picture) types returned by `get_frame_type`.
+//! The guest includes this file as well, so both sides agree on the values.
+
+// Each side only uses the conversions it needs
+#![allow(dead_code)]
+
+/// How a frame was coded, as exchanged with the guest
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrameType {
+    // Intra coded, decodable on its own (keyframes)
+    I,
+    // Predicted from earlier frames
+    P,
+    // Predicted from earlier and later frames
+    B,
+    // MPEG-4 sprite (global motion compensated)
+    S,
+    // H.264 switching intra
+    SI,
+    // H.264 switching predicted
+    SP,
+    // Bi-directionally predicted from intra frames only
+    BI,
+    // The decoder did not classify the frame
+    Unknown,
+}
+
+impl FrameType {
+    pub fn code(&self) -> i32 {
+        match self {
+            FrameType::I => 0,
+            FrameType::P => 1,
+            FrameType::B => 2,
+            FrameType::S => 3,
+            FrameType::SI => 4,
+            FrameType::SP => 5,
+            FrameType::BI => 6,
+            FrameType::Unknown => 7,
+        }
+    }
+
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            0 => Some(FrameType::I),
+            1 => Some(FrameType::P),
+            2 => Some(FrameType::B),
+            3 => Some(FrameType::S),
+            4 => Some(FrameType::SI),
+            5 => Some(FrameType::SP),
+            6 => Some(FrameType::BI),
+            7 => Some(FrameType::Unknown),
+            _ => None,
+        }
+    }
+}
@@ -1,14 +1,60 @@
-use std::sync::{Arc, Mutex};
+//! WasmEdge plugin decoding, processing and encoding video with FFMPEG for WASM guests.
+//!
+//! Every video is worked on in its own session. `load_video_*`, `open_video` and `probe_video`
+//! open one and hand back its handle, which functions working on a video take as their first
+//! argument, until `close_session` closes it. Decode options, the context cache and the
+//! progress of the running operation are shared by all sessions.
+//!
+//! Host functions return a single i32 (i64 for `free_frames` and `run_pipeline`) by the same
+//! convention:
+//! - `0` is success for functions that only perform an action (`get_frame`, `write_frame`,
+//!   `assemble_output_frames_to_video`, the `set_*` functions, ...).
+//! - A positive value is a session handle, count, length, index or code where the function
+//!   documents one, e.g. the full length of a string for functions copying one into a guest
+//!   buffer, so a truncated copy can be retried with a larger buffer.
+//! - A negative value is a failure, the negated code of a `VideoProcessingPluginError`. A
+//!   function documented to "fail with `InvalidBufferSize`" returns `-5`. Values the function
+//!   writes through guest pointers on failure (required sizes, missing frame indices, error
+//!   messages) are readable by the guest afterwards.
+//! Values that may themselves be negative (timestamps, hashes) are written through pointers
+//! instead. Only faults of the host itself, such as a poisoned lock, trap the guest.
 
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+mod audio;
 mod decode_video;
+mod diagnostics;
 mod encode_video;
+mod frame_ops;
+mod frame_type;
+mod memory_io;
+mod network;
+mod pipeline;
+mod still_image;
+mod subtitles;
+#[cfg(test)]
+mod test_clips;
 mod time;
+mod y4m;
+
+use audio::{AudioTrack, AudioVisualisation};
+use decode_video::{ContextCache, DecodeOptions, HwDevice, VideoDecoderError};
+use encode_video::{EncoderSettings, EvenDimensionAdjustment, OutputFrame, VideoEncoderError};
+use frame_ops::FramePool;
+use frame_type::FrameType;
+use still_image::StillFormat;
 
 use ffmpeg::{
-    dictionary,
+    codec, color, dictionary,
+    ffi::{AVColorPrimaries, AVColorRange, AVColorSpace, AVColorTransferCharacteristic},
     format::Pixel,
-    frame,
+    frame, media,
     picture::{self},
+    software::scaling::Flags as ScalingFlags,
     Codec, Rational,
 };
 
@@ -17,12 +63,13 @@ use wasmedge_sdk::{
     error::HostFuncError,
     host_function,
     plugin::{ffi, PluginDescriptor, PluginModuleBuilder, PluginVersion},
-    Caller, Memory, NeverType, WasmValue,
+    Caller, Engine, Memory, NeverType, WasmValue,
 };
 
+use std::borrow::Cow;
 use std::fmt::Debug;
 
-use log::{debug, error, LevelFilter};
+use log::{debug, error, warn, LevelFilter};
 
 #[derive(Debug, Copy, Clone)]
 pub struct Width(pub u32);
@@ -50,9 +97,250 @@ pub struct VideoInfo {
     pub itcx_number_streams: u32,
     pub bitrate: BitRate,
     pub max_bitrate: MaxBitRate,
+    pub color: ColorProperties,
+    // Name of the demuxer the source was opened with, e.g. "mov,mp4,m4a,3gp,3g2,mj2"
+    pub container_format: String,
+    // Time base of the source stream, which frame timestamps are expressed in
+    pub time_base: Option<Rational>,
+    // Stream indices of each program of multi-program containers (e.g. MPEG-TS), empty otherwise
+    pub programs: Vec<Vec<u32>>,
+    // Every stream of the container, by index
+    pub streams: Vec<StreamDescription>,
+    // Audio of the source muxed back unchanged when encoding, `None` for sources without audio
+    // and for frame ranges or streamed frames
+    pub audio: Option<AudioTrack>,
+    // Codec the source stream is encoded with, `codec` being the one output is encoded with
+    pub source_codec: codec::Id,
+    // Duration of the source stream, `None` when neither it nor its container report one
+    pub duration_millis: Option<i64>,
+}
+
+/// Kind and codec of one stream of a container, as listed by `list_streams`
+#[derive(Debug, Copy, Clone)]
+pub struct StreamDescription {
+    pub medium: media::Type,
+    pub codec: codec::Id,
+    // Coded dimensions of video streams, `0` for other streams
+    pub width: u32,
+    pub height: u32,
+}
+
+impl StreamDescription {
+    /// Code of the stream's kind exchanged with the guest: `0` video, `1` audio, `2` subtitles,
+    /// `3` data and attachments, `-1` unknown
+    pub fn medium_code(&self) -> i32 {
+        match self.medium {
+            media::Type::Video => 0,
+            media::Type::Audio => 1,
+            media::Type::Subtitle => 2,
+            media::Type::Data | media::Type::Attachment => 3,
+            media::Type::Unknown => -1,
+        }
+    }
+}
+
+/// Color signaling of a stream, telling players how to interpret its pixel values
+#[derive(Debug, Copy, Clone)]
+pub struct ColorProperties {
+    pub primaries: color::Primaries,
+    pub transfer: color::TransferCharacteristic,
+    pub matrix: color::Space,
+    pub range: color::Range,
+}
+
+impl ColorProperties {
+    /// Builds the properties from FFMPEG's integer codes (`AVColorPrimaries`,
+    /// `AVColorTransferCharacteristic`, `AVColorSpace` and `AVColorRange`).
+    /// Returns `None` if any code is reserved or not a known value.
+    pub fn from_codes(primaries: i32, transfer: i32, matrix: i32, range: i32) -> Option<Self> {
+        let valid_primaries = matches!(primaries, 1 | 2 | 4..=12 | 22);
+        let valid_transfer = matches!(transfer, 1 | 2 | 4..=18);
+        // 0 (RGB) is excluded as the encoder always outputs YUV
+        let valid_matrix = matches!(matrix, 1 | 2 | 4..=14);
+        let valid_range = matches!(range, 0..=2);
+
+        if !(valid_primaries && valid_transfer && valid_matrix && valid_range) {
+            return None;
+        }
+
+        // Safety: every code was checked above to be a discriminant of its FFMPEG enum
+        unsafe {
+            Some(ColorProperties {
+                primaries: std::mem::transmute::<i32, AVColorPrimaries>(primaries).into(),
+                transfer: std::mem::transmute::<i32, AVColorTransferCharacteristic>(transfer)
+                    .into(),
+                matrix: std::mem::transmute::<i32, AVColorSpace>(matrix).into(),
+                range: std::mem::transmute::<i32, AVColorRange>(range).into(),
+            })
+        }
+    }
+}
+
+/// Failures reported to the guest, each as its own stable code the host function returns negated
+#[derive(Debug)]
+pub enum VideoProcessingPluginError {
+    // The input file does not exist
+    FileNotFound,
+    // The input could not be opened or decoded
+    DecodeFailed,
+    // Indices of the frames that have no output frame
+    MissingFrames(Vec<usize>),
+    // The output could not be encoded or written
+    EncodeFailed,
+    // A guest buffer is too small for (or does not match) the data exchanged through it
+    InvalidBufferSize,
+    // The function needs a video loaded by `load_video_to_host_memory`
+    NoVideoLoaded,
+    // The requested frame index does not exist
+    FrameIndexOutOfRange,
+    // An argument is outside the values the function accepts
+    InvalidArgument,
+    // A guest pointer lies outside WASM linear memory
+    MemoryAccessFailed,
+    // A string read from the guest is not valid UTF-8
+    InvalidString,
+    // An FFMPEG filter or host side frame operation failed
+    ProcessingFailed,
+    // The buffer written by the guest does not match its checksum
+    ChecksumMismatch,
+    // A guest callback is missing, trapped or returned an error
+    CallbackFailed,
+    // The operation was cancelled through `cancel_operation`
+    Cancelled,
+    // The requested plane does not exist in the frame's pixel format
+    PlaneOutOfRange,
+    // The crop rectangle is empty or does not fit within the frame
+    InvalidCropRegion,
+    // The requested encoder is not available in the linked FFMPEG
+    UnsupportedCodec,
+    // The source has more than 8 bits per component, see `set_bit_depth_reduction`
+    UnsupportedBitDepth,
+    // The input has no video stream, or none at the index given to `set_video_stream`
+    StreamNotFound,
+    // The requested muxer is not available in the linked FFMPEG
+    UnsupportedFormat,
+    // An image written by the guest cannot be scaled to the video's dimensions
+    DimensionMismatch,
+    // A network input (e.g. an `rtsp://` URL) could not be connected to or read from
+    NetworkError,
+    // No open session has the handle passed to a host function
+    SessionNotFound,
+    // The rotation passed to `rotate_frame` is not a multiple of 90 degrees
+    InvalidRotation,
+    // The subtitle stream holds images (e.g. PGS or DVD) rather than text
+    UnsupportedSubtitleFormat,
+    // The container metadata has no entry with the key passed to `get_metadata_value`
+    MetadataKeyNotFound,
+    // The input decoded without errors but held no frames
+    EmptyVideo,
+}
+
+impl VideoProcessingPluginError {
+    /// Stable code identifying the error to the guest (never `0`, which means success)
+    pub fn code(&self) -> u32 {
+        match self {
+            VideoProcessingPluginError::FileNotFound => 1,
+            VideoProcessingPluginError::DecodeFailed => 2,
+            VideoProcessingPluginError::MissingFrames(_) => 3,
+            VideoProcessingPluginError::EncodeFailed => 4,
+            VideoProcessingPluginError::InvalidBufferSize => 5,
+            VideoProcessingPluginError::NoVideoLoaded => 6,
+            VideoProcessingPluginError::FrameIndexOutOfRange => 7,
+            VideoProcessingPluginError::InvalidArgument => 8,
+            VideoProcessingPluginError::MemoryAccessFailed => 9,
+            VideoProcessingPluginError::InvalidString => 10,
+            VideoProcessingPluginError::ProcessingFailed => 11,
+            VideoProcessingPluginError::ChecksumMismatch => 12,
+            VideoProcessingPluginError::CallbackFailed => 13,
+            VideoProcessingPluginError::Cancelled => 14,
+            VideoProcessingPluginError::PlaneOutOfRange => 15,
+            VideoProcessingPluginError::InvalidCropRegion => 16,
+            VideoProcessingPluginError::UnsupportedCodec => 17,
+            VideoProcessingPluginError::UnsupportedBitDepth => 18,
+            VideoProcessingPluginError::StreamNotFound => 19,
+            VideoProcessingPluginError::UnsupportedFormat => 20,
+            VideoProcessingPluginError::DimensionMismatch => 21,
+            VideoProcessingPluginError::NetworkError => 22,
+            VideoProcessingPluginError::SessionNotFound => 23,
+            VideoProcessingPluginError::InvalidRotation => 24,
+            VideoProcessingPluginError::UnsupportedSubtitleFormat => 25,
+            VideoProcessingPluginError::MetadataKeyNotFound => 26,
+            VideoProcessingPluginError::EmptyVideo => 27,
+        }
+    }
+}
+
+impl From<VideoProcessingPluginError> for HostFuncError {
+    fn from(value: VideoProcessingPluginError) -> Self {
+        HostFuncError::User(value.code())
+    }
+}
+
+impl From<VideoDecoderError> for VideoProcessingPluginError {
+    fn from(value: VideoDecoderError) -> Self {
+        match value {
+            VideoDecoderError::FFMpegError(ffmpeg::Error::Other { errno })
+                if std::io::Error::from_raw_os_error(errno).kind()
+                    == std::io::ErrorKind::NotFound =>
+            {
+                VideoProcessingPluginError::FileNotFound
+            }
+            VideoDecoderError::FFMpegError(ffmpeg::Error::StreamNotFound) => {
+                VideoProcessingPluginError::StreamNotFound
+            }
+            VideoDecoderError::Cancelled => VideoProcessingPluginError::Cancelled,
+            VideoDecoderError::UnsupportedBitDepth(_) => {
+                VideoProcessingPluginError::UnsupportedBitDepth
+            }
+            VideoDecoderError::ProtocolNotAllowed(_) => VideoProcessingPluginError::InvalidArgument,
+            VideoDecoderError::NetworkError(_) => VideoProcessingPluginError::NetworkError,
+            _ => VideoProcessingPluginError::DecodeFailed,
+        }
+    }
+}
+
+impl From<VideoEncoderError> for VideoProcessingPluginError {
+    fn from(value: VideoEncoderError) -> Self {
+        match value {
+            VideoEncoderError::Cancelled => VideoProcessingPluginError::Cancelled,
+            VideoEncoderError::UnsupportedCodec(_) => VideoProcessingPluginError::UnsupportedCodec,
+            VideoEncoderError::UnsupportedFormat(_) => {
+                VideoProcessingPluginError::UnsupportedFormat
+            }
+            _ => VideoProcessingPluginError::EncodeFailed,
+        }
+    }
 }
 
-pub enum VideoProcessingPluginError {}
+impl From<picture::Type> for FrameType {
+    fn from(value: picture::Type) -> Self {
+        match value {
+            picture::Type::I => FrameType::I,
+            picture::Type::P => FrameType::P,
+            picture::Type::B => FrameType::B,
+            picture::Type::S => FrameType::S,
+            picture::Type::SI => FrameType::SI,
+            picture::Type::SP => FrameType::SP,
+            picture::Type::BI => FrameType::BI,
+            picture::Type::None => FrameType::Unknown,
+        }
+    }
+}
+
+impl From<FrameType> for picture::Type {
+    fn from(value: FrameType) -> Self {
+        match value {
+            FrameType::I => picture::Type::I,
+            FrameType::P => picture::Type::P,
+            FrameType::B => picture::Type::B,
+            FrameType::S => picture::Type::S,
+            FrameType::SI => picture::Type::SI,
+            FrameType::SP => picture::Type::SP,
+            FrameType::BI => picture::Type::BI,
+            FrameType::Unknown => picture::Type::None,
+        }
+    }
+}
 
 impl Debug for VideoInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -65,35 +353,25 @@ impl Debug for VideoInfo {
             .field("frame_rate", &self.frame_rate.0)
             .field("input_stream_meta_data", &self.input_stream_meta_data)
             .field("itcx_number_streams", &self.itcx_number_streams)
+            .field("color", &self.color)
+            .field("container_format", &self.container_format)
+            .field("time_base", &self.time_base)
+            .field("programs", &self.programs)
+            .field("streams", &self.streams)
+            .field(
+                "audio_packets",
+                &self.audio.as_ref().map(|audio| audio.packets.len()),
+            )
+            .field("source_codec", &self.source_codec)
+            .field("duration_millis", &self.duration_millis)
             .finish()
     }
 }
 
 impl VideoInfo {
-    pub fn new(
-        codec: Codec,
-        format: Pixel,
-        width: Width,
-        height: Height,
-        aspect_ratio: AspectRatio,
-        frame_rate: FrameRate,
-        input_stream_meta_data: dictionary::Owned,
-        itcx_number_streams: u32,
-        bitrate: BitRate,
-        max_bitrate: MaxBitRate,
-    ) -> Self {
-        VideoInfo {
-            codec,
-            format,
-            width,
-            height,
-            aspect_ratio,
-            frame_rate,
-            input_stream_meta_data,
-            itcx_number_streams,
-            bitrate,
-            max_bitrate,
-        }
+    /// Starts describing a video, see `VideoInfoBuilder` for the fields that must be set
+    pub fn builder() -> VideoInfoBuilder {
+        VideoInfoBuilder::default()
     }
 
     pub fn width(&self) -> u32 {
@@ -105,37 +383,293 @@ impl VideoInfo {
     }
 }
 
+/// Builds a `VideoInfo` field by field, so fields of the same type cannot be transposed as with
+/// a positional constructor. The codecs, pixel format and dimensions must be set, the other
+/// fields default to unknown (no frame rate, time base or duration, unspecified color) or empty.
+#[derive(Default)]
+pub struct VideoInfoBuilder {
+    codec: Option<Codec>,
+    format: Option<Pixel>,
+    width: Option<Width>,
+    height: Option<Height>,
+    aspect_ratio: Option<AspectRatio>,
+    frame_rate: Option<FrameRate>,
+    input_stream_meta_data: Option<dictionary::Owned>,
+    itcx_number_streams: u32,
+    bitrate: Option<BitRate>,
+    max_bitrate: Option<MaxBitRate>,
+    color: Option<ColorProperties>,
+    container_format: String,
+    time_base: Option<Rational>,
+    programs: Vec<Vec<u32>>,
+    streams: Vec<StreamDescription>,
+    audio: Option<AudioTrack>,
+    source_codec: Option<codec::Id>,
+    duration_millis: Option<i64>,
+}
+
+impl VideoInfoBuilder {
+    /// Encoder the video is re-encoded with
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = Some(codec);
+        self
+    }
+
+    /// Codec of the source stream
+    pub fn source_codec(mut self, source_codec: codec::Id) -> Self {
+        self.source_codec = Some(source_codec);
+        self
+    }
+
+    pub fn format(mut self, format: Pixel) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    pub fn width(mut self, width: Width) -> Self {
+        self.width = Some(width);
+        self
+    }
+
+    pub fn height(mut self, height: Height) -> Self {
+        self.height = Some(height);
+        self
+    }
+
+    pub fn aspect_ratio(mut self, aspect_ratio: AspectRatio) -> Self {
+        self.aspect_ratio = Some(aspect_ratio);
+        self
+    }
+
+    pub fn frame_rate(mut self, frame_rate: FrameRate) -> Self {
+        self.frame_rate = Some(frame_rate);
+        self
+    }
+
+    pub fn metadata(mut self, metadata: dictionary::Owned) -> Self {
+        self.input_stream_meta_data = Some(metadata);
+        self
+    }
+
+    pub fn number_streams(mut self, number_streams: u32) -> Self {
+        self.itcx_number_streams = number_streams;
+        self
+    }
+
+    pub fn bitrate(mut self, bitrate: BitRate) -> Self {
+        self.bitrate = Some(bitrate);
+        self
+    }
+
+    pub fn max_bitrate(mut self, max_bitrate: MaxBitRate) -> Self {
+        self.max_bitrate = Some(max_bitrate);
+        self
+    }
+
+    pub fn color(mut self, color: ColorProperties) -> Self {
+        self.color = Some(color);
+        self
+    }
+
+    pub fn container_format(mut self, container_format: impl Into<String>) -> Self {
+        self.container_format = container_format.into();
+        self
+    }
+
+    pub fn time_base(mut self, time_base: Option<Rational>) -> Self {
+        self.time_base = time_base;
+        self
+    }
+
+    pub fn programs(mut self, programs: Vec<Vec<u32>>) -> Self {
+        self.programs = programs;
+        self
+    }
+
+    pub fn streams(mut self, streams: Vec<StreamDescription>) -> Self {
+        self.streams = streams;
+        self
+    }
+
+    pub fn audio(mut self, audio: Option<AudioTrack>) -> Self {
+        self.audio = audio;
+        self
+    }
+
+    pub fn duration_millis(mut self, duration_millis: Option<i64>) -> Self {
+        self.duration_millis = duration_millis;
+        self
+    }
+
+    /// The described video, or the name of the first required field left unset
+    pub fn build(self) -> Result<VideoInfo, &'static str> {
+        Ok(VideoInfo {
+            codec: self.codec.ok_or("codec")?,
+            format: self.format.ok_or("format")?,
+            width: self.width.ok_or("width")?,
+            height: self.height.ok_or("height")?,
+            aspect_ratio: self
+                .aspect_ratio
+                .unwrap_or(AspectRatio(Rational::new(0, 1))),
+            frame_rate: self.frame_rate.unwrap_or(FrameRate(None)),
+            input_stream_meta_data: self
+                .input_stream_meta_data
+                .unwrap_or_else(dictionary::Owned::new),
+            itcx_number_streams: self.itcx_number_streams,
+            bitrate: self.bitrate.unwrap_or(BitRate(0)),
+            max_bitrate: self.max_bitrate.unwrap_or(MaxBitRate(0)),
+            color: self.color.unwrap_or(ColorProperties {
+                primaries: color::Primaries::Unspecified,
+                transfer: color::TransferCharacteristic::Unspecified,
+                matrix: color::Space::Unspecified,
+                range: color::Range::Unspecified,
+            }),
+            container_format: self.container_format,
+            time_base: self.time_base,
+            programs: self.programs,
+            streams: self.streams,
+            audio: self.audio,
+            source_codec: self.source_codec.ok_or("source_codec")?,
+            duration_millis: self.duration_millis,
+        })
+    }
+}
+
 #[host_function]
 fn init_plugin_logging(
     caller: Caller,
     args: Vec<WasmValue>,
-    _data: &mut Arc<Mutex<FramesMap>>,
+    _data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    guest_status(|| {
+        let log_level_ptr = args[0].to_i32() as *mut i32;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let log_level_main_memory = main_memory.try_get_ptr::<u32>(log_level_ptr as u32, 1)?;
+
+        let log_level = log_level_filter(unsafe { *log_level_main_memory } as i32);
+
+        if let Err(err) = CombinedLogger::init(vec![TermLogger::new(
+            // Lines are filtered by the global max level instead, which `set_log_level` adjusts
+            LevelFilter::Trace,
+            Config::default(),
+            TerminalMode::Mixed,
+            ColorChoice::Always,
+        )]) {
+            eprintln!("Could not Initialize Plugin Logging {}", err);
+        };
+        apply_log_level(log_level);
+
+        return Ok(vec![WasmValue::from_i32(0)]);
+    })
+}
+
+/// Changes the verbosity of the plugin's and FFMPEG's logging after `init_plugin_logging`,
+/// e.g. to get debug output while diagnosing a decode issue.
+/// `level` takes the values of `init_plugin_logging`: 0 Off, 1 Error, 2 Warn, 3 Info, 4 Debug,
+/// higher values Trace.
+#[host_function]
+fn set_log_level(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    _data: &mut Arc<RwLock<Sessions>>,
 ) -> Result<Vec<WasmValue>, HostFuncError> {
-    let log_level_ptr = args[0].to_i32() as *mut i32;
+    guest_status(|| {
+        let level = args[0].to_i32();
+        if level < 0 {
+            error!("Log level {level} must not be negative");
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
 
-    let mut main_memory = caller.memory(0).ok_or(HostFuncError::User(1))?;
+        apply_log_level(log_level_filter(level));
+        debug!("set_log_level {level}");
 
-    let log_level_main_memory = main_memory.try_get_ptr::<u32>(log_level_ptr as u32, 1)?;
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
 
-    let log_level = match unsafe { *log_level_main_memory } {
+fn log_level_filter(level: i32) -> LevelFilter {
+    match level {
         0 => LevelFilter::Off,
         1 => LevelFilter::Error,
         2 => LevelFilter::Warn,
         3 => LevelFilter::Info,
         4 => LevelFilter::Debug,
         _ => LevelFilter::Trace,
-    };
+    }
+}
 
-    if let Err(err) = CombinedLogger::init(vec![TermLogger::new(
-        log_level,
-        Config::default(),
-        TerminalMode::Mixed,
-        ColorChoice::Always,
-    )]) {
-        eprintln!("Could not Initialize Plugin Logging {}", err);
-    };
+fn apply_log_level(log_level: LevelFilter) {
+    log::set_max_level(log_level);
+    diagnostics::set_ffmpeg_log_level(log_level);
+}
+
+// Exclusive access for host functions that modify the plugin data
+fn lock_frames(
+    data: &Arc<RwLock<Sessions>>,
+) -> Result<RwLockWriteGuard<'_, Sessions>, HostFuncError> {
+    data.write().map_err(|err| {
+        error!("Lock Carrying plugin Data Poisoned {err}");
+        HostFuncError::Runtime(1)
+    })
+}
+
+// Shared access for host functions that only read the plugin data, e.g. guest threads
+// calling `get_frame` for different frames at once
+fn read_frames(
+    data: &Arc<RwLock<Sessions>>,
+) -> Result<RwLockReadGuard<'_, Sessions>, HostFuncError> {
+    data.read().map_err(|err| {
+        error!("Lock Carrying plugin Data Poisoned {err}");
+        HostFuncError::Runtime(1)
+    })
+}
+
+// Splits the arguments of a host function working on a session into the session handle, always
+// its first argument, and the function's own arguments
+fn session_args(args: &[WasmValue]) -> (u32, &[WasmValue]) {
+    (args[0].to_i32() as u32, &args[1..])
+}
+
+// `lock_frames` for host functions working on the session `session_id`, failing with
+// `SessionNotFound` for handles of sessions never created or already closed
+fn lock_session(
+    data: &Arc<RwLock<Sessions>>,
+    session_id: u32,
+) -> Result<SessionGuard<RwLockWriteGuard<'_, Sessions>>, HostFuncError> {
+    SessionGuard::new(lock_frames(data)?, session_id)
+}
+
+// `read_frames` for host functions only reading the session `session_id`
+fn read_session(
+    data: &Arc<RwLock<Sessions>>,
+    session_id: u32,
+) -> Result<SessionGuard<RwLockReadGuard<'_, Sessions>>, HostFuncError> {
+    SessionGuard::new(read_frames(data)?, session_id)
+}
+
+/// Runs the body of a host function, returning a `VideoProcessingPluginError` to the guest as its
+/// negated code rather than trapping, see the crate documentation
+fn guest_status(
+    body: impl FnOnce() -> Result<Vec<WasmValue>, HostFuncError>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    match body() {
+        Err(HostFuncError::User(code)) => Ok(vec![WasmValue::from_i32(-(code as i32))]),
+        result => result,
+    }
+}
 
-    return Ok(vec![WasmValue::from_i32(0)]);
+/// `guest_status` for host functions returning an i64
+fn guest_status_i64(
+    body: impl FnOnce() -> Result<Vec<WasmValue>, HostFuncError>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    match body() {
+        Err(HostFuncError::User(code)) => Ok(vec![WasmValue::from_i64(-(code as i64))]),
+        result => result,
+    }
 }
 
 trait TryGetPointer {
@@ -148,297 +682,5075 @@ impl TryGetPointer for Memory {
             Ok(x) => Ok(x as *mut T),
             Err(err) => {
                 error!("Error Getting Value from Pointer {}", err);
-                Err(HostFuncError::User(1))
+                Err(VideoProcessingPluginError::MemoryAccessFailed.into())
+            }
+        }
+    }
+}
+
+trait TryWriteBytes {
+    fn try_write_bytes(&mut self, offset: u32, bytes: &[u8]) -> Result<(), HostFuncError>;
+}
+
+impl TryWriteBytes for Memory {
+    fn try_write_bytes(&mut self, offset: u32, bytes: &[u8]) -> Result<(), HostFuncError> {
+        match self.write(bytes, offset) {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                error!("Error Writing Bytes to Pointer {}", err);
+                Err(VideoProcessingPluginError::MemoryAccessFailed.into())
             }
         }
     }
 }
 
+/// Writes as much of `text` as fits into the guest buffer, truncating on a char boundary.
+/// Returns the full length of `text` so the guest can detect truncation and retry.
+fn write_guest_str(
+    memory: &mut Memory,
+    buf_ptr: u32,
+    buf_len: u32,
+    text: &str,
+) -> Result<usize, HostFuncError> {
+    memory.try_write_bytes(
+        buf_ptr,
+        truncate_to_char_boundary(text, buf_len as usize).as_bytes(),
+    )?;
+    Ok(text.len())
+}
+
+/// Longest prefix of `text` of at most `max_len` bytes that does not split a char
+fn truncate_to_char_boundary(text: &str, max_len: usize) -> &str {
+    let mut end = text.len().min(max_len);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Copies an RGB24 frame tightly packed into the guest buffer after checking it fits
+fn copy_frame_to_guest(
+    memory: &mut Memory,
+    frame: &frame::Video,
+    buf_ptr: i32,
+    buf_len: i32,
+) -> Result<(), HostFuncError> {
+    check_frame_buffer_len(frame, buf_len)?;
+    memory.try_write_bytes(buf_ptr as u32, &frame_ops::packed_rgb24_bytes(frame))
+}
+
+/// Copies the frame of `frame_map` into the guest buffer as `get_frame` copies its planes in
+/// `frame_format`, after checking it fits in `buf_len` bytes
+fn copy_guest_frame(
+    memory: &mut Memory,
+    frame_map: &FrameMap,
+    frame_format: PixelFormat,
+    buf_ptr: u32,
+    buf_len: i32,
+) -> Result<(), HostFuncError> {
+    let frame = frame_map.guest_frame(frame_format).map_err(|err| {
+        error!("Error Converting Frame to {:?} {:?}", frame_format, err);
+        VideoProcessingPluginError::ProcessingFailed
+    })?;
+    let frame_bytes = frame_ops::packed_frame_bytes(&frame);
+    if buf_len < 0 || (buf_len as usize) < frame_bytes.len() {
+        error!(
+            "Image buffer of {buf_len} bytes too small, {} required",
+            frame_bytes.len()
+        );
+        return Err(VideoProcessingPluginError::InvalidBufferSize.into());
+    }
+    memory.try_write_bytes(buf_ptr, &frame_bytes)
+}
+
+/// Fails unless a guest buffer of `buf_len` bytes holds the frame's tightly packed pixels
+fn check_frame_buffer_len(frame: &frame::Video, buf_len: i32) -> Result<(), HostFuncError> {
+    let required_len = frame.width() as usize * frame.height() as usize * 3;
+    if buf_len < 0 || (buf_len as usize) < required_len {
+        error!("Image buffer of {buf_len} bytes too small, {required_len} required");
+        return Err(VideoProcessingPluginError::InvalidBufferSize.into());
+    }
+    Ok(())
+}
+
+/// Copies a UTF-8 string out of guest memory into an owned `String`
+fn read_guest_string(memory: &Memory, ptr: i32, len: i32) -> Result<String, HostFuncError> {
+    let bytes = match memory.read(ptr as u32, len as u32) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            error!("Error Reading String from Pointer {}", err);
+            return Err(VideoProcessingPluginError::MemoryAccessFailed.into());
+        }
+    };
+
+    String::from_utf8(bytes).map_err(|err| {
+        error!("Guest String is not valid UTF-8 {}", err);
+        HostFuncError::from(VideoProcessingPluginError::InvalidString)
+    })
+}
+
+/// Caps the resolution frames are stored at by subsequent loads.
+/// Larger sources are downscaled during decode preserving their aspect ratio,
+/// the effective dimensions are reported by `load_video_to_host_memory`.
+/// Passing `0, 0` removes the cap.
 #[host_function]
-fn load_video_to_host_memory(
+fn set_max_decode_resolution(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_max_decode_resolution");
+
+    guest_status(|| {
+        let mut data_guard = lock_frames(data)?;
+
+        let max_width = args[0].to_i32();
+        let max_height = args[1].to_i32();
+
+        data_guard.decode_options.max_resolution = match (max_width, max_height) {
+            (0, 0) => None,
+            (w, h) if w > 0 && h > 0 => Some((Width(w as u32), Height(h as u32))),
+            (w, h) => {
+                error!("Invalid max decode resolution {w}x{h}");
+                return Err(VideoProcessingPluginError::InvalidArgument.into());
+            }
+        };
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// With `enabled` non-zero, subsequent loads turn frames upright according to the source's
+/// display rotation (e.g. phone videos recorded in portrait), reporting the rotated dimensions
+/// from `load_video_to_host_memory` and `get_video_info`. Frames read with `next_frame` are
+/// returned as decoded.
+#[host_function]
+fn set_auto_rotate(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_auto_rotate");
+
+    guest_status(|| {
+        let mut data_guard = lock_frames(data)?;
+        data_guard.decode_options.auto_rotate = args[0].to_i32() != 0;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Selects the stream subsequent loads (and `open_video`) decode by its index in the container,
+/// for files with several video streams (e.g. multi-angle recordings), up to the stream count
+/// reported by `get_video_info`. Loads fail with `StreamNotFound` when the stream at the index is
+/// not a video stream. A negative index restores decoding the container's best video stream.
+#[host_function]
+fn set_video_stream(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_video_stream");
+
+    guest_status(|| {
+        let stream_index = args[0].to_i32();
+
+        let mut data_guard = lock_frames(data)?;
+        data_guard.decode_options.stream_index = match stream_index {
+            stream_index if stream_index < 0 => None,
+            stream_index => Some(stream_index as usize),
+        };
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Selects the hardware device subsequent loads decode on by FFMPEG's device type name, "vaapi"
+/// or "cuda", with "none" (or an empty name) restoring software decoding. Frames are downloaded to
+/// system memory before being stored, so the rest of the plugin handles them as usual. When the
+/// device cannot be opened or does not support the codec, loads decode in software with a warning.
+#[host_function]
+fn set_hw_decode_device(
     caller: Caller,
     args: Vec<WasmValue>,
-    data: &mut Arc<Mutex<FramesMap>>,
+    data: &mut Arc<RwLock<Sessions>>,
 ) -> Result<Vec<WasmValue>, HostFuncError> {
-    debug!("Load_video");
+    debug!("set_hw_decode_device");
 
-    let data_guard = match data.lock() {
-        Ok(x) => x,
-        Err(err) => {
-            error!("Mutex Carrying plugin Data Poisoned {err}");
-            return Err(HostFuncError::Runtime(1));
-        }
-    };
+    guest_status(|| {
+        let mut data_guard = lock_frames(data)?;
+
+        let main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let device_name = read_guest_string(&main_memory, args[0].to_i32(), args[1].to_i32())?;
+
+        data_guard.decode_options.hw_device = match device_name.as_str() {
+            "" | "none" => None,
+            name => match HwDevice::from_name(name) {
+                Some(hw_device) => Some(hw_device),
+                None => {
+                    error!("Unknown hardware decode device {name}");
+                    return Err(VideoProcessingPluginError::InvalidArgument.into());
+                }
+            },
+        };
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Frames are stored with 8 bits per component, so by default loading a deeper source (e.g. 10-bit
+/// HDR) fails with `UnsupportedBitDepth` rather than silently losing precision. With `enabled`
+/// non-zero subsequent loads convert such sources to 8 bits per component instead.
+#[host_function]
+fn set_bit_depth_reduction(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_bit_depth_reduction");
+
+    guest_status(|| {
+        let mut data_guard = lock_frames(data)?;
+        data_guard.decode_options.reduce_bit_depth = args[0].to_i32() != 0;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
 
-    let mut main_memory = caller.memory(0).ok_or(HostFuncError::User(1))?;
+// The body of `load_video_to_host_memory`, reading its arguments from `main_memory`
+fn load_video_for_guest(
+    main_memory: &mut Memory,
+    args: &[WasmValue],
+    data: &Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    let mut data_guard = lock_frames(data)?;
 
     let filename_ptr = args[0].to_i32();
     let filename_len = args[1].to_i32();
-    let filaname_capacity = args[2].to_i32();
+    // args[2] is the capacity of the guest's String, unused as the filename is copied out
 
     let width_ptr = args[3].to_i32() as *mut i32;
     let height_ptr = args[4].to_i32() as *mut i32;
     let frames_ptr = args[5].to_i32() as *mut i32;
+    let err_ptr = args[6].to_i32();
+    let err_cap = args[7].to_i32();
+    let err_len_ptr = args[8].to_i32() as *mut i32;
+
+    if err_cap < 0 {
+        error!("Negative error buffer capacity {err_cap}");
+        return Err(VideoProcessingPluginError::InvalidBufferSize.into());
+    }
 
     let width_ptr_main_memory = main_memory.try_get_ptr::<u32>(width_ptr as u32, 1)?;
     let height_ptr_main_memory = main_memory.try_get_ptr::<u32>(height_ptr as u32, 1)?;
     let frames_ptr_main_memory = main_memory.try_get_ptr::<u32>(frames_ptr as u32, 1)?;
+    let err_len_ptr_main_memory = main_memory.try_get_ptr::<u32>(err_len_ptr as u32, 1)?;
 
-    let filename_ptr_main_memory =
-        main_memory.try_get_ptr::<u8>(filename_ptr as u32, filename_len as u32)?;
-
-    let filename: String = unsafe {
-        String::from_raw_parts(
-            filename_ptr_main_memory,
-            filename_len as usize,
-            filaname_capacity as usize,
-        )
-    };
+    let filename = read_guest_string(main_memory, filename_ptr, filename_len)?;
 
     debug!("Call FFMPEG dump Frames");
 
-    let res = match decode_video::dump_frames(&filename) {
+    let sessions = &mut *data_guard;
+    let res = match decode_video::dump_frames(
+        &filename,
+        &sessions.decode_options,
+        sessions.context_cache.get_mut(),
+        &sessions.operation,
+    ) {
+        Ok((frames, _)) if frames.is_empty() => {
+            let message = format!("Video file {} contained No Frames", filename);
+            error!("{message}");
+            unsafe {
+                *width_ptr_main_memory = 0;
+                *height_ptr_main_memory = 0;
+                *frames_ptr_main_memory = 0;
+            }
+            Err((VideoProcessingPluginError::EmptyVideo, message))
+        }
         Ok((frames, video_info)) => {
             debug!("Input Frame Count {}", frames.len());
-            if frames.len() > 0 {
-                unsafe {
-                    *width_ptr_main_memory = frames[0].input_frame.width();
-                    *height_ptr_main_memory = frames[0].input_frame.height();
-                }
-            } else {
-                error!("Video file {} contained No Frames", filename);
-                return Err(HostFuncError::User(1));
+            unsafe {
+                *width_ptr_main_memory = frames[0].input_frame.width();
+                *height_ptr_main_memory = frames[0].input_frame.height();
             }
 
-            let mut vid_gaurd = data_guard;
-            vid_gaurd.video_info = Some(video_info);
-            vid_gaurd.frames = frames;
+            let mut session = sessions.new_session();
+            session.video_info = Some(video_info);
+            session.frames = frames;
+            session.source_filename = Some(filename.clone());
             unsafe {
-                *frames_ptr_main_memory = vid_gaurd.frames.len() as u32;
+                *frames_ptr_main_memory = session.frames.len() as u32;
             }
-            Ok(vec![WasmValue::from_i32(0)])
+            Ok(vec![WasmValue::from_i32(sessions.insert(session) as i32)])
         }
         Err(err) => {
-            error!("Error Loading Frames {:?}", err);
-            Err(HostFuncError::User(1))
+            let message = format!("Error Loading Frames {:?}", err);
+            error!("{message}");
+            Err((VideoProcessingPluginError::from(err), message))
         }
     };
 
-    // Need to forget x otherwise we get a double free
-    std::mem::forget(filename);
-    res
+    res.or_else(|(err, message)| {
+        let written = truncate_to_char_boundary(&message, err_cap as usize);
+        main_memory.try_write_bytes(err_ptr as u32, written.as_bytes())?;
+        unsafe {
+            *err_len_ptr_main_memory = written.len() as u32;
+        }
+        if written.len() < message.len() {
+            error!(
+                "Error buffer of {err_cap} bytes too small, {} required",
+                message.len()
+            );
+            return Err(VideoProcessingPluginError::InvalidBufferSize.into());
+        }
+        Err(err.into())
+    })
 }
 
+/// Decodes the video at the given filename into host memory as a new session, returning its handle
+/// for the other host functions to take, and writes the dimensions of the stored frames and the
+/// frame count through the pointers. Streams changing resolution mid-stream have
+/// every frame scaled to the dimensions of the first one. A video without any decodable frame
+/// writes zeros through the three pointers and fails with `EmptyVideo`; they are left untouched
+/// by other failures. A failure returns its negated error code after writing a UTF-8 description
+/// of the error, truncated to `err_cap` bytes, to `err_ptr` and its written length to
+/// `err_len_ptr`; a description that does not fit returns `InvalidBufferSize`. No session is
+/// created by a failed load. The filename may be a URL (e.g. `http://` or `rtsp://`) of a
+/// protocol the host lists in `VIDEO_PROC_ALLOWED_PROTOCOLS`, failing with `NetworkError` when it
+/// cannot be connected to.
 #[host_function]
-fn get_frame(
+fn load_video_to_host_memory(
     caller: Caller,
     args: Vec<WasmValue>,
-    data: &mut Arc<Mutex<FramesMap>>,
+    data: &mut Arc<RwLock<Sessions>>,
 ) -> Result<Vec<WasmValue>, HostFuncError> {
-    debug!("get_frame");
+    debug!("Load_video");
 
-    let data_guard = match data.lock() {
-        Ok(x) => x,
-        Err(err) => {
-            error!("Mutex Carrying plugin Data Poisoned {err}");
-            return Err(HostFuncError::Runtime(1));
-        }
-    };
+    guest_status(|| {
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+        load_video_for_guest(&mut main_memory, &args, data)
+    })
+}
 
-    let mut main_memory = caller.memory(0).ok_or(HostFuncError::User(1))?;
+/// Decodes only frames `start` to `end` (inclusive) of the video at the given filename into host
+/// memory as a new session, returning its handle, e.g. a short clip of a long recording. Decoding
+/// starts at the keyframe before `start` and stops after `end`. The stored frames are indexed from
+/// 0, writes their dimensions and count through the pointers. A range starting after the last
+/// frame writes zeros through them and fails with `FrameIndexOutOfRange`.
+#[host_function]
+fn load_video_range(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("load_video_range");
 
-    let idx: i32 = args[0].to_i32();
-    let image_buf_ptr = args[1].to_i32();
-    let image_buf_len = args[2].to_i32() as usize;
-    let image_buf_capacity = args[3].to_i32() as usize;
+    guest_status(|| {
+        let mut data_guard = lock_frames(data)?;
 
-    debug!("LIB image_buf_ptr {:?}", image_buf_ptr);
-    debug!("LIB image_buf_len {:?}", image_buf_len);
-    debug!("LIB image_buf_capacity {:?}", image_buf_capacity);
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
 
-    let image_ptr_wasm_memory = main_memory
-        .data_pointer_mut(image_buf_ptr as u32, image_buf_len as u32)
-        .expect("Could not get Data pointer");
+        let filename_ptr = args[0].to_i32();
+        let filename_len = args[1].to_i32();
+        let start = args[2].to_i32();
+        let end = args[3].to_i32();
+        let width_ptr = args[4].to_i32();
+        let height_ptr = args[5].to_i32();
+        let frames_ptr = args[6].to_i32();
 
-    let mut vec =
-        unsafe { Vec::from_raw_parts(image_ptr_wasm_memory, image_buf_len, image_buf_capacity) };
+        if start < 0 || end < start {
+            error!("Invalid frame range {start}..={end}");
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
 
-    if let Some(frame) = data_guard.frames.get(idx as usize) {
-        debug!("LIB data {:?}", frame.input_frame.data(0).len());
-        vec.copy_from_slice(frame.input_frame.data(0));
-    } else {
-        error!("Return error if frame does not exist");
-    };
+        let filename = read_guest_string(&main_memory, filename_ptr, filename_len)?;
 
-    // Need to forget x otherwise we get a double free
-    std::mem::forget(vec);
-    Ok(vec![WasmValue::from_i32(0)])
+        let sessions = &mut *data_guard;
+        let options = DecodeOptions {
+            frame_range: Some((start as usize, end as usize)),
+            ..sessions.decode_options
+        };
+        let (frames, video_info) = decode_video::dump_frames(
+            &filename,
+            &options,
+            sessions.context_cache.get_mut(),
+            &sessions.operation,
+        )
+        .map_err(|err| {
+            error!(
+                "Error Loading Frames {start}..={end} of {filename} {:?}",
+                err
+            );
+            VideoProcessingPluginError::from(err)
+        })?;
+
+        write_loaded_frames_info(&mut main_memory, width_ptr, height_ptr, frames_ptr, &frames)?;
+
+        if frames.is_empty() {
+            error!("Video file {filename} has no frames in {start}..={end}");
+            return Err(VideoProcessingPluginError::FrameIndexOutOfRange.into());
+        }
+
+        let mut session = sessions.new_session();
+        session.video_info = Some(video_info);
+        session.frames = frames;
+        session.source_filename = Some(filename);
+
+        Ok(vec![WasmValue::from_i32(sessions.insert(session) as i32)])
+    })
 }
 
+/// Decodes the video at the given filename into host memory as a new session, returning its
+/// handle, keeping only every `stride`th frame, e.g. to run inference on a high frame rate source
+/// without holding every frame. The retained frames keep their timestamps and span the skipped
+/// frames, so reassembling them preserves the clip's timing. Writes the dimensions of the stored
+/// frames and their count through the pointers, zeros for a video without frames, which fails
+/// with `EmptyVideo`.
 #[host_function]
-fn write_frame(
+fn load_video_sampled(
     caller: Caller,
     args: Vec<WasmValue>,
-    data: &mut Arc<Mutex<FramesMap>>,
+    data: &mut Arc<RwLock<Sessions>>,
 ) -> Result<Vec<WasmValue>, HostFuncError> {
-    debug!("write_frame");
+    debug!("load_video_sampled");
 
-    let mut data_guard = match data.lock() {
-        Ok(x) => x,
-        Err(err) => {
-            error!("Mutex Carrying plugin Data Poisoned {err}");
-            return Err(HostFuncError::Runtime(1));
-        }
-    };
+    guest_status(|| {
+        let mut data_guard = lock_frames(data)?;
 
-    let mut main_memory = caller.memory(0).ok_or(HostFuncError::User(1))?;
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
 
-    let video_info = data_guard
-        .video_info
-        .as_ref()
-        .expect("Could not get Video Info data ");
+        let filename_ptr = args[0].to_i32();
+        let filename_len = args[1].to_i32();
+        let stride = args[2].to_i32();
+        let width_ptr = args[3].to_i32();
+        let height_ptr = args[4].to_i32();
+        let frames_ptr = args[5].to_i32();
 
-    let idx = args[0].to_i32() as usize;
-    let image_buf_ptr = args[1].to_i32();
-    let image_buf_len = args[2].to_i32() as usize;
+        if stride <= 0 {
+            error!("Invalid frame stride {stride}");
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
 
-    let image_ptr_wasm_memory = main_memory
-        .data_pointer_mut(image_buf_ptr as u32, image_buf_len as u32)
-        .expect("Could not get Data pointer");
+        let filename = read_guest_string(&main_memory, filename_ptr, filename_len)?;
 
-    let vec = unsafe {
-        Vec::from_raw_parts(
-            image_ptr_wasm_memory,
-            image_buf_len,
-            (video_info.width() * video_info.height() * 3) as usize,
+        let sessions = &mut *data_guard;
+        let options = DecodeOptions {
+            frame_stride: stride as usize,
+            ..sessions.decode_options
+        };
+        let (frames, video_info) = decode_video::dump_frames(
+            &filename,
+            &options,
+            sessions.context_cache.get_mut(),
+            &sessions.operation,
         )
-    };
-
-    debug!(
-        "BUFFER SIZE {}",
-        video_info.width() * video_info.height() * 3
-    );
+        .map_err(|err| {
+            error!(
+                "Error Loading every {stride}th Frame of {filename} {:?}",
+                err
+            );
+            VideoProcessingPluginError::from(err)
+        })?;
 
-    let mut video_frame = frame::Video::new(
-        ffmpeg::format::Pixel::RGB24,
-        video_info.width.0,
-        video_info.height.0,
-    );
+        write_loaded_frames_info(&mut main_memory, width_ptr, height_ptr, frames_ptr, &frames)?;
 
-    {
-        let data = video_frame.data_mut(0);
-        data.copy_from_slice(&vec);
-    }
-
-    debug!("Writing Frame {idx}");
+        if frames.is_empty() {
+            error!("Video file {filename} contained No Frames");
+            return Err(VideoProcessingPluginError::EmptyVideo.into());
+        }
 
-    if let Some(frame_map) = data_guard.frames.get_mut(idx) {
-        frame_map.output_frame = Some(video_frame);
-    } else {
-        // Need to forget x otherwise we get a double free
-        std::mem::forget(vec);
-        return Ok(vec![WasmValue::from_i32(1)]);
-    };
+        let mut session = sessions.new_session();
+        session.video_info = Some(video_info);
+        session.frames = frames;
+        session.source_filename = Some(filename);
 
-    // Need to forget x otherwise we get a double free
-    std::mem::forget(vec);
-    Ok(vec![WasmValue::from_i32(0)])
+        Ok(vec![WasmValue::from_i32(sessions.insert(session) as i32)])
+    })
 }
 
+/// Decodes an encoded video held in the guest buffer into host memory as a new session, as
+/// `load_video_to_host_memory` does for a file, e.g. for guests without filesystem access.
+/// The bytes are copied out of the guest first. Writes the dimensions of the stored frames and
+/// the frame count through the pointers, zeros for a video without frames, which fails with
+/// `EmptyVideo`.
 #[host_function]
-fn assemble_output_frames_to_video(
+fn load_video_from_bytes(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("load_video_from_bytes");
+
+    guest_status(|| {
+        let mut data_guard = lock_frames(data)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let buf_ptr = args[0].to_i32();
+        let buf_len = args[1].to_i32();
+        let width_ptr = args[2].to_i32();
+        let height_ptr = args[3].to_i32();
+        let frames_ptr = args[4].to_i32();
+
+        if buf_len <= 0 {
+            error!("Video buffer of {buf_len} bytes is empty");
+            return Err(VideoProcessingPluginError::InvalidBufferSize.into());
+        }
+
+        let bytes = main_memory
+            .read(buf_ptr as u32, buf_len as u32)
+            .map_err(|err| {
+                error!("Error Reading Video Bytes from Pointer {}", err);
+                VideoProcessingPluginError::MemoryAccessFailed
+            })?;
+
+        let sessions = &mut *data_guard;
+        let (frames, video_info) = decode_video::dump_frames_from_bytes(
+            bytes,
+            &sessions.decode_options,
+            sessions.context_cache.get_mut(),
+            &sessions.operation,
+        )
+        .map_err(|err| {
+            error!("Error Loading Frames from Bytes {:?}", err);
+            VideoProcessingPluginError::from(err)
+        })?;
+
+        write_loaded_frames_info(&mut main_memory, width_ptr, height_ptr, frames_ptr, &frames)?;
+
+        if frames.is_empty() {
+            error!("Video buffer contained No Frames");
+            return Err(VideoProcessingPluginError::EmptyVideo.into());
+        }
+
+        let mut session = sessions.new_session();
+        session.video_info = Some(video_info);
+        session.frames = frames;
+        // There is no file to re-open, functions needing one report that no video is loaded
+        session.source_filename = None;
+
+        Ok(vec![WasmValue::from_i32(sessions.insert(session) as i32)])
+    })
+}
+
+// Writes the dimensions of the first of the loaded `frames` and their count through the guest
+// pointers, zeros when nothing was decoded so the guest never reads what was there before
+fn write_loaded_frames_info(
+    memory: &mut Memory,
+    width_ptr: i32,
+    height_ptr: i32,
+    frames_ptr: i32,
+    frames: &Frames,
+) -> Result<(), HostFuncError> {
+    let (width, height) = frames.first().map_or((0, 0), |first| {
+        (first.input_frame.width(), first.input_frame.height())
+    });
+    memory.try_write_bytes(width_ptr as u32, &(width as i32).to_le_bytes())?;
+    memory.try_write_bytes(height_ptr as u32, &(height as i32).to_le_bytes())?;
+    memory.try_write_bytes(frames_ptr as u32, &(frames.len() as i32).to_le_bytes())
+}
+
+/// Releases the decoder and scaler contexts cached between loads.
+/// Returns how many decoder/scaler pairs were released.
+#[host_function]
+fn clear_context_cache(
+    _caller: Caller,
+    _args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("clear_context_cache");
+
+    guest_status(|| {
+        let mut data_guard = lock_frames(data)?;
+        let released = data_guard.context_cache.get_mut().clear();
+
+        Ok(vec![WasmValue::from_i32(released as i32)])
+    })
+}
+
+/// Opens a video for streaming decode through `next_frame` as a new session, returning its handle
+/// as `load_video_to_host_memory` does. Only the frame being pulled is kept in memory instead of
+/// every frame. Writes the dimensions of the pulled frames through the pointers,
+/// `get_video_info` reports the stream before any frame is pulled. Live network streams (e.g.
+/// `rtsp://` URLs the host allows, as for `load_video_to_host_memory`) must be read this way.
+/// Opening, `seek_to_timestamp` and `next_frame` can each be stopped by `cancel_operation` or
+/// `set_operation_timeout`, including while blocked on a stalled input, failing with `Cancelled`.
+#[host_function]
+fn open_video(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("open_video");
+
+    guest_status(|| {
+        let mut data_guard = lock_frames(data)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let filename_ptr = args[0].to_i32();
+        let filename_len = args[1].to_i32();
+        let width_ptr = args[2].to_i32();
+        let height_ptr = args[3].to_i32();
+
+        let filename = read_guest_string(&main_memory, filename_ptr, filename_len)?;
+
+        let stream = decode_video::FrameStream::open(
+            &filename,
+            &data_guard.decode_options,
+            data_guard.operation.clone(),
+        )
+        .map_err(|err| {
+            error!("Error Opening {filename} {:?}", err);
+            VideoProcessingPluginError::from(err)
+        })?;
+
+        let video_info = stream.video_info().clone();
+        main_memory
+            .try_write_bytes(width_ptr as u32, &(video_info.width() as i32).to_le_bytes())?;
+        main_memory.try_write_bytes(
+            height_ptr as u32,
+            &(video_info.height() as i32).to_le_bytes(),
+        )?;
+
+        let mut session = data_guard.new_session();
+        session.video_info = Some(video_info);
+        session.source_filename = Some(filename);
+        *session.frame_stream.get_mut() = Some(stream);
+
+        Ok(vec![WasmValue::from_i32(data_guard.insert(session) as i32)])
+    })
+}
+
+/// Decodes the next frame of the video opened by `open_video` and copies it into the guest
+/// buffer as RGB24. Returns `1` when a frame was copied and `0` once the stream is exhausted.
+#[host_function]
+fn next_frame(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("next_frame");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let buf_ptr = args[0].to_i32();
+        let buf_len = args[1].to_i32();
+
+        let stream = data_guard.frame_stream.get_mut().as_mut().ok_or_else(|| {
+            error!("No Video opened to pull frames from");
+            VideoProcessingPluginError::NoVideoLoaded
+        })?;
+
+        let frame_map = stream.next_frame().map_err(|err| {
+            error!("Error Decoding Next Frame {:?}", err);
+            VideoProcessingPluginError::from(err)
+        })?;
+
+        match frame_map {
+            Some(frame_map) => {
+                copy_frame_to_guest(&mut main_memory, &frame_map.input_frame, buf_ptr, buf_len)?;
+                Ok(vec![WasmValue::from_i32(1)])
+            }
+            None => Ok(vec![WasmValue::from_i32(0)]),
+        }
+    })
+}
+
+/// Seeks the video opened by `open_video` to the keyframe at or before `ts_millis` from its start,
+/// so `next_frame` continues from there without decoding the frames preceding it. As seeks snap to
+/// keyframes, the time in milliseconds of the frame `next_frame` returns next is written to
+/// `landed_ptr` as an i64. Returns the index of that frame in the stream.
+#[host_function]
+fn seek_to_timestamp(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("seek_to_timestamp");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let ts_millis = args[0].to_i64();
+        let landed_ptr = args[1].to_i32();
+
+        if ts_millis < 0 {
+            error!("Cannot seek to negative timestamp {ts_millis}ms");
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
+
+        let stream = data_guard.frame_stream.get_mut().as_mut().ok_or_else(|| {
+            error!("No Video opened to seek in");
+            VideoProcessingPluginError::NoVideoLoaded
+        })?;
+
+        let landed = stream.seek(ts_millis).map_err(|err| {
+            error!("Error Seeking to {ts_millis}ms {:?}", err);
+            VideoProcessingPluginError::from(err)
+        })?;
+
+        match landed {
+            Some((index, landed_millis)) => {
+                main_memory.try_write_bytes(landed_ptr as u32, &landed_millis.to_le_bytes())?;
+                Ok(vec![WasmValue::from_i32(index as i32)])
+            }
+            None => {
+                error!("No frame at or after {ts_millis}ms");
+                Err(VideoProcessingPluginError::FrameIndexOutOfRange.into())
+            }
+        }
+    })
+}
+
+/// Releases the frames and video information of the loaded video, for guests processing many
+/// clips one after the other. The session stays open until `close_session`. Returns roughly how
+/// many bytes of pixel data were freed, `0` when no video is loaded.
+#[host_function]
+fn free_frames(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("free_frames");
+
+    guest_status_i64(|| {
+        let (session_id, _) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let freed = data_guard.release_video();
+
+        debug!("Freed {freed} bytes of frames");
+        Ok(vec![WasmValue::from_i64(freed as i64)])
+    })
+}
+
+/// Drops every frame written by `write_frame` (or rendered by `burn_subtitles`, `compose_pip`,
+/// ...) while keeping the decoded input frames, so a guest can redo a processing pass with other
+/// parameters without decoding the video again. Returns how many output frames were dropped.
+#[host_function]
+fn reset_output_frames(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("reset_output_frames");
+
+    guest_status(|| {
+        let (session_id, _) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let reset = data_guard.release_output_frames();
+
+        debug!("Reset {reset} output frames");
+        Ok(vec![WasmValue::from_i32(reset as i32)])
+    })
+}
+
+/// Closes the session `session_id`, freeing its frames and dropping any open stream or encoder
+/// (finish an encode first to keep its output). The handle is not reused. Fails with
+/// `SessionNotFound` for handles of sessions never created or already closed.
+#[host_function]
+fn close_session(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("close_session");
+
+    guest_status(|| {
+        let (session_id, _) = session_args(&args);
+
+        let mut data_guard = lock_frames(data)?;
+        if data_guard.open.remove(&session_id).is_none() {
+            error!("Session {session_id} does not exist");
+            return Err(VideoProcessingPluginError::SessionNotFound.into());
+        }
+
+        debug!("Closed Session {session_id}");
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Writes how many bytes of pixel data the stored frames hold as `i64`s: in total to `total_ptr`,
+/// for the input frames (with the conversions `get_frame` hands out) to `input_ptr` and for the
+/// output frames written so far to `output_ptr`. Lets guests decide when to call `free_frames`.
+#[host_function]
+fn get_memory_usage(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_memory_usage");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let total_ptr = args[0].to_i32();
+        let input_ptr = args[1].to_i32();
+        let output_ptr = args[2].to_i32();
+
+        let (input_bytes, output_bytes) =
+            data_guard
+                .frames
+                .iter()
+                .fold((0, 0), |(input_bytes, output_bytes), frame_map| {
+                    (
+                        input_bytes + frame_map.input_data_size(),
+                        output_bytes + frame_map.output_data_size(),
+                    )
+                });
+
+        main_memory.try_write_bytes(
+            total_ptr as u32,
+            &((input_bytes + output_bytes) as i64).to_le_bytes(),
+        )?;
+        main_memory.try_write_bytes(input_ptr as u32, &(input_bytes as i64).to_le_bytes())?;
+        main_memory.try_write_bytes(output_ptr as u32, &(output_bytes as i64).to_le_bytes())?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Copies the warnings/errors FFMPEG logged during the last decode into the guest buffer,
+/// one line per message. Returns the full length of the log, which is larger than `buf_len`
+/// when the copy was truncated.
+#[host_function]
+fn get_decode_diagnostics(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    _data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_decode_diagnostics");
+
+    guest_status(|| {
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let buf_ptr = args[0].to_i32();
+        let buf_len = args[1].to_i32();
+
+        let report = diagnostics::report();
+        let report_len =
+            write_guest_str(&mut main_memory, buf_ptr as u32, buf_len as u32, &report)?;
+
+        Ok(vec![WasmValue::from_i32(report_len as i32)])
+    })
+}
+
+/// Copies the name of the demuxer the loaded video was opened with into the guest buffer.
+/// Returns the full length of the name, which is larger than `buf_len` when truncated.
+#[host_function]
+fn get_container_format(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_container_format");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let buf_ptr = args[0].to_i32();
+        let buf_len = args[1].to_i32();
+
+        let video_info = data_guard.video_info.as_ref().ok_or_else(|| {
+            error!("No Video loaded to get the container format of");
+            VideoProcessingPluginError::NoVideoLoaded
+        })?;
+
+        let name_len = write_guest_str(
+            &mut main_memory,
+            buf_ptr as u32,
+            buf_len as u32,
+            &video_info.container_format,
+        )?;
+
+        Ok(vec![WasmValue::from_i32(name_len as i32)])
+    })
+}
+
+/// Returns the number of entries in the loaded video's container metadata
+#[host_function]
+fn get_metadata_count(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_metadata_count");
+
+    guest_status(|| {
+        let (session_id, _) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let video_info = data_guard.video_info.as_ref().ok_or_else(|| {
+            error!("No Video loaded to get the metadata of");
+            VideoProcessingPluginError::NoVideoLoaded
+        })?;
+
+        let count = video_info.input_stream_meta_data.iter().count();
+
+        Ok(vec![WasmValue::from_i32(count as i32)])
+    })
+}
+
+/// Copies the key of the metadata entry at `idx` (below `get_metadata_count`) into the guest
+/// buffer, so guests can iterate the metadata. Returns the full length of the key, which is
+/// larger than `buf_len` when truncated.
+#[host_function]
+fn get_metadata_key(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_metadata_key");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let idx = args[0].to_i32();
+        let buf_ptr = args[1].to_i32();
+        let buf_len = args[2].to_i32();
+
+        let video_info = data_guard.video_info.as_ref().ok_or_else(|| {
+            error!("No Video loaded to get the metadata of");
+            VideoProcessingPluginError::NoVideoLoaded
+        })?;
+
+        let key = usize::try_from(idx)
+            .ok()
+            .and_then(|idx| video_info.input_stream_meta_data.iter().nth(idx))
+            .map(|(key, _)| key)
+            .ok_or_else(|| {
+                error!("Metadata entry {idx} does not exist");
+                VideoProcessingPluginError::InvalidArgument
+            })?;
+
+        let key_len = write_guest_str(&mut main_memory, buf_ptr as u32, buf_len as u32, key)?;
+
+        Ok(vec![WasmValue::from_i32(key_len as i32)])
+    })
+}
+
+/// Looks up the key in the loaded video's container metadata (e.g. "title" or "encoder") and
+/// copies its value into the guest buffer. Returns the full length of the value, which is larger
+/// than `val_cap` when truncated, failing with `MetadataKeyNotFound` when the metadata has no such
+/// key.
+#[host_function]
+fn get_metadata_value(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_metadata_value");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let key = read_guest_string(&main_memory, args[0].to_i32(), args[1].to_i32())?;
+        let val_ptr = args[2].to_i32();
+        let val_cap = args[3].to_i32();
+
+        let video_info = data_guard.video_info.as_ref().ok_or_else(|| {
+            error!("No Video loaded to get the metadata of");
+            VideoProcessingPluginError::NoVideoLoaded
+        })?;
+
+        let value = match video_info.input_stream_meta_data.get(&key) {
+            Some(value) => value,
+            None => {
+                debug!("No metadata entry {key}");
+                return Err(VideoProcessingPluginError::MetadataKeyNotFound.into());
+            }
+        };
+
+        let value_len = write_guest_str(&mut main_memory, val_ptr as u32, val_cap as u32, value)?;
+
+        Ok(vec![WasmValue::from_i32(value_len as i32)])
+    })
+}
+
+/// Opens the video at the given filename and reads its stream parameters without decoding any
+/// frames, to check a file before loading it. Copies the source codec's name into the guest
+/// buffer and writes the width and height as `i32`, the duration in milliseconds as `i64` (`-1`
+/// when unknown) and the container's stream count as `i32` through the pointers. Returns the full
+/// length of the codec name, which is larger than `codec_buf_len` when truncated. The properties
+/// are kept in a new session, its handle written as `i32` through `session_ptr`, so functions
+/// reading them (e.g. `get_video_duration` or `list_streams`) can be called before loading.
+#[host_function]
+fn probe_video(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("probe_video");
+
+    guest_status(|| {
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let filename = read_guest_string(&main_memory, args[0].to_i32(), args[1].to_i32())?;
+        let codec_buf_ptr = args[2].to_i32();
+        let codec_buf_len = args[3].to_i32();
+
+        let video_info = decode_video::probe(&filename).map_err(|err| {
+            error!("Probe {filename} Error {:?}", err);
+            VideoProcessingPluginError::from(err)
+        })?;
+
+        let i32_fields = [
+            (args[4].to_i32(), video_info.width() as i32),
+            (args[5].to_i32(), video_info.height() as i32),
+            (args[7].to_i32(), video_info.itcx_number_streams as i32),
+        ];
+        for (ptr, value) in i32_fields {
+            main_memory.try_write_bytes(ptr as u32, &value.to_le_bytes())?;
+        }
+        main_memory.try_write_bytes(
+            args[6].to_i32() as u32,
+            &video_info.duration_millis.unwrap_or(-1).to_le_bytes(),
+        )?;
+
+        let codec_name_len = write_guest_str(
+            &mut main_memory,
+            codec_buf_ptr as u32,
+            codec_buf_len as u32,
+            video_info.source_codec.name(),
+        )?;
+
+        let mut data_guard = lock_frames(data)?;
+        let mut session = data_guard.new_session();
+        session.probed_info = Some(video_info);
+        session.probed_filename = Some(filename);
+        let session_id = data_guard.insert(session);
+        let session_ptr = args[8].to_i32() as u32;
+        if let Err(err) =
+            main_memory.try_write_bytes(session_ptr, &(session_id as i32).to_le_bytes())
+        {
+            data_guard.open.remove(&session_id);
+            return Err(err);
+        }
+
+        Ok(vec![WasmValue::from_i32(codec_name_len as i32)])
+    })
+}
+
+/// Writes the duration of the session's video in milliseconds to `duration_ptr` as an i64, and
+/// whether the stream or its container reports one to `valid_ptr` as an i32 (`1` or `0`, with
+/// `-1` written as the duration). For a session opened by `probe_video` the probed video is
+/// reported.
+#[host_function]
+fn get_video_duration(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_video_duration");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let duration_ptr = args[0].to_i32();
+        let valid_ptr = args[1].to_i32();
+
+        let video_info = data_guard
+            .video_info
+            .as_ref()
+            .or(data_guard.probed_info.as_ref())
+            .ok_or_else(|| {
+                error!("No Video loaded or probed to get the duration of");
+                VideoProcessingPluginError::NoVideoLoaded
+            })?;
+
+        main_memory.try_write_bytes(
+            duration_ptr as u32,
+            &video_info.duration_millis.unwrap_or(-1).to_le_bytes(),
+        )?;
+        main_memory.try_write_bytes(
+            valid_ptr as u32,
+            &(video_info.duration_millis.is_some() as i32).to_le_bytes(),
+        )?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Writes the stream properties of the loaded video through the pointers: width, height,
+/// aspect ratio and frame rate as numerator/denominator pairs (`0/0` when the frame rate is
+/// unknown) as `i32`, bitrate and max bitrate as `i64`, and the container's stream count.
+#[host_function]
+fn get_video_info(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_video_info");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let video_info = data_guard.video_info.as_ref().ok_or_else(|| {
+            error!("No Video loaded to get the information of");
+            VideoProcessingPluginError::NoVideoLoaded
+        })?;
+
+        let (frame_rate_num, frame_rate_den) = match video_info.frame_rate.0 {
+            Some(frame_rate) => (frame_rate.numerator(), frame_rate.denominator()),
+            None => (0, 0),
+        };
+
+        let i32_fields = [
+            (args[0].to_i32(), video_info.width() as i32),
+            (args[1].to_i32(), video_info.height() as i32),
+            (args[2].to_i32(), video_info.aspect_ratio.0.numerator()),
+            (args[3].to_i32(), video_info.aspect_ratio.0.denominator()),
+            (args[4].to_i32(), frame_rate_num),
+            (args[5].to_i32(), frame_rate_den),
+        ];
+        for (ptr, value) in i32_fields {
+            main_memory.try_write_bytes(ptr as u32, &value.to_le_bytes())?;
+        }
+
+        main_memory.try_write_bytes(
+            args[6].to_i32() as u32,
+            &(video_info.bitrate.0 as i64).to_le_bytes(),
+        )?;
+        main_memory.try_write_bytes(
+            args[7].to_i32() as u32,
+            &(video_info.max_bitrate.0 as i64).to_le_bytes(),
+        )?;
+        main_memory.try_write_bytes(
+            args[8].to_i32() as u32,
+            &(video_info.itcx_number_streams as i32).to_le_bytes(),
+        )?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Returns how many programs the loaded container carries (e.g. the services of an MPEG-TS
+/// broadcast capture). Single program containers report `0`.
+#[host_function]
+fn get_program_count(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_program_count");
+
+    guest_status(|| {
+        let (session_id, _) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let video_info = data_guard.video_info.as_ref().ok_or_else(|| {
+            error!("No Video loaded to get the programs of");
+            VideoProcessingPluginError::NoVideoLoaded
+        })?;
+
+        Ok(vec![WasmValue::from_i32(video_info.programs.len() as i32)])
+    })
+}
+
+/// Writes the indices of the streams belonging to program `idx` into the guest buffer as `i32`s,
+/// as many as fit in `buf_len` bytes. Returns the program's full stream count.
+#[host_function]
+fn get_program_streams(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_program_streams");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let idx = args[0].to_i32();
+        let buf_ptr = args[1].to_i32();
+        let buf_len = args[2].to_i32();
+
+        let video_info = data_guard.video_info.as_ref().ok_or_else(|| {
+            error!("No Video loaded to get the programs of");
+            VideoProcessingPluginError::NoVideoLoaded
+        })?;
+
+        let streams = video_info.programs.get(idx as usize).ok_or_else(|| {
+            error!("Program {idx} does not exist");
+            VideoProcessingPluginError::InvalidArgument
+        })?;
+
+        let fitting = streams
+            .len()
+            .min(buf_len.max(0) as usize / std::mem::size_of::<i32>());
+        let bytes: Vec<u8> = streams[..fitting]
+            .iter()
+            .flat_map(|&stream| (stream as i32).to_le_bytes())
+            .collect();
+        main_memory.try_write_bytes(buf_ptr as u32, &bytes)?;
+
+        Ok(vec![WasmValue::from_i32(streams.len() as i32)])
+    })
+}
+
+/// Describes stream `idx` of the container of the session's video, loaded or checked by
+/// `probe_video`, e.g. to pick the stream `set_video_stream` selects. Writes the stream's kind
+/// (`0` video, `1` audio, `2` subtitles, `3` data, `-1` unknown) to `type_ptr` and for video
+/// streams its width and height (`0` otherwise) as `i32`, and copies the codec's name into the
+/// guest buffer. Returns the full length of the codec name, which is larger than
+/// `codec_buf_len` when truncated. Indices past the container's stream count fail with
+/// `StreamNotFound`.
+#[host_function]
+fn list_streams(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("list_streams");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let idx = args[0].to_i32();
+        let codec_buf_ptr = args[1].to_i32();
+        let codec_buf_len = args[2].to_i32();
+
+        let video_info = data_guard
+            .video_info
+            .as_ref()
+            .or(data_guard.probed_info.as_ref())
+            .ok_or_else(|| {
+                error!("No Video loaded or probed to list the streams of");
+                VideoProcessingPluginError::NoVideoLoaded
+            })?;
+
+        let stream = usize::try_from(idx)
+            .ok()
+            .and_then(|idx| video_info.streams.get(idx))
+            .ok_or_else(|| {
+                error!(
+                    "Stream {idx} does not exist, the container has {}",
+                    video_info.streams.len()
+                );
+                VideoProcessingPluginError::StreamNotFound
+            })?;
+
+        let i32_fields = [
+            (args[3].to_i32(), stream.medium_code()),
+            (args[4].to_i32(), stream.width as i32),
+            (args[5].to_i32(), stream.height as i32),
+        ];
+        for (ptr, value) in i32_fields {
+            main_memory.try_write_bytes(ptr as u32, &value.to_le_bytes())?;
+        }
+
+        let codec_name_len = write_guest_str(
+            &mut main_memory,
+            codec_buf_ptr as u32,
+            codec_buf_len as u32,
+            stream.codec.name(),
+        )?;
+
+        Ok(vec![WasmValue::from_i32(codec_name_len as i32)])
+    })
+}
+
+// The body of `get_frame`, copying the frame into `main_memory`
+fn get_frame_for_guest(
+    main_memory: &mut Memory,
+    args: &[WasmValue],
+    data: &Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    let (session_id, args) = session_args(args);
+    let data_guard = read_session(data, session_id)?;
+
+    let idx: i32 = args[0].to_i32();
+    let image_buf_ptr = args[1].to_i32();
+    let image_buf_len = args[2].to_i32() as usize;
+    let image_buf_capacity = args[3].to_i32() as usize;
+    let plane = args[4].to_i32();
+    let stride_ptr = args[5].to_i32();
+    let required_len_ptr = args[6].to_i32();
+
+    debug!("LIB image_buf_ptr {:?}", image_buf_ptr);
+    debug!("LIB image_buf_len {:?}", image_buf_len);
+    debug!("LIB image_buf_capacity {:?}", image_buf_capacity);
+
+    // Rejected before touching the guest buffer, negative indices included
+    let frame = match usize::try_from(idx)
+        .ok()
+        .and_then(|idx| data_guard.frames.get(idx))
+    {
+        Some(frame_map) => frame_map,
+        None => {
+            error!(
+                "Frame {idx} out of range, {} frames loaded",
+                data_guard.frames.len()
+            );
+            return Err(VideoProcessingPluginError::FrameIndexOutOfRange.into());
+        }
+    };
+
+    let frame = frame.guest_frame(data_guard.frame_format).map_err(|err| {
+        error!(
+            "Error Converting Frame {idx} to {:?} {:?}",
+            data_guard.frame_format, err
+        );
+        VideoProcessingPluginError::ProcessingFailed
+    })?;
+    let frame = frame.as_ref();
+
+    let (frame_bytes, stride) = match plane {
+        ALL_PLANES => (frame_ops::packed_frame_bytes(frame), frame.stride(0)),
+        plane if plane >= 0 && (plane as usize) < frame.planes() => (
+            frame_ops::packed_plane_bytes(frame, plane as usize),
+            frame.stride(plane as usize),
+        ),
+        plane => {
+            error!(
+                "Plane {plane} out of range, {:?} frames have {} planes",
+                frame.format(),
+                frame.planes()
+            );
+            return Err(VideoProcessingPluginError::PlaneOutOfRange.into());
+        }
+    };
+
+    if image_buf_len < frame_bytes.len() {
+        error!(
+            "Frame {idx} holds {} bytes, guest buffer holds {image_buf_len}",
+            frame_bytes.len()
+        );
+        main_memory.try_write_bytes(
+            required_len_ptr as u32,
+            &(frame_bytes.len() as i32).to_le_bytes(),
+        )?;
+        return Err(VideoProcessingPluginError::InvalidBufferSize.into());
+    }
+
+    debug!("LIB data {:?}", frame_bytes.len());
+    main_memory.try_write_bytes(image_buf_ptr as u32, &frame_bytes)?;
+
+    main_memory.try_write_bytes(stride_ptr as u32, &(stride as i32).to_le_bytes())?;
+    Ok(vec![WasmValue::from_i32(0)])
+}
+
+/// Copies plane `plane` of the input frame at `idx`, in the format selected through
+/// `set_frame_pixel_format`, tightly packed into the guest buffer. A frame converted with
+/// `convert_pixel_format`, `resize_frame` or `crop_frame` is copied in the format and size it was
+/// converted to. The linesize the plane has on the host, which includes any row padding, is written
+/// to `stride_ptr` for guests that want the raw layout. `ALL_PLANES` copies every plane one after
+/// the other instead, writing the linesize of plane 0. A buffer too small for the data fails with
+/// `InvalidBufferSize`, writing the required size to `required_len_ptr` so the guest can resize and
+/// retry.
+#[host_function]
+fn get_frame(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_frame");
+
+    guest_status(|| {
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+        get_frame_for_guest(&mut main_memory, &args, data)
+    })
+}
+
+/// Encodes the input frame at `idx` (or the conversion `get_frame` would return in its place) as
+/// a still image and copies it into the guest buffer, e.g. to send it to an image API. `format`
+/// is `0` for JPEG and `1` for PNG, `quality` from 1 to 100 applies to JPEG with `0` selecting
+/// the default of 90. Returns the size of the encoded image; when it is larger than `buf_len`
+/// nothing is copied, so the guest can retry with a buffer of that size.
+#[host_function]
+fn get_frame_encoded(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_frame_encoded");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let idx = args[0].to_i32();
+        let format_code = args[1].to_i32();
+        let quality = args[2].to_i32();
+        let buf_ptr = args[3].to_i32();
+        let buf_len = args[4].to_i32();
+
+        let format = StillFormat::from_code(format_code).ok_or_else(|| {
+            error!("Unknown image format {format_code}");
+            VideoProcessingPluginError::InvalidArgument
+        })?;
+        let quality = match quality {
+            0 => DEFAULT_JPEG_QUALITY,
+            quality if (1..=100).contains(&quality) => quality as u8,
+            quality => {
+                error!("JPEG quality {quality} must be between 1 and 100");
+                return Err(VideoProcessingPluginError::InvalidArgument.into());
+            }
+        };
+        if buf_len < 0 {
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
+
+        let frame_map = usize::try_from(idx)
+            .ok()
+            .and_then(|idx| data_guard.frames.get(idx))
+            .ok_or_else(|| {
+                error!(
+                    "Frame {idx} out of range, {} frames loaded",
+                    data_guard.frames.len()
+                );
+                VideoProcessingPluginError::FrameIndexOutOfRange
+            })?;
+
+        let encoded = to_rgb24(frame_map.exchanged_frame())
+            .map_err(|err| {
+                error!("Error Converting Frame {idx} to RGB24 {:?}", err);
+                VideoProcessingPluginError::ProcessingFailed
+            })
+            .and_then(|frame| {
+                still_image::encode_rgb24(&frame, format, quality).map_err(|err| {
+                    error!("Error Encoding Frame {idx} as {:?} {:?}", format, err);
+                    VideoProcessingPluginError::ProcessingFailed
+                })
+            })?;
+
+        if encoded.len() > buf_len as usize {
+            error!(
+                "Encoded frame of {} bytes does not fit the guest buffer of {} bytes",
+                encoded.len(),
+                buf_len
+            );
+        } else {
+            main_memory.try_write_bytes(buf_ptr as u32, &encoded)?;
+        }
+
+        Ok(vec![WasmValue::from_i32(encoded.len() as i32)])
+    })
+}
+
+// The frame in RGB24, converted when it is stored in another format
+fn to_rgb24(frame: &frame::Video) -> Result<Cow<'_, frame::Video>, ffmpeg::Error> {
+    if frame.format() == Pixel::RGB24 {
+        Ok(Cow::Borrowed(frame))
+    } else {
+        frame_ops::convert_pixel_format(frame, Pixel::RGB24).map(Cow::Owned)
+    }
+}
+
+/// Copies `count` consecutive frames from `start_idx` into the guest buffer one after the other,
+/// each tightly packed as `get_frame` with `ALL_PLANES` copies it, amortising the cost of a call
+/// over many frames. The size of one frame is written to `frame_stride_ptr` so the guest can
+/// slice the buffer. Fewer frames are copied when the video or the buffer ends first, a buffer too
+/// small for even one frame fails with `InvalidBufferSize`. Returns how many frames were copied.
+#[host_function]
+fn get_frames_batch(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_frames_batch");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let start_idx = args[0].to_i32();
+        let count = args[1].to_i32();
+        let buf_ptr = args[2].to_i32();
+        let buf_len = args[3].to_i32();
+        let frame_stride_ptr = args[4].to_i32();
+
+        if count < 0 || buf_len < 0 {
+            error!("Invalid batch of {count} frames into {buf_len} bytes");
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
+
+        let frames = match usize::try_from(start_idx)
+            .ok()
+            .and_then(|start| data_guard.frames.get(start..))
+        {
+            Some(frames) if !frames.is_empty() || count == 0 => frames,
+            _ => {
+                error!(
+                    "Frame {start_idx} out of range, {} frames loaded",
+                    data_guard.frames.len()
+                );
+                return Err(VideoProcessingPluginError::FrameIndexOutOfRange.into());
+            }
+        };
+        let frames = &frames[..frames.len().min(count as usize)];
+
+        let mut frame_stride = None;
+        let mut written = 0;
+        for (offset, frame_map) in frames.iter().enumerate() {
+            let idx = start_idx as usize + offset;
+            let frame = frame_map
+                .guest_frame(data_guard.frame_format)
+                .map_err(|err| {
+                    error!(
+                        "Error Converting Frame {idx} to {:?} {:?}",
+                        data_guard.frame_format, err
+                    );
+                    VideoProcessingPluginError::ProcessingFailed
+                })?;
+            let frame_bytes = frame_ops::packed_frame_bytes(&frame);
+
+            // Frames converted or resized on their own may not match the rest of the batch
+            let stride = *frame_stride.get_or_insert(frame_bytes.len());
+            if frame_bytes.len() != stride {
+                error!(
+                    "Frame {idx} holds {} bytes, the batch's frames {stride}",
+                    frame_bytes.len()
+                );
+                return Err(VideoProcessingPluginError::InvalidArgument.into());
+            }
+
+            let end = (written + 1) * stride;
+            if end > buf_len as usize {
+                if written == 0 {
+                    error!(
+                        "Frame of {stride} bytes does not fit the guest buffer of {buf_len} bytes"
+                    );
+                    return Err(VideoProcessingPluginError::InvalidBufferSize.into());
+                }
+                break;
+            }
+
+            main_memory
+                .try_write_bytes(buf_ptr as u32 + (written * stride) as u32, &frame_bytes)?;
+            written += 1;
+        }
+
+        main_memory.try_write_bytes(
+            frame_stride_ptr as u32,
+            &(frame_stride.unwrap_or(0) as i32).to_le_bytes(),
+        )?;
+        Ok(vec![WasmValue::from_i32(written as i32)])
+    })
+}
+
+/// Copies the frame at `idx` into the guest buffer in BGR channel order, as OpenCV style guests
+/// expect, swapping red and blue during the copy. Like `get_frame` it copies the conversion made
+/// on request in place of the input frame, whatever the selected pixel format. The buffer must
+/// hold `w * h * 3` bytes.
+#[host_function]
+fn get_frame_bgr(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_frame_bgr");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let idx = args[0].to_i32();
+        let buf_ptr = args[1].to_i32();
+        let buf_len = args[2].to_i32();
+
+        let frame = data_guard.frames.get(idx as usize).ok_or_else(|| {
+            error!("Frame {idx} does not exist");
+            VideoProcessingPluginError::FrameIndexOutOfRange
+        })?;
+
+        let frame = frame.guest_frame(PixelFormat::Rgb24).map_err(|err| {
+            error!(
+                "Error Converting Frame {idx} to {:?} {:?}",
+                PixelFormat::Rgb24,
+                err
+            );
+            VideoProcessingPluginError::ProcessingFailed
+        })?;
+        // Conversions made on request keep their own format
+        let frame = match frame.format() {
+            Pixel::RGB24 => frame,
+            format => frame_ops::convert_pixel_format(&frame, Pixel::RGB24)
+                .map(Cow::Owned)
+                .map_err(|err| {
+                    error!("Error Converting Frame {idx} from {:?} {:?}", format, err);
+                    VideoProcessingPluginError::ProcessingFailed
+                })?,
+        };
+
+        check_frame_buffer_len(&frame, buf_len)?;
+        main_memory.try_write_bytes(buf_ptr as u32, &frame_ops::packed_bgr24_bytes(&frame))?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Copies FFMPEG's name for the pixel format of the input frame at `idx` (e.g. "rgb24") into the
+/// guest buffer. Returns the full length of the name, which is larger than `buf_len` when truncated.
+#[host_function]
+fn get_pixel_format_name(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_pixel_format_name");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let idx = args[0].to_i32();
+        let buf_ptr = args[1].to_i32();
+        let buf_len = args[2].to_i32();
+
+        let frame = data_guard.frames.get(idx as usize).ok_or_else(|| {
+            error!("Frame {idx} does not exist");
+            VideoProcessingPluginError::FrameIndexOutOfRange
+        })?;
+
+        let format = frame.input_frame.format();
+        let name = format
+            .descriptor()
+            .map(|descriptor| descriptor.name())
+            .ok_or_else(|| {
+                error!("No name known for pixel format {:?}", format);
+                VideoProcessingPluginError::ProcessingFailed
+            })?;
+
+        let name_len = write_guest_str(&mut main_memory, buf_ptr as u32, buf_len as u32, name)?;
+
+        Ok(vec![WasmValue::from_i32(name_len as i32)])
+    })
+}
+
+/// Copies the frame at `idx` into the guest buffer starting `dst_offset` bytes in, as `get_frame`
+/// copies it, so guests can pack several frames into one pre-allocated arena.
+/// Fails if `dst_offset` plus the frame's size exceeds `buf_len`.
+#[host_function]
+fn get_frame_into(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_frame_into");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let idx = args[0].to_i32();
+        let buf_ptr = args[1].to_i32();
+        let buf_len = args[2].to_i32();
+        let dst_offset = args[3].to_i32();
+
+        if dst_offset < 0 || dst_offset > buf_len {
+            error!("Offset {dst_offset} outside of buffer of {buf_len} bytes");
+            return Err(VideoProcessingPluginError::InvalidBufferSize.into());
+        }
+
+        let frame = data_guard.frames.get(idx as usize).ok_or_else(|| {
+            error!("Frame {idx} does not exist");
+            VideoProcessingPluginError::FrameIndexOutOfRange
+        })?;
+
+        let dst_ptr = buf_ptr
+            .checked_add(dst_offset)
+            .and_then(|dst_ptr| u32::try_from(dst_ptr).ok())
+            .ok_or_else(|| {
+                error!("Offset {dst_offset} from {buf_ptr} outside of guest memory");
+                VideoProcessingPluginError::InvalidArgument
+            })?;
+
+        copy_guest_frame(
+            &mut main_memory,
+            frame,
+            data_guard.frame_format,
+            dst_ptr,
+            buf_len - dst_offset,
+        )?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Registers `len` bytes of guest memory at `ptr` as the region `map_frame` stages frames into,
+/// split in `slot_count` equal slots. The guest allocates the region once and reads frames in
+/// place from it instead of passing a buffer per frame; `len` of `0` unregisters it. The region
+/// stays registered across sessions.
+#[host_function]
+fn set_frame_staging_region(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_frame_staging_region");
+
+    guest_status(|| {
+        let mut data_guard = lock_frames(data)?;
+
+        let main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let ptr = args[0].to_i32();
+        let len = args[1].to_i32();
+        let slot_count = args[2].to_i32();
+
+        if len == 0 {
+            *data_guard
+                .frame_staging
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = None;
+            return Ok(vec![WasmValue::from_i32(0)]);
+        }
+
+        if ptr < 0 || len < 0 || slot_count <= 0 || len / slot_count == 0 {
+            error!("Invalid staging region of {len} bytes at {ptr} in {slot_count} slots");
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
+
+        // Checked once here so a region outside linear memory fails at registration
+        main_memory
+            .data_pointer(ptr as u32, len as u32)
+            .map_err(|_| VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        *data_guard
+            .frame_staging
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(FrameStaging {
+            offset: ptr as u32,
+            slot_len: (len / slot_count) as u32,
+            slot_count: slot_count as u32,
+            next_slot: 0,
+        });
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Stages the frame `get_frame` would return for `idx` (all planes, tightly packed) into the
+/// next slot of the region registered by `set_frame_staging_region`, and writes the offset of
+/// the frame in guest memory and its length through `offset_ptr` and `len_ptr`. The pixels are
+/// written straight into the slot, without the intermediate buffer `get_frame` copies from, and
+/// stay valid until `slot_count` further frames have been mapped. Fails with `InvalidBufferSize`
+/// when the frame is larger than a slot.
+#[host_function]
+fn map_frame(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("map_frame");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let idx = args[0].to_i32();
+        let offset_ptr = args[1].to_i32();
+        let len_ptr = args[2].to_i32();
+
+        let video_struct = &mut *data_guard;
+        let mut staging_guard = video_struct
+            .frame_staging
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let staging = staging_guard.as_mut().ok_or_else(|| {
+            error!("No staging region registered by set_frame_staging_region");
+            VideoProcessingPluginError::InvalidArgument
+        })?;
+
+        let frame = usize::try_from(idx)
+            .ok()
+            .and_then(|idx| video_struct.frames.get(idx))
+            .ok_or_else(|| {
+                error!("Frame {idx} does not exist");
+                VideoProcessingPluginError::FrameIndexOutOfRange
+            })?;
+
+        let frame = frame
+            .guest_frame(video_struct.frame_format)
+            .map_err(|err| {
+                error!(
+                    "Error Converting Frame {idx} to {:?} {:?}",
+                    video_struct.frame_format, err
+                );
+                VideoProcessingPluginError::ProcessingFailed
+            })?;
+        let frame = frame.as_ref();
+
+        let frame_len = frame_ops::frame_byte_size(frame.format(), frame.width(), frame.height());
+        if frame_len > staging.slot_len as usize {
+            error!(
+                "Frame {idx} holds {frame_len} bytes, staging slots hold {}",
+                staging.slot_len
+            );
+            return Err(VideoProcessingPluginError::InvalidBufferSize.into());
+        }
+
+        let slot_offset = staging.offset + staging.next_slot * staging.slot_len;
+        let slot_ptr = main_memory
+            .data_pointer_mut(slot_offset, frame_len as u32)
+            .map_err(|_| VideoProcessingPluginError::MemoryAccessFailed)?;
+        let slot = unsafe { std::slice::from_raw_parts_mut(slot_ptr, frame_len) };
+        frame_ops::write_packed_frame(frame, slot);
+
+        staging.next_slot = (staging.next_slot + 1) % staging.slot_count;
+
+        main_memory.try_write_bytes(offset_ptr as u32, &(slot_offset as i32).to_le_bytes())?;
+        main_memory.try_write_bytes(len_ptr as u32, &(frame_len as i32).to_le_bytes())?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Decodes just the first keyframe of `filename` without loading the video, turned upright by the
+/// source's rotation and scaled so its largest side is `max_dimension`. The packed RGB24 pixels
+/// go into the guest buffer and the resulting dimensions through `width_ptr` and `height_ptr`.
+#[host_function]
+fn get_preview_frame(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    _data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_preview_frame");
+
+    guest_status(|| {
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let filename_ptr = args[0].to_i32();
+        let filename_len = args[1].to_i32();
+        let max_dimension = args[2].to_i32();
+        let buf_ptr = args[3].to_i32();
+        let buf_len = args[4].to_i32();
+        let width_ptr = args[5].to_i32();
+        let height_ptr = args[6].to_i32();
+
+        if max_dimension <= 0 {
+            error!("Preview max dimension {max_dimension} must be positive");
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
+
+        let filename = read_guest_string(&main_memory, filename_ptr, filename_len)?;
+
+        let preview =
+            decode_video::decode_preview_frame(&filename, max_dimension as u32).map_err(|err| {
+                error!("Error Decoding Preview Frame of {filename} {:?}", err);
+                VideoProcessingPluginError::from(err)
+            })?;
+
+        copy_frame_to_guest(&mut main_memory, &preview, buf_ptr, buf_len)?;
+        main_memory.try_write_bytes(width_ptr as u32, &preview.width().to_le_bytes())?;
+        main_memory.try_write_bytes(height_ptr as u32, &preview.height().to_le_bytes())?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Picks a poster frame for the session's video, loaded or checked by `probe_video`, by seeking to
+/// the first keyframe past `THUMBNAIL_POSITION` of its duration and decoding only that frame. The
+/// frame is turned upright by the source's rotation, encoded as JPEG and copied into the guest
+/// buffer, with its time from the start of the video in milliseconds written to `timestamp_ptr` as
+/// an `i64` (`NO_TIMESTAMP` when unknown). Returns the size of the JPEG; when it is larger than
+/// `buf_len` nothing is copied, so the guest can retry with a buffer of that size.
+#[host_function]
+fn extract_thumbnail(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("extract_thumbnail");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let buf_ptr = args[0].to_i32();
+        let buf_len = args[1].to_i32();
+        let timestamp_ptr = args[2].to_i32();
+
+        if buf_len < 0 {
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
+
+        let filename = {
+            let data_guard = read_session(data, session_id)?;
+            data_guard
+                .source_filename
+                .clone()
+                .or_else(|| data_guard.probed_filename.clone())
+                .ok_or_else(|| {
+                    error!("No Video loaded or probed to extract a thumbnail of");
+                    VideoProcessingPluginError::NoVideoLoaded
+                })?
+        };
+
+        let (thumbnail, timestamp) =
+            decode_video::decode_thumbnail_frame(&filename, THUMBNAIL_POSITION).map_err(|err| {
+                error!("Error Decoding Thumbnail of {filename} {:?}", err);
+                VideoProcessingPluginError::from(err)
+            })?;
+
+        let encoded =
+            still_image::encode_rgb24(&thumbnail, StillFormat::Jpeg, DEFAULT_JPEG_QUALITY)
+                .map_err(|err| {
+                    error!("Error Encoding Thumbnail of {filename} {:?}", err);
+                    VideoProcessingPluginError::ProcessingFailed
+                })?;
+
+        main_memory.try_write_bytes(
+            timestamp_ptr as u32,
+            &timestamp.unwrap_or(NO_TIMESTAMP).to_le_bytes(),
+        )?;
+
+        if encoded.len() > buf_len as usize {
+            error!(
+                "Thumbnail of {} bytes does not fit the guest buffer of {} bytes",
+                encoded.len(),
+                buf_len
+            );
+        } else {
+            main_memory.try_write_bytes(buf_ptr as u32, &encoded)?;
+        }
+
+        Ok(vec![WasmValue::from_i32(encoded.len() as i32)])
+    })
+}
+
+/// Reorders the stored frames, `0` sorting them by presentation timestamp and `1` by decode
+/// timestamp. Frames without the relevant timestamp keep their index, as they do when decoded.
+/// Each frame keeps its source timestamps through the reordering, so this only changes how
+/// `get_frame(idx)` indexes frames: the output is always encoded in presentation order, stamped
+/// with the source timestamps.
+#[host_function]
+fn set_frame_ordering(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_frame_ordering");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let ordering = match args[0].to_i32() {
+            0 => FrameOrdering::Presentation,
+            1 => FrameOrdering::Decode,
+            mode => {
+                error!("Unknown frame ordering {mode}");
+                return Err(VideoProcessingPluginError::InvalidArgument.into());
+            }
+        };
+
+        match ordering {
+            FrameOrdering::Presentation => {
+                sort_timestamped(&mut data_guard.frames, |frame_map| frame_map.timestamp)
+            }
+            FrameOrdering::Decode => sort_timestamped(&mut data_guard.frames, |frame_map| {
+                frame_map.decode_timestamp
+            }),
+        }
+        data_guard.frame_ordering = ordering;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Returns the order `get_frame(idx)` indexes frames in,
+/// `0` for presentation (display) order and `1` for decode order
+#[host_function]
+fn get_frame_ordering_mode(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_frame_ordering_mode");
+
+    guest_status(|| {
+        let (session_id, _) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        Ok(vec![WasmValue::from_i32(data_guard.frame_ordering as i32)])
+    })
+}
+
+/// Estimates the source's GOP size as the average distance in frames between consecutive
+/// I-frames of the decoded frames. Returns `0` when fewer than two I-frames were decoded.
+#[host_function]
+fn get_source_gop_size(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_source_gop_size");
+
+    guest_status(|| {
+        let (session_id, _) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let keyframes: Vec<usize> = data_guard
+            .frames
+            .iter()
+            .enumerate()
+            .filter(|(_, frame)| frame.frame_type == FrameType::I)
+            .map(|(idx, _)| idx)
+            .collect();
+
+        let gop_size = match (keyframes.first(), keyframes.last()) {
+            (Some(first), Some(last)) if keyframes.len() >= 2 => {
+                ((last - first) as f64 / (keyframes.len() - 1) as f64).round() as i32
+            }
+            _ => 0,
+        };
+
+        Ok(vec![WasmValue::from_i32(gop_size)])
+    })
+}
+
+/// Writes the decode timestamp (DTS) of the frame at `idx` in the input stream's time base to
+/// `dts_ptr` as an i64, `NO_TIMESTAMP` when the decoder did not provide one.
+#[host_function]
+fn get_frame_dts(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_frame_dts");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let idx = args[0].to_i32();
+        let dts_ptr = args[1].to_i32();
+
+        let frame = data_guard.frames.get(idx as usize).ok_or_else(|| {
+            error!("Frame {idx} does not exist");
+            VideoProcessingPluginError::FrameIndexOutOfRange
+        })?;
+
+        main_memory.try_write_bytes(
+            dts_ptr as u32,
+            &frame.decode_timestamp.unwrap_or(NO_TIMESTAMP).to_le_bytes(),
+        )?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Writes the presentation timestamp (PTS) of the frame at `idx`, in the input stream's time base,
+/// to `pts_ptr` as an i64, and whether the decoder provided one to `valid_ptr` as an i32 (`1` or
+/// `0`, with `NO_TIMESTAMP` written as the PTS).
+#[host_function]
+fn get_frame_timestamp(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_frame_timestamp");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let idx = args[0].to_i32();
+        let pts_ptr = args[1].to_i32();
+        let valid_ptr = args[2].to_i32();
+
+        let timestamp = match usize::try_from(idx)
+            .ok()
+            .and_then(|idx| data_guard.frames.get(idx))
+        {
+            Some(frame_map) => frame_map.timestamp,
+            None => {
+                error!(
+                    "Frame {idx} out of range, {} frames loaded",
+                    data_guard.frames.len()
+                );
+                return Err(VideoProcessingPluginError::FrameIndexOutOfRange.into());
+            }
+        };
+
+        main_memory.try_write_bytes(
+            pts_ptr as u32,
+            &timestamp.unwrap_or(NO_TIMESTAMP).to_le_bytes(),
+        )?;
+        main_memory.try_write_bytes(
+            valid_ptr as u32,
+            &(timestamp.is_some() as i32).to_le_bytes(),
+        )?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Returns the type the frame at `idx` was coded as in the input stream, as a `FrameType` code
+/// (`0` I, `1` P, `2` B, ...), e.g. for guests only processing keyframes.
+#[host_function]
+fn get_frame_type(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_frame_type");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let idx = args[0].to_i32();
+
+        match usize::try_from(idx)
+            .ok()
+            .and_then(|idx| data_guard.frames.get(idx))
+        {
+            Some(frame_map) => Ok(vec![WasmValue::from_i32(frame_map.frame_type.code())]),
+            None => {
+                error!(
+                    "Frame {idx} out of range, {} frames loaded",
+                    data_guard.frames.len()
+                );
+                Err(VideoProcessingPluginError::FrameIndexOutOfRange.into())
+            }
+        }
+    })
+}
+
+/// Selects how written frames are guarded against stray guest values. `0` stores them as received
+/// and fails `write_frame_f32` with `InvalidArgument` for samples outside 0.0-1.0. `1` clamps such
+/// samples into the range and the frames' YUV planes into the limited range (luma 16-235, chroma
+/// 16-240) once the encoder converted them, unless the output signals full range.
+#[host_function]
+fn set_write_clamp_mode(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_write_clamp_mode");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        data_guard.encoder_settings.clamp_limited_range = match args[0].to_i32() {
+            0 => false,
+            1 => true,
+            mode => {
+                error!("Unknown clamp mode {mode}");
+                return Err(VideoProcessingPluginError::InvalidArgument.into());
+            }
+        };
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Selects the pixel format `get_frame` and `write_frame` exchange frames with the guest in:
+/// `0` RGB24 (the default), `1` RGBA, `2` GRAY8, `3` YUV420P, `4` YUV422P, `5` YUV444P, `6` NV12.
+/// Buffers hold every plane of the format tightly packed one after the other. RGBA frames from
+/// `get_frame` are opaque, those written are composited over black as the output has no alpha.
+#[host_function]
+fn set_frame_pixel_format(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_frame_pixel_format");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        data_guard.frame_format = match PixelFormat::from_code(args[0].to_i32()) {
+            Some(format) => format,
+            None => {
+                error!("Unknown pixel format {}", args[0].to_i32());
+                return Err(VideoProcessingPluginError::InvalidArgument.into());
+            }
+        };
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Converts the frame `get_frame` returns for `idx` (the input frame, or its conversion by
+/// `resize_frame`, `crop_frame`, `rotate_frame` or an earlier call) to the pixel format `format`
+/// (codes as for `set_frame_pixel_format`), keeping the result so later `get_frame` calls on that
+/// index return the converted layout without converting again. A frame already in `format` is
+/// kept as it is. The input frame itself, which encoding uses, is left untouched.
+#[host_function]
+fn convert_pixel_format(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("convert_pixel_format");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let idx = args[0].to_i32();
+        let format = match PixelFormat::from_code(args[1].to_i32()) {
+            Some(format) => format.pixel(),
+            None => {
+                error!("Unknown pixel format {}", args[1].to_i32());
+                return Err(VideoProcessingPluginError::InvalidArgument.into());
+            }
+        };
+
+        let frames_len = data_guard.frames.len();
+        let frame_map = match usize::try_from(idx)
+            .ok()
+            .and_then(|idx| data_guard.frames.get_mut(idx))
+        {
+            Some(frame_map) => frame_map,
+            None => {
+                error!("Frame {idx} out of range, {frames_len} frames loaded");
+                return Err(VideoProcessingPluginError::FrameIndexOutOfRange.into());
+            }
+        };
+
+        // Converting the kept frame rather than the input keeps earlier preprocessing steps
+        if frame_map.exchanged_frame().format() != format {
+            let converted = frame_ops::convert_pixel_format(frame_map.exchanged_frame(), format)
+                .map_err(|err| {
+                    error!("Error Converting Frame {idx} to {:?} {:?}", format, err);
+                    VideoProcessingPluginError::ProcessingFailed
+                })?;
+            frame_map.derived_frame = Some(converted);
+        }
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Converts frame `idx` to single channel GRAY8 for models taking grayscale input, keeping the
+/// result for `get_frame` as `convert_pixel_format` does. Like the other preprocessing steps it
+/// starts from the frame `get_frame` returns, so a frame already resized, cropped or rotated is
+/// converted as it is.
+/// `get_frame` then copies one byte per pixel, `w * h` bytes in total.
+#[host_function]
+fn to_grayscale(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("to_grayscale");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let idx = args[0].to_i32();
+
+        let frames_len = data_guard.frames.len();
+        let frame_map = match usize::try_from(idx)
+            .ok()
+            .and_then(|idx| data_guard.frames.get_mut(idx))
+        {
+            Some(frame_map) => frame_map,
+            None => {
+                error!("Frame {idx} out of range, {frames_len} frames loaded");
+                return Err(VideoProcessingPluginError::FrameIndexOutOfRange.into());
+            }
+        };
+
+        let frame = frame_map.exchanged_frame();
+        if frame.format() == Pixel::GRAY8 {
+            return Ok(vec![WasmValue::from_i32(0)]);
+        }
+
+        let gray = frame_ops::convert_pixel_format(frame, Pixel::GRAY8).map_err(|err| {
+            error!("Error Converting Frame {idx} to Grayscale {:?}", err);
+            VideoProcessingPluginError::ProcessingFailed
+        })?;
+        frame_map.derived_frame = Some(gray);
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Scales frame `idx` to `target_w` x `target_h` (e.g. the fixed input of a detection model),
+/// keeping the result for `get_frame` as `convert_pixel_format` does. A frame already converted is
+/// scaled from its converted copy, so the two can be combined. `scale_flags` takes swscale's
+/// `SWS_*` algorithm flags, `0` selecting bilinear scaling.
+#[host_function]
+fn resize_frame(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("resize_frame");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let idx = args[0].to_i32();
+        let target_w = args[1].to_i32();
+        let target_h = args[2].to_i32();
+        let scale_flags = args[3].to_i32();
+
+        if target_w <= 0 || target_h <= 0 {
+            error!("Invalid resize target {target_w}x{target_h}");
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
+
+        let flags = match scaling_flags(scale_flags) {
+            Some(flags) => flags,
+            None => {
+                error!("Unknown scale flags {:#x}", scale_flags);
+                return Err(VideoProcessingPluginError::InvalidArgument.into());
+            }
+        };
+
+        let frames_len = data_guard.frames.len();
+        let frame_map = match usize::try_from(idx)
+            .ok()
+            .and_then(|idx| data_guard.frames.get_mut(idx))
+        {
+            Some(frame_map) => frame_map,
+            None => {
+                error!("Frame {idx} out of range, {frames_len} frames loaded");
+                return Err(VideoProcessingPluginError::FrameIndexOutOfRange.into());
+            }
+        };
+
+        let resized = frame_ops::resize_frame(
+            frame_map.exchanged_frame(),
+            target_w as u32,
+            target_h as u32,
+            flags,
+        )
+        .map_err(|err| {
+            error!(
+                "Error Resizing Frame {idx} to {target_w}x{target_h} {:?}",
+                err
+            );
+            VideoProcessingPluginError::ProcessingFailed
+        })?;
+        frame_map.derived_frame = Some(resized);
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// The swscale algorithm for the guest's `SWS_*` flags, `0` selecting bilinear scaling
+fn scaling_flags(bits: i32) -> Option<ScalingFlags> {
+    match bits {
+        0 => Some(ScalingFlags::BILINEAR),
+        bits => ScalingFlags::from_bits(bits),
+    }
+}
+
+/// Crops the frame `get_frame` returns for `idx` to the `w` x `h` rectangle at `x`, `y`, keeping
+/// the result for `get_frame` as `convert_pixel_format` does. A rectangle that is empty or does
+/// not fit within that frame fails with `InvalidCropRegion`. For chroma subsampled formats `x` and `y` are rounded
+/// down to even coordinates, so the chroma planes start on a whole sample.
+#[host_function]
+fn crop_frame(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("crop_frame");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let idx = args[0].to_i32();
+        let x = args[1].to_i32();
+        let y = args[2].to_i32();
+        let w = args[3].to_i32();
+        let h = args[4].to_i32();
+
+        let frames_len = data_guard.frames.len();
+        let frame_map = match usize::try_from(idx)
+            .ok()
+            .and_then(|idx| data_guard.frames.get_mut(idx))
+        {
+            Some(frame_map) => frame_map,
+            None => {
+                error!("Frame {idx} out of range, {frames_len} frames loaded");
+                return Err(VideoProcessingPluginError::FrameIndexOutOfRange.into());
+            }
+        };
+
+        let frame = frame_map.exchanged_frame();
+        let fits = |offset: i32, len: i32, frame_len: u32| {
+            offset >= 0 && len > 0 && offset as i64 + len as i64 <= frame_len as i64
+        };
+        if !fits(x, w, frame.width()) || !fits(y, h, frame.height()) {
+            error!(
+                "Crop region {w}x{h} at {x},{y} does not fit frame {idx} of {}x{}",
+                frame.width(),
+                frame.height()
+            );
+            return Err(VideoProcessingPluginError::InvalidCropRegion.into());
+        }
+
+        // Rounding down keeps the rectangle within the frame
+        let (x, y) = frame_ops::align_to_chroma(frame.format(), x as u32, y as u32);
+        let cropped = frame_ops::crop_frame(frame, x, y, w as u32, h as u32);
+        frame_map.derived_frame = Some(cropped);
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Rotates the frame `get_frame` returns for `idx` (the input frame, or its conversion by
+/// `convert_pixel_format`, `resize_frame`, `crop_frame` or `to_grayscale`) clockwise by `degrees`, keeping the
+/// result for `get_frame` as `convert_pixel_format` does. Turns by 90 or 270 degrees swap the
+/// width and height. `degrees` must be a multiple of 90, negative values turning
+/// counter-clockwise, or the call fails with `InvalidRotation`.
+#[host_function]
+fn rotate_frame(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("rotate_frame");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let idx = args[0].to_i32();
+        let degrees = args[1].to_i32();
+
+        if degrees % 90 != 0 {
+            error!("Rotation of {degrees} degrees is not a multiple of 90");
+            return Err(VideoProcessingPluginError::InvalidRotation.into());
+        }
+        let quarter_turns = (degrees.rem_euclid(360) / 90) as u32;
+
+        let frames_len = data_guard.frames.len();
+        let frame_map = match usize::try_from(idx)
+            .ok()
+            .and_then(|idx| data_guard.frames.get_mut(idx))
+        {
+            Some(frame_map) => frame_map,
+            None => {
+                error!("Frame {idx} out of range, {frames_len} frames loaded");
+                return Err(VideoProcessingPluginError::FrameIndexOutOfRange.into());
+            }
+        };
+
+        let rotated =
+            frame_ops::rotate_frame(frame_map.exchanged_frame(), quarter_turns).map_err(|err| {
+                error!("Error Rotating Frame {idx} by {degrees} degrees {:?}", err);
+                VideoProcessingPluginError::ProcessingFailed
+            })?;
+        frame_map.derived_frame = Some(rotated);
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Copies the frame nearest to `fraction` (clamped to 0.0-1.0) of the clip's duration into the
+/// guest buffer as `get_frame` copies it, e.g. `0.1` for a thumbnail at 10%. Frames are matched by
+/// timestamp, falling back to their index when the stream has no timestamps. Returns the index of
+/// the chosen frame.
+#[host_function]
+fn get_frame_at_fraction(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_frame_at_fraction");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let fraction = args[0].to_f32();
+        let image_buf_ptr = args[1].to_i32();
+        let image_buf_len = args[2].to_i32();
+
+        let fraction = if fraction.is_nan() {
+            0.0
+        } else {
+            fraction.clamp(0.0, 1.0) as f64
+        };
+
+        let frames = &data_guard.frames;
+        if frames.is_empty() {
+            error!("No Frames loaded");
+            return Err(VideoProcessingPluginError::NoVideoLoaded.into());
+        }
+
+        let timestamps: Vec<(usize, i64)> = frames
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, frame)| frame.timestamp.map(|ts| (idx, ts)))
+            .collect();
+
+        let first = timestamps.iter().map(|(_, ts)| *ts).min();
+        let last = timestamps.iter().map(|(_, ts)| *ts).max();
+
+        let idx = match (first, last) {
+            (Some(first), Some(last)) => {
+                let target = first as f64 + fraction * (last - first) as f64;
+                timestamps
+                    .iter()
+                    .min_by(|(_, a), (_, b)| {
+                        (*a as f64 - target)
+                            .abs()
+                            .total_cmp(&(*b as f64 - target).abs())
+                    })
+                    .map(|(idx, _)| *idx)
+                    .unwrap_or(0)
+            }
+            _ => (fraction * (frames.len() - 1) as f64).round() as usize,
+        };
+
+        copy_guest_frame(
+            &mut main_memory,
+            &frames[idx],
+            data_guard.frame_format,
+            image_buf_ptr as u32,
+            image_buf_len,
+        )?;
+
+        Ok(vec![WasmValue::from_i32(idx as i32)])
+    })
+}
+
+/// Estimates the dense optical flow from the input frame at `idx` to the one at `idx + 1`
+/// (see `frame_ops::block_matching_flow`), writing an `(dx, dy)` pair of `f32`s per pixel in row
+/// major order. The buffer must hold `width * height * 2 * 4` bytes.
+#[host_function]
+fn compute_optical_flow(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("compute_optical_flow");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let idx = args[0].to_i32();
+        let flow_buf_ptr = args[1].to_i32();
+        let flow_buf_len = args[2].to_i32();
+
+        let pair = usize::try_from(idx)
+            .ok()
+            .and_then(|idx| data_guard.frames.get(idx..idx + 2));
+        let (prev, next) = match pair {
+            Some([prev, next]) => (&prev.input_frame, &next.input_frame),
+            _ => {
+                error!("Frames {idx} and {} do not both exist", idx + 1);
+                return Err(VideoProcessingPluginError::FrameIndexOutOfRange.into());
+            }
+        };
+
+        let required_len = prev.width() as usize * prev.height() as usize * 2 * 4;
+        if flow_buf_len < 0 || (flow_buf_len as usize) < required_len {
+            error!("Flow buffer of {flow_buf_len} bytes too small, {required_len} required");
+            return Err(VideoProcessingPluginError::InvalidBufferSize.into());
+        }
+
+        let flow: Vec<u8> = frame_ops::block_matching_flow(prev, next)
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        main_memory.try_write_bytes(flow_buf_ptr as u32, &flow)?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Computes the 64 bit DCT based perceptual hash (see `frame_ops::perceptual_hash`) of the input
+/// frame at `idx`, writing its high and low 32 bits through `hash_hi_ptr` and `hash_lo_ptr`.
+/// Frames whose hashes differ in few bits look alike. The hash is always of the decoded RGB24
+/// input, not of conversions or output frames, so it identifies the source frame.
+#[host_function]
+fn get_frame_phash(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_frame_phash");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let idx = args[0].to_i32();
+        let hash_hi_ptr = args[1].to_i32();
+        let hash_lo_ptr = args[2].to_i32();
+
+        let frame = data_guard.frames.get(idx as usize).ok_or_else(|| {
+            error!("Frame {idx} does not exist");
+            VideoProcessingPluginError::FrameIndexOutOfRange
+        })?;
+
+        let hash = frame_ops::perceptual_hash(&frame.input_frame);
+        main_memory.try_write_bytes(hash_hi_ptr as u32, &((hash >> 32) as u32).to_le_bytes())?;
+        main_memory.try_write_bytes(hash_lo_ptr as u32, &(hash as u32).to_le_bytes())?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Writes the 64 bit difference hash (see `frame_ops::difference_hash`) of the input frame at
+/// `idx` to `hash_ptr` as an i64. Near-duplicate frames have hashes within a small Hamming
+/// distance, so guests can skip them before inference. Like `get_frame_phash` it always hashes the
+/// decoded RGB24 input.
+#[host_function]
+fn frame_dhash(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("frame_dhash");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let idx = args[0].to_i32();
+        let hash_ptr = args[1].to_i32();
+
+        let frame = usize::try_from(idx)
+            .ok()
+            .and_then(|idx| data_guard.frames.get(idx))
+            .ok_or_else(|| {
+                error!("Frame {idx} does not exist");
+                VideoProcessingPluginError::FrameIndexOutOfRange
+            })?;
+
+        let hash = frame_ops::difference_hash(&frame.input_frame);
+        main_memory.try_write_bytes(hash_ptr as u32, &hash.to_le_bytes())?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+// Reads the frame the guest wrote for `idx` in the selected pixel format as the RGB24 frame the
+// encoder takes, verifying its size and checksum as `write_frame` documents
+fn read_guest_frame(
+    main_memory: &mut Memory,
+    data: &mut FramesMap,
+    idx: usize,
+    image_buf_ptr: i32,
+    image_buf_len: usize,
+    expected_checksum: u32,
+    required_len_ptr: i32,
+) -> Result<frame::Video, HostFuncError> {
+    let (width, height) = match data.video_info.as_ref() {
+        Some(video_info) => (video_info.width(), video_info.height()),
+        None => {
+            error!("No Video loaded to write frames to");
+            return Err(VideoProcessingPluginError::NoVideoLoaded.into());
+        }
+    };
+
+    let format = data.frame_format.pixel();
+    let frame_size = frame_ops::frame_byte_size(format, width, height);
+    if image_buf_len != frame_size {
+        error!("Frame {idx} buffer holds {image_buf_len} bytes, expected {frame_size}");
+        main_memory.try_write_bytes(required_len_ptr as u32, &(frame_size as i32).to_le_bytes())?;
+        return Err(VideoProcessingPluginError::InvalidBufferSize.into());
+    }
+
+    let image_ptr_wasm_memory = main_memory
+        .data_pointer(image_buf_ptr as u32, image_buf_len as u32)
+        .map_err(|_| VideoProcessingPluginError::MemoryAccessFailed)?;
+
+    // Borrowed in place rather than copied, the guest buffer is only read until it was stored
+    let bytes = unsafe { std::slice::from_raw_parts(image_ptr_wasm_memory, image_buf_len) };
+
+    debug!("BUFFER SIZE {}", frame_size);
+
+    if expected_checksum != SKIP_CHECKSUM {
+        let checksum = frame_ops::adler32(bytes);
+        if checksum != expected_checksum {
+            error!(
+                "Frame {idx} checksum {checksum:#010x} does not match {expected_checksum:#010x}"
+            );
+            return Err(VideoProcessingPluginError::ChecksumMismatch.into());
+        }
+    }
+
+    let mut received_frame = data.frame_pool.take(format, width, height);
+    frame_ops::fill_from_packed(&mut received_frame, bytes);
+
+    // Output frames are kept as RGB24 like the input frames, which the encoder converts from.
+    // Frames received in other formats are converted into a pooled frame as well.
+    let video_frame = match format {
+        Pixel::RGB24 => received_frame,
+        // The encoded video has no alpha, so transparency is flattened rather than dropped
+        Pixel::RGBA => {
+            let mut composited = data.frame_pool.take(Pixel::RGB24, width, height);
+            frame_ops::composite_over_black(&received_frame, &mut composited);
+            data.frame_pool.recycle(received_frame);
+            composited
+        }
+        _ => {
+            let mut converted = data.frame_pool.take(Pixel::RGB24, width, height);
+            let conversion = frame_ops::convert_pixel_format_into(&received_frame, &mut converted);
+            data.frame_pool.recycle(received_frame);
+            if let Err(err) = conversion {
+                error!("Error Converting Frame {idx} from {:?} {:?}", format, err);
+                data.frame_pool.recycle(converted);
+                return Err(VideoProcessingPluginError::ProcessingFailed.into());
+            }
+            converted
+        }
+    };
+
+    Ok(video_frame)
+}
+
+// The body of `write_frame`, storing the frame the guest wrote to `main_memory`
+fn store_guest_frame(
+    main_memory: &mut Memory,
+    args: &[WasmValue],
+    data: &Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    let (session_id, args) = session_args(args);
+    let mut data_guard = lock_session(data, session_id)?;
+
+    let idx = match usize::try_from(args[0].to_i32()) {
+        Ok(idx) => idx,
+        Err(_) => {
+            error!("Negative frame index {}", args[0].to_i32());
+            return Err(VideoProcessingPluginError::FrameIndexOutOfRange.into());
+        }
+    };
+    let image_buf_ptr = args[1].to_i32();
+    let image_buf_len = args[2].to_i32() as usize;
+    let expected_checksum = args[3].to_i32() as u32;
+    let required_len_ptr = args[4].to_i32();
+
+    let video_frame = read_guest_frame(
+        main_memory,
+        &mut data_guard,
+        idx,
+        image_buf_ptr,
+        image_buf_len,
+        expected_checksum,
+        required_len_ptr,
+    )?;
+
+    debug!("Writing Frame {idx}");
+
+    let video_struct = &mut *data_guard;
+    if let Some(frame_map) = video_struct.frames.get_mut(idx) {
+        // A frame written again hands the buffer of its previous output to the next write
+        if let Some(previous) = frame_map.output_frame.replace(video_frame) {
+            video_struct.frame_pool.recycle(previous);
+        }
+    } else {
+        error!("Frame {idx} does not exist");
+        return Err(VideoProcessingPluginError::FrameIndexOutOfRange.into());
+    };
+
+    Ok(vec![WasmValue::from_i32(0)])
+}
+
+/// Stores the guest buffer as the output frame at `idx`. When `checksum` is not `SKIP_CHECKSUM`
+/// it must be the Adler-32 of the buffer, catching miscomputed pointers or lengths in the guest.
+/// A buffer not holding exactly one frame fails with `InvalidBufferSize`, writing the expected
+/// size to `required_len_ptr`.
+#[host_function]
+fn write_frame(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("write_frame");
+
+    guest_status(|| {
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+        store_guest_frame(&mut main_memory, &args, data)
+    })
+}
+
+/// Stores the guest buffer of interleaved RGB `f32` samples in 0.0-1.0, three per pixel in row
+/// order as a float tensor holds them, as the output frame at `idx`. Samples outside the range are
+/// handled as `set_write_clamp_mode` selects. A buffer not holding exactly one frame fails with
+/// `InvalidBufferSize`, writing the expected size in bytes to `required_len_ptr`.
+#[host_function]
+fn write_frame_f32(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("write_frame_f32");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let idx = match usize::try_from(args[0].to_i32()) {
+            Ok(idx) => idx,
+            Err(_) => {
+                error!("Negative frame index {}", args[0].to_i32());
+                return Err(VideoProcessingPluginError::FrameIndexOutOfRange.into());
+            }
+        };
+        let buf_ptr = args[1].to_i32();
+        let buf_len = args[2].to_i32() as usize;
+        let required_len_ptr = args[3].to_i32();
+
+        let (width, height) = match &data_guard.video_info {
+            Some(video_info) => (video_info.width(), video_info.height()),
+            None => {
+                error!("No Video loaded to write frames to");
+                return Err(VideoProcessingPluginError::NoVideoLoaded.into());
+            }
+        };
+        if idx >= data_guard.frames.len() {
+            error!("Frame {idx} does not exist");
+            return Err(VideoProcessingPluginError::FrameIndexOutOfRange.into());
+        }
+
+        let frame_size = frame_ops::f32_frame_byte_size(width, height);
+        if buf_len != frame_size {
+            error!("Frame {idx} buffer holds {buf_len} bytes, expected {frame_size}");
+            main_memory
+                .try_write_bytes(required_len_ptr as u32, &(frame_size as i32).to_le_bytes())?;
+            return Err(VideoProcessingPluginError::InvalidBufferSize.into());
+        }
+
+        let buf_ptr_wasm_memory = main_memory
+            .data_pointer(buf_ptr as u32, buf_len as u32)
+            .map_err(|_| VideoProcessingPluginError::MemoryAccessFailed)?;
+        // Borrowed in place rather than copied, the guest buffer is only read until it was stored
+        let bytes = unsafe { std::slice::from_raw_parts(buf_ptr_wasm_memory, buf_len) };
+
+        let video_struct = &mut *data_guard;
+        let mut video_frame = video_struct.frame_pool.take(Pixel::RGB24, width, height);
+        let clamp = video_struct.encoder_settings.clamp_limited_range;
+        if let Err(sample) = frame_ops::fill_rgb24_from_f32(&mut video_frame, bytes, clamp) {
+            error!("Frame {idx} sample {sample} is outside 0.0-1.0");
+            video_struct.frame_pool.recycle(video_frame);
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
+
+        debug!("Writing Frame {idx}");
+        // A frame written again hands the buffer of its previous output to the next write
+        if let Some(previous) = video_struct.frames[idx].output_frame.replace(video_frame) {
+            video_struct.frame_pool.recycle(previous);
+        }
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Decodes the JPEG (`format` `0`) or PNG (`format` `1`) image in the guest buffer and stores it
+/// as the output frame at `idx`, for guests producing annotated frames as images. An image of
+/// other dimensions than the video is scaled to them as long as its aspect ratio matches (within
+/// 1%), otherwise the call fails with `DimensionMismatch`. Transparency is dropped.
+#[host_function]
+fn write_frame_encoded(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("write_frame_encoded");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let idx = match usize::try_from(args[0].to_i32()) {
+            Ok(idx) => idx,
+            Err(_) => {
+                error!("Negative frame index {}", args[0].to_i32());
+                return Err(VideoProcessingPluginError::FrameIndexOutOfRange.into());
+            }
+        };
+        let format_code = args[1].to_i32();
+        let buf_ptr = args[2].to_i32();
+        let buf_len = args[3].to_i32();
+
+        let format = StillFormat::from_code(format_code).ok_or_else(|| {
+            error!("Unknown image format {format_code}");
+            VideoProcessingPluginError::InvalidArgument
+        })?;
+        if buf_len <= 0 {
+            error!("Image buffer of {buf_len} bytes is empty");
+            return Err(VideoProcessingPluginError::InvalidBufferSize.into());
+        }
+
+        let (width, height) = match &data_guard.video_info {
+            Some(video_info) => (video_info.width(), video_info.height()),
+            None => {
+                error!("No Video loaded to write frames to");
+                return Err(VideoProcessingPluginError::NoVideoLoaded.into());
+            }
+        };
+        if idx >= data_guard.frames.len() {
+            error!("Frame {idx} does not exist");
+            return Err(VideoProcessingPluginError::FrameIndexOutOfRange.into());
+        }
+
+        let bytes = main_memory
+            .read(buf_ptr as u32, buf_len as u32)
+            .map_err(|err| {
+                error!("Error Reading Image Bytes from Pointer {}", err);
+                VideoProcessingPluginError::MemoryAccessFailed
+            })?;
+
+        let image = still_image::decode_rgb24(&bytes, format).map_err(|err| {
+            error!("Error Decoding Frame {idx} from {:?} {:?}", format, err);
+            VideoProcessingPluginError::DecodeFailed
+        })?;
+
+        let video_frame = if (image.width(), image.height()) == (width, height) {
+            image
+        } else {
+            let image_aspect = image.width() as f64 / image.height() as f64;
+            let video_aspect = width as f64 / height as f64;
+            if (image_aspect / video_aspect - 1.0).abs() > 0.01 {
+                error!(
+                    "Frame {idx} image of {}x{} cannot be scaled to {width}x{height}",
+                    image.width(),
+                    image.height()
+                );
+                return Err(VideoProcessingPluginError::DimensionMismatch.into());
+            }
+            frame_ops::resize_frame(&image, width, height, ScalingFlags::BICUBIC).map_err(
+                |err| {
+                    error!("Error Scaling Frame {idx} to {width}x{height} {:?}", err);
+                    VideoProcessingPluginError::ProcessingFailed
+                },
+            )?
+        };
+
+        debug!("Writing Frame {idx}");
+        let video_struct = &mut *data_guard;
+        // A frame written again hands the buffer of its previous output to the next write
+        if let Some(previous) = video_struct.frames[idx].output_frame.replace(video_frame) {
+            video_struct.frame_pool.recycle(previous);
+        }
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Decodes `overlay_filename` and composites it picture-in-picture onto every output frame,
+/// scaled by `scale` with its top left corner at `x`, `y`. Frames without an output yet start
+/// from their input frame. If the overlay has fewer frames its last frame is held.
+#[host_function]
+fn compose_pip(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("compose_pip");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let filename_ptr = args[0].to_i32();
+        let filename_len = args[1].to_i32();
+        let x = args[2].to_i32();
+        let y = args[3].to_i32();
+        let scale = args[4].to_f32();
+
+        let (width, height) = match &data_guard.video_info {
+            Some(video_info) => (video_info.width(), video_info.height()),
+            None => {
+                error!("No Video loaded to compose picture-in-picture onto");
+                return Err(VideoProcessingPluginError::NoVideoLoaded.into());
+            }
+        };
+
+        if !(scale > 0.0 && scale <= 1.0) {
+            error!("Picture-in-picture scale {scale} must be in (0, 1]");
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
+
+        let overlay_filename = read_guest_string(&main_memory, filename_ptr, filename_len)?;
+
+        let (video_struct, context_cache) = data_guard.with_context_cache();
+        let (pip_frames, pip_info) = decode_video::dump_frames(
+            &overlay_filename,
+            &DecodeOptions::default(),
+            context_cache,
+            &video_struct.operation,
+        )
+        .map_err(|err| {
+            error!("Error Loading Picture-in-picture Frames {:?}", err);
+            VideoProcessingPluginError::from(err)
+        })?;
+
+        if pip_frames.is_empty() {
+            error!("Video file {} contained No Frames", overlay_filename);
+            return Err(VideoProcessingPluginError::DecodeFailed.into());
+        }
+
+        let pip_width = ((pip_info.width() as f32 * scale).round() as u32).max(1);
+        let pip_height = ((pip_info.height() as f32 * scale).round() as u32).max(1);
+
+        if x < 0 || y < 0 || x as u32 + pip_width > width || y as u32 + pip_height > height {
+            error!("Picture-in-picture {pip_width}x{pip_height} at ({x}, {y}) does not fit in {width}x{height}");
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
+
+        frame_ops::compose_pip(
+            &mut data_guard.frames,
+            &pip_frames,
+            x as u32,
+            y as u32,
+            pip_width,
+            pip_height,
+        )
+        .map_err(|err| {
+            error!("Error Composing Picture-in-picture {:?}", err);
+            VideoProcessingPluginError::ProcessingFailed
+        })?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Burns the subtitles of the SRT/ASS file `sub_filename` permanently into the output frames,
+/// each frame showing the subtitles timed at its timestamp (see `subtitles::burn_subtitles`).
+/// Frames without an output yet start from their input frame.
+#[host_function]
+fn burn_subtitles(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("burn_subtitles");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let filename_ptr = args[0].to_i32();
+        let filename_len = args[1].to_i32();
+
+        let sub_filename = read_guest_string(&main_memory, filename_ptr, filename_len)?;
+
+        let video_struct = &mut *data_guard;
+        let video_info = video_struct.video_info.as_ref().ok_or_else(|| {
+            error!("No Video loaded to burn subtitles onto");
+            VideoProcessingPluginError::NoVideoLoaded
+        })?;
+
+        if let Err(err) =
+            subtitles::burn_subtitles(&mut video_struct.frames, video_info, &sub_filename)
+        {
+            match err {
+                subtitles::SubtitleError::FilterUnavailable => {
+                    error!("FFMPEG was built without libass, the subtitles filter is unavailable")
+                }
+                subtitles::SubtitleError::FontUnavailable(report) => {
+                    error!("No font available to render {sub_filename}:\n{report}")
+                }
+                err => error!("Error Burning Subtitles {sub_filename} {:?}", err),
+            }
+            return Err(VideoProcessingPluginError::ProcessingFailed.into());
+        }
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Decodes the text subtitle stream at `stream_index` of the loaded video's container (see
+/// `list_streams`) and copies it into the guest buffer as SRT formatted UTF-8. Returns the full
+/// length of the subtitles; when it is larger than `buf_cap` nothing is copied, so the guest can
+/// retry with a buffer of that size. Bitmap subtitles fail with `UnsupportedSubtitleFormat`,
+/// indices of streams that are not subtitles with `StreamNotFound`.
+#[host_function]
+fn extract_subtitles(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("extract_subtitles");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let stream_index = args[0].to_i32();
+        let buf_ptr = args[1].to_i32();
+        let buf_cap = args[2].to_i32();
+
+        if stream_index < 0 || buf_cap < 0 {
+            error!("Invalid stream {stream_index} or subtitle buffer capacity {buf_cap}");
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
+
+        let filename = data_guard.source_filename.as_ref().ok_or_else(|| {
+            error!("No Video loaded to extract the subtitles of");
+            VideoProcessingPluginError::NoVideoLoaded
+        })?;
+
+        let srt = subtitles::extract_subtitles(filename, stream_index as usize).map_err(|err| {
+            error!(
+                "Error Extracting Subtitles of Stream {stream_index} {:?}",
+                err
+            );
+            match err {
+                subtitles::SubtitleError::StreamNotFound => {
+                    VideoProcessingPluginError::StreamNotFound
+                }
+                subtitles::SubtitleError::UnsupportedFormat(_) => {
+                    VideoProcessingPluginError::UnsupportedSubtitleFormat
+                }
+                subtitles::SubtitleError::OpenError(err) => err.into(),
+                _ => VideoProcessingPluginError::DecodeFailed,
+            }
+        })?;
+
+        if srt.len() > buf_cap as usize {
+            error!(
+                "Subtitles of {} bytes do not fit the guest buffer of {} bytes",
+                srt.len(),
+                buf_cap
+            );
+        } else {
+            main_memory.try_write_bytes(buf_ptr as u32, srt.as_bytes())?;
+        }
+
+        Ok(vec![WasmValue::from_i32(srt.len() as i32)])
+    })
+}
+
+/// Renders the audio track of the loaded video into the guest buffer as a packed RGB24 image
+fn render_audio_to_guest(
+    caller: &Caller,
+    args: &[WasmValue],
+    data: &Arc<RwLock<Sessions>>,
+    visualisation: AudioVisualisation,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    let (session_id, args) = session_args(args);
+    let data_guard = read_session(data, session_id)?;
+
+    let mut main_memory = caller
+        .memory(0)
+        .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+    let width = args[0].to_i32();
+    let height = args[1].to_i32();
+    let image_buf_ptr = args[2].to_i32();
+    let image_buf_len = args[3].to_i32();
+
+    if width <= 0 || height <= 0 {
+        error!("Invalid audio visualisation size {width}x{height}");
+        return Err(VideoProcessingPluginError::InvalidArgument.into());
+    }
+
+    let required_len = width as usize * height as usize * 3;
+    if (image_buf_len as usize) < required_len {
+        error!("Image buffer of {image_buf_len} bytes too small, {required_len} required");
+        return Err(VideoProcessingPluginError::InvalidBufferSize.into());
+    }
+
+    let filename = data_guard.source_filename.as_ref().ok_or_else(|| {
+        error!("No Video loaded to render audio from");
+        VideoProcessingPluginError::NoVideoLoaded
+    })?;
+
+    let picture = audio::render_visualisation(filename, visualisation, width as u32, height as u32)
+        .map_err(|err| {
+            error!("Error Rendering Audio {:?} {:?}", visualisation, err);
+            VideoProcessingPluginError::ProcessingFailed
+        })?;
+
+    main_memory.try_write_bytes(
+        image_buf_ptr as u32,
+        &frame_ops::packed_rgb24_bytes(&picture),
+    )?;
+
+    Ok(vec![WasmValue::from_i32(0)])
+}
+
+/// Renders a `width` x `height` RGB24 waveform of the loaded video's audio into the guest buffer
+#[host_function]
+fn render_audio_waveform(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("render_audio_waveform");
+    guest_status(|| render_audio_to_guest(&caller, &args, data, AudioVisualisation::Waveform))
+}
+
+/// Renders a `width` x `height` RGB24 spectrogram of the loaded video's audio into the guest buffer
+#[host_function]
+fn render_spectrogram(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("render_spectrogram");
+    guest_status(|| render_audio_to_guest(&caller, &args, data, AudioVisualisation::Spectrogram))
+}
+
+/// Measures the EBU R128 loudness of the loaded video's audio, writing the integrated loudness
+/// (LUFS), the highest true peak across channels (dBFS) and the loudness range (LU) as `f32`s
+/// through the given pointers. Fails if the source has no audio stream.
+#[host_function]
+fn measure_loudness(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("measure_loudness");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let integrated_ptr = args[0].to_i32();
+        let true_peak_ptr = args[1].to_i32();
+        let range_ptr = args[2].to_i32();
+
+        let filename = data_guard.source_filename.as_ref().ok_or_else(|| {
+            error!("No Video loaded to measure the loudness of");
+            VideoProcessingPluginError::NoVideoLoaded
+        })?;
+
+        let loudness = audio::measure_loudness(filename).map_err(|err| {
+            error!("Error Measuring Loudness {:?}", err);
+            VideoProcessingPluginError::ProcessingFailed
+        })?;
+
+        main_memory.try_write_bytes(integrated_ptr as u32, &loudness.integrated.to_le_bytes())?;
+        main_memory.try_write_bytes(true_peak_ptr as u32, &loudness.true_peak.to_le_bytes())?;
+        main_memory.try_write_bytes(range_ptr as u32, &loudness.range.to_le_bytes())?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Normalizes the loaded video's audio to an integrated loudness of `target_lufs` (e.g. `-23.0`
+/// for EBU R128 broadcast) with FFMPEG's two pass `loudnorm`, measuring it as `measure_loudness`
+/// does and then applying the gain that reaches the target, limiting true peaks to -1 dBTP.
+/// `target_lufs` must lie within the -70 to -5 LUFS `loudnorm` takes. The normalized audio is
+/// re-encoded with the source's codec and muxed by later encodes in place of the source audio.
+/// Fails if the loaded video has no audio to mux.
+#[host_function]
+fn normalize_loudness(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("normalize_loudness");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let target_lufs = args[0].to_f32();
+        if !(-70.0..=-5.0).contains(&target_lufs) {
+            error!("Target loudness {target_lufs} LUFS must be within -70 to -5");
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
+
+        let video_struct = &mut *data_guard;
+        let (Some(filename), Some(video_info)) = (
+            &video_struct.source_filename,
+            video_struct.video_info.as_mut(),
+        ) else {
+            error!("No Video loaded to normalize the loudness of");
+            return Err(VideoProcessingPluginError::NoVideoLoaded.into());
+        };
+        if video_info.audio.is_none() {
+            error!("The loaded video has no audio to normalize");
+            return Err(VideoProcessingPluginError::ProcessingFailed.into());
+        }
+
+        let normalized = audio::normalize_loudness(filename, target_lufs).map_err(|err| {
+            error!("Error Normalizing Loudness {:?}", err);
+            VideoProcessingPluginError::ProcessingFailed
+        })?;
+        video_info.audio = Some(normalized);
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Overrides the color signaling written onto the output stream, given as FFMPEG's integer codes
+/// for primaries, transfer characteristic, matrix and range. By default the source's values are
+/// carried through, passing `-1` for all four restores that behaviour.
+#[host_function]
+fn set_output_color_properties(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_output_color_properties");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let primaries = args[0].to_i32();
+        let transfer = args[1].to_i32();
+        let matrix = args[2].to_i32();
+        let range = args[3].to_i32();
+
+        data_guard.encoder_settings.color = match (primaries, transfer, matrix, range) {
+            (-1, -1, -1, -1) => None,
+            _ => match ColorProperties::from_codes(primaries, transfer, matrix, range) {
+                Some(color) => Some(color),
+                None => {
+                    error!("Invalid color properties primaries {primaries} transfer {transfer} matrix {matrix} range {range}");
+                    return Err(VideoProcessingPluginError::InvalidArgument.into());
+                }
+            },
+        };
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Sets the encoder's rate control (VBV) buffer size in bits. The bitrate may exceed the maximum
+/// rate (the source's `max_bitrate`) only as long as the excess fits in this buffer, so smaller
+/// buffers cap the bitrate more strictly over short windows, as live/streaming profiles need.
+/// `bufsize` must be positive, `0` restores the encoder's default of no buffer constraint.
+#[host_function]
+fn set_encoder_rc_buffer(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_encoder_rc_buffer");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        data_guard.encoder_settings.rc_buffer_size = match args[0].to_i32() {
+            0 => None,
+            bufsize if bufsize > 0 => Some(bufsize as u32),
+            bufsize => {
+                error!("Rate control buffer size {bufsize} must be positive");
+                return Err(VideoProcessingPluginError::InvalidArgument.into());
+            }
+        };
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Returns the rate control buffer size set by `set_encoder_rc_buffer`, `0` when unset
+#[host_function]
+fn get_encoder_rc_buffer(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_encoder_rc_buffer");
+
+    guest_status(|| {
+        let (session_id, _) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+        let bufsize = data_guard.encoder_settings.rc_buffer_size.unwrap_or(0);
+
+        Ok(vec![WasmValue::from_i32(bufsize as i32)])
+    })
+}
+
+/// Sets the output's target and maximum bitrate in bits per second, `0` restoring the defaults of
+/// a near lossless bitrate without a maximum. A non-negative `crf` sets a constant rate factor
+/// instead, taking precedence over the target bitrate for encoders supporting it (e.g. libx264,
+/// libx265 and libvpx-vp9) and ignored by others; a negative `crf` clears it.
+#[host_function]
+fn set_output_bitrate(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_output_bitrate");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let bit_rate = args[0].to_i32();
+        let max_bit_rate = args[1].to_i32();
+        let crf = args[2].to_f32();
+
+        if bit_rate < 0 || max_bit_rate < 0 || crf.is_nan() {
+            error!("Invalid bitrate {bit_rate} max bitrate {max_bit_rate} crf {crf}");
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
+        if bit_rate > 0 && max_bit_rate > 0 && max_bit_rate < bit_rate {
+            error!("Max bitrate {max_bit_rate} is below the target bitrate {bit_rate}");
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
+
+        let settings = &mut data_guard.encoder_settings;
+        settings.bit_rate = (bit_rate > 0).then_some(bit_rate as usize);
+        settings.max_bit_rate = (max_bit_rate > 0).then_some(max_bit_rate as usize);
+        settings.crf = (crf >= 0.0).then_some(crf);
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Sets the encoder's keyframe interval to `gop_size` frames and allows up to `max_b_frames`
+/// consecutive B-frames, e.g. `30`, `0` for a keyframe every 30 frames. `-1` keeps FFMPEG's default
+/// for either. Once either is set the encoder decides each frame's type, otherwise every frame is
+/// encoded as a keyframe.
+#[host_function]
+fn set_encoder_gop(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_encoder_gop");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let gop_size = match args[0].to_i32() {
+            -1 => None,
+            gop_size if gop_size > 0 => Some(gop_size as u32),
+            gop_size => {
+                error!("GOP size {gop_size} must be positive");
+                return Err(VideoProcessingPluginError::InvalidArgument.into());
+            }
+        };
+        let max_b_frames = match args[1].to_i32() {
+            -1 => None,
+            max_b_frames if max_b_frames >= 0 => Some(max_b_frames as usize),
+            max_b_frames => {
+                error!("Max B-frames {max_b_frames} must not be negative");
+                return Err(VideoProcessingPluginError::InvalidArgument.into());
+            }
+        };
+
+        data_guard.encoder_settings.gop_size = gop_size;
+        data_guard.encoder_settings.max_b_frames = max_b_frames;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// With `enabled` non-zero, each frame is encoded with its source frame type (I/P/B) as a hint so
+/// keyframes stay aligned with the source's. libx264 and libx265 honor all three types (B-frames
+/// only as far as `max_b_frames` allows), while libvpx, libaom and FFMPEG's native encoders only
+/// honor I, forcing a keyframe, and ignore P and B. Disabled by default.
+#[host_function]
+fn set_frame_type_hints(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_frame_type_hints");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+        data_guard.encoder_settings.frame_type_hints = args[0].to_i32() != 0;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Scales the output to `width` x `height` when encoding, e.g. to emit 1080p from a 4K source.
+/// Both must be positive, odd values are rounded down to even; `0`, `0` restores encoding at the
+/// source's resolution. A warning is logged if the aspect ratio changes.
+#[host_function]
+fn set_output_resolution(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_output_resolution");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        data_guard.encoder_settings.resolution = match (args[0].to_i32(), args[1].to_i32()) {
+            (0, 0) => None,
+            (width, height) if width > 1 && height > 1 => Some((width as u32, height as u32)),
+            (width, height) => {
+                error!("Output resolution {width}x{height} must be at least 2x2");
+                return Err(VideoProcessingPluginError::InvalidArgument.into());
+            }
+        };
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Encodes the output at the constant frame rate `numerator` / `denominator` (e.g. `30000`,
+/// `1001`), restamping every frame to follow the previous one by one frame interval (or as many
+/// as a deduplicated frame spans) instead of keeping its source timestamp. Turns variable frame
+/// rate or sampled frames into a constant rate stream; no frames are dropped or duplicated, so
+/// the output's duration follows from its frame count. `0`, `0` restores the source timestamps
+/// and frame rate.
+#[host_function]
+fn set_output_frame_rate(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_output_frame_rate");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        data_guard.encoder_settings.frame_rate = match (args[0].to_i32(), args[1].to_i32()) {
+            (0, 0) => None,
+            (numerator, denominator) if numerator > 0 && denominator > 0 => {
+                Some(Rational::new(numerator, denominator))
+            }
+            (numerator, denominator) => {
+                error!("Output frame rate {numerator}/{denominator} must be positive");
+                return Err(VideoProcessingPluginError::InvalidArgument.into());
+            }
+        };
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Selects the encoder output is transcoded with by its FFMPEG name (e.g. "libx265" or
+/// "libvpx-vp9"), which must be a video encoder available in the linked FFMPEG or the call fails
+/// with `UnsupportedCodec`. An empty name restores the default of encoding with the source's codec.
+#[host_function]
+fn set_output_codec(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_output_codec");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let codec_name = read_guest_string(&main_memory, args[0].to_i32(), args[1].to_i32())?;
+
+        data_guard.encoder_settings.codec_name = if codec_name.is_empty() {
+            None
+        } else {
+            match ffmpeg::encoder::find_by_name(&codec_name) {
+                Some(codec) if codec.is_video() => Some(codec_name),
+                _ => {
+                    error!("No video encoder named {codec_name} in the linked FFMPEG");
+                    return Err(VideoProcessingPluginError::UnsupportedCodec.into());
+                }
+            }
+        };
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Selects the container output files are written as by FFMPEG's muxer name (e.g. "mp4",
+/// "matroska" or "webm"), for output names without or with another extension. The muxer must be
+/// available in the linked FFMPEG or the call fails with `UnsupportedFormat`. An empty name
+/// restores inferring the container from the output file's extension.
+#[host_function]
+fn set_output_format(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_output_format");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let format_name = read_guest_string(&main_memory, args[0].to_i32(), args[1].to_i32())?;
+
+        data_guard.encoder_settings.format_name = if format_name.is_empty() {
+            None
+        } else if encode_video::muxer_exists(&format_name) {
+            Some(format_name)
+        } else {
+            error!("No muxer named {format_name} in the linked FFMPEG");
+            return Err(VideoProcessingPluginError::UnsupportedFormat.into());
+        };
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Applies gamma correction to the frame `get_frame` returns for every index, mapping each RGB
+/// channel value `v` to `255 * (v / 255) ^ (1 / gamma)` so values above 1 brighten and values
+/// below 1 darken. Frames converted to another format are converted back to RGB24 first. The
+/// result is kept for `get_frame` as `convert_pixel_format` does, leaving the input frames
+/// untouched. `gamma` must be greater than 0.
+#[host_function]
+fn apply_gamma(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("apply_gamma");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let gamma = args[0].to_f32();
+        if !(gamma.is_finite() && gamma > 0.0) {
+            error!("Gamma {gamma} must be greater than 0");
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
+
+        // On RGB24 frames one table covers every channel
+        let lut = frame_ops::gamma_lut(gamma);
+        for (idx, frame_map) in data_guard.frames.iter_mut().enumerate() {
+            let frame = frame_map.exchanged_frame();
+            let mut corrected = match frame.format() {
+                Pixel::RGB24 => frame.clone(),
+                format => frame_ops::convert_pixel_format(frame, Pixel::RGB24).map_err(|err| {
+                    error!("Error Converting Frame {idx} from {:?} {:?}", format, err);
+                    VideoProcessingPluginError::ProcessingFailed
+                })?,
+            };
+            frame_ops::apply_lut_rgb24(&mut corrected, &lut);
+            frame_map.derived_frame = Some(corrected);
+        }
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Removes frames nearly identical to the frame before them, extending that frame's duration
+/// instead so the encoded output keeps its timing with a variable frame rate.
+/// `threshold` is the mean absolute difference per byte (0-255) under which frames count as equal.
+/// Frame indices change, the new frame count is returned.
+#[host_function]
+fn dedupe_frames(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("dedupe_frames");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let threshold = args[0].to_f32();
+        if !(0.0..=255.0).contains(&threshold) {
+            error!("Dedupe threshold {threshold} must be in [0, 255]");
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
+
+        let frames = std::mem::take(&mut data_guard.frames);
+        let original_count = frames.len();
+        data_guard.frames = frame_ops::dedupe_frames(frames, threshold);
+        debug!(
+            "Deduplicated {} frames into {}",
+            original_count,
+            data_guard.frames.len()
+        );
+
+        Ok(vec![WasmValue::from_i32(data_guard.frames.len() as i32)])
+    })
+}
+
+/// Selects what `assemble_output_frames_to_video` does with frames that were never written.
+/// `0` (strict, the default) fails the assembly, `1` (lenient) passes their input frame through
+/// unchanged, so guests only need to write the frames they modify.
+#[host_function]
+fn set_assembly_policy(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_assembly_policy");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        data_guard.assembly_policy = match args[0].to_i32() {
+            0 => AssemblyPolicy::Strict,
+            1 => AssemblyPolicy::Lenient,
+            policy => {
+                error!("Unknown assembly policy {policy}");
+                return Err(VideoProcessingPluginError::InvalidArgument.into());
+            }
+        };
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Writes how many frames the running (or last) decode/encode has processed and how many it
+/// expects in total through `done_ptr` and `total_ptr`. The total is `0` when unknown.
+/// Does not wait for the running operation, so it can be polled while one is in progress.
+#[host_function]
+fn get_progress(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<OperationContext>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_progress");
+
+    guest_status(|| {
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let done_ptr = args[0].to_i32();
+        let total_ptr = args[1].to_i32();
+
+        let (done, total) = data.progress();
+        main_memory.try_write_bytes(done_ptr as u32, &(done as u32).to_le_bytes())?;
+        main_memory.try_write_bytes(total_ptr as u32, &(total as u32).to_le_bytes())?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Asks the running decode/encode to stop at the next frame, after which it fails.
+/// Has no effect on operations started afterwards.
+#[host_function]
+fn cancel_operation(
+    _caller: Caller,
+    _args: Vec<WasmValue>,
+    data: &mut Arc<OperationContext>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("cancel_operation");
+
+    guest_status(|| {
+        data.cancel();
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Cancels decodes and encodes begun afterwards that run for longer than `timeout_millis`, as
+/// `cancel_operation` would, including FFMPEG calls blocked on reading the input. Timed out loads
+/// fail with `Cancelled`. `0` removes the limit.
+#[host_function]
+fn set_operation_timeout(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<OperationContext>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_operation_timeout");
+
+    guest_status(|| {
+        let timeout = match args[0].to_i32() {
+            0 => None,
+            timeout_millis if timeout_millis > 0 => {
+                Some(Duration::from_millis(timeout_millis as u64))
+            }
+            timeout_millis => {
+                error!("Operation timeout {timeout_millis} must not be negative");
+                return Err(VideoProcessingPluginError::InvalidArgument.into());
+            }
+        };
+        data.set_timeout(timeout);
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Reports whether the last `assemble_output_frames_to_video` cropped odd dimensions by one pixel
+/// to the even dimensions H.264 requires, dropping the last column and/or row of every frame.
+/// Returns a bitmask, bit 0 set when the width was cropped and bit 1 when the height was.
+#[host_function]
+fn get_even_dimension_adjustment(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("get_even_dimension_adjustment");
+
+    guest_status(|| {
+        let (session_id, _) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        Ok(vec![WasmValue::from_i32(
+            data_guard.even_dimension_adjustment.code(),
+        )])
+    })
+}
+
+/// Runs the guest exported function `callback_name` once per frame, so the host drives the loop.
+/// Each input frame is copied into the guest buffer as decoded RGB24, whatever the selected pixel
+/// format or conversions made on request, and the callback is invoked as
+/// `callback(frame_index, buf_ptr, buf_len) -> i32`; it transforms the pixels in place and returns
+/// `0`, after which the buffer is stored as that frame's output. A non zero return aborts the loop.
+#[host_function]
+fn process_all_frames(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("process_all_frames");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let callback_name_ptr = args[0].to_i32();
+        let callback_name_len = args[1].to_i32();
+        let image_buf_ptr = args[2].to_i32();
+        let image_buf_len = args[3].to_i32();
+
+        let callback_name = read_guest_string(&main_memory, callback_name_ptr, callback_name_len)?;
+
+        let instance = caller.instance().ok_or_else(|| {
+            error!("No calling instance to find {callback_name} in");
+            HostFuncError::Runtime(1)
+        })?;
+        let callback = instance.func(&callback_name).map_err(|err| {
+            error!("Guest does not export callback {callback_name} {err}");
+            VideoProcessingPluginError::CallbackFailed
+        })?;
+        let executor = caller.executor().ok_or_else(|| {
+            error!("No executor to run {callback_name} with");
+            HostFuncError::Runtime(1)
+        })?;
+
+        let frame_count = lock_session(data, session_id)?.frames.len();
+
+        for idx in 0..frame_count {
+            // The callback may call back into the plugin, so the lock is never held while it runs
+            let (width, height) = {
+                let data_guard = read_session(data, session_id)?;
+                let frame = &data_guard
+                    .frames
+                    .get(idx)
+                    .ok_or_else(|| {
+                        error!("Frame {idx} was removed while processing");
+                        VideoProcessingPluginError::FrameIndexOutOfRange
+                    })?
+                    .input_frame;
+                copy_frame_to_guest(&mut main_memory, frame, image_buf_ptr, image_buf_len)?;
+                (frame.width(), frame.height())
+            };
+
+            let returns = executor
+                .run_func(
+                    &callback,
+                    vec![
+                        WasmValue::from_i32(idx as i32),
+                        WasmValue::from_i32(image_buf_ptr),
+                        WasmValue::from_i32(image_buf_len),
+                    ],
+                )
+                .map_err(|err| {
+                    error!("Callback {callback_name} trapped on frame {idx} {err}");
+                    VideoProcessingPluginError::CallbackFailed
+                })?;
+
+            match returns.first().map(|value| value.to_i32()) {
+                Some(0) => {}
+                code => {
+                    error!("Callback {callback_name} failed on frame {idx} returning {code:?}");
+                    return Err(VideoProcessingPluginError::CallbackFailed.into());
+                }
+            }
+
+            let processed = main_memory
+                .read(image_buf_ptr as u32, width * height * 3)
+                .map_err(|err| {
+                    error!("Error Reading processed frame {idx} {err}");
+                    VideoProcessingPluginError::MemoryAccessFailed
+                })?;
+
+            let mut data_guard = lock_session(data, session_id)?;
+            if let Some(frame_map) = data_guard.frames.get_mut(idx) {
+                frame_map.output_frame =
+                    Some(frame_ops::rgb24_from_packed(&processed, width, height));
+            }
+        }
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Serializes the decoded input frames as a YUV4MPEG2 stream (4:4:4, the source's frame rate,
+/// aspect ratio, matrix and range) into the guest buffer, a lossless export for other tools.
+/// Returns the stream's size in bytes; nothing is written when it exceeds `buf_len`, so
+/// passing `0` queries the size to allocate.
+#[host_function]
+fn export_y4m(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("export_y4m");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let data_guard = read_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let buf_ptr = args[0].to_i32();
+        let buf_len = args[1].to_i32();
+
+        let video_info = data_guard.video_info.as_ref().ok_or_else(|| {
+            error!("No Video loaded to export");
+            VideoProcessingPluginError::NoVideoLoaded
+        })?;
+
+        let stream = y4m::Y4mStream::new(&data_guard.frames, video_info).map_err(|err| {
+            error!("Cannot export frames as Y4M {:?}", err);
+            VideoProcessingPluginError::ProcessingFailed
+        })?;
+
+        let stream_len = stream.byte_len();
+        if stream_len > i32::MAX as usize {
+            error!("Y4M stream of {stream_len} bytes exceeds the guest address space");
+            return Err(VideoProcessingPluginError::InvalidBufferSize.into());
+        }
+
+        if buf_len >= 0 && stream_len <= buf_len as usize {
+            let bytes = stream.serialize().map_err(|err| {
+                error!("Error Serializing Y4M stream {:?}", err);
+                VideoProcessingPluginError::ProcessingFailed
+            })?;
+            main_memory.try_write_bytes(buf_ptr as u32, &bytes)?;
+        }
+
+        Ok(vec![WasmValue::from_i32(stream_len as i32)])
+    })
+}
+
+/// Parses the transform pipeline spec in the guest buffer (see `pipeline::PipelineSpec` for the
+/// versioned format) and runs the whole decode, transform and encode chain natively in one call,
+/// independently of the loaded video. Returns the size of the written output file in bytes.
+#[host_function]
+fn run_pipeline(
     caller: Caller,
     args: Vec<WasmValue>,
-    data: &mut Arc<Mutex<FramesMap>>,
+    data: &mut Arc<RwLock<Sessions>>,
 ) -> Result<Vec<WasmValue>, HostFuncError> {
-    debug!("assemble_video");
-    let mut data_guard = match data.lock() {
-        Ok(x) => x,
-        Err(err) => {
-            error!("Mutex Carrying plugin Data Poisoned {err}");
-            return Err(HostFuncError::Runtime(1));
+    debug!("run_pipeline");
+
+    guest_status_i64(|| {
+        let mut data_guard = lock_frames(data)?;
+
+        let main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let spec_ptr = args[0].to_i32();
+        let spec_len = args[1].to_i32();
+
+        let spec_text = read_guest_string(&main_memory, spec_ptr, spec_len)?;
+
+        let log_pipeline_error = |err: pipeline::PipelineError| match err {
+            pipeline::PipelineError::InvalidSpec { line, message } => {
+                error!("Invalid pipeline spec at line {line}: {message}");
+                VideoProcessingPluginError::InvalidArgument
+            }
+            pipeline::PipelineError::InvalidStep { step, message } => {
+                error!("Pipeline step {step} failed: {message}");
+                VideoProcessingPluginError::ProcessingFailed
+            }
+            pipeline::PipelineError::DecodeError(err) => {
+                error!("Error Running Pipeline {:?}", err);
+                VideoProcessingPluginError::from(err)
+            }
+            pipeline::PipelineError::EncodeError(err) => {
+                error!("Error Running Pipeline {:?}", err);
+                VideoProcessingPluginError::from(err)
+            }
+            err => {
+                error!("Error Running Pipeline {:?}", err);
+                VideoProcessingPluginError::ProcessingFailed
+            }
+        };
+
+        let spec = pipeline::parse_spec(&spec_text).map_err(log_pipeline_error)?;
+        let video_struct = &mut *data_guard;
+        let output_size = pipeline::run_pipeline(
+            &spec,
+            video_struct.context_cache.get_mut(),
+            &video_struct.operation,
+        )
+        .map_err(log_pipeline_error)?;
+
+        Ok(vec![WasmValue::from_i64(output_size as i64)])
+    })
+}
+
+/// Output frames in presentation order for encoding, with any missing frame handled per
+/// `assembly_policy`. They are sorted by their source timestamp whatever order they were written
+/// in, so the output is in presentation order even when the stored frames are in decode order
+/// (see `set_frame_ordering`). Frames without a timestamp keep their index position, only the
+/// timestamped frames are sorted among themselves. The frames are borrowed rather than copied,
+/// the caller releasing the output frames once they were encoded.
+fn collect_output_frames(
+    frames: &Frames,
+    assembly_policy: AssemblyPolicy,
+) -> Result<Vec<OutputFrame<'_>>, VideoProcessingPluginError> {
+    check_output_frames(frames, assembly_policy)?;
+
+    let mut output_frames: Vec<_> = frames
+        .iter()
+        .map(|frame_map| OutputFrame {
+            // The input frame substituted for a missing one stays available to `get_frame`
+            frame: frame_map.current_frame(),
+            frame_type: frame_map.frame_type,
+            timestamp: frame_map.timestamp,
+            duration: frame_map.duration,
+            force_keyframe: frame_map.force_keyframe,
+        })
+        .collect();
+
+    sort_timestamped(&mut output_frames, |output_frame| output_frame.timestamp);
+    Ok(output_frames)
+}
+
+// Fails with `MissingFrames` listing the frames without an output frame, unless
+// `assembly_policy` substitutes their input frames
+fn check_output_frames(
+    frames: &Frames,
+    assembly_policy: AssemblyPolicy,
+) -> Result<(), VideoProcessingPluginError> {
+    let pass_through_missing = assembly_policy == AssemblyPolicy::Lenient;
+
+    // Check Frames have all been Written
+    // Save Indexes of frames that have not been written
+    let missing_frames: Vec<usize> = frames
+        .iter()
+        .enumerate()
+        .filter(|(_, frame_map)| frame_map.output_frame.is_none())
+        .map(|(idx, _)| idx)
+        .collect();
+
+    if missing_frames.len() > 0 {
+        if pass_through_missing {
+            warn!(
+                "Substituted {} input frames for missing output frames",
+                missing_frames.len()
+            );
+        } else {
+            error!("Error Missing Frames {:?} ", missing_frames);
+            return Err(VideoProcessingPluginError::MissingFrames(missing_frames));
         }
-    };
+    }
+    Ok(())
+}
+
+// Sorts the frames with a `timestamp` by it into the positions such frames had, leaving the frames
+// without one in their index position. Every place frames are ordered goes through it, so they
+// agree on where untimestamped frames end up.
+fn sort_timestamped<T>(frames: &mut Vec<T>, timestamp: impl Fn(&T) -> Option<i64>) {
+    let slots: Vec<usize> = frames
+        .iter()
+        .enumerate()
+        .filter(|(_, frame)| timestamp(frame).is_some())
+        .map(|(idx, _)| idx)
+        .collect();
+    let mut sorted = slots.clone();
+    sorted.sort_by_key(|&idx| timestamp(&frames[idx]));
+
+    let mut sources: Vec<usize> = (0..frames.len()).collect();
+    for (slot, source) in slots.into_iter().zip(sorted) {
+        sources[slot] = source;
+    }
+    let mut unsorted: Vec<_> = std::mem::take(frames).into_iter().map(Some).collect();
+    *frames = sources
+        .into_iter()
+        .map(|source| unsorted[source].take().expect("each frame is placed once"))
+        .collect();
+}
+
+/// Marks frame `idx` as a forced keyframe (`enabled` of `1`) or clears the mark (`0`), e.g. to
+/// start a new GOP at each scene cut the guest detected. Marked frames are encoded as keyframes
+/// by `assemble_output_frames_to_video`, `assemble_output_frames_to_buffer` and `push_frame`
+/// whatever the GOP settings and frame type hints, the other frames are left to them.
+#[host_function]
+fn set_forced_keyframe(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("set_forced_keyframe");
+
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let idx = args[0].to_i32();
+        let enabled = args[1].to_i32() != 0;
 
-    let mut main_memory = caller.memory(0).ok_or(HostFuncError::Runtime(1))?;
+        let frames_len = data_guard.frames.len();
+        let frame_map = usize::try_from(idx)
+            .ok()
+            .and_then(|idx| data_guard.frames.get_mut(idx))
+            .ok_or_else(|| {
+                error!("Frame {idx} out of range, {frames_len} frames loaded");
+                VideoProcessingPluginError::FrameIndexOutOfRange
+            })?;
+        frame_map.force_keyframe = enabled;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+// The body of `assemble_output_frames_to_video`, reading its arguments from `main_memory`
+fn assemble_video_for_guest(
+    main_memory: &mut Memory,
+    args: &[WasmValue],
+    data: &Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    let (session_id, args) = session_args(args);
+    let mut data_guard = lock_session(data, session_id)?;
 
     let filename_ptr = args[0].to_i32();
     let filename_len = args[1].to_i32();
-    let filaname_capacity = args[2].to_i32();
+    // args[2] is the capacity of the guest's String, unused as the filename is copied out
+    let missing_ptr = args[3].to_i32();
+    let missing_cap = args[4].to_i32();
+    let missing_count_ptr = args[5].to_i32();
 
-    // TODO proper Handling of errors
-    let filename_ptr_main_memory = main_memory.try_get_ptr::<u8>(filename_ptr as u32, 1)?;
+    if missing_cap < 0 {
+        error!("Negative missing frames buffer capacity {missing_cap}");
+        return Err(VideoProcessingPluginError::InvalidBufferSize.into());
+    }
+
+    let output_file = read_guest_string(main_memory, filename_ptr, filename_len)?;
 
     let video_struct = &mut (*data_guard);
-    let frames = &mut video_struct.frames;
-    let video_info = match &video_struct.video_info {
-        Some(video_info) => video_info,
-        None => {
-            error!("No Video Information when attempting to Assemble output assemble_output_frames_to_video");
-            return Err(HostFuncError::User(1));
+
+    // Checked before the encode is begun so no file is written for an incomplete video
+    match check_output_frames(&video_struct.frames, video_struct.assembly_policy) {
+        Ok(()) => {}
+        Err(VideoProcessingPluginError::MissingFrames(missing_frames)) => {
+            let written: Vec<u8> = missing_frames
+                .iter()
+                .take(missing_cap as usize)
+                .flat_map(|&idx| (idx as i32).to_le_bytes())
+                .collect();
+            main_memory.try_write_bytes(missing_ptr as u32, &written)?;
+            main_memory.try_write_bytes(
+                missing_count_ptr as u32,
+                &(missing_frames.len() as i32).to_le_bytes(),
+            )?;
+            return Err(VideoProcessingPluginError::MissingFrames(missing_frames).into());
         }
-    };
+        Err(err) => return Err(err.into()),
+    }
+
+    // The same steps as a guest calling `begin_encode`, `push_frame` for every frame and
+    // `finish_encode`
+    video_struct.begin_encode(&output_file)?;
+
+    let frames = collect_output_frames(&video_struct.frames, video_struct.assembly_policy)?;
+    let frames_encoded = frames.len();
+    let operation = &video_struct.operation;
+    operation.begin(frames_encoded);
+    let encode_result = frames.iter().try_for_each(|output_frame| {
+        if operation.is_cancelled() {
+            return Err(VideoProcessingPluginError::Cancelled);
+        }
+        push_output_frame(video_struct.encoder.get_mut(), output_frame)?;
+        operation.advance();
+        Ok(())
+    });
+
+    // Finished after a failure as well, so what was encoded so far stays playable
+    let finish_result = video_struct.finish_encode();
+    encode_result.and(finish_result)?;
+
+    debug!("Encoded {frames_encoded} frames into {output_file}");
+    video_struct.release_output_frames();
+    Ok(vec![WasmValue::from_i32(0)])
+}
+
+/// Encodes the output frames into the video file at the given filename. When frames have no output
+/// frame (and the assembly policy does not substitute them) it fails with `MissingFrames`, writing
+/// the indices of the missing frames as i32s to `missing_ptr`, as many as `missing_cap` holds, and
+/// how many are missing to `missing_count_ptr`, so the guest can process only the gaps.
+/// Returns `0` once the video is written, a frame failing to encode fails with `EncodeFailed`
+/// rather than leaving the guest to find a broken file.
+#[host_function]
+fn assemble_output_frames_to_video(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("assemble_video");
+    guest_status(|| {
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+        assemble_video_for_guest(&mut main_memory, &args, data)
+    })
+}
+
+/// Encodes the output frames like `assemble_output_frames_to_video`, muxed as the container
+/// `format` (e.g. "mp4"), and copies the encoded video into the guest buffer. The encoded size is
+/// written to `size_ptr` either way. A buffer too small for it fails with `InvalidBufferSize`,
+/// keeping the output frames so the guest can retry with a buffer of that size.
+#[host_function]
+fn assemble_output_frames_to_buffer(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("assemble_output_frames_to_buffer");
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let format_ptr = args[0].to_i32();
+        let format_len = args[1].to_i32();
+        let buf_ptr = args[2].to_i32();
+        let buf_len = args[3].to_i32();
+        let size_ptr = args[4].to_i32();
 
-    let output_file: String = unsafe {
-        String::from_raw_parts(
-            filename_ptr_main_memory,
-            filename_len as usize,
-            filaname_capacity as usize,
+        if buf_len < 0 {
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
+
+        // Container to mux into (e.g. "mp4"), there is no file extension to guess it from
+        let format_name = read_guest_string(&main_memory, format_ptr, format_len)?;
+
+        let video_struct = &mut (*data_guard);
+        let video_info = match &video_struct.video_info {
+            Some(video_info) => video_info,
+            None => {
+                error!("No Video Information when attempting to assemble_output_frames_to_buffer");
+                return Err(VideoProcessingPluginError::NoVideoLoaded.into());
+            }
+        };
+
+        let frames = collect_output_frames(&video_struct.frames, video_struct.assembly_policy)?;
+
+        let encode_result = encode_video::VideoEncoder::new_in_memory(
+            &video_info,
+            &video_struct.encoder_settings,
+            &format_name,
         )
-    };
+        .and_then(|mut video_encoder| {
+            video_struct.even_dimension_adjustment = video_encoder.dimension_adjustment(video_info);
+            video_encoder.receive_and_process_decoded_frames(&frames, &video_struct.operation)?;
+            Ok(video_encoder)
+        });
 
-    // Check Frames have all been Written
-    // Save Indexes of frames that have not been written
-    let (mut frames, missing_frames) = frames.into_iter().enumerate().fold(
-        (Vec::new(), Vec::new()),
-        |(mut iter_frames, mut iter_missing), (idx, frame_map)| {
-            match frame_map.output_frame.as_mut() {
-                Some(fr) => {
-                    // TODO REMOVE CLONE
-                    iter_frames.push((fr.clone(), frame_map.frame_type, frame_map.timestamp))
+        let encoded = match encode_result {
+            Ok(video_encoder) => video_encoder
+                .into_bytes()
+                .expect("in-memory encoders always hold their output"),
+            Err(err) => {
+                error!("Encode stream Error {:?}", err);
+                return Err(VideoProcessingPluginError::from(err).into());
+            }
+        };
+
+        // Reported either way, so a guest with too small a buffer can retry with the right size
+        main_memory.try_write_bytes(size_ptr as u32, &(encoded.len() as i32).to_le_bytes())?;
+
+        if encoded.len() > buf_len as usize {
+            error!(
+                "Encoded video of {} bytes does not fit the guest buffer of {} bytes",
+                encoded.len(),
+                buf_len
+            );
+            return Err(VideoProcessingPluginError::InvalidBufferSize.into());
+        }
+        main_memory.try_write_bytes(buf_ptr as u32, &encoded)?;
+        video_struct.release_output_frames();
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Opens an encoder writing to the video file at the given filename, for guests encoding frame by
+/// frame with `push_frame` instead of writing every frame before `assemble_output_frames_to_video`.
+/// An encode begun earlier and not finished is abandoned.
+#[host_function]
+fn begin_encode(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("begin_encode");
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let filename_ptr = args[0].to_i32();
+        let filename_len = args[1].to_i32();
+
+        let output_file = read_guest_string(&main_memory, filename_ptr, filename_len)?;
+
+        data_guard.begin_encode(&output_file)?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Encodes the guest buffer as the frame at `idx`, taking the same arguments as `write_frame`.
+/// Frames must be pushed in presentation order. Any output or converted frame stored at `idx` is
+/// released, the decoded frame staying available to `get_frame`; an `idx` past the loaded frames
+/// (e.g. when reading frames with `next_frame`) is encoded at the constant frame rate position.
+#[host_function]
+fn push_frame(
+    caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("push_frame");
+    guest_status(|| {
+        let (session_id, args) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let mut main_memory = caller
+            .memory(0)
+            .ok_or(VideoProcessingPluginError::MemoryAccessFailed)?;
+
+        let idx = match usize::try_from(args[0].to_i32()) {
+            Ok(idx) => idx,
+            Err(_) => {
+                error!("Negative frame index {}", args[0].to_i32());
+                return Err(VideoProcessingPluginError::FrameIndexOutOfRange.into());
+            }
+        };
+        let image_buf_ptr = args[1].to_i32();
+        let image_buf_len = args[2].to_i32() as usize;
+        let expected_checksum = args[3].to_i32() as u32;
+        let required_len_ptr = args[4].to_i32();
+
+        if data_guard.encoder.get_mut().is_none() {
+            error!("push_frame called before begin_encode");
+            return Err(VideoProcessingPluginError::InvalidArgument.into());
+        }
+
+        let frame = read_guest_frame(
+            &mut main_memory,
+            &mut data_guard,
+            idx,
+            image_buf_ptr,
+            image_buf_len,
+            expected_checksum,
+            required_len_ptr,
+        )?;
+
+        let video_struct = &mut (*data_guard);
+        let output_frame = match video_struct.frames.get_mut(idx) {
+            Some(frame_map) => {
+                let released = [
+                    frame_map.output_frame.take(),
+                    frame_map.derived_frame.take(),
+                ];
+                for released_frame in released.into_iter().flatten() {
+                    video_struct.frame_pool.recycle(released_frame);
                 }
-                None => iter_missing.push(idx),
-            };
-            (iter_frames, iter_missing)
-        },
-    );
+                OutputFrame {
+                    frame: &frame,
+                    frame_type: frame_map.frame_type,
+                    timestamp: frame_map.timestamp,
+                    duration: frame_map.duration,
+                    force_keyframe: frame_map.force_keyframe,
+                }
+            }
+            None => OutputFrame {
+                frame: &frame,
+                frame_type: FrameType::Unknown,
+                timestamp: None,
+                duration: 1,
+                force_keyframe: false,
+            },
+        };
 
-    if missing_frames.len() > 0 {
-        error!("Error Missing Frames {:?} ", missing_frames);
-        return Err(HostFuncError::User(1));
-    }
+        push_output_frame(video_struct.encoder.get_mut(), &output_frame)?;
+        // The encoder took a scaled copy, so the next pushed frame can be read into this one
+        video_struct.frame_pool.recycle(frame);
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Flushes the frames the encoder opened by `begin_encode` still holds and closes the video file
+#[host_function]
+fn finish_encode(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("finish_encode");
+    guest_status(|| {
+        let (session_id, _) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        data_guard.finish_encode()?;
+
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
+
+/// Finalizes the output of the encoder opened by `begin_encode` as far as it got, e.g. after
+/// `push_frame` failed, leaving a playable video truncated at the last encoded frame. Unlike
+/// `finish_encode` it does nothing when no encode is open, so guests can call it on any error
+/// path. Dropped encoders (by a new `begin_encode` or `close_session`) are finalized the same way.
+#[host_function]
+fn finalize_output(
+    _caller: Caller,
+    args: Vec<WasmValue>,
+    data: &mut Arc<RwLock<Sessions>>,
+) -> Result<Vec<WasmValue>, HostFuncError> {
+    debug!("finalize_output");
+    guest_status(|| {
+        let (session_id, _) = session_args(&args);
+        let mut data_guard = lock_session(data, session_id)?;
+
+        let Some(mut video_encoder) = data_guard.encoder.get_mut().take() else {
+            return Ok(vec![WasmValue::from_i32(0)]);
+        };
+
+        if let Err(err) = video_encoder.finish() {
+            error!("Finalize Output Error {:?}", err);
+            return Err(VideoProcessingPluginError::from(VideoEncoderError::from(err)).into());
+        }
 
-    let mut video_encoder = encode_video::VideoEncoder::new(&video_info, &output_file)
-        .map_err(|_| HostFuncError::User(1))?;
+        Ok(vec![WasmValue::from_i32(0)])
+    })
+}
 
-    if let Err(err) = video_encoder.receive_and_process_decoded_frames(&mut frames) {
-        error!("Encode stream Error {:?}", err);
+// Encodes `output_frame` with the encoder opened by `begin_encode`, as `push_frame` does. It takes
+// only the encoder so the frames can stay borrowed while they are pushed.
+fn push_output_frame(
+    encoder: &mut Option<encode_video::VideoEncoder>,
+    output_frame: &OutputFrame,
+) -> Result<(), VideoProcessingPluginError> {
+    let Some(video_encoder) = encoder.as_mut() else {
+        error!("push_frame called before begin_encode");
+        return Err(VideoProcessingPluginError::InvalidArgument);
     };
 
-    // Need to forget x otherwise we get a double free
-    std::mem::forget(output_file);
-    Ok(vec![WasmValue::from_i32(0)])
+    video_encoder.encode_frame(output_frame).map_err(|err| {
+        error!("Encode Frame Error {:?}", err);
+        VideoProcessingPluginError::from(err)
+    })
+}
+
+struct FramesMap {
+    frames: Frames,
+    video_info: Option<VideoInfo>,
+    // Pixel format frames are exchanged with the guest in
+    frame_format: PixelFormat,
+    // Order `frames` is currently sorted in
+    frame_ordering: FrameOrdering,
+    assembly_policy: AssemblyPolicy,
+    encoder_settings: EncoderSettings,
+    // Cropping applied to reach even dimensions by the last assembly
+    even_dimension_adjustment: EvenDimensionAdjustment,
+    // Path of the loaded video, used by functions that re-open it (e.g. to decode audio)
+    source_filename: Option<String>,
+    // Video opened by `open_video`, decoded one frame at a time by `next_frame`
+    frame_stream: WriteLocked<Option<decode_video::FrameStream>>,
+    // Encoder opened by `begin_encode`, fed one frame at a time by `push_frame`
+    encoder: WriteLocked<Option<encode_video::VideoEncoder>>,
+    // Properties and path of the video checked by the `probe_video` that opened the session
+    probed_info: Option<VideoInfo>,
+    probed_filename: Option<String>,
+    // Buffers of frames read from the guest that are no longer in use, for reuse by the next write
+    frame_pool: FramePool,
+    // Guest memory `map_frame` stages frames into, shared by every session as it belongs to the
+    // guest rather than to a video
+    frame_staging: Arc<Mutex<Option<FrameStaging>>>,
+    // Shared with `get_progress`/`cancel_operation`, which do not wait for the lock
+    operation: Arc<OperationContext>,
+}
+
+// The plugin data: the open sessions by handle, each a video loaded (or opened or probed) by the
+// guest with its frames and settings, and what the sessions share
+struct Sessions {
+    open: HashMap<u32, FramesMap>,
+    // Handle of the most recently created session, handles are never reused
+    last_id: u32,
+    // Options the next loads decode with
+    decode_options: DecodeOptions,
+    // Decoder and scaler contexts of earlier loads, reused by the loads of any session
+    context_cache: WriteLocked<ContextCache>,
+    // Handed to every session, see `FramesMap`
+    frame_staging: Arc<Mutex<Option<FrameStaging>>>,
+    operation: Arc<OperationContext>,
+}
+
+impl Sessions {
+    fn new(operation: Arc<OperationContext>) -> Self {
+        Sessions {
+            open: HashMap::new(),
+            last_id: 0,
+            decode_options: DecodeOptions::default(),
+            context_cache: WriteLocked::default(),
+            frame_staging: Arc::default(),
+            operation,
+        }
+    }
+
+    // A session with the default settings for a video about to be loaded, only kept once
+    // `insert`ed so a failed load leaves no session behind
+    fn new_session(&self) -> FramesMap {
+        FramesMap::new(self.operation.clone(), self.frame_staging.clone())
+    }
+
+    // Keeps `session` open, returning its handle
+    fn insert(&mut self, session: FramesMap) -> u32 {
+        self.last_id += 1;
+        self.open.insert(self.last_id, session);
+        debug!("Created Session {}", self.last_id);
+        self.last_id
+    }
+}
+
+// A session of the plugin data, which stays locked for as long as the guard is held
+struct SessionGuard<G> {
+    sessions: G,
+    session_id: u32,
+}
+
+impl<G: Deref<Target = Sessions>> SessionGuard<G> {
+    fn new(sessions: G, session_id: u32) -> Result<Self, HostFuncError> {
+        if !sessions.open.contains_key(&session_id) {
+            error!("Session {session_id} does not exist");
+            return Err(VideoProcessingPluginError::SessionNotFound.into());
+        }
+        Ok(SessionGuard {
+            sessions,
+            session_id,
+        })
+    }
+}
+
+impl<G: DerefMut<Target = Sessions>> SessionGuard<G> {
+    // The session along with the context cache, for functions decoding another source into it
+    fn with_context_cache(&mut self) -> (&mut FramesMap, &mut ContextCache) {
+        let sessions = &mut *self.sessions;
+        let session = sessions
+            .open
+            .get_mut(&self.session_id)
+            .expect("session checked when locked");
+        (session, sessions.context_cache.get_mut())
+    }
+}
+
+impl<G: Deref<Target = Sessions>> Deref for SessionGuard<G> {
+    type Target = FramesMap;
+
+    fn deref(&self) -> &FramesMap {
+        &self.sessions.open[&self.session_id]
+    }
+}
+
+impl<G: DerefMut<Target = Sessions>> DerefMut for SessionGuard<G> {
+    fn deref_mut(&mut self) -> &mut FramesMap {
+        self.sessions
+            .open
+            .get_mut(&self.session_id)
+            .expect("session checked when locked")
+    }
+}
+
+// Region of guest memory registered by `set_frame_staging_region`, its slots reused round robin
+struct FrameStaging {
+    offset: u32,
+    slot_len: u32,
+    slot_count: u32,
+    next_slot: u32,
+}
+
+// FFMPEG state that is not Sync (open inputs, decoders, scalers and encoders), kept so `Sessions`
+// can be shared behind the `RwLock`. It is only reachable through `&mut`, so only under the write
+// lock, where the `Mutex` never needs locking.
+#[derive(Default)]
+struct WriteLocked<T>(Mutex<T>);
+
+impl<T> WriteLocked<T> {
+    fn get_mut(&mut self) -> &mut T {
+        // Never locked, so never poisoned
+        self.0.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl FramesMap {
+    // An empty session with the default settings
+    fn new(
+        operation: Arc<OperationContext>,
+        frame_staging: Arc<Mutex<Option<FrameStaging>>>,
+    ) -> Self {
+        FramesMap {
+            frames: Vec::new(),
+            video_info: None,
+            frame_format: PixelFormat::Rgb24,
+            frame_ordering: FrameOrdering::Presentation,
+            assembly_policy: AssemblyPolicy::Strict,
+            encoder_settings: EncoderSettings::default(),
+            even_dimension_adjustment: EvenDimensionAdjustment::default(),
+            source_filename: None,
+            frame_stream: WriteLocked::default(),
+            encoder: WriteLocked::default(),
+            probed_info: None,
+            probed_filename: None,
+            frame_pool: FramePool::default(),
+            frame_staging,
+            operation,
+        }
+    }
+
+    // Drops the loaded video, returning roughly how many bytes of pixel data were freed
+    fn release_video(&mut self) -> usize {
+        let freed = self.frames.iter().map(FrameMap::pixel_data_size).sum();
+        self.frames.clear();
+        self.frames.shrink_to_fit();
+        self.video_info = None;
+        *self.frame_stream.get_mut() = None;
+        self.frame_pool.clear();
+        freed
+    }
+
+    // Opens an encoder writing the loaded video to `output_file`, as `begin_encode` does,
+    // abandoning an encode begun earlier and not finished
+    fn begin_encode(&mut self, output_file: &str) -> Result<(), VideoProcessingPluginError> {
+        let video_info = match &self.video_info {
+            Some(video_info) => video_info,
+            None => {
+                error!("No Video Information when attempting to begin_encode");
+                return Err(VideoProcessingPluginError::NoVideoLoaded);
+            }
+        };
+
+        if self.encoder.get_mut().take().is_some() {
+            warn!("Abandoning the unfinished encode to begin encoding {output_file}");
+        }
+
+        let video_encoder =
+            encode_video::VideoEncoder::new(video_info, &self.encoder_settings, output_file)
+                .map_err(|err| {
+                    error!("Encoder Error {:?}", err);
+                    VideoProcessingPluginError::from(err)
+                })?;
+        self.even_dimension_adjustment = video_encoder.dimension_adjustment(video_info);
+        *self.encoder.get_mut() = Some(video_encoder);
+        Ok(())
+    }
+
+    // Flushes and closes the encoder opened by `begin_encode`, as `finish_encode` does
+    fn finish_encode(&mut self) -> Result<(), VideoProcessingPluginError> {
+        let mut video_encoder = match self.encoder.get_mut().take() {
+            Some(video_encoder) => video_encoder,
+            None => {
+                error!("finish_encode called before begin_encode");
+                return Err(VideoProcessingPluginError::InvalidArgument);
+            }
+        };
+
+        video_encoder.finish().map_err(|err| {
+            error!("Finish Encode Error {:?}", err);
+            VideoProcessingPluginError::from(VideoEncoderError::from(err))
+        })
+    }
+
+    // Releases every output frame into the frame pool, e.g. once they were encoded, returning
+    // how many there were
+    fn release_output_frames(&mut self) -> usize {
+        let mut released = 0;
+        for frame_map in self.frames.iter_mut() {
+            if let Some(output_frame) = frame_map.output_frame.take() {
+                self.frame_pool.recycle(output_frame);
+                released += 1;
+            }
+        }
+        released
+    }
+}
+
+/// Progress and cancellation of the long running operation (decode or encode) currently holding
+/// the plugin data, shared so guests can monitor and stop it without waiting for the lock
+#[derive(Debug, Default)]
+pub struct OperationContext {
+    cancel: AtomicBool,
+    progress: AtomicUsize,
+    // Number of units (frames) the operation expects to process, 0 when unknown
+    total: AtomicUsize,
+    // Time operations may run for before being cancelled, in milliseconds, 0 for no limit
+    timeout_millis: AtomicU64,
+    // When the current operation times out
+    deadline: Mutex<Option<Instant>>,
+}
+
+impl OperationContext {
+    /// Starts tracking a new operation of `total` units, clearing any earlier cancellation
+    /// and starting its timeout
+    pub fn begin(&self, total: usize) {
+        self.cancel.store(false, Ordering::SeqCst);
+        self.progress.store(0, Ordering::SeqCst);
+        self.total.store(total, Ordering::SeqCst);
+
+        let deadline = match self.timeout_millis.load(Ordering::SeqCst) {
+            0 => None,
+            timeout_millis => Some(Instant::now() + Duration::from_millis(timeout_millis)),
+        };
+        *self.deadline.lock().unwrap_or_else(PoisonError::into_inner) = deadline;
+    }
+
+    /// Updates the units the current operation expects once known, e.g. after opening its input
+    pub fn set_total(&self, total: usize) {
+        self.total.store(total, Ordering::SeqCst);
+    }
+
+    /// Limits the time operations begun afterwards may run for, `None` removing the limit
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        let timeout_millis = timeout.map_or(0, |timeout| timeout.as_millis().max(1) as u64);
+        self.timeout_millis.store(timeout_millis, Ordering::SeqCst);
+    }
+
+    /// Records one more processed unit
+    pub fn advance(&self) {
+        self.progress.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the operation was cancelled or ran past its timeout
+    pub fn is_cancelled(&self) -> bool {
+        if self.cancel.load(Ordering::SeqCst) {
+            return true;
+        }
+        let deadline = *self.deadline.lock().unwrap_or_else(PoisonError::into_inner);
+        match deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                warn!("Operation timed out, cancelling it");
+                self.cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Processed and expected units of the current operation
+    pub fn progress(&self) -> (usize, usize) {
+        (
+            self.progress.load(Ordering::SeqCst),
+            self.total.load(Ordering::SeqCst),
+        )
+    }
+}
+
+/// Order the stored frames are indexed in
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum FrameOrdering {
+    // Display order, the order the decoder outputs frames in
+    Presentation = 0,
+    // The order frames were stored in the stream, differs from display order with B-Frames
+    Decode = 1,
+}
+
+/// How `assemble_output_frames_to_video` handles frames without an output frame
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum AssemblyPolicy {
+    // Fail the assembly listing the missing frames
+    Strict,
+    // Encode the unchanged input frame in their place
+    Lenient,
+}
+
+/// Pixel formats the guest can exchange frames in, set through `set_frame_pixel_format`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb24,
+    Rgba,
+    Gray8,
+    Yuv420p,
+    Yuv422p,
+    Yuv444p,
+    Nv12,
+}
+
+impl PixelFormat {
+    fn from_code(code: i32) -> Option<Self> {
+        match code {
+            0 => Some(PixelFormat::Rgb24),
+            1 => Some(PixelFormat::Rgba),
+            2 => Some(PixelFormat::Gray8),
+            3 => Some(PixelFormat::Yuv420p),
+            4 => Some(PixelFormat::Yuv422p),
+            5 => Some(PixelFormat::Yuv444p),
+            6 => Some(PixelFormat::Nv12),
+            _ => None,
+        }
+    }
+
+    pub fn pixel(self) -> Pixel {
+        match self {
+            PixelFormat::Rgb24 => Pixel::RGB24,
+            PixelFormat::Rgba => Pixel::RGBA,
+            PixelFormat::Gray8 => Pixel::GRAY8,
+            PixelFormat::Yuv420p => Pixel::YUV420P,
+            PixelFormat::Yuv422p => Pixel::YUV422P,
+            PixelFormat::Yuv444p => Pixel::YUV444P,
+            PixelFormat::Nv12 => Pixel::NV12,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct FrameMap {
+    input_frame: frame::Video,
+    // Input Frame Type, as classified by the decoder
+    frame_type: FrameType,
+    // Input Frame Timestamp
+    timestamp: Option<i64>,
+    // Input Frame Decode Timestamp, differs from `timestamp` when B-Frames are reordered
+    decode_timestamp: Option<i64>,
+    // Number of frame intervals the frame is shown for, more than 1 once duplicates are removed
+    duration: u32,
+    // Encoded as a keyframe whatever the encoder settings, set through `set_forced_keyframe`
+    force_keyframe: bool,
+    // Option as we are not sure if it has been processed yet or not
+    output_frame: Option<frame::Video>,
+    // Copy of `input_frame` converted on request (by `convert_pixel_format`, `resize_frame`,
+    // `crop_frame`, `rotate_frame` or `to_grayscale`, each starting from the previous result),
+    // which `get_frame` returns in its place
+    derived_frame: Option<frame::Video>,
 }
 
-#[derive(Clone)]
-struct FramesMap {
-    frames: Frames,
-    video_info: Option<VideoInfo>,
+impl FrameMap {
+    // The output frame if one was written, otherwise the input frame
+    fn current_frame(&self) -> &frame::Video {
+        self.output_frame.as_ref().unwrap_or(&self.input_frame)
+    }
+
+    // The frame `get_frame` hands out: a conversion made on request, otherwise the input frame
+    fn exchanged_frame(&self) -> &frame::Video {
+        self.derived_frame.as_ref().unwrap_or(&self.input_frame)
+    }
+
+    // The frame as `get_frame` copies it: the derived frame as is, otherwise the input frame in
+    // `frame_format`, converted when it is stored in another format
+    fn guest_frame(
+        &self,
+        frame_format: PixelFormat,
+    ) -> Result<Cow<'_, frame::Video>, ffmpeg::Error> {
+        let frame = self.exchanged_frame();
+        let format = match &self.derived_frame {
+            Some(derived) => derived.format(),
+            None => frame_format.pixel(),
+        };
+        if frame.format() == format {
+            Ok(Cow::Borrowed(frame))
+        } else {
+            frame_ops::convert_pixel_format(frame, format).map(Cow::Owned)
+        }
+    }
+
+    // Bytes held by the pixel data of the input, output and derived frames
+    fn pixel_data_size(&self) -> usize {
+        self.input_data_size() + self.output_data_size()
+    }
+
+    // Bytes held by the pixel data of the input frame and the conversion derived from it
+    fn input_data_size(&self) -> usize {
+        frame_data_size(&self.input_frame) + self.derived_frame.as_ref().map_or(0, frame_data_size)
+    }
+
+    // Bytes held by the pixel data of the output frame, 0 until one was written
+    fn output_data_size(&self) -> usize {
+        self.output_frame.as_ref().map_or(0, frame_data_size)
+    }
 }
 
-#[derive(Clone)]
-pub struct FrameMap {
-    input_frame: frame::Video,
-    // Input Frame Type
-    frame_type: picture::Type,
-    // Input Frame Timestamp
-    timestamp: Option<i64>,
-    // Option as we are not sure if it has been processed yet or not
-    output_frame: Option<frame::Video>,
+fn frame_data_size(frame: &frame::Video) -> usize {
+    (0..frame.planes())
+        .map(|plane| frame.data(plane).len())
+        .sum()
 }
 
 type Frames = Vec<FrameMap>;
-type ShareFrames = Arc<Mutex<FramesMap>>;
+
+/// Returned to the guest in place of a timestamp the decoder did not provide
+const NO_TIMESTAMP: i64 = i64::MIN;
+/// Passed by the guest as `write_frame`'s checksum to skip verification (`-1`), an Adler-32
+/// never reaches it as both of its halves are below 65521
+const SKIP_CHECKSUM: u32 = u32::MAX;
+/// Passed by the guest as `get_frame`'s plane to copy every plane of the frame
+const ALL_PLANES: i32 = -1;
+/// JPEG quality used by `get_frame_encoded` when the guest passes `0`, and by `extract_thumbnail`
+const DEFAULT_JPEG_QUALITY: u8 = 90;
+/// Fraction of the duration `extract_thumbnail` seeks past, skipping intros and fades from black
+const THUMBNAIL_POSITION: f64 = 0.1;
+type ShareFrames = Arc<RwLock<Sessions>>;
 
 /// Defines Plugin module instance
 unsafe extern "C" fn create_test_module(
@@ -446,13 +5758,15 @@ unsafe extern "C" fn create_test_module(
 ) -> *mut ffi::WasmEdge_ModuleInstanceContext {
     let module_name = "yolo-video-proc";
 
-    let video_frames = FramesMap {
-        frames: Vec::new(),
-        video_info: None,
-    };
+    let operation = Arc::new(OperationContext::default());
+
+    let video_frames = Sessions::new(operation.clone());
+
+    let video_frames_arc = Box::new(Arc::new(RwLock::new(video_frames)));
 
-    let video_frames_arc = Box::new(Arc::new(Mutex::new(video_frames)));
+    diagnostics::install_log_callback();
 
+    type Session = i32;
     type Width = i32;
     type Height = i32;
     type Frames = i32;
@@ -460,30 +5774,560 @@ unsafe extern "C" fn create_test_module(
     let plugin_module = PluginModuleBuilder::<NeverType>::new()
         .with_func::<i32, i32, ()>("init_plugin_logging", init_plugin_logging, None)
         .expect("failed to create init_plugin_logging host function")
-        .with_func::<(i32, i32, i32, Width, Height, Frames), i32, ShareFrames>(
+        .with_func::<i32, i32, ()>("set_log_level", set_log_level, None)
+        .expect("failed to create set_log_level host function")
+        .with_func::<(i32, i32), i32, ShareFrames>(
+            "set_max_decode_resolution",
+            set_max_decode_resolution,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_max_decode_resolution host function")
+        .with_func::<i32, i32, ShareFrames>(
+            "set_auto_rotate",
+            set_auto_rotate,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_auto_rotate host function")
+        .with_func::<i32, i32, ShareFrames>(
+            "set_video_stream",
+            set_video_stream,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_video_stream host function")
+        .with_func::<i32, i32, ShareFrames>(
+            "set_bit_depth_reduction",
+            set_bit_depth_reduction,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_bit_depth_reduction host function")
+        .with_func::<(i32, i32), i32, ShareFrames>(
+            "set_hw_decode_device",
+            set_hw_decode_device,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_hw_decode_device host function")
+        .with_func::<(i32, i32, i32, Width, Height, Frames, i32, i32, i32), i32, ShareFrames>(
             "load_video_to_host_memory",
             load_video_to_host_memory,
             Some(video_frames_arc.clone()),
         )
         .expect("failed to create load_video_to_host_memory host function")
+        .with_func::<(i32, i32, i32, i32, i32, i32, i32), i32, ShareFrames>(
+            "load_video_range",
+            load_video_range,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create load_video_range host function")
+        .with_func::<(i32, i32, i32, i32, i32, i32), i32, ShareFrames>(
+            "load_video_sampled",
+            load_video_sampled,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create load_video_sampled host function")
+        .with_func::<(i32, i32, i32, i32, i32), i32, ShareFrames>(
+            "load_video_from_bytes",
+            load_video_from_bytes,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create load_video_from_bytes host function")
+        .with_func::<(), i32, ShareFrames>(
+            "clear_context_cache",
+            clear_context_cache,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create clear_context_cache host function")
         .with_func::<(i32, i32, i32, i32), i32, ShareFrames>(
+            "open_video",
+            open_video,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create open_video host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "next_frame",
+            next_frame,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create next_frame host function")
+        .with_func::<(Session, i64, i32), i32, ShareFrames>(
+            "seek_to_timestamp",
+            seek_to_timestamp,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create seek_to_timestamp host function")
+        .with_func::<i32, i32, ShareFrames>(
+            "close_session",
+            close_session,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create close_session host function")
+        .with_func::<Session, i64, ShareFrames>(
+            "free_frames",
+            free_frames,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create free_frames host function")
+        .with_func::<Session, i32, ShareFrames>(
+            "reset_output_frames",
+            reset_output_frames,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create reset_output_frames host function")
+        .with_func::<(Session, i32, i32, i32), i32, ShareFrames>(
+            "get_memory_usage",
+            get_memory_usage,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_memory_usage host function")
+        .with_func::<(i32, i32), i32, ShareFrames>(
+            "get_decode_diagnostics",
+            get_decode_diagnostics,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_decode_diagnostics host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "get_container_format",
+            get_container_format,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_container_format host function")
+        .with_func::<(i32, i32, i32, i32, i32, i32, i32, i32, i32), i32, ShareFrames>(
+            "probe_video",
+            probe_video,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create probe_video host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "get_video_duration",
+            get_video_duration,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_video_duration host function")
+        .with_func::<Session, i32, ShareFrames>(
+            "get_metadata_count",
+            get_metadata_count,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_metadata_count host function")
+        .with_func::<(Session, i32, i32, i32), i32, ShareFrames>(
+            "get_metadata_key",
+            get_metadata_key,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_metadata_key host function")
+        .with_func::<(Session, i32, i32, i32, i32), i32, ShareFrames>(
+            "get_metadata_value",
+            get_metadata_value,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_metadata_value host function")
+        .with_func::<(Session, i32, i32, i32, i32, i32, i32, i32, i32, i32), i32, ShareFrames>(
+            "get_video_info",
+            get_video_info,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_video_info host function")
+        .with_func::<Session, i32, ShareFrames>(
+            "get_program_count",
+            get_program_count,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_program_count host function")
+        .with_func::<(Session, i32, i32, i32), i32, ShareFrames>(
+            "get_program_streams",
+            get_program_streams,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_program_streams host function")
+        .with_func::<(Session, i32, i32, i32, i32, i32, i32), i32, ShareFrames>(
+            "list_streams",
+            list_streams,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create list_streams host function")
+        .with_func::<(Session, i32, i32, i32, i32, i32, i32, i32), i32, ShareFrames>(
             "get_frame",
             get_frame,
             Some(video_frames_arc.clone()),
         )
         .expect("failed to create get_frame host function")
+        .with_func::<(Session, i32, i32, i32, i32, i32), i32, ShareFrames>(
+            "get_frame_encoded",
+            get_frame_encoded,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_frame_encoded host function")
+        .with_func::<(Session, i32, i32, i32, i32, i32), i32, ShareFrames>(
+            "get_frames_batch",
+            get_frames_batch,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_frames_batch host function")
+        .with_func::<(Session, i32, i32, i32, i32), i32, ShareFrames>(
+            "get_frame_into",
+            get_frame_into,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_frame_into host function")
         .with_func::<(i32, i32, i32), i32, ShareFrames>(
+            "set_frame_staging_region",
+            set_frame_staging_region,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_frame_staging_region host function")
+        .with_func::<(Session, i32, i32, i32), i32, ShareFrames>(
+            "map_frame",
+            map_frame,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create map_frame host function")
+        .with_func::<(Session, i32, i32, i32), i32, ShareFrames>(
+            "get_frame_bgr",
+            get_frame_bgr,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_frame_bgr host function")
+        .with_func::<(Session, i32, i32, i32), i32, ShareFrames>(
+            "get_pixel_format_name",
+            get_pixel_format_name,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_pixel_format_name host function")
+        .with_func::<(i32, i32, i32, i32, i32, i32, i32), i32, ShareFrames>(
+            "get_preview_frame",
+            get_preview_frame,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_preview_frame host function")
+        .with_func::<(Session, i32, i32, i32), i32, ShareFrames>(
+            "extract_thumbnail",
+            extract_thumbnail,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create extract_thumbnail host function")
+        .with_func::<(Session, i32), i32, ShareFrames>(
+            "set_frame_ordering",
+            set_frame_ordering,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_frame_ordering host function")
+        .with_func::<Session, i32, ShareFrames>(
+            "get_frame_ordering_mode",
+            get_frame_ordering_mode,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_frame_ordering_mode host function")
+        .with_func::<Session, i32, ShareFrames>(
+            "get_source_gop_size",
+            get_source_gop_size,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_source_gop_size host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "get_frame_dts",
+            get_frame_dts,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_frame_dts host function")
+        .with_func::<(Session, i32, i32, i32), i32, ShareFrames>(
+            "get_frame_timestamp",
+            get_frame_timestamp,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_frame_timestamp host function")
+        .with_func::<(Session, i32), i32, ShareFrames>(
+            "get_frame_type",
+            get_frame_type,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_frame_type host function")
+        .with_func::<(Session, f32, i32, i32), i32, ShareFrames>(
+            "get_frame_at_fraction",
+            get_frame_at_fraction,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_frame_at_fraction host function")
+        .with_func::<(Session, i32, i32, i32), i32, ShareFrames>(
+            "compute_optical_flow",
+            compute_optical_flow,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create compute_optical_flow host function")
+        .with_func::<(Session, i32, i32, i32), i32, ShareFrames>(
+            "get_frame_phash",
+            get_frame_phash,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_frame_phash host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "frame_dhash",
+            frame_dhash,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create frame_dhash host function")
+        .with_func::<(Session, i32, i32, i32, i32, i32), i32, ShareFrames>(
             "write_frame",
             write_frame,
             Some(video_frames_arc.clone()),
         )
         .expect("failed to create write_frame host function")
-        .with_func::<(i32, i32, i32), i32, ShareFrames>(
+        .with_func::<(Session, i32, i32, i32, i32), i32, ShareFrames>(
+            "write_frame_f32",
+            write_frame_f32,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create write_frame_f32 host function")
+        .with_func::<(Session, i32, i32, i32, i32), i32, ShareFrames>(
+            "write_frame_encoded",
+            write_frame_encoded,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create write_frame_encoded host function")
+        .with_func::<(Session, i32), i32, ShareFrames>(
+            "set_write_clamp_mode",
+            set_write_clamp_mode,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_write_clamp_mode host function")
+        .with_func::<(Session, i32), i32, ShareFrames>(
+            "set_frame_pixel_format",
+            set_frame_pixel_format,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_frame_pixel_format host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "convert_pixel_format",
+            convert_pixel_format,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create convert_pixel_format host function")
+        .with_func::<(Session, i32), i32, ShareFrames>(
+            "to_grayscale",
+            to_grayscale,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create to_grayscale host function")
+        .with_func::<(Session, i32, i32, i32, i32), i32, ShareFrames>(
+            "resize_frame",
+            resize_frame,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create resize_frame host function")
+        .with_func::<(Session, i32, i32, i32, i32, i32), i32, ShareFrames>(
+            "crop_frame",
+            crop_frame,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create crop_frame host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "rotate_frame",
+            rotate_frame,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create rotate_frame host function")
+        .with_func::<(Session, i32, i32, i32, i32, f32), i32, ShareFrames>(
+            "compose_pip",
+            compose_pip,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create compose_pip host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "burn_subtitles",
+            burn_subtitles,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create burn_subtitles host function")
+        .with_func::<(Session, i32, i32, i32), i32, ShareFrames>(
+            "extract_subtitles",
+            extract_subtitles,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create extract_subtitles host function")
+        .with_func::<(Session, i32, i32, i32, i32), i32, ShareFrames>(
+            "render_audio_waveform",
+            render_audio_waveform,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create render_audio_waveform host function")
+        .with_func::<(Session, i32, i32, i32, i32), i32, ShareFrames>(
+            "render_spectrogram",
+            render_spectrogram,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create render_spectrogram host function")
+        .with_func::<(Session, i32, i32, i32), i32, ShareFrames>(
+            "measure_loudness",
+            measure_loudness,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create measure_loudness host function")
+        .with_func::<(Session, f32), i32, ShareFrames>(
+            "normalize_loudness",
+            normalize_loudness,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create normalize_loudness host function")
+        .with_func::<(Session, i32, i32, i32, i32), i32, ShareFrames>(
+            "set_output_color_properties",
+            set_output_color_properties,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_output_color_properties host function")
+        .with_func::<(Session, i32), i32, ShareFrames>(
+            "set_encoder_rc_buffer",
+            set_encoder_rc_buffer,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_encoder_rc_buffer host function")
+        .with_func::<Session, i32, ShareFrames>(
+            "get_encoder_rc_buffer",
+            get_encoder_rc_buffer,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_encoder_rc_buffer host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "set_output_codec",
+            set_output_codec,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_output_codec host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "set_output_format",
+            set_output_format,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_output_format host function")
+        .with_func::<(Session, i32, i32, f32), i32, ShareFrames>(
+            "set_output_bitrate",
+            set_output_bitrate,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_output_bitrate host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "set_output_resolution",
+            set_output_resolution,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_output_resolution host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "set_output_frame_rate",
+            set_output_frame_rate,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_output_frame_rate host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "set_encoder_gop",
+            set_encoder_gop,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_encoder_gop host function")
+        .with_func::<(Session, i32), i32, ShareFrames>(
+            "set_frame_type_hints",
+            set_frame_type_hints,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_frame_type_hints host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "set_forced_keyframe",
+            set_forced_keyframe,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_forced_keyframe host function")
+        .with_func::<(Session, f32), i32, ShareFrames>(
+            "apply_gamma",
+            apply_gamma,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create apply_gamma host function")
+        .with_func::<(Session, f32), i32, ShareFrames>(
+            "dedupe_frames",
+            dedupe_frames,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create dedupe_frames host function")
+        .with_func::<(Session, i32, i32, i32, i32), i32, ShareFrames>(
+            "process_all_frames",
+            process_all_frames,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create process_all_frames host function")
+        .with_func::<(Session, i32), i32, ShareFrames>(
+            "set_assembly_policy",
+            set_assembly_policy,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create set_assembly_policy host function")
+        .with_func::<(Session, i32, i32, i32, i32, i32, i32), i32, ShareFrames>(
             "assemble_output_frames_to_video",
             assemble_output_frames_to_video,
             Some(video_frames_arc.clone()),
         )
         .expect("failed to create assemble_output_frames_to_video host function")
+        .with_func::<(Session, i32, i32, i32, i32, i32), i32, ShareFrames>(
+            "assemble_output_frames_to_buffer",
+            assemble_output_frames_to_buffer,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create assemble_output_frames_to_buffer host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "begin_encode",
+            begin_encode,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create begin_encode host function")
+        .with_func::<(Session, i32, i32, i32, i32, i32), i32, ShareFrames>(
+            "push_frame",
+            push_frame,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create push_frame host function")
+        .with_func::<Session, i32, ShareFrames>(
+            "finish_encode",
+            finish_encode,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create finish_encode host function")
+        .with_func::<Session, i32, ShareFrames>(
+            "finalize_output",
+            finalize_output,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create finalize_output host function")
+        .with_func::<Session, i32, ShareFrames>(
+            "get_even_dimension_adjustment",
+            get_even_dimension_adjustment,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create get_even_dimension_adjustment host function")
+        .with_func::<(i32, i32), i32, Arc<OperationContext>>(
+            "get_progress",
+            get_progress,
+            Some(Box::new(operation.clone())),
+        )
+        .expect("failed to create get_progress host function")
+        .with_func::<(), i32, Arc<OperationContext>>(
+            "cancel_operation",
+            cancel_operation,
+            Some(Box::new(operation.clone())),
+        )
+        .expect("failed to create cancel_operation host function")
+        .with_func::<i32, i32, Arc<OperationContext>>(
+            "set_operation_timeout",
+            set_operation_timeout,
+            Some(Box::new(operation.clone())),
+        )
+        .expect("failed to create set_operation_timeout host function")
+        .with_func::<(Session, i32, i32), i32, ShareFrames>(
+            "export_y4m",
+            export_y4m,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create export_y4m host function")
+        .with_func::<(i32, i32), i64, ShareFrames>(
+            "run_pipeline",
+            run_pipeline,
+            Some(video_frames_arc.clone()),
+        )
+        .expect("failed to create run_pipeline host function")
         .build(module_name)
         .expect("failed to create plugin module");
 
@@ -509,3 +6353,405 @@ pub extern "C" fn plugin_hook() -> *const ffi::WasmEdge_PluginDescriptor {
 
     plugin.as_raw_ptr()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use wasmedge_sdk::MemoryType;
+
+    fn status(body: impl FnOnce() -> Result<Vec<WasmValue>, HostFuncError>) -> i32 {
+        guest_status(body).expect("status returned to the guest")[0].to_i32()
+    }
+
+    const CLIP_WIDTH: u32 = 64;
+    const CLIP_HEIGHT: u32 = 48;
+    const CLIP_FRAMES: usize = 4;
+    const RGB_FRAME_LEN: i32 = (CLIP_WIDTH * CLIP_HEIGHT * 3) as i32;
+
+    // Guest memory the host function bodies are driven with: out-params, then a string, then a
+    // buffer large enough for a frame of the clip
+    const OUT_PTR: i32 = 0x100;
+    const STRING_PTR: i32 = 0x200;
+    const BUF_PTR: i32 = 0x1000;
+
+    // One page of linear memory, as a guest module exports it
+    fn guest_memory() -> Memory {
+        let memory_type = MemoryType::new(1, None, false).expect("valid memory type");
+        Memory::new(memory_type).expect("memory created")
+    }
+
+    fn read_i32(memory: &Memory, ptr: i32) -> i32 {
+        let bytes = memory.read(ptr as u32, 4).expect("guest memory read");
+        i32::from_le_bytes(bytes.try_into().expect("4 bytes read"))
+    }
+
+    fn i32_args(values: &[i32]) -> Vec<WasmValue> {
+        values
+            .iter()
+            .map(|&value| WasmValue::from_i32(value))
+            .collect()
+    }
+
+    fn clip_bytes() -> Vec<u8> {
+        let frames = test_clips::gradient_frames(CLIP_FRAMES, CLIP_WIDTH, CLIP_HEIGHT);
+        let video_info = test_clips::clip_info(codec::Id::MPEG4, CLIP_WIDTH, CLIP_HEIGHT);
+        test_clips::encode_clip(&frames, &video_info, &EncoderSettings::default())
+    }
+
+    // A session holding the decoded frames of a test clip, as a load leaves it
+    fn loaded_session(data: &ShareFrames) -> u32 {
+        let (frames, video_info) = test_clips::decode_clip(clip_bytes(), &DecodeOptions::default());
+        let mut data_guard = lock_frames(data).expect("write lock taken");
+        let mut session = data_guard.new_session();
+        session.frames = frames;
+        session.video_info = Some(video_info);
+        data_guard.insert(session)
+    }
+
+    #[test]
+    fn get_frame_failures_return_negated_codes() {
+        let data = plugin_data();
+        let mut memory = guest_memory();
+        let loaded = loaded_session(&data);
+        let empty = open_session(&data);
+        let get_frame = |memory: &mut Memory, session_id: u32, idx: i32, len: i32, plane: i32| {
+            let args = i32_args(&[
+                session_id as i32,
+                idx,
+                BUF_PTR,
+                len,
+                len,
+                plane,
+                OUT_PTR,
+                OUT_PTR + 4,
+            ]);
+            status(|| get_frame_for_guest(memory, &args, &data))
+        };
+
+        assert_eq!(
+            get_frame(&mut memory, loaded, 0, RGB_FRAME_LEN, ALL_PLANES),
+            0
+        );
+        assert_eq!(
+            get_frame(
+                &mut memory,
+                loaded,
+                CLIP_FRAMES as i32,
+                RGB_FRAME_LEN,
+                ALL_PLANES
+            ),
+            -7
+        );
+        assert_eq!(
+            get_frame(&mut memory, loaded, -1, RGB_FRAME_LEN, ALL_PLANES),
+            -7
+        );
+        // RGB24 frames have a single plane
+        assert_eq!(get_frame(&mut memory, loaded, 0, RGB_FRAME_LEN, 1), -15);
+        assert_eq!(
+            get_frame(&mut memory, loaded, 0, RGB_FRAME_LEN - 1, ALL_PLANES),
+            -5
+        );
+        assert_eq!(read_i32(&memory, OUT_PTR + 4), RGB_FRAME_LEN);
+        // Without a video loaded no index is in range
+        assert_eq!(
+            get_frame(&mut memory, empty, 0, RGB_FRAME_LEN, ALL_PLANES),
+            -7
+        );
+    }
+
+    #[test]
+    fn write_frame_failures_return_negated_codes() {
+        let data = plugin_data();
+        let mut memory = guest_memory();
+        let loaded = loaded_session(&data);
+        let empty = open_session(&data);
+        let write_frame = |memory: &mut Memory, session_id: u32, idx: i32, len: i32, checksum| {
+            let args = i32_args(&[session_id as i32, idx, BUF_PTR, len, checksum, OUT_PTR]);
+            status(|| store_guest_frame(memory, &args, &data))
+        };
+        let skip = SKIP_CHECKSUM as i32;
+        // The guest buffer is still all zeros
+        let checksum = frame_ops::adler32(&vec![0; RGB_FRAME_LEN as usize]) as i32;
+
+        assert_eq!(write_frame(&mut memory, loaded, 0, RGB_FRAME_LEN, skip), 0);
+        assert_eq!(
+            write_frame(&mut memory, loaded, 1, RGB_FRAME_LEN, checksum),
+            0
+        );
+        assert_eq!(
+            write_frame(&mut memory, loaded, 2, RGB_FRAME_LEN, checksum ^ 1),
+            -12
+        );
+        assert_eq!(
+            write_frame(&mut memory, loaded, CLIP_FRAMES as i32, RGB_FRAME_LEN, skip),
+            -7
+        );
+        assert_eq!(
+            write_frame(&mut memory, loaded, 0, RGB_FRAME_LEN + 1, skip),
+            -5
+        );
+        assert_eq!(read_i32(&memory, OUT_PTR), RGB_FRAME_LEN);
+        assert_eq!(write_frame(&mut memory, empty, 0, RGB_FRAME_LEN, skip), -6);
+
+        let data_guard = read_session(&data, loaded).expect("session open");
+        let written: Vec<_> = data_guard
+            .frames
+            .iter()
+            .map(|frame_map| frame_map.output_frame.is_some())
+            .collect();
+        assert_eq!(written, [true, true, false, false]);
+    }
+
+    #[test]
+    fn load_failures_return_negated_codes_and_describe_them() {
+        let data = plugin_data();
+        let mut memory = guest_memory();
+        let load = |memory: &mut Memory, filename: &str, err_cap: i32| {
+            memory
+                .write(filename.as_bytes(), STRING_PTR as u32)
+                .expect("filename written");
+            let len = filename.len() as i32;
+            let args = i32_args(&[
+                STRING_PTR,
+                len,
+                len,
+                OUT_PTR,
+                OUT_PTR + 4,
+                OUT_PTR + 8,
+                BUF_PTR,
+                err_cap,
+                OUT_PTR + 12,
+            ]);
+            status(|| load_video_for_guest(memory, &args, &data))
+        };
+
+        assert_eq!(load(&mut memory, "no/such/clip.mkv", 256), -1);
+        let err_len = read_i32(&memory, OUT_PTR + 12);
+        assert!(err_len > 0);
+        assert!(memory
+            .read(BUF_PTR as u32, err_len as u32)
+            .is_ok_and(|message| String::from_utf8(message).is_ok()));
+        // A description that does not fit is truncated to the buffer
+        assert_eq!(load(&mut memory, "no/such/clip.mkv", 4), -5);
+        assert_eq!(read_i32(&memory, OUT_PTR + 12), 4);
+        assert_eq!(load(&mut memory, "no/such/clip.mkv", -1), -5);
+        assert!(read_frames(&data).expect("read lock taken").open.is_empty());
+
+        let clip_path =
+            std::env::temp_dir().join(format!("video_proc_load_{}.mkv", std::process::id()));
+        std::fs::write(&clip_path, clip_bytes()).expect("clip written");
+        let session_id = load(&mut memory, clip_path.to_str().expect("UTF-8 path"), 256);
+        std::fs::remove_file(&clip_path).expect("clip removed");
+        assert!(session_id > 0);
+        assert_eq!(read_i32(&memory, OUT_PTR), CLIP_WIDTH as i32);
+        assert_eq!(read_i32(&memory, OUT_PTR + 4), CLIP_HEIGHT as i32);
+        assert_eq!(read_i32(&memory, OUT_PTR + 8), CLIP_FRAMES as i32);
+    }
+
+    #[test]
+    fn assemble_failures_return_negated_codes() {
+        let data = plugin_data();
+        let mut memory = guest_memory();
+        let loaded = loaded_session(&data);
+        let empty = open_session(&data);
+        let output =
+            std::env::temp_dir().join(format!("video_proc_assemble_{}.mkv", std::process::id()));
+        let filename = output.to_str().expect("UTF-8 path");
+        memory
+            .write(filename.as_bytes(), STRING_PTR as u32)
+            .expect("filename written");
+        let assemble = |memory: &mut Memory, session_id: u32, missing_cap: i32| {
+            let len = filename.len() as i32;
+            let args = i32_args(&[
+                session_id as i32,
+                STRING_PTR,
+                len,
+                len,
+                BUF_PTR,
+                missing_cap,
+                OUT_PTR,
+            ]);
+            status(|| assemble_video_for_guest(memory, &args, &data))
+        };
+
+        // No frame was written, the indices are cut off at the buffer's capacity
+        assert_eq!(assemble(&mut memory, loaded, 2), -3);
+        assert_eq!(read_i32(&memory, OUT_PTR), CLIP_FRAMES as i32);
+        assert_eq!(read_i32(&memory, BUF_PTR), 0);
+        assert_eq!(read_i32(&memory, BUF_PTR + 4), 1);
+        assert_eq!(read_i32(&memory, BUF_PTR + 8), 0);
+        assert!(!output.exists());
+        assert_eq!(assemble(&mut memory, loaded, -1), -5);
+        assert_eq!(assemble(&mut memory, empty, 2), -6);
+    }
+
+    #[test]
+    fn i64_functions_return_negated_codes() {
+        let result = guest_status_i64(|| Err(VideoProcessingPluginError::NoVideoLoaded.into()));
+        assert_eq!(
+            result.expect("status returned to the guest")[0].to_i64(),
+            -6
+        );
+    }
+
+    fn stored_frame(timestamp: Option<i64>) -> FrameMap {
+        FrameMap {
+            input_frame: frame::Video::empty(),
+            frame_type: FrameType::Unknown,
+            timestamp,
+            decode_timestamp: None,
+            duration: 1,
+            force_keyframe: false,
+            output_frame: None,
+            derived_frame: None,
+        }
+    }
+
+    #[test]
+    fn output_is_in_presentation_order_whatever_the_write_order() {
+        // Stored in decode order, with a frame the decoder gave no timestamp
+        let mut frames: Frames = [Some(30), None, Some(10), Some(20)]
+            .into_iter()
+            .map(stored_frame)
+            .collect();
+        for frame_map in frames.iter_mut().rev() {
+            frame_map.output_frame = Some(frame::Video::empty());
+        }
+
+        let output_frames = collect_output_frames(&frames, AssemblyPolicy::Strict)
+            .expect("all frames were written");
+        let timestamps: Vec<_> = output_frames
+            .iter()
+            .map(|output_frame| output_frame.timestamp)
+            .collect();
+        assert_eq!(timestamps, [Some(10), None, Some(20), Some(30)]);
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn plugin_data_is_shared_with_guest_threads() {
+        assert_send_sync::<ShareFrames>();
+    }
+
+    fn plugin_data() -> ShareFrames {
+        Arc::new(RwLock::new(Sessions::new(Arc::default())))
+    }
+
+    fn open_session(data: &ShareFrames) -> u32 {
+        let mut data_guard = lock_frames(data).expect("write lock taken");
+        let session = data_guard.new_session();
+        data_guard.insert(session)
+    }
+
+    #[test]
+    fn sessions_are_worked_on_independently() {
+        let data = plugin_data();
+        let first = open_session(&data);
+        let second = open_session(&data);
+        assert_ne!(first, second);
+
+        let mut data_guard = lock_session(&data, first).expect("session open");
+        data_guard.frames.push(stored_frame(Some(0)));
+        drop(data_guard);
+        assert_eq!(
+            read_session(&data, first)
+                .expect("session open")
+                .frames
+                .len(),
+            1
+        );
+        assert!(read_session(&data, second)
+            .expect("session open")
+            .frames
+            .is_empty());
+
+        // As `close_session` does
+        lock_frames(&data)
+            .expect("write lock taken")
+            .open
+            .remove(&first);
+        let closed = || read_session(&data, first).map(|_| Vec::new());
+        assert_eq!(status(closed), -23);
+        assert!(read_session(&data, second).is_ok());
+        // Handles of closed sessions are not reused
+        assert!(open_session(&data) > second);
+    }
+
+    #[test]
+    fn racing_reads_and_writes_do_not_deadlock() {
+        const WRITERS: usize = 2;
+        const READERS: usize = 2;
+        const ITERATIONS: usize = 1000;
+
+        let data = plugin_data();
+        let session_id = open_session(&data);
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        for writer in (0..WRITERS + READERS).map(|thread| thread < WRITERS) {
+            let data = data.clone();
+            let done_tx = done_tx.clone();
+            std::thread::spawn(move || {
+                for _ in 0..ITERATIONS {
+                    if writer {
+                        let mut data_guard =
+                            lock_session(&data, session_id).expect("write lock taken");
+                        data_guard.frames.push(stored_frame(None));
+                    } else {
+                        let data_guard = read_session(&data, session_id).expect("read lock taken");
+                        assert!(data_guard.frames.len() <= WRITERS * ITERATIONS);
+                    }
+                }
+                done_tx.send(()).expect("test still waiting");
+            });
+        }
+
+        for _ in 0..WRITERS + READERS {
+            done_rx
+                .recv_timeout(Duration::from_secs(10))
+                .expect("thread finished without deadlocking");
+        }
+        let data_guard = read_session(&data, session_id).expect("read lock taken");
+        assert_eq!(data_guard.frames.len(), WRITERS * ITERATIONS);
+    }
+
+    #[test]
+    fn transcodes_to_the_output_codec_and_defaults_to_the_source_codec() {
+        // A tiny clip encoded with FFMPEG's native MPEG-4 Part 2 encoder
+        let frames = test_clips::gradient_frames(4, 64, 48);
+        let source_info = test_clips::clip_info(codec::Id::MPEG4, 64, 48);
+        let source = test_clips::encode_clip(&frames, &source_info, &EncoderSettings::default());
+
+        let (_, decoded_info) = test_clips::decode_clip(source, &DecodeOptions::default());
+        assert_eq!(decoded_info.source_codec, codec::Id::MPEG4);
+        // Without an output codec the source's codec is kept
+        assert_eq!(decoded_info.codec.id(), codec::Id::MPEG4);
+
+        let settings = EncoderSettings {
+            codec_name: Some("mpeg2video".to_string()),
+            ..EncoderSettings::default()
+        };
+        let transcoded = test_clips::encode_clip(&frames, &decoded_info, &settings);
+        let (_, transcoded_info) = test_clips::decode_clip(transcoded, &DecodeOptions::default());
+        assert_eq!(transcoded_info.source_codec, codec::Id::MPEG2VIDEO);
+    }
+
+    #[test]
+    fn resize_defaults_to_bilinear_scaling() {
+        assert_eq!(scaling_flags(0), Some(ScalingFlags::BILINEAR));
+        assert_eq!(
+            scaling_flags(ScalingFlags::BICUBIC.bits()),
+            Some(ScalingFlags::BICUBIC)
+        );
+        assert_eq!(scaling_flags(1 << 30), None);
+    }
+
+    #[test]
+    fn host_faults_still_trap() {
+        assert!(matches!(
+            guest_status(|| Err(HostFuncError::Runtime(1))),
+            Err(HostFuncError::Runtime(1))
+        ));
+    }
+}
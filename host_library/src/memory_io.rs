@@ -0,0 +1,282 @@
+use std::ffi::{c_int, c_void, CString};
+use std::ptr;
+
+use ffmpeg::ffi::{
+    av_free, av_freep, av_malloc, avformat_alloc_context, avformat_alloc_output_context2,
+    avformat_close_input, avformat_find_stream_info, avformat_open_input, avio_alloc_context,
+    avio_context_free, avio_flush, AVIOContext, AVERROR_EOF, AVFMT_FLAG_CUSTOM_IO, AVSEEK_SIZE,
+};
+use ffmpeg::format::context::{Input, Output};
+use ffmpeg::Error as FFmpegError;
+
+// Size of the buffer FFMPEG reads or writes the in-memory data through
+const AVIO_BUFFER_SIZE: usize = 64 * 1024;
+
+// `whence` values of the seek callback, as defined by C's stdio
+const SEEK_SET: c_int = 0;
+const SEEK_CUR: c_int = 1;
+const SEEK_END: c_int = 2;
+
+// Bytes read by FFMPEG through the AVIO callbacks, with the position of the next read
+struct MemorySource {
+    bytes: Vec<u8>,
+    position: usize,
+}
+
+unsafe extern "C" fn read_memory(opaque: *mut c_void, buf: *mut u8, buf_size: c_int) -> c_int {
+    let source = &mut *(opaque as *mut MemorySource);
+    let remaining = source.bytes.len() - source.position;
+    if remaining == 0 {
+        return AVERROR_EOF;
+    }
+
+    let len = remaining.min(buf_size.max(0) as usize);
+    ptr::copy_nonoverlapping(source.bytes.as_ptr().add(source.position), buf, len);
+    source.position += len;
+    len as c_int
+}
+
+unsafe extern "C" fn seek_memory(opaque: *mut c_void, offset: i64, whence: c_int) -> i64 {
+    let source = &mut *(opaque as *mut MemorySource);
+    seek_position(&mut source.position, source.bytes.len(), offset, whence)
+}
+
+// Moves `position` within `len` bytes as an AVIO seek callback, returning the new position
+fn seek_position(position: &mut usize, len: usize, offset: i64, whence: c_int) -> i64 {
+    let len = len as i64;
+    if whence & AVSEEK_SIZE as c_int != 0 {
+        return len;
+    }
+
+    // AVSEEK_FORCE may be combined with the base, it makes no difference for memory
+    let base = match whence & 0xff {
+        SEEK_SET => 0,
+        SEEK_CUR => *position as i64,
+        SEEK_END => len,
+        _ => return -1,
+    };
+
+    let target = base + offset;
+    if !(0..=len).contains(&target) {
+        return -1;
+    }
+    *position = target as usize;
+    target
+}
+
+// Bytes written by the muxer through the AVIO callbacks, with the position of the next write.
+// Muxers seek back to patch headers (e.g. MP4's moov), so writes may overwrite earlier bytes.
+struct MemorySink {
+    bytes: Vec<u8>,
+    position: usize,
+}
+
+unsafe extern "C" fn write_memory(opaque: *mut c_void, buf: *mut u8, buf_size: c_int) -> c_int {
+    let sink = &mut *(opaque as *mut MemorySink);
+    let data = std::slice::from_raw_parts(buf, buf_size.max(0) as usize);
+
+    let end = sink.position + data.len();
+    if end > sink.bytes.len() {
+        sink.bytes.resize(end, 0);
+    }
+    sink.bytes[sink.position..end].copy_from_slice(data);
+    sink.position = end;
+    buf_size
+}
+
+unsafe extern "C" fn seek_sink(opaque: *mut c_void, offset: i64, whence: c_int) -> i64 {
+    let sink = &mut *(opaque as *mut MemorySink);
+    seek_position(&mut sink.position, sink.bytes.len(), offset, whence)
+}
+
+// Signature of the AVIO read and write callbacks
+type PacketCallback = unsafe extern "C" fn(*mut c_void, *mut u8, c_int) -> c_int;
+
+// Frees an AVIO context allocated with `avio_alloc_context` along with its buffer
+unsafe fn free_avio(avio: &mut *mut AVIOContext) {
+    av_freep(&mut (**avio).buffer as *mut *mut u8 as *mut c_void);
+    avio_context_free(avio);
+}
+
+// Allocates an AVIO context over `opaque` with a buffer of `AVIO_BUFFER_SIZE` bytes
+unsafe fn alloc_avio(
+    opaque: *mut c_void,
+    write: bool,
+    read_packet: Option<PacketCallback>,
+    write_packet: Option<PacketCallback>,
+    seek: unsafe extern "C" fn(*mut c_void, i64, c_int) -> i64,
+) -> Result<*mut AVIOContext, FFmpegError> {
+    let buffer = av_malloc(AVIO_BUFFER_SIZE) as *mut u8;
+    if buffer.is_null() {
+        return Err(FFmpegError::Unknown);
+    }
+
+    let avio = avio_alloc_context(
+        buffer,
+        AVIO_BUFFER_SIZE as c_int,
+        write as c_int,
+        opaque,
+        read_packet,
+        write_packet,
+        Some(seek),
+    );
+    if avio.is_null() {
+        av_free(buffer as *mut c_void);
+        return Err(FFmpegError::Unknown);
+    }
+    Ok(avio)
+}
+
+/// Demuxer reading an encoded video from memory through a custom AVIO context,
+/// for guests that hold the video's bytes but have no filesystem access
+pub struct MemoryInput {
+    // Option so the demuxer can be closed before the AVIO context it reads through is freed
+    ictx: Option<Input>,
+    avio: *mut AVIOContext,
+    // Boxed so the pointer handed to the AVIO callbacks stays valid
+    _source: Box<MemorySource>,
+}
+
+impl MemoryInput {
+    /// Opens the container held in `bytes` and probes its streams, as `format::input` does
+    pub fn open(bytes: Vec<u8>) -> Result<Self, FFmpegError> {
+        let mut source = Box::new(MemorySource { bytes, position: 0 });
+
+        unsafe {
+            let mut avio = alloc_avio(
+                &mut *source as *mut MemorySource as *mut c_void,
+                false,
+                Some(read_memory),
+                None,
+                seek_memory,
+            )?;
+
+            let mut format_context = avformat_alloc_context();
+            if format_context.is_null() {
+                free_avio(&mut avio);
+                return Err(FFmpegError::Unknown);
+            }
+            (*format_context).pb = avio;
+            (*format_context).flags |= AVFMT_FLAG_CUSTOM_IO as c_int;
+
+            // Frees the format context itself on failure
+            let res = avformat_open_input(
+                &mut format_context,
+                ptr::null(),
+                ptr::null(),
+                ptr::null_mut(),
+            );
+            if res < 0 {
+                free_avio(&mut avio);
+                return Err(FFmpegError::from(res));
+            }
+
+            let res = avformat_find_stream_info(format_context, ptr::null_mut());
+            if res < 0 {
+                avformat_close_input(&mut format_context);
+                free_avio(&mut avio);
+                return Err(FFmpegError::from(res));
+            }
+
+            Ok(MemoryInput {
+                ictx: Some(Input::wrap(format_context)),
+                avio,
+                _source: source,
+            })
+        }
+    }
+
+    pub fn input(&mut self) -> &mut Input {
+        self.ictx
+            .as_mut()
+            .expect("MemoryInput is only emptied when dropped")
+    }
+}
+
+impl Drop for MemoryInput {
+    fn drop(&mut self) {
+        // The demuxer does not free a custom AVIO context, which must outlive it
+        drop(self.ictx.take());
+        unsafe { free_avio(&mut self.avio) };
+    }
+}
+
+/// Muxer writing into memory through a custom AVIO context, for guests that want the encoded
+/// video returned to them rather than written to a file
+pub struct MemoryOutput {
+    // Option so the muxer can be released before the AVIO context it writes through is freed
+    octx: Option<Output>,
+    avio: *mut AVIOContext,
+    // Boxed so the pointer handed to the AVIO callbacks stays valid
+    sink: Box<MemorySink>,
+}
+
+impl MemoryOutput {
+    /// Creates a muxer for the container `format_name` (e.g. "mp4" or "matroska")
+    pub fn open(format_name: &str) -> Result<Self, FFmpegError> {
+        let format_name = CString::new(format_name).map_err(|_| FFmpegError::InvalidData)?;
+        let mut sink = Box::new(MemorySink {
+            bytes: Vec::new(),
+            position: 0,
+        });
+
+        unsafe {
+            let mut avio = alloc_avio(
+                &mut *sink as *mut MemorySink as *mut c_void,
+                true,
+                None,
+                Some(write_memory),
+                seek_sink,
+            )?;
+
+            let mut format_context = ptr::null_mut();
+            let res = avformat_alloc_output_context2(
+                &mut format_context,
+                ptr::null_mut(),
+                format_name.as_ptr(),
+                ptr::null_mut(),
+            );
+            if res < 0 {
+                free_avio(&mut avio);
+                return Err(FFmpegError::from(res));
+            }
+            (*format_context).pb = avio;
+            (*format_context).flags |= AVFMT_FLAG_CUSTOM_IO as c_int;
+
+            Ok(MemoryOutput {
+                octx: Some(Output::wrap(format_context)),
+                avio,
+                sink,
+            })
+        }
+    }
+
+    pub fn output(&self) -> &Output {
+        self.octx
+            .as_ref()
+            .expect("MemoryOutput is only emptied when dropped")
+    }
+
+    pub fn output_mut(&mut self) -> &mut Output {
+        self.octx
+            .as_mut()
+            .expect("MemoryOutput is only emptied when dropped")
+    }
+
+    /// Bytes written so far, complete once the trailer was written
+    pub fn take_bytes(&mut self) -> Vec<u8> {
+        unsafe { avio_flush(self.avio) };
+        std::mem::take(&mut self.sink.bytes)
+    }
+}
+
+impl Drop for MemoryOutput {
+    fn drop(&mut self) {
+        // The muxer closes its AVIO context as if it were a file it opened, detach it first
+        if let Some(mut octx) = self.octx.take() {
+            unsafe { (*octx.as_mut_ptr()).pb = ptr::null_mut() };
+            drop(octx);
+        }
+        unsafe { free_avio(&mut self.avio) };
+    }
+}
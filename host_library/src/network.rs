@@ -0,0 +1,178 @@
+use std::sync::OnceLock;
+
+use ffmpeg::Dictionary;
+
+use log::debug;
+
+// Environment variable the host lists the protocols guests may open in, comma separated
+// (e.g. "http,https,rtsp"). Unset, guests can only open local files.
+const ALLOWED_PROTOCOLS_VAR: &str = "VIDEO_PROC_ALLOWED_PROTOCOLS";
+
+// Time FFMPEG waits on a stalled connection before failing the read, in microseconds
+const NETWORK_TIMEOUT_MICROS: &str = "10000000";
+
+// Characters of a protocol name, as FFMPEG matches them at the start of a URL
+fn is_scheme_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.')
+}
+
+static ALLOWED_PROTOCOLS: OnceLock<Vec<String>> = OnceLock::new();
+
+/// The protocol FFMPEG opens `filename` with when it is not a local file, found the way FFMPEG
+/// finds it: "rtsp" for `rtsp://camera/stream`, and likewise for nested protocols and those
+/// without `//`, e.g. "concat" for `concat:http://a|http://b`, "subfile" for
+/// `subfile,,start,0,end,0,,:http://a`, "pipe" for `pipe:` or "crypto+http" for
+/// `crypto+http://a`. `None` for local paths, `file:` URLs and DOS drive letters included.
+pub fn protocol(filename: &str) -> Option<&str> {
+    let scheme_len = filename
+        .find(|c: char| !is_scheme_char(c))
+        .unwrap_or(filename.len());
+    let (scheme, rest) = filename.split_at(scheme_len);
+
+    // `subfile` takes its options before the `:` of the URL it reads from
+    let is_protocol = rest.starts_with(':')
+        || (scheme == "subfile" && rest.starts_with(',') && rest.contains(':'));
+    // A single letter before the `:` is a drive, e.g. `C:\clips\a.mp4`
+    let is_drive = scheme.len() == 1 && scheme.chars().all(|c| c.is_ascii_alphabetic());
+
+    match scheme {
+        "file" => None,
+        scheme if !scheme.is_empty() && is_protocol && !is_drive => Some(scheme),
+        _ => None,
+    }
+}
+
+fn allowed_protocols() -> &'static [String] {
+    ALLOWED_PROTOCOLS.get_or_init(|| {
+        let allowed: Vec<String> = std::env::var(ALLOWED_PROTOCOLS_VAR)
+            .unwrap_or_default()
+            .split(',')
+            .map(|protocol| protocol.trim().to_ascii_lowercase())
+            .filter(|protocol| !protocol.is_empty())
+            .collect();
+        debug!("Network protocols allowed for guests {:?}", allowed);
+        allowed
+    })
+}
+
+/// Whether the host allows guests to open URLs of `protocol`, every protocol of a nested one
+/// such as "crypto+http" being allowed
+pub fn is_allowed(protocol: &str) -> bool {
+    protocol.split('+').all(|protocol| {
+        allowed_protocols()
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(protocol))
+    })
+}
+
+// Protocols FFMPEG opens underneath `protocol`, which its whitelist has to list as well
+fn transports(protocol: &str) -> &'static [&'static str] {
+    match protocol {
+        "http" | "rtmp" | "tls" => &["tcp"],
+        "https" | "rtmps" => &["tls", "tcp"],
+        "rtsp" => &["tcp", "udp", "rtp"],
+        "rtsps" => &["tls", "tcp", "udp", "rtp"],
+        "rtp" => &["udp"],
+        "srtp" => &["rtp", "udp"],
+        _ => &[],
+    }
+}
+
+// Options restricting FFMPEG to local files, the `allowed` protocols and their transports
+fn whitelisted_options(allowed: &[String]) -> Dictionary<'static> {
+    let mut whitelist = vec!["file"];
+    for protocol in allowed.iter().map(String::as_str) {
+        for protocol in std::iter::once(protocol).chain(transports(protocol).iter().copied()) {
+            if !whitelist.contains(&protocol) {
+                whitelist.push(protocol);
+            }
+        }
+    }
+
+    let mut options = Dictionary::new();
+    options.set("protocol_whitelist", &whitelist.join(","));
+    options
+}
+
+/// Options FFMPEG opens local files with. FFMPEG itself is restricted to local files and the
+/// protocols the host allows (with the transports they run over, e.g. "tcp" for "http"), so
+/// sources referencing other URLs (e.g. playlists) fail to open.
+pub fn local_options() -> Dictionary<'static> {
+    whitelisted_options(allowed_protocols())
+}
+
+/// Options FFMPEG opens URLs of `protocol` with, restricted as `local_options` are
+pub fn input_options(protocol: &str) -> Dictionary<'static> {
+    let mut options = local_options();
+    match protocol {
+        // UDP loses packets (and so frames) on congested links, TCP interleaving does not
+        "rtsp" | "rtsps" => {
+            options.set("rtsp_transport", "tcp");
+            options.set("timeout", NETWORK_TIMEOUT_MICROS);
+        }
+        "http" | "https" => {
+            options.set("reconnect", "1");
+            options.set("rw_timeout", NETWORK_TIMEOUT_MICROS);
+        }
+        _ => {
+            options.set("rw_timeout", NETWORK_TIMEOUT_MICROS);
+        }
+    }
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_url_protocols() {
+        assert_eq!(protocol("rtsp://camera/stream"), Some("rtsp"));
+        assert_eq!(protocol("crypto+http://host/a.ts"), Some("crypto+http"));
+    }
+
+    #[test]
+    fn finds_protocols_without_slashes() {
+        assert_eq!(
+            protocol("concat:http://a/1.ts|http://a/2.ts"),
+            Some("concat")
+        );
+        assert_eq!(
+            protocol("subfile,,start,0,end,0,,:http://a/1.ts"),
+            Some("subfile")
+        );
+        assert_eq!(protocol("pipe:"), Some("pipe"));
+        assert_eq!(protocol("data:video/mp4;base64,AAAA"), Some("data"));
+    }
+
+    #[test]
+    fn whitelists_the_transports_of_allowed_protocols() {
+        let whitelist = |allowed: &[&str]| {
+            let allowed: Vec<String> = allowed
+                .iter()
+                .map(|protocol| protocol.to_string())
+                .collect();
+            whitelisted_options(&allowed)
+                .get("protocol_whitelist")
+                .map(str::to_string)
+        };
+
+        assert_eq!(whitelist(&[]).as_deref(), Some("file"));
+        assert_eq!(
+            whitelist(&["http", "https"]).as_deref(),
+            Some("file,http,tcp,https,tls")
+        );
+        assert_eq!(
+            whitelist(&["rtsp"]).as_deref(),
+            Some("file,rtsp,tcp,udp,rtp")
+        );
+    }
+
+    #[test]
+    fn local_paths_have_no_protocol() {
+        assert_eq!(protocol("clips/a.mp4"), None);
+        assert_eq!(protocol("/clips/a:b.mp4"), None);
+        assert_eq!(protocol("file:///clips/a.mp4"), None);
+        assert_eq!(protocol("C:\\clips\\a.mp4"), None);
+    }
+}
@@ -0,0 +1,484 @@
+use std::path::Path;
+
+use ffmpeg::Error as FFmpegError;
+
+use log::debug;
+
+use crate::{
+    decode_video::{self, ContextCache, DecodeOptions, VideoDecoderError},
+    encode_video::{EncoderSettings, OutputFrame, VideoEncoder, VideoEncoderError},
+    frame_ops, ColorProperties, Frames, Height, OperationContext, Width,
+};
+
+/// Spec format version understood by `parse_spec`
+pub const PIPELINE_SPEC_VERSION: u32 = 1;
+
+// Largest width/height a `scale` step may produce
+const MAX_DIMENSION: u32 = 16384;
+
+/// A transform pipeline, parsed from a text spec with one directive per line:
+///
+/// ```text
+/// version 1
+/// input <path>
+/// trim <start_frame> <end_frame>
+/// scale <width> <height>
+/// overlay <x> <y> <scale> <path>
+/// color <primaries> <transfer> <matrix> <range>
+/// output <path>
+/// ```
+///
+/// `version` must come first and `input`/`output` appear exactly once. `trim`, `scale` and
+/// `overlay` are applied in the order given. `trim` keeps the frames in `[start, end)`, starting
+/// the output at the first kept frame and dropping the source's audio, `overlay` composites another
+/// video picture-in-picture (see `frame_ops::compose_pip`) and `color` sets the output's color
+/// signaling from FFMPEG's integer codes. Paths run to the end of the line,
+/// blank lines and lines starting with `#` are ignored.
+#[derive(Debug)]
+pub struct PipelineSpec {
+    pub input: String,
+    pub steps: Vec<PipelineStep>,
+    pub encoder_settings: EncoderSettings,
+    pub output: String,
+}
+
+#[derive(Debug)]
+pub enum PipelineStep {
+    Trim {
+        start: usize,
+        end: usize,
+    },
+    Scale {
+        width: u32,
+        height: u32,
+    },
+    Overlay {
+        filename: String,
+        x: u32,
+        y: u32,
+        scale: f32,
+    },
+}
+
+#[derive(Debug)]
+pub enum PipelineError {
+    // The spec is malformed, with the 1-based line number it was detected on (0 for the whole spec)
+    InvalidSpec { line: usize, message: String },
+    // A step cannot be applied to the frames it received
+    InvalidStep { step: usize, message: String },
+    DecodeError(VideoDecoderError),
+    EncodeError(VideoEncoderError),
+    FFMpegError(FFmpegError),
+    IoError(std::io::Error),
+}
+
+impl From<VideoDecoderError> for PipelineError {
+    fn from(value: VideoDecoderError) -> Self {
+        PipelineError::DecodeError(value)
+    }
+}
+
+impl From<VideoEncoderError> for PipelineError {
+    fn from(value: VideoEncoderError) -> Self {
+        PipelineError::EncodeError(value)
+    }
+}
+
+impl From<FFmpegError> for PipelineError {
+    fn from(value: FFmpegError) -> Self {
+        PipelineError::FFMpegError(value)
+    }
+}
+
+impl From<std::io::Error> for PipelineError {
+    fn from(value: std::io::Error) -> Self {
+        PipelineError::IoError(value)
+    }
+}
+
+fn invalid(line: usize, message: impl Into<String>) -> PipelineError {
+    PipelineError::InvalidSpec {
+        line,
+        message: message.into(),
+    }
+}
+
+// Parses whitespace separated operand `name` of the directive on `line`
+fn parse_operand<T: std::str::FromStr>(
+    line: usize,
+    name: &str,
+    operand: Option<&str>,
+) -> Result<T, PipelineError> {
+    let operand = operand.ok_or_else(|| invalid(line, format!("missing {name}")))?;
+    operand
+        .parse()
+        .map_err(|_| invalid(line, format!("invalid {name} {operand:?}")))
+}
+
+// Splits the first whitespace separated token off `text`
+fn split_token(text: &str) -> (Option<&str>, &str) {
+    let text = text.trim_start();
+    if text.is_empty() {
+        return (None, text);
+    }
+    match text.split_once(char::is_whitespace) {
+        Some((token, rest)) => (Some(token), rest),
+        None => (Some(text), ""),
+    }
+}
+
+// Takes the rest of the directive on `line` as a path, which may contain spaces
+fn parse_path(line: usize, rest: &str) -> Result<String, PipelineError> {
+    let path = rest.trim();
+    if path.is_empty() {
+        return Err(invalid(line, "missing path"));
+    }
+    Ok(path.to_string())
+}
+
+/// Parses and validates a version 1 pipeline spec, see `PipelineSpec` for the format
+pub fn parse_spec(spec: &str) -> Result<PipelineSpec, PipelineError> {
+    let mut version = None;
+    let mut input = None;
+    let mut output = None;
+    let mut color = None;
+    let mut steps = Vec::new();
+
+    for (idx, text) in spec.lines().enumerate() {
+        let line = idx + 1;
+        let text = text.trim();
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+
+        let (directive, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let mut operands = rest.split_whitespace();
+
+        if version.is_none() && directive != "version" {
+            return Err(invalid(line, "spec must start with a version directive"));
+        }
+
+        match directive {
+            "version" => {
+                if version.is_some() {
+                    return Err(invalid(line, "duplicate version directive"));
+                }
+                let spec_version: u32 = parse_operand(line, "version", operands.next())?;
+                if spec_version != PIPELINE_SPEC_VERSION {
+                    return Err(invalid(
+                        line,
+                        format!(
+                            "unsupported spec version {spec_version}, \
+                             expected {PIPELINE_SPEC_VERSION}"
+                        ),
+                    ));
+                }
+                version = Some(spec_version);
+            }
+            "input" => {
+                if input.replace(parse_path(line, rest)?).is_some() {
+                    return Err(invalid(line, "duplicate input directive"));
+                }
+            }
+            "output" => {
+                if output.replace(parse_path(line, rest)?).is_some() {
+                    return Err(invalid(line, "duplicate output directive"));
+                }
+            }
+            "trim" => {
+                let start: usize = parse_operand(line, "start frame", operands.next())?;
+                let end: usize = parse_operand(line, "end frame", operands.next())?;
+                if start >= end {
+                    return Err(invalid(line, format!("empty trim range {start}..{end}")));
+                }
+                steps.push(PipelineStep::Trim { start, end });
+            }
+            "scale" => {
+                let width: u32 = parse_operand(line, "width", operands.next())?;
+                let height: u32 = parse_operand(line, "height", operands.next())?;
+                if !(1..=MAX_DIMENSION).contains(&width) || !(1..=MAX_DIMENSION).contains(&height) {
+                    return Err(invalid(
+                        line,
+                        format!("invalid scale size {width}x{height}"),
+                    ));
+                }
+                steps.push(PipelineStep::Scale { width, height });
+            }
+            "overlay" => {
+                let (x, rest) = split_token(rest);
+                let (y, rest) = split_token(rest);
+                let (scale, path) = split_token(rest);
+                let x: u32 = parse_operand(line, "x", x)?;
+                let y: u32 = parse_operand(line, "y", y)?;
+                let scale: f32 = parse_operand(line, "scale", scale)?;
+                if !(scale > 0.0 && scale <= 1.0) {
+                    return Err(invalid(
+                        line,
+                        format!("overlay scale {scale} must be in (0, 1]"),
+                    ));
+                }
+                let filename = parse_path(line, path)?;
+                steps.push(PipelineStep::Overlay {
+                    filename,
+                    x,
+                    y,
+                    scale,
+                });
+            }
+            "color" => {
+                let primaries: i32 = parse_operand(line, "primaries", operands.next())?;
+                let transfer: i32 = parse_operand(line, "transfer", operands.next())?;
+                let matrix: i32 = parse_operand(line, "matrix", operands.next())?;
+                let range: i32 = parse_operand(line, "range", operands.next())?;
+                let properties = ColorProperties::from_codes(primaries, transfer, matrix, range)
+                    .ok_or_else(|| invalid(line, "invalid color properties"))?;
+                if color.replace(properties).is_some() {
+                    return Err(invalid(line, "duplicate color directive"));
+                }
+            }
+            other => return Err(invalid(line, format!("unknown directive {other:?}"))),
+        }
+
+        let takes_path = matches!(directive, "input" | "output" | "overlay");
+        if !takes_path && operands.next().is_some() {
+            return Err(invalid(line, format!("too many operands for {directive}")));
+        }
+    }
+
+    if version.is_none() {
+        return Err(invalid(0, "spec is empty"));
+    }
+
+    Ok(PipelineSpec {
+        input: input.ok_or_else(|| invalid(0, "missing input directive"))?,
+        steps,
+        encoder_settings: EncoderSettings {
+            color,
+            ..Default::default()
+        },
+        output: output.ok_or_else(|| invalid(0, "missing output directive"))?,
+    })
+}
+
+// Keeps the frames in `[start, end)`, their timestamps rebased so the first kept frame starts at 0
+fn trim_frames(frames: &mut Frames, start: usize, end: usize) {
+    frames.truncate(end);
+    frames.drain(..start);
+    let base = match frames.first().and_then(|frame_map| frame_map.timestamp) {
+        Some(base) => base,
+        None => return,
+    };
+    for frame_map in frames.iter_mut() {
+        frame_map.timestamp = frame_map.timestamp.map(|timestamp| timestamp - base);
+        frame_map.decode_timestamp = frame_map.decode_timestamp.map(|timestamp| timestamp - base);
+    }
+}
+
+/// Decodes the spec's input, applies its steps in order and encodes the result to its output.
+/// Returns the size of the written output file in bytes.
+pub fn run_pipeline(
+    spec: &PipelineSpec,
+    cache: &mut ContextCache,
+    operation: &OperationContext,
+) -> Result<u64, PipelineError> {
+    let (mut frames, mut video_info) =
+        decode_video::dump_frames(&spec.input, &DecodeOptions::default(), cache, operation)?;
+    debug!(
+        "Pipeline decoded {} frames from {}",
+        frames.len(),
+        spec.input
+    );
+
+    for (step_idx, step) in spec.steps.iter().enumerate() {
+        let step_error = |message: String| PipelineError::InvalidStep {
+            step: step_idx,
+            message,
+        };
+        debug!("Pipeline step {step_idx} {:?}", step);
+
+        match step {
+            PipelineStep::Trim { start, end } => {
+                if *end > frames.len() {
+                    return Err(step_error(format!(
+                        "trim {start}..{end} exceeds the {} frames",
+                        frames.len()
+                    )));
+                }
+                trim_frames(&mut frames, *start, *end);
+                // As for loaded frame ranges, the audio of the whole source would not line up
+                video_info.audio = None;
+            }
+            PipelineStep::Scale { width, height } => {
+                frame_ops::scale_frames(&mut frames, *width, *height)?;
+                video_info.width = Width(*width);
+                video_info.height = Height(*height);
+            }
+            PipelineStep::Overlay {
+                filename,
+                x,
+                y,
+                scale,
+            } => {
+                let (pip_frames, pip_info) = decode_video::dump_frames(
+                    filename,
+                    &DecodeOptions::default(),
+                    cache,
+                    operation,
+                )?;
+                if pip_frames.is_empty() {
+                    return Err(step_error(format!(
+                        "overlay {filename} contained no frames"
+                    )));
+                }
+
+                let pip_width = ((pip_info.width() as f32 * scale).round() as u32).max(1);
+                let pip_height = ((pip_info.height() as f32 * scale).round() as u32).max(1);
+                // Offsets overflowing with the overlay's size do not fit either
+                let fits_across = x
+                    .checked_add(pip_width)
+                    .is_some_and(|right| right <= video_info.width());
+                let fits_down = y
+                    .checked_add(pip_height)
+                    .is_some_and(|bottom| bottom <= video_info.height());
+                if !fits_across || !fits_down {
+                    return Err(step_error(format!(
+                        "overlay {pip_width}x{pip_height} at ({x}, {y}) does not fit in {}x{}",
+                        video_info.width(),
+                        video_info.height()
+                    )));
+                }
+
+                frame_ops::compose_pip(&mut frames, &pip_frames, *x, *y, pip_width, pip_height)?;
+            }
+        }
+    }
+
+    if frames.is_empty() {
+        return Err(PipelineError::InvalidStep {
+            step: spec.steps.len(),
+            message: "no frames left to encode".into(),
+        });
+    }
+
+    let encoder_frames: Vec<_> = frames
+        .iter()
+        .map(|frame_map| OutputFrame {
+            frame: frame_map.current_frame(),
+            frame_type: frame_map.frame_type,
+            timestamp: frame_map.timestamp,
+            duration: frame_map.duration,
+            force_keyframe: frame_map.force_keyframe,
+        })
+        .collect();
+
+    let mut video_encoder = VideoEncoder::new(&video_info, &spec.encoder_settings, &spec.output)?;
+    video_encoder.receive_and_process_decoded_frames(&encoder_frames, operation)?;
+
+    Ok(std::fs::metadata(Path::new(&spec.output))?.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ffmpeg::util::mathematics::rescale::Rescale;
+    use ffmpeg::{codec, Rational};
+
+    use crate::test_clips::{self, CLIP_FPS};
+
+    fn invalid_line(spec: &str) -> usize {
+        match parse_spec(spec) {
+            Err(PipelineError::InvalidSpec { line, .. }) => line,
+            other => panic!("expected an invalid spec, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_every_directive() {
+        let spec = parse_spec(
+            "version 1\n\
+             # comment\n\
+             \n\
+             input in dir/clip one.mp4\n\
+             trim 10 20\n\
+             scale 640 360\n\
+             overlay 8 16 0.5 logo clip.mp4\n\
+             color 1 1 1 1\n\
+             output out.mp4\n",
+        )
+        .expect("valid spec");
+
+        assert_eq!(spec.input, "in dir/clip one.mp4");
+        assert_eq!(spec.output, "out.mp4");
+        assert!(spec.encoder_settings.color.is_some());
+        assert!(matches!(
+            spec.steps.as_slice(),
+            [
+                PipelineStep::Trim { start: 10, end: 20 },
+                PipelineStep::Scale {
+                    width: 640,
+                    height: 360
+                },
+                PipelineStep::Overlay { x: 8, y: 16, .. },
+            ]
+        ));
+        match &spec.steps[2] {
+            PipelineStep::Overlay {
+                filename, scale, ..
+            } => {
+                assert_eq!(filename, "logo clip.mp4");
+                assert_eq!(*scale, 0.5);
+            }
+            step => panic!("expected an overlay, got {:?}", step),
+        }
+    }
+
+    #[test]
+    fn reports_the_line_of_malformed_directives() {
+        assert_eq!(invalid_line("input a.mp4\nversion 1\n"), 1);
+        assert_eq!(invalid_line("version 2\n"), 1);
+        assert_eq!(invalid_line("version 1\nversion 1\n"), 2);
+        assert_eq!(invalid_line("version 1\ninput a.mp4\ninput b.mp4\n"), 3);
+        assert_eq!(invalid_line("version 1\n\ntrim 5 5\n"), 3);
+        assert_eq!(invalid_line("version 1\ntrim 5\n"), 2);
+        assert_eq!(invalid_line("version 1\ntrim 1 2 3\n"), 2);
+        assert_eq!(invalid_line("version 1\nscale 0 360\n"), 2);
+        assert_eq!(invalid_line("version 1\nscale wide 360\n"), 2);
+        assert_eq!(invalid_line("version 1\noverlay 0 0 1.5 logo.mp4\n"), 2);
+        assert_eq!(invalid_line("version 1\noverlay 0 0 0.5\n"), 2);
+        assert_eq!(invalid_line("version 1\ncolor 1 1 1\n"), 2);
+        assert_eq!(invalid_line("version 1\nblur 3\n"), 2);
+        assert_eq!(invalid_line("version 1\noutput\n"), 2);
+    }
+
+    #[test]
+    fn requires_a_version_an_input_and_an_output() {
+        assert_eq!(invalid_line(""), 0);
+        assert_eq!(invalid_line("# only a comment\n"), 0);
+        assert_eq!(invalid_line("version 1\noutput out.mp4\n"), 0);
+        assert_eq!(invalid_line("version 1\ninput in.mp4\n"), 0);
+    }
+
+    #[test]
+    fn trimmed_frames_start_at_timestamp_zero() {
+        let source = test_clips::gradient_frames(10, 64, 48);
+        let video_info = test_clips::clip_info(codec::Id::MPEG4, 64, 48);
+        let bytes = test_clips::encode_clip(&source, &video_info, &EncoderSettings::default());
+        let (mut frames, decoded_info) = test_clips::decode_clip(bytes, &DecodeOptions::default());
+        let time_base = decoded_info.time_base.expect("stream time base");
+
+        trim_frames(&mut frames, 3, 7);
+
+        // The kept frames were stamped 3..7 in 1/CLIP_FPS
+        let timestamps: Vec<_> = frames
+            .iter()
+            .map(|frame_map| {
+                frame_map
+                    .timestamp
+                    .expect("decoded frame timestamp")
+                    .rescale(time_base, Rational::new(1, CLIP_FPS))
+            })
+            .collect();
+        assert_eq!(timestamps, [0, 1, 2, 3]);
+    }
+}
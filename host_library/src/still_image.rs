@@ -0,0 +1,68 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::{ColorType, ImageEncoder, ImageError, ImageFormat};
+
+use ffmpeg::util::frame::video::Video;
+
+use crate::frame_ops;
+
+/// Image formats single frames are exchanged with the guest in
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StillFormat {
+    Jpeg,
+    Png,
+}
+
+impl StillFormat {
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            0 => Some(StillFormat::Jpeg),
+            1 => Some(StillFormat::Png),
+            _ => None,
+        }
+    }
+
+    fn image_format(self) -> ImageFormat {
+        match self {
+            StillFormat::Jpeg => ImageFormat::Jpeg,
+            StillFormat::Png => ImageFormat::Png,
+        }
+    }
+}
+
+/// Encodes an RGB24 frame as a JPEG or PNG image, `quality` (1 to 100) only applying to JPEG
+pub fn encode_rgb24(
+    frame: &Video,
+    format: StillFormat,
+    quality: u8,
+) -> Result<Vec<u8>, ImageError> {
+    let pixels = frame_ops::packed_rgb24_bytes(frame);
+    let mut encoded = Vec::new();
+
+    match format {
+        StillFormat::Jpeg => JpegEncoder::new_with_quality(&mut encoded, quality).write_image(
+            &pixels,
+            frame.width(),
+            frame.height(),
+            ColorType::Rgb8,
+        )?,
+        StillFormat::Png => PngEncoder::new(&mut encoded).write_image(
+            &pixels,
+            frame.width(),
+            frame.height(),
+            ColorType::Rgb8,
+        )?,
+    }
+
+    Ok(encoded)
+}
+
+/// Decodes a JPEG or PNG image into an RGB24 frame of its dimensions, dropping any alpha channel
+pub fn decode_rgb24(bytes: &[u8], format: StillFormat) -> Result<Video, ImageError> {
+    let image = image::load_from_memory_with_format(bytes, format.image_format())?.to_rgb8();
+    Ok(frame_ops::rgb24_from_packed(
+        image.as_raw(),
+        image.width(),
+        image.height(),
+    ))
+}
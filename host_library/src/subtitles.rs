@@ -0,0 +1,269 @@
+use std::fmt::Write;
+
+use ffmpeg::{
+    codec::{self, subtitle},
+    filter,
+    media::Type,
+    util::frame::video::Video,
+    util::mathematics::rescale::{Rescale, TIME_BASE},
+    Rational,
+};
+
+use ffmpeg::Error as FFmpegError;
+
+use log::debug;
+
+use crate::{
+    decode_video::{open_source, VideoDecoderError},
+    diagnostics, Frames, VideoInfo,
+};
+
+#[derive(Debug)]
+pub enum SubtitleError {
+    FFMpegError(FFmpegError),
+    // FFMPEG was built without libass, which provides the `subtitles` filter
+    FilterUnavailable,
+    // libass could not load a font to render the subtitles with, holds FFMPEG's log
+    FontUnavailable(String),
+    // The subtitle file could not be loaded or rendered, holds FFMPEG's log
+    RenderError(FFmpegError, String),
+    // The container has no subtitle stream at the requested index
+    StreamNotFound,
+    // The subtitles are images (e.g. PGS or DVD), with the codec's name
+    UnsupportedFormat(&'static str),
+    // The source could not be opened, e.g. a URL of a protocol the host does not allow
+    OpenError(VideoDecoderError),
+}
+
+const MILLISECOND: Rational = Rational(1, 1000);
+
+impl From<FFmpegError> for SubtitleError {
+    fn from(value: FFmpegError) -> Self {
+        SubtitleError::FFMpegError(value)
+    }
+}
+
+impl From<VideoDecoderError> for SubtitleError {
+    fn from(value: VideoDecoderError) -> Self {
+        SubtitleError::OpenError(value)
+    }
+}
+
+// Backslash escapes every character of `special` (and backslashes) in a filter graph value
+fn escape_filter_value(value: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Renders the subtitles of the SRT/ASS file `sub_filename` onto every frame with FFMPEG's
+/// `subtitles` filter, writing the result to the output frames (frames without one start from
+/// their input frame). Each frame shows the subtitles scheduled at its presentation timestamp,
+/// frames are timed by index at the source frame rate if the stream has no timestamps.
+pub fn burn_subtitles(
+    frames: &mut Frames,
+    video_info: &VideoInfo,
+    sub_filename: &str,
+) -> Result<(), SubtitleError> {
+    ffmpeg::init()?;
+
+    if filter::find("subtitles").is_none() {
+        return Err(SubtitleError::FilterUnavailable);
+    }
+
+    // libass reports unreadable files and missing fonts through FFMPEG's log
+    let _diagnostics = diagnostics::capture();
+
+    render_subtitles(frames, video_info, sub_filename)
+        .map_err(|err| SubtitleError::RenderError(err, diagnostics::report()))?;
+
+    let report = diagnostics::report();
+    let font_problem = report.lines().any(|line| {
+        let line = line.to_lowercase();
+        line.contains("font")
+            && (line.contains("fail") || line.contains("not found") || line.contains("error"))
+    });
+    if font_problem {
+        return Err(SubtitleError::FontUnavailable(report));
+    }
+
+    Ok(())
+}
+
+fn render_subtitles(
+    frames: &mut Frames,
+    video_info: &VideoInfo,
+    sub_filename: &str,
+) -> Result<(), FFmpegError> {
+    let frame_rate = video_info.frame_rate.0.unwrap_or(Rational::new(30, 1));
+    let timestamped = frames.iter().all(|frame_map| frame_map.timestamp.is_some());
+    let time_base = match video_info.time_base {
+        Some(time_base) if timestamped => time_base,
+        _ => frame_rate.invert(),
+    };
+
+    let buffer_args = format!(
+        "video_size={}x{}:pix_fmt=rgb24:time_base={}:pixel_aspect=1/1:frame_rate={}",
+        video_info.width(),
+        video_info.height(),
+        time_base,
+        frame_rate
+    );
+    debug!("Subtitle Buffer Args {buffer_args}");
+
+    // The filename is an option value inside a filter graph description, escape it for both
+    let filename = escape_filter_value(&escape_filter_value(sub_filename, "':="), "'[],;");
+    let spec = format!("subtitles=filename={filename},format=rgb24");
+
+    let mut graph = filter::Graph::new();
+    graph.add(
+        &filter::find("buffer").ok_or(FFmpegError::FilterNotFound)?,
+        "in",
+        &buffer_args,
+    )?;
+    graph.add(
+        &filter::find("buffersink").ok_or(FFmpegError::FilterNotFound)?,
+        "out",
+        "",
+    )?;
+    graph.output("in", 0)?.input("out", 0)?.parse(&spec)?;
+    graph.validate()?;
+
+    // The filter renders one frame per input frame, so each result is pulled right after its push
+    for (idx, frame_map) in frames.iter_mut().enumerate() {
+        let mut source = frame_map.current_frame().clone();
+        source.set_pts(match frame_map.timestamp {
+            Some(timestamp) if timestamped => Some(timestamp),
+            _ => Some(idx as i64),
+        });
+
+        graph
+            .get("in")
+            .ok_or(FFmpegError::FilterNotFound)?
+            .source()
+            .add(&source)?;
+
+        let mut subtitled = Video::empty();
+        graph
+            .get("out")
+            .ok_or(FFmpegError::FilterNotFound)?
+            .sink()
+            .frame(&mut subtitled)?;
+
+        frame_map.output_frame = Some(subtitled);
+    }
+
+    Ok(())
+}
+
+/// Decodes the text subtitle stream at `stream_index` of `filename` into SRT formatted UTF-8,
+/// one numbered cue per subtitle with its display interval. ASS styling (override tags) is
+/// dropped, keeping the text. Bitmap subtitles fail with `UnsupportedFormat`.
+pub fn extract_subtitles(filename: &str, stream_index: usize) -> Result<String, SubtitleError> {
+    ffmpeg::init()?;
+
+    let mut ictx = open_source(filename, None)?;
+    let stream = ictx
+        .stream(stream_index)
+        .filter(|stream| stream.parameters().medium() == Type::Subtitle)
+        .ok_or(SubtitleError::StreamNotFound)?;
+    let time_base = stream.time_base().unwrap_or(TIME_BASE);
+    let mut decoder = codec::context::Context::from_parameters(stream.parameters())?
+        .decoder()
+        .subtitle()?;
+
+    let mut srt = String::new();
+    let mut cue = 0;
+    for res in ictx.packets() {
+        let (stream, packet) = res?;
+        if stream.index() != stream_index {
+            continue;
+        }
+
+        let mut decoded = subtitle::Subtitle::new();
+        if !decoder.decode(&packet, &mut decoded)? {
+            continue;
+        }
+
+        let mut lines = Vec::new();
+        for rect in decoded.rects() {
+            match rect {
+                subtitle::Rect::Text(text) => lines.push(text.get().trim().to_string()),
+                subtitle::Rect::Ass(ass) => lines.push(ass_dialogue_text(ass.get())),
+                subtitle::Rect::Bitmap(_) => {
+                    let codec = stream.parameters().id().name();
+                    return Err(SubtitleError::UnsupportedFormat(codec));
+                }
+                subtitle::Rect::None(_) => {}
+            }
+        }
+        lines.retain(|line| !line.is_empty());
+        if lines.is_empty() {
+            continue;
+        }
+
+        // Display times are relative to the packet, which may carry the duration instead
+        let packet_millis = packet.pts().unwrap_or(0).rescale(time_base, MILLISECOND);
+        let start = packet_millis + decoded.start() as i64;
+        let end = match decoded.end() {
+            0 | u32::MAX => packet_millis + packet.duration().rescale(time_base, MILLISECOND),
+            end => packet_millis + end as i64,
+        };
+
+        cue += 1;
+        let _ = write!(
+            srt,
+            "{cue}\n{} --> {}\n{}\n\n",
+            srt_timestamp(start),
+            srt_timestamp(end.max(start)),
+            lines.join("\n")
+        );
+    }
+
+    debug!("Extracted {cue} subtitles from stream {stream_index} of {filename}");
+    Ok(srt)
+}
+
+// The text of an ASS dialogue event ("ReadOrder,Layer,Style,Name,MarginL,MarginR,MarginV,Effect,
+// Text", older FFMPEG versions prefixing "Dialogue: " and timings) without its override tags
+fn ass_dialogue_text(event: &str) -> String {
+    let fields = match event.strip_prefix("Dialogue:") {
+        Some(_) => 10,
+        None => 9,
+    };
+    let text = event.splitn(fields, ',').last().unwrap_or_default();
+
+    let mut plain = String::with_capacity(text.len());
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '{' => in_tag = true,
+            '}' if in_tag => in_tag = false,
+            c if !in_tag => plain.push(c),
+            _ => {}
+        }
+    }
+    plain
+        .replace("\\N", "\n")
+        .replace("\\n", "\n")
+        .replace("\\h", " ")
+        .trim()
+        .to_string()
+}
+
+// `HH:MM:SS,mmm` as SRT writes cue times
+fn srt_timestamp(millis: i64) -> String {
+    let millis = millis.max(0);
+    format!(
+        "{:02}:{:02}:{:02},{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
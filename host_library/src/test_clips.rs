@@ -0,0 +1,139 @@
+//! Tiny clips encoded in memory with FFMPEG's native encoders, so tests can decode and encode
+//! real streams without sample files.
+
+use ffmpeg::{
+    codec, encoder,
+    ffi::{av_display_rotation_set, av_stream_new_side_data, AVPacketSideDataType},
+    format::Pixel,
+    frame, media, Rational,
+};
+
+use crate::decode_video::{self, ContextCache, DecodeOptions};
+use crate::encode_video::{EncoderSettings, OutputFrame, VideoEncoder};
+use crate::frame_type::FrameType;
+use crate::memory_io::{MemoryInput, MemoryOutput};
+use crate::{FrameRate, Frames, Height, OperationContext, VideoInfo, Width};
+
+/// Frame rate of the clips, their frames are timestamped 0, 1, 2... in `1/CLIP_FPS`
+pub const CLIP_FPS: i32 = 25;
+
+/// `count` RGB24 frames of `width` x `height`, each a horizontal gradient
+pub fn gradient_frames(count: usize, width: u32, height: u32) -> Vec<frame::Video> {
+    (0..count)
+        .map(|_| {
+            let mut frame = frame::Video::new(Pixel::RGB24, width, height);
+            let stride = frame.stride(0);
+            let data = frame.data_mut(0);
+            for y in 0..height as usize {
+                for x in 0..width as usize * 3 {
+                    data[y * stride + x] = (x * 255 / (width as usize * 3)) as u8;
+                }
+            }
+            frame
+        })
+        .collect()
+}
+
+/// A `width` x `height` source at `CLIP_FPS` to encode with `codec_id`
+pub fn clip_info(codec_id: codec::Id, width: u32, height: u32) -> VideoInfo {
+    VideoInfo::builder()
+        .codec(encoder::find(codec_id).expect("native encoder"))
+        .source_codec(codec_id)
+        .format(Pixel::YUV420P)
+        .width(Width(width))
+        .height(Height(height))
+        .frame_rate(FrameRate(Some(Rational::new(CLIP_FPS, 1))))
+        .time_base(Some(Rational::new(1, CLIP_FPS)))
+        .build()
+        .expect("all required fields set")
+}
+
+/// Encodes `frames` as Matroska, each shown for one frame interval
+pub fn encode_clip(
+    frames: &[frame::Video],
+    video_info: &VideoInfo,
+    settings: &EncoderSettings,
+) -> Vec<u8> {
+    ffmpeg::init().expect("FFMPEG initialized");
+
+    let output_frames: Vec<_> = frames
+        .iter()
+        .enumerate()
+        .map(|(idx, frame)| OutputFrame {
+            frame,
+            frame_type: FrameType::Unknown,
+            timestamp: Some(idx as i64),
+            duration: 1,
+            force_keyframe: false,
+        })
+        .collect();
+
+    let mut video_encoder =
+        VideoEncoder::new_in_memory(video_info, settings, "matroska").expect("encoder opened");
+    video_encoder
+        .receive_and_process_decoded_frames(&output_frames, &OperationContext::default())
+        .expect("frames encoded");
+    video_encoder.into_bytes().expect("encoded in memory")
+}
+
+/// Decodes every frame of an encoded clip
+pub fn decode_clip(bytes: Vec<u8>, options: &DecodeOptions) -> (Frames, VideoInfo) {
+    decode_video::dump_frames_from_bytes(
+        bytes,
+        options,
+        &mut ContextCache::default(),
+        &OperationContext::default(),
+    )
+    .expect("clip decoded")
+}
+
+/// Remuxes the video of an encoded clip into MP4 with a display matrix turning it by `degrees`
+/// counter clockwise, as phones tag the videos they record
+pub fn with_display_rotation(bytes: Vec<u8>, degrees: f64) -> Vec<u8> {
+    let mut memory_input = MemoryInput::open(bytes).expect("clip opened");
+    let ictx = memory_input.input();
+    let mut memory_output = MemoryOutput::open("mp4").expect("muxer created");
+    let octx = memory_output.output_mut();
+
+    let (video_index, input_time_base) = {
+        let stream = ictx
+            .streams()
+            .best(media::Type::Video)
+            .expect("clip has video");
+        (
+            stream.index(),
+            stream.time_base().expect("stream time base"),
+        )
+    };
+
+    let mut ost = octx.add_stream().expect("stream added");
+    ost.set_parameters(ictx.stream(video_index).expect("video stream").parameters());
+    unsafe {
+        // The Matroska tag means nothing in MP4
+        (*(*ost.as_mut_ptr()).codecpar).codec_tag = 0;
+        let display_matrix = av_stream_new_side_data(
+            ost.as_mut_ptr(),
+            AVPacketSideDataType::AV_PKT_DATA_DISPLAYMATRIX,
+            9 * std::mem::size_of::<i32>(),
+        );
+        av_display_rotation_set(display_matrix as *mut i32, degrees);
+    }
+
+    octx.write_header().expect("header written");
+    let output_time_base = octx
+        .stream(0)
+        .and_then(|stream| stream.time_base())
+        .expect("output time base");
+    for res in ictx.packets() {
+        let (stream, mut packet) = res.expect("packet read");
+        if stream.index() != video_index {
+            continue;
+        }
+        packet.set_stream(0);
+        packet.rescale_ts(input_time_base, output_time_base);
+        packet.write_interleaved(octx).expect("packet written");
+    }
+    octx.write_trailer().expect("trailer written");
+
+    memory_output.take_bytes()
+}
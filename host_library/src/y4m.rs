@@ -0,0 +1,134 @@
+use ffmpeg::{
+    color,
+    format::Pixel,
+    software::scaling::{Context as Scaler, Flags},
+    util::frame::video::Video,
+    Rational,
+};
+
+use ffmpeg::Error as FFmpegError;
+
+use crate::{frame_ops, Frames, VideoInfo};
+
+// Marker starting every frame of a YUV4MPEG2 stream
+const FRAME_HEADER: &[u8] = b"FRAME\n";
+
+// YUV 4:4:4 keeps every chroma sample of the RGB frames
+const PLANES: usize = 3;
+
+#[derive(Debug)]
+pub enum Y4mError {
+    FFMpegError(FFmpegError),
+    NoFrames,
+    // Y4M streams have one size for every frame
+    InconsistentDimensions { idx: usize, width: u32, height: u32 },
+    ZeroDimensions,
+}
+
+impl From<FFmpegError> for Y4mError {
+    fn from(value: FFmpegError) -> Self {
+        Y4mError::FFMpegError(value)
+    }
+}
+
+/// A YUV4MPEG2 stream of the decoded frames, in 4:4:4 with the source's matrix and range
+pub struct Y4mStream<'a> {
+    frames: &'a Frames,
+    video_info: &'a VideoInfo,
+    width: u32,
+    height: u32,
+    header: String,
+}
+
+impl<'a> Y4mStream<'a> {
+    /// Checks every input frame shares the first frame's dimensions and builds the stream header
+    pub fn new(frames: &'a Frames, video_info: &'a VideoInfo) -> Result<Self, Y4mError> {
+        let first = frames.first().ok_or(Y4mError::NoFrames)?;
+        let (width, height) = (first.input_frame.width(), first.input_frame.height());
+        if width == 0 || height == 0 {
+            return Err(Y4mError::ZeroDimensions);
+        }
+
+        if let Some((idx, frame_map)) = frames.iter().enumerate().find(|(_, frame_map)| {
+            (
+                frame_map.input_frame.width(),
+                frame_map.input_frame.height(),
+            ) != (width, height)
+        }) {
+            return Err(Y4mError::InconsistentDimensions {
+                idx,
+                width: frame_map.input_frame.width(),
+                height: frame_map.input_frame.height(),
+            });
+        }
+
+        // Same fallback as the encoder when the decoder reported no frame rate
+        let frame_rate = video_info.frame_rate.0.unwrap_or(Rational::new(30, 1));
+        // 0:0 marks an unknown pixel aspect ratio
+        let aspect_ratio = match video_info.aspect_ratio.0 {
+            ratio if ratio.numerator() > 0 && ratio.denominator() > 0 => ratio,
+            _ => Rational::new(0, 0),
+        };
+        let color_range = match video_info.color.range {
+            color::Range::JPEG => "FULL",
+            _ => "LIMITED",
+        };
+
+        let header = format!(
+            "YUV4MPEG2 W{width} H{height} F{}:{} Ip A{}:{} C444 XCOLORRANGE={color_range}\n",
+            frame_rate.numerator(),
+            frame_rate.denominator(),
+            aspect_ratio.numerator(),
+            aspect_ratio.denominator()
+        );
+
+        Ok(Y4mStream {
+            frames,
+            video_info,
+            width,
+            height,
+            header,
+        })
+    }
+
+    /// Size of the serialized stream in bytes
+    pub fn byte_len(&self) -> usize {
+        let frame_len = FRAME_HEADER.len() + self.width as usize * self.height as usize * PLANES;
+        self.header.len() + self.frames.len() * frame_len
+    }
+
+    /// Converts every input frame to YUV and serializes the header and frames
+    pub fn serialize(&self) -> Result<Vec<u8>, Y4mError> {
+        let mut scaler = Scaler::get(
+            Pixel::RGB24,
+            self.width,
+            self.height,
+            Pixel::YUV444P,
+            self.width,
+            self.height,
+            Flags::empty(),
+        )?;
+        let color = self.video_info.color;
+        frame_ops::set_yuv_colorspace(&mut scaler, color.matrix, color.range, false);
+
+        let mut stream = Vec::with_capacity(self.byte_len());
+        stream.extend_from_slice(self.header.as_bytes());
+
+        let row_len = self.width as usize;
+        let mut yuv_frame = Video::empty();
+        for frame_map in self.frames {
+            scaler.run(&frame_map.input_frame, &mut yuv_frame)?;
+
+            stream.extend_from_slice(FRAME_HEADER);
+            for plane in 0..PLANES {
+                let stride = yuv_frame.stride(plane);
+                let data = yuv_frame.data(plane);
+                for row in 0..self.height as usize {
+                    stream.extend_from_slice(&data[row * stride..row * stride + row_len]);
+                }
+            }
+        }
+
+        Ok(stream)
+    }
+}
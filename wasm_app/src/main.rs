@@ -3,13 +3,34 @@ extern crate log;
 extern crate simplelog;
 
 use image::{GenericImage, ImageBuffer, Rgb};
-use log::{debug, info, LevelFilter};
+use log::{debug, error, info, LevelFilter};
 use prgrs::Prgrs;
 use simplelog::{ColorChoice, CombinedLogger, Config, TermLogger, TerminalMode};
 
+// Shared with the host library so both agree on the codes `get_frame_type` returns
+#[path = "../../host_library/src/frame_type.rs"]
+mod frame_type;
+
+// Functions return 0 on success, or a documented count, length or index. Failures return the
+// negated `VideoProcessingPluginError` code, e.g. -5 for `InvalidBufferSize`.
 mod plugin {
     use log::LevelFilter;
 
+    /// `write_frame` checksum telling the host not to verify the buffer
+    pub const SKIP_CHECKSUM: i32 = -1;
+
+    /// `get_frame` plane copying every plane of the frame tightly packed
+    pub const ALL_PLANES: i32 = -1;
+
+    /// Returned by `assemble_output_frames_to_video` with the missing frame indices written back
+    pub const MISSING_FRAMES: i32 = -3;
+
+    /// Returned when a frame failed to encode or the output could not be written
+    pub const ENCODE_FAILED: i32 = -4;
+
+    /// Returned when a guest buffer is too small, with the required size written back
+    pub const INVALID_BUFFER_SIZE: i32 = -5;
+
     pub fn init_plugin_logging_with_log_level(level_filter: LevelFilter) {
         let level_filter_i32 = level_filter as i32;
         let level_filter_ptr = std::ptr::addr_of!(level_filter_i32);
@@ -20,6 +41,18 @@ mod plugin {
     extern "C" {
         pub fn init_plugin_logging(level: *const i32) -> i32;
 
+        pub fn set_log_level(level: i32) -> i32;
+
+        pub fn set_max_decode_resolution(max_width: i32, max_height: i32) -> i32;
+
+        pub fn set_auto_rotate(enabled: i32) -> i32;
+
+        pub fn set_video_stream(stream_index: i32) -> i32;
+
+        pub fn set_bit_depth_reduction(enabled: i32) -> i32;
+
+        pub fn set_hw_decode_device(name_ptr: i32, name_len: i32) -> i32;
+
         pub fn load_video_to_host_memory(
             str_ptr: i32,
             str_len: i32,
@@ -27,23 +60,418 @@ mod plugin {
             width_ptr: *mut i32,
             height_ptr: *mut i32,
             frame_count: *mut i32,
+            err_ptr: i32,
+            err_cap: i32,
+            err_len_ptr: *mut i32,
+        ) -> i32;
+
+        pub fn load_video_range(
+            str_ptr: i32,
+            str_len: i32,
+            start: i32,
+            end: i32,
+            width_ptr: *mut i32,
+            height_ptr: *mut i32,
+            frame_count: *mut i32,
+        ) -> i32;
+
+        pub fn load_video_sampled(
+            str_ptr: i32,
+            str_len: i32,
+            stride: i32,
+            width_ptr: *mut i32,
+            height_ptr: *mut i32,
+            frame_count: *mut i32,
+        ) -> i32;
+
+        pub fn load_video_from_bytes(
+            buf_ptr: i32,
+            buf_len: i32,
+            width_ptr: *mut i32,
+            height_ptr: *mut i32,
+            frame_count: *mut i32,
+        ) -> i32;
+
+        pub fn clear_context_cache() -> i32;
+
+        pub fn open_video(
+            str_ptr: i32,
+            str_len: i32,
+            width_ptr: *mut i32,
+            height_ptr: *mut i32,
+        ) -> i32;
+
+        pub fn next_frame(session_id: i32, image_buf_ptr: i32, image_buf_len: i32) -> i32;
+
+        pub fn seek_to_timestamp(session_id: i32, ts_millis: i64, landed_ptr: *mut i64) -> i32;
+
+        pub fn free_frames(session_id: i32) -> i64;
+
+        pub fn reset_output_frames(session_id: i32) -> i32;
+
+        pub fn close_session(session_id: i32) -> i32;
+
+        pub fn get_memory_usage(
+            session_id: i32,
+            total_ptr: *mut i64,
+            input_ptr: *mut i64,
+            output_ptr: *mut i64,
+        ) -> i32;
+
+        pub fn get_decode_diagnostics(buf_ptr: i32, buf_len: i32) -> i32;
+
+        pub fn get_container_format(session_id: i32, buf_ptr: i32, buf_len: i32) -> i32;
+
+        pub fn probe_video(
+            str_ptr: i32,
+            str_len: i32,
+            codec_buf_ptr: i32,
+            codec_buf_len: i32,
+            width_ptr: *mut i32,
+            height_ptr: *mut i32,
+            duration_ptr: *mut i64,
+            stream_count_ptr: *mut i32,
+            session_ptr: *mut i32,
+        ) -> i32;
+
+        pub fn get_video_duration(
+            session_id: i32,
+            duration_ptr: *mut i64,
+            valid_ptr: *mut i32,
+        ) -> i32;
+
+        pub fn get_metadata_count(session_id: i32) -> i32;
+        pub fn get_metadata_key(session_id: i32, idx: i32, buf_ptr: i32, buf_len: i32) -> i32;
+        pub fn get_metadata_value(
+            session_id: i32,
+            key_ptr: i32,
+            key_len: i32,
+            val_ptr: i32,
+            val_cap: i32,
+        ) -> i32;
+
+        pub fn get_video_info(
+            session_id: i32,
+            width_ptr: *mut i32,
+            height_ptr: *mut i32,
+            aspect_num_ptr: *mut i32,
+            aspect_den_ptr: *mut i32,
+            frame_rate_num_ptr: *mut i32,
+            frame_rate_den_ptr: *mut i32,
+            bitrate_ptr: *mut i64,
+            max_bitrate_ptr: *mut i64,
+            stream_count_ptr: *mut i32,
+        ) -> i32;
+
+        pub fn get_program_count(session_id: i32) -> i32;
+
+        pub fn get_program_streams(
+            session_id: i32,
+            program_index: i32,
+            buf_ptr: i32,
+            buf_len: i32,
+        ) -> i32;
+
+        pub fn list_streams(
+            session_id: i32,
+            stream_index: i32,
+            codec_buf_ptr: i32,
+            codec_buf_len: i32,
+            type_ptr: *mut i32,
+            width_ptr: *mut i32,
+            height_ptr: *mut i32,
         ) -> i32;
 
         pub fn get_frame(
+            session_id: i32,
             frame_index: i32,
             image_buf_ptr: i32,
             image_buf_len: i32,
             image_buf_capacity: i32,
+            plane: i32,
+            stride_ptr: *mut i32,
+            required_len_ptr: *mut i32,
+        ) -> i32;
+
+        pub fn get_frame_encoded(
+            session_id: i32,
+            frame_index: i32,
+            format: i32,
+            quality: i32,
+            buf_ptr: i32,
+            buf_len: i32,
+        ) -> i32;
+
+        pub fn get_frames_batch(
+            session_id: i32,
+            start_idx: i32,
+            count: i32,
+            buf_ptr: i32,
+            buf_len: i32,
+            frame_stride_ptr: *mut i32,
+        ) -> i32;
+
+        pub fn get_frame_into(
+            session_id: i32,
+            frame_index: i32,
+            buf_ptr: i32,
+            buf_len: i32,
+            dst_offset: i32,
+        ) -> i32;
+
+        pub fn get_frame_bgr(session_id: i32, frame_index: i32, buf_ptr: i32, buf_len: i32) -> i32;
+
+        pub fn set_frame_staging_region(ptr: i32, len: i32, slot_count: i32) -> i32;
+
+        pub fn map_frame(
+            session_id: i32,
+            frame_index: i32,
+            offset_ptr: *mut i32,
+            len_ptr: *mut i32,
+        ) -> i32;
+
+        pub fn get_pixel_format_name(
+            session_id: i32,
+            frame_index: i32,
+            buf_ptr: i32,
+            buf_len: i32,
+        ) -> i32;
+
+        pub fn get_preview_frame(
+            filename_str_ptr: i32,
+            filename_str_len: i32,
+            max_dimension: i32,
+            buf_ptr: i32,
+            buf_len: i32,
+            width_ptr: *mut i32,
+            height_ptr: *mut i32,
+        ) -> i32;
+
+        pub fn extract_thumbnail(
+            session_id: i32,
+            buf_ptr: i32,
+            buf_len: i32,
+            timestamp_ptr: *mut i64,
+        ) -> i32;
+
+        pub fn set_frame_ordering(session_id: i32, mode: i32) -> i32;
+
+        pub fn get_frame_ordering_mode(session_id: i32) -> i32;
+
+        pub fn get_source_gop_size(session_id: i32) -> i32;
+
+        pub fn get_frame_dts(session_id: i32, frame_index: i32, dts_ptr: *mut i64) -> i32;
+
+        pub fn get_frame_type(session_id: i32, frame_index: i32) -> i32;
+
+        pub fn get_frame_timestamp(
+            session_id: i32,
+            frame_index: i32,
+            pts_ptr: *mut i64,
+            valid_ptr: *mut i32,
+        ) -> i32;
+
+        pub fn get_frame_at_fraction(
+            session_id: i32,
+            fraction: f32,
+            image_buf_ptr: i32,
+            image_buf_len: i32,
+        ) -> i32;
+
+        pub fn compute_optical_flow(
+            session_id: i32,
+            frame_index: i32,
+            flow_buf_ptr: i32,
+            flow_buf_len: i32,
+        ) -> i32;
+
+        pub fn get_frame_phash(
+            session_id: i32,
+            frame_index: i32,
+            hash_hi_ptr: *mut i32,
+            hash_lo_ptr: *mut i32,
+        ) -> i32;
+
+        pub fn frame_dhash(session_id: i32, frame_index: i32, hash_ptr: *mut i64) -> i32;
+
+        pub fn write_frame(
+            session_id: i32,
+            frame_index: i32,
+            image_buf_ptr: i32,
+            image_buf_len: i32,
+            checksum: i32,
+            required_len_ptr: *mut i32,
+        ) -> i32;
+
+        pub fn write_frame_f32(
+            session_id: i32,
+            frame_index: i32,
+            buf_ptr: i32,
+            buf_len: i32,
+            required_len_ptr: *mut i32,
+        ) -> i32;
+
+        pub fn write_frame_encoded(
+            session_id: i32,
+            frame_index: i32,
+            format: i32,
+            image_buf_ptr: i32,
+            image_buf_len: i32,
+        ) -> i32;
+
+        pub fn set_write_clamp_mode(session_id: i32, mode: i32) -> i32;
+
+        pub fn set_frame_pixel_format(session_id: i32, format: i32) -> i32;
+
+        pub fn convert_pixel_format(session_id: i32, idx: i32, format: i32) -> i32;
+
+        pub fn to_grayscale(session_id: i32, frame_index: i32) -> i32;
+
+        pub fn resize_frame(
+            session_id: i32,
+            idx: i32,
+            target_w: i32,
+            target_h: i32,
+            scale_flags: i32,
+        ) -> i32;
+
+        pub fn crop_frame(session_id: i32, idx: i32, x: i32, y: i32, w: i32, h: i32) -> i32;
+
+        pub fn rotate_frame(session_id: i32, idx: i32, degrees: i32) -> i32;
+
+        pub fn compose_pip(
+            session_id: i32,
+            overlay_str_ptr: i32,
+            overlay_str_len: i32,
+            x: i32,
+            y: i32,
+            scale: f32,
+        ) -> i32;
+
+        pub fn burn_subtitles(session_id: i32, sub_str_ptr: i32, sub_str_len: i32) -> i32;
+
+        pub fn extract_subtitles(
+            session_id: i32,
+            stream_index: i32,
+            buf_ptr: i32,
+            buf_cap: i32,
+        ) -> i32;
+
+        pub fn render_audio_waveform(
+            session_id: i32,
+            width: i32,
+            height: i32,
+            image_buf_ptr: i32,
+            image_buf_len: i32,
+        ) -> i32;
+
+        pub fn render_spectrogram(
+            session_id: i32,
+            width: i32,
+            height: i32,
+            image_buf_ptr: i32,
+            image_buf_len: i32,
+        ) -> i32;
+
+        pub fn measure_loudness(
+            session_id: i32,
+            integrated_ptr: *mut f32,
+            true_peak_ptr: *mut f32,
+            range_ptr: *mut f32,
+        ) -> i32;
+
+        pub fn normalize_loudness(session_id: i32, target_lufs: f32) -> i32;
+
+        pub fn set_output_color_properties(
+            session_id: i32,
+            primaries: i32,
+            transfer: i32,
+            matrix: i32,
+            range: i32,
+        ) -> i32;
+
+        pub fn set_encoder_rc_buffer(session_id: i32, bufsize: i32) -> i32;
+
+        pub fn get_encoder_rc_buffer(session_id: i32) -> i32;
+
+        pub fn set_output_codec(session_id: i32, name_ptr: i32, name_len: i32) -> i32;
+
+        pub fn set_output_format(session_id: i32, name_ptr: i32, name_len: i32) -> i32;
+
+        pub fn set_output_bitrate(
+            session_id: i32,
+            bit_rate: i32,
+            max_bit_rate: i32,
+            crf: f32,
+        ) -> i32;
+
+        pub fn set_output_resolution(session_id: i32, width: i32, height: i32) -> i32;
+
+        pub fn set_output_frame_rate(session_id: i32, numerator: i32, denominator: i32) -> i32;
+
+        pub fn set_encoder_gop(session_id: i32, gop_size: i32, max_b_frames: i32) -> i32;
+
+        pub fn set_frame_type_hints(session_id: i32, enabled: i32) -> i32;
+
+        pub fn set_forced_keyframe(session_id: i32, frame_index: i32, enabled: i32) -> i32;
+
+        pub fn apply_gamma(session_id: i32, gamma: f32) -> i32;
+
+        pub fn dedupe_frames(session_id: i32, threshold: f32) -> i32;
+
+        pub fn process_all_frames(
+            session_id: i32,
+            callback_name_ptr: i32,
+            callback_name_len: i32,
+            image_buf_ptr: i32,
+            image_buf_len: i32,
         ) -> i32;
 
-        pub fn write_frame(frame_index: i32, image_buf_ptr: i32, image_buf_len: i32) -> i32;
+        pub fn set_assembly_policy(session_id: i32, policy: i32) -> i32;
 
         pub fn assemble_output_frames_to_video(
+            session_id: i32,
             str_ptr: i32,
             str_len: i32,
             str_capacity: i32,
+            missing_ptr: *mut i32,
+            missing_cap: i32,
+            missing_count_ptr: *mut i32,
+        ) -> i32;
+
+        pub fn assemble_output_frames_to_buffer(
+            session_id: i32,
+            format_ptr: i32,
+            format_len: i32,
+            buf_ptr: i32,
+            buf_len: i32,
+            size_ptr: *mut i32,
         ) -> i32;
 
+        pub fn begin_encode(session_id: i32, filename_ptr: i32, filename_len: i32) -> i32;
+        pub fn push_frame(
+            session_id: i32,
+            frame_index: i32,
+            image_buf_ptr: i32,
+            image_buf_len: i32,
+            checksum: i32,
+            required_len_ptr: *mut i32,
+        ) -> i32;
+        pub fn finish_encode(session_id: i32) -> i32;
+
+        pub fn finalize_output(session_id: i32) -> i32;
+
+        pub fn get_even_dimension_adjustment(session_id: i32) -> i32;
+
+        pub fn get_progress(done_ptr: *mut i32, total_ptr: *mut i32) -> i32;
+
+        pub fn cancel_operation() -> i32;
+
+        pub fn set_operation_timeout(timeout_millis: i32) -> i32;
+
+        pub fn export_y4m(session_id: i32, buf_ptr: i32, buf_len: i32) -> i32;
+
+        pub fn run_pipeline(spec_str_ptr: i32, spec_str_len: i32) -> i64;
     }
 }
 
@@ -57,6 +485,9 @@ fn process_video(mut filename: String, fail: u32) -> Result<(), ()> {
     let height_ptr = std::ptr::addr_of_mut!(height);
     let frame_count_ptr = std::ptr::addr_of_mut!(frame_count);
 
+    let mut err_buf: Vec<u8> = vec![0; 256];
+    let mut err_len: i32 = 0;
+
     let mut red_square = image::RgbImage::new(32, 32);
     let mut blue_square = image::RgbImage::new(32, 32);
     for x in 0..32 {
@@ -75,10 +506,24 @@ fn process_video(mut filename: String, fail: u32) -> Result<(), ()> {
             width_ptr,
             height_ptr,
             frame_count_ptr,
+            err_buf.as_mut_ptr() as usize as i32,
+            err_buf.len() as i32,
+            std::ptr::addr_of_mut!(err_len),
         )
     };
 
+    if result < 0 {
+        error!(
+            "Failed to load video ({}): {}",
+            -result,
+            String::from_utf8_lossy(&err_buf[..err_len as usize])
+        );
+        return Err(());
+    }
+    let session_id = result;
+
     if fail == 1 {
+        unsafe { plugin::close_session(session_id) };
         return Ok(());
     }
 
@@ -101,13 +546,49 @@ fn process_video(mut filename: String, fail: u32) -> Result<(), ()> {
         debug!("WASM image_buf_capacity {:?}", buf_capacity);
 
         {
-            unsafe { plugin::get_frame(idx, buf_ptr_raw, buf_len, buf_capacity) };
+            let mut stride: i32 = 0;
+            let mut required_len: i32 = 0;
+            let status = unsafe {
+                plugin::get_frame(
+                    session_id,
+                    idx,
+                    buf_ptr_raw,
+                    buf_len,
+                    buf_capacity,
+                    plugin::ALL_PLANES,
+                    std::ptr::addr_of_mut!(stride),
+                    std::ptr::addr_of_mut!(required_len),
+                )
+            };
+            if status == plugin::INVALID_BUFFER_SIZE {
+                error!("Frame {} needs a buffer of {} bytes", idx, required_len);
+                return Err(());
+            } else if status < 0 {
+                error!("Failed to get frame {}: {}", idx, -status);
+                return Err(());
+            }
             let mut image_buf: ImageBuffer<image::Rgb<u8>, Vec<u8>> =
                 ImageBuffer::from_vec(width as u32, height as u32, image_buf).unwrap();
             let _ = image_buf.copy_from(&red_square, 0, 0);
             let _ = image_buf.copy_from(&blue_square, 64, 64);
 
-            unsafe { plugin::write_frame(idx, buf_ptr_raw, buf_len) };
+            let status = unsafe {
+                plugin::write_frame(
+                    session_id,
+                    idx,
+                    buf_ptr_raw,
+                    buf_len,
+                    plugin::SKIP_CHECKSUM,
+                    std::ptr::addr_of_mut!(required_len),
+                )
+            };
+            if status < 0 {
+                error!(
+                    "Failed to write frame {} ({} bytes expected): {}",
+                    idx, required_len, -status
+                );
+                return Err(());
+            }
         }
     }
 
@@ -119,16 +600,43 @@ fn process_video(mut filename: String, fail: u32) -> Result<(), ()> {
     let mut output_filename = out.join("");
 
     info!("Begin Encode Video {:?}", output_filename);
+    let mut missing_frames = vec![0i32; 16];
+    let mut missing_count = 0i32;
     let output_code = unsafe {
         plugin::assemble_output_frames_to_video(
+            session_id,
             output_filename.as_mut_ptr() as usize as i32,
             output_filename.len() as i32,
             output_filename.capacity() as i32,
+            missing_frames.as_mut_ptr(),
+            missing_frames.len() as i32,
+            &mut missing_count,
         )
     };
+    if output_code == plugin::MISSING_FRAMES {
+        let shown = (missing_count as usize).min(missing_frames.len());
+        error!(
+            "{} frames missing, starting with {:?}",
+            missing_count,
+            &missing_frames[..shown]
+        );
+        return Err(());
+    }
+    if output_code == plugin::ENCODE_FAILED {
+        error!("Failed to encode video {}", output_filename);
+        return Err(());
+    } else if output_code < 0 {
+        error!(
+            "Failed to assemble video {}: {}",
+            output_filename, -output_code
+        );
+        return Err(());
+    }
 
     info!("Finished Encoding Video : {}", output_filename);
 
+    unsafe { plugin::close_session(session_id) };
+
     Ok(())
 }
 